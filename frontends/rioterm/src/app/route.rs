@@ -128,6 +128,7 @@ impl Route {
         let sugarloaf_renderer = SugarloafRenderer {
             power_preference,
             backend,
+            ..SugarloafRenderer::default()
         };
 
         let padding_y_bottom = padding_bottom_from_config(&config);
@@ -0,0 +1,64 @@
+struct BloomUniforms {
+    // Offset between two taps of the blur, in uv
+    direction: vec2<f32>,
+    // Luminance under which pixels don't glow
+    threshold: f32,
+    intensity: f32,
+    // Blur radius in pixels
+    radius: f32,
+    // Keeps the struct 32 bytes, a vec3 would be 16-byte aligned
+    _padding0: f32,
+    _padding1: vec2<f32>,
+}
+
+@group(0) @binding(0) var<uniform> bloom: BloomUniforms;
+@group(0) @binding(1) var source: texture_2d<f32>;
+@group(0) @binding(2) var source_sampler: sampler;
+@group(0) @binding(3) var glow: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+// Keeps the part of the color above the threshold
+fn bright(color: vec4<f32>) -> vec3<f32> {
+    let luminance = dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+    return color.rgb * max(luminance - bloom.threshold, 0.0) / max(luminance, 0.0001);
+}
+
+// One direction of a separable gaussian blur over the bright pixels
+@fragment
+fn fs_blur(input: VertexOutput) -> @location(0) vec4<f32> {
+    let taps = i32(ceil(bloom.radius));
+    let sigma = max(bloom.radius * 0.5, 0.0001);
+    var sum = vec3<f32>(0.0);
+    var weights = 0.0;
+    for (var i = -taps; i <= taps; i++) {
+        let offset = f32(i);
+        let weight = exp(-(offset * offset) / (2.0 * sigma * sigma));
+        let uv = input.uv + bloom.direction * offset;
+        sum += bright(textureSampleLevel(source, source_sampler, uv, 0.0)) * weight;
+        weights += weight;
+    }
+    return vec4<f32>(sum / weights, 1.0);
+}
+
+// Adds the blurred bright pixels on top of the source, premultiplied
+@fragment
+fn fs_composite(input: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(source, source_sampler, input.uv, 0.0);
+    let light = textureSampleLevel(glow, source_sampler, input.uv, 0.0).rgb
+        * bloom.intensity;
+    let alpha = max(color.a, max(light.r, max(light.g, light.b)));
+    return vec4<f32>(min(color.rgb + light, vec3<f32>(1.0)), min(alpha, 1.0));
+}
@@ -0,0 +1,401 @@
+use crate::components::post_process::Stage;
+use crate::context::Context;
+use bytemuck::{Pod, Zeroable};
+use fnv::FnvHashMap;
+use std::{borrow::Cow, mem};
+
+/// Largest blur radius in pixels, each pixel samples twice that many taps
+/// per direction.
+pub const MAX_RADIUS: f32 = 32.0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Zeroable, Pod)]
+struct Uniforms {
+    direction: [f32; 2],
+    threshold: f32,
+    intensity: f32,
+    radius: f32,
+    _padding: [f32; 3],
+}
+
+impl Uniforms {
+    fn new(bloom: &Bloom, direction: [f32; 2]) -> Self {
+        Self {
+            direction,
+            threshold: bloom.threshold,
+            intensity: bloom.intensity,
+            radius: bloom.radius,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+struct Pipelines {
+    blur: wgpu::RenderPipeline,
+    composite: wgpu::RenderPipeline,
+}
+
+struct Targets {
+    source: wgpu::Texture,
+    source_view: wgpu::TextureView,
+    blurred_view: wgpu::TextureView,
+    glow_view: wgpu::TextureView,
+    horizontal: wgpu::BindGroup,
+    vertical: wgpu::BindGroup,
+    composite: wgpu::BindGroup,
+}
+
+/// Makes bright pixels glow. The frame rendered into `source_view` is
+/// thresholded and blurred horizontally, then vertically, into two
+/// intermediate textures, and the result is added back on top of it when
+/// drawn to the target.
+pub struct Bloom {
+    threshold: f32,
+    intensity: f32,
+    radius: f32,
+    horizontal: wgpu::Buffer,
+    vertical: wgpu::Buffer,
+    current_uniforms: [Uniforms; 2],
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    pipelines: FnvHashMap<wgpu::TextureFormat, Pipelines>,
+    targets: Option<Targets>,
+}
+
+impl Bloom {
+    pub fn new(context: &Context, threshold: f32, intensity: f32, radius: f32) -> Self {
+        let device = &context.device;
+        let uniform_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let horizontal = uniform_buffer("bloom::Uniforms::horizontal");
+        let vertical = uniform_buffer("bloom::Uniforms::vertical");
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("bloom::BindGroupLayout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+                            ),
+                        },
+                        count: None,
+                    },
+                    texture_entry(1),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(
+                            wgpu::SamplerBindingType::Filtering,
+                        ),
+                        count: None,
+                    },
+                    texture_entry(3),
+                ],
+            });
+
+        let pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("bloom::PipelineLayout"),
+                push_constant_ranges: &[],
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("bloom::Shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("bloom.wgsl"))),
+        });
+
+        let mut bloom = Self {
+            threshold: 0.0,
+            intensity: 0.0,
+            radius: 0.0,
+            horizontal,
+            vertical,
+            current_uniforms: [Uniforms::zeroed(); 2],
+            sampler,
+            bind_group_layout,
+            pipeline_layout,
+            shader,
+            pipelines: FnvHashMap::default(),
+            targets: None,
+        };
+        bloom.set_params(threshold, intensity, radius);
+        bloom
+    }
+
+    /// `threshold` is the luminance, from `0.0` to `1.0`, above which pixels
+    /// glow, `intensity` scales the glow and `radius` is how far it spreads
+    /// in pixels, up to [`MAX_RADIUS`].
+    pub fn set_params(&mut self, threshold: f32, intensity: f32, radius: f32) {
+        self.threshold = threshold.clamp(0.0, 1.0);
+        self.intensity = intensity.max(0.0);
+        self.radius = radius.clamp(0.0, MAX_RADIUS);
+    }
+
+    #[inline]
+    pub fn params(&self) -> (f32, f32, f32) {
+        (self.threshold, self.intensity, self.radius)
+    }
+
+    fn create_target(
+        ctx: &Context,
+        size: wgpu::Extent3d,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> wgpu::Texture {
+        ctx.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+    }
+
+    fn create_bind_group(
+        &self,
+        ctx: &Context,
+        uniforms: &wgpu::Buffer,
+        source: &wgpu::TextureView,
+        glow: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("bloom::BindGroup"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniforms.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(glow),
+                },
+            ],
+        })
+    }
+
+    fn create_pipeline(
+        ctx: &Context,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+        entry_point: &str,
+        blend: Option<wgpu::BlendState>,
+    ) -> wgpu::RenderPipeline {
+        ctx.device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("bloom::Pipeline"),
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+                cache: None,
+            })
+    }
+}
+
+impl Stage for Bloom {
+    fn source_view(
+        &mut self,
+        ctx: &Context,
+        format: wgpu::TextureFormat,
+    ) -> &wgpu::TextureView {
+        let size = wgpu::Extent3d {
+            width: (ctx.size.width as u32).max(1),
+            height: (ctx.size.height as u32).max(1),
+            depth_or_array_layers: 1,
+        };
+        let outdated = self.targets.as_ref().map_or(true, |targets| {
+            targets.source.size() != size || targets.source.format() != format
+        });
+        if outdated {
+            let source = Self::create_target(ctx, size, format, "bloom::Source");
+            let blurred = Self::create_target(ctx, size, format, "bloom::Blurred");
+            let glow = Self::create_target(ctx, size, format, "bloom::Glow");
+            let source_view = source.create_view(&wgpu::TextureViewDescriptor::default());
+            let blurred_view =
+                blurred.create_view(&wgpu::TextureViewDescriptor::default());
+            let glow_view = glow.create_view(&wgpu::TextureViewDescriptor::default());
+            // The blur doesn't read `glow`, it's bound to a texture the pass
+            // doesn't render into to keep the layout shared
+            let horizontal =
+                self.create_bind_group(ctx, &self.horizontal, &source_view, &source_view);
+            let vertical =
+                self.create_bind_group(ctx, &self.vertical, &blurred_view, &blurred_view);
+            let composite =
+                self.create_bind_group(ctx, &self.horizontal, &source_view, &glow_view);
+            self.targets = Some(Targets {
+                source,
+                source_view,
+                blurred_view,
+                glow_view,
+                horizontal,
+                vertical,
+                composite,
+            });
+        }
+
+        &self.targets.as_ref().unwrap().source_view
+    }
+
+    fn render(
+        &mut self,
+        ctx: &Context,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        let Some(targets) = &self.targets else {
+            return;
+        };
+
+        let width = targets.source.width() as f32;
+        let height = targets.source.height() as f32;
+        let mut vertical = Uniforms::new(self, [0.0, 1.0 / height]);
+        // The vertical pass blurs pixels which are already thresholded
+        vertical.threshold = 0.0;
+        let uniforms = [Uniforms::new(self, [1.0 / width, 0.0]), vertical];
+        for (index, buffer) in [&self.horizontal, &self.vertical].into_iter().enumerate()
+        {
+            if uniforms[index] != self.current_uniforms[index] {
+                ctx.queue
+                    .write_buffer(buffer, 0, bytemuck::bytes_of(&uniforms[index]));
+            }
+        }
+        self.current_uniforms = uniforms;
+
+        let (layout, shader) = (&self.pipeline_layout, &self.shader);
+        let pipelines = self.pipelines.entry(format).or_insert_with(|| Pipelines {
+            blur: Self::create_pipeline(ctx, layout, shader, format, "fs_blur", None),
+            composite: Self::create_pipeline(
+                ctx,
+                layout,
+                shader,
+                format,
+                "fs_composite",
+                Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+            ),
+        });
+
+        let passes = [
+            (
+                &pipelines.blur,
+                &targets.horizontal,
+                &targets.blurred_view,
+                wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            ),
+            (
+                &pipelines.blur,
+                &targets.vertical,
+                &targets.glow_view,
+                wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            ),
+            (&pipelines.composite, &targets.composite, view, load),
+        ];
+        for (pipeline, bind_group, view, load) in passes {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("bloom::render"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uniforms_alignment() {
+        assert_eq!(mem::size_of::<Uniforms>(), 32);
+    }
+
+    #[test]
+    fn test_shader_is_valid() {
+        use wgpu::naga;
+
+        let module = naga::front::wgsl::parse_str(include_str!("bloom.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+}
@@ -24,3 +24,175 @@ pub fn orthographic_projection(width: f32, height: f32) -> [f32; 16] {
         1.0,
     ]
 }
+
+/// Same as `orthographic_projection` with the origin at the bottom left and
+/// y growing upwards.
+pub fn orthographic_projection_flipped(width: f32, height: f32) -> [f32; 16] {
+    [
+        2.0 / width,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        2.0 / height,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        -1.0,
+        -1.0,
+        0.0,
+        1.0,
+    ]
+}
+
+pub const IDENTITY_MATRIX: [f32; 16] = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+/// Multiplies two column-major 4x4 matrices (`a * b`).
+#[inline]
+pub fn multiply_matrix(a: &[f32; 16], b: &[f32; 16]) -> [f32; 16] {
+    let mut out = [0.0; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            out[col * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[col * 4 + k]).sum();
+        }
+    }
+    out
+}
+
+/// Clockwise rotation applied to the whole rendered output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Rotation {
+    /// Size to lay content out at for a `width` x `height` target, which
+    /// quarter turns swap.
+    #[inline]
+    pub fn content_size(self, width: f32, height: f32) -> (f32, f32) {
+        match self {
+            Rotation::None | Rotation::Rotate180 => (width, height),
+            Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+        }
+    }
+}
+
+/// Builds a column-major matrix rotating content laid out at
+/// `rotation.content_size(width, height)` onto a `width` x `height` target,
+/// center to center.
+#[inline]
+pub fn rotation_matrix(rotation: Rotation, width: f32, height: f32) -> [f32; 16] {
+    let (cos, sin) = match rotation {
+        Rotation::None => return IDENTITY_MATRIX,
+        Rotation::Rotate90 => (0.0, 1.0),
+        Rotation::Rotate180 => (-1.0, 0.0),
+        Rotation::Rotate270 => (0.0, -1.0),
+    };
+    let (content_width, content_height) = rotation.content_size(width, height);
+    let (cx, cy) = (content_width / 2.0, content_height / 2.0);
+    [
+        cos,
+        sin,
+        0.0,
+        0.0,
+        -sin,
+        cos,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        width / 2.0 - cos * cx + sin * cy,
+        height / 2.0 - sin * cx - cos * cy,
+        0.0,
+        1.0,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiply_matrix_identity() {
+        let projection = orthographic_projection(800., 600.);
+        assert_eq!(multiply_matrix(&projection, &IDENTITY_MATRIX), projection);
+        assert_eq!(multiply_matrix(&IDENTITY_MATRIX, &projection), projection);
+    }
+
+    #[test]
+    fn test_multiply_matrix_translation() {
+        let mut translate = IDENTITY_MATRIX;
+        translate[12] = 10.0;
+        translate[13] = 20.0;
+        let result = multiply_matrix(&translate, &translate);
+        assert_eq!(result[12], 20.0);
+        assert_eq!(result[13], 40.0);
+        assert_eq!(result[0], 1.0);
+    }
+
+    fn transform_point(m: &[f32; 16], x: f32, y: f32) -> (f32, f32) {
+        (m[0] * x + m[4] * y + m[12], m[1] * x + m[5] * y + m[13])
+    }
+
+    #[test]
+    fn test_orthographic_projection_flipped() {
+        let m = orthographic_projection(800., 600.);
+        assert_eq!(transform_point(&m, 0., 0.), (-1., 1.));
+        assert_eq!(transform_point(&m, 800., 600.), (1., -1.));
+
+        let m = orthographic_projection_flipped(800., 600.);
+        assert_eq!(transform_point(&m, 0., 0.), (-1., -1.));
+        assert_eq!(transform_point(&m, 800., 600.), (1., 1.));
+        assert_eq!(transform_point(&m, 400., 150.), (0., -0.5));
+    }
+
+    #[test]
+    fn test_rotation_matrix() {
+        let m = rotation_matrix(Rotation::None, 800., 600.);
+        assert_eq!(m, IDENTITY_MATRIX);
+
+        let m = rotation_matrix(Rotation::Rotate180, 800., 600.);
+        assert_eq!(transform_point(&m, 0., 0.), (800., 600.));
+        assert_eq!(transform_point(&m, 400., 300.), (400., 300.));
+
+        // The center of the 600x800 content lands on the center of the target
+        let m = rotation_matrix(Rotation::Rotate90, 800., 600.);
+        assert_eq!(transform_point(&m, 300., 400.), (400., 300.));
+        // Moving right on the content moves down on screen
+        assert_eq!(transform_point(&m, 310., 400.), (400., 310.));
+
+        let m = rotation_matrix(Rotation::Rotate270, 800., 600.);
+        assert_eq!(transform_point(&m, 310., 400.), (400., 290.));
+    }
+
+    #[test]
+    fn test_quarter_turns_fill_non_square_targets() {
+        assert_eq!(Rotation::Rotate90.content_size(800., 600.), (600., 800.));
+        assert_eq!(Rotation::Rotate180.content_size(800., 600.), (800., 600.));
+
+        // Corners of the 600x800 content land on the corners of the target,
+        // top left first
+        let m = rotation_matrix(Rotation::Rotate90, 800., 600.);
+        assert_eq!(transform_point(&m, 0., 0.), (800., 0.));
+        assert_eq!(transform_point(&m, 600., 0.), (800., 600.));
+        assert_eq!(transform_point(&m, 600., 800.), (0., 600.));
+        assert_eq!(transform_point(&m, 0., 800.), (0., 0.));
+
+        let m = rotation_matrix(Rotation::Rotate270, 800., 600.);
+        assert_eq!(transform_point(&m, 0., 0.), (0., 600.));
+        assert_eq!(transform_point(&m, 600., 0.), (0., 0.));
+        assert_eq!(transform_point(&m, 600., 800.), (800., 0.));
+        assert_eq!(transform_point(&m, 0., 800.), (800., 600.));
+    }
+}
@@ -0,0 +1,21 @@
+// Appended to the post-process prelude. params.x is the scanline intensity
+// and params.y the curvature.
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    let scanline_intensity = post_process.params.x;
+    let curvature = post_process.params.y;
+
+    // Bend the coordinates away from the center, like the glass of a tube
+    var centered = input.uv * 2.0 - 1.0;
+    centered *= 1.0 + curvature * centered.yx * centered.yx;
+    let uv = centered * 0.5 + 0.5;
+    var color = textureSampleLevel(source, source_sampler, uv, 0.0);
+    if uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0 {
+        color = vec4<f32>(0.0);
+    }
+
+    // Darken every other row of pixels
+    let scanline = 0.5 + 0.5 * cos(uv.y * post_process.resolution.y * 3.14159265);
+    return vec4<f32>(color.rgb * (1.0 - scanline_intensity * scanline), color.a);
+}
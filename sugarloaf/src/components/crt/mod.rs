@@ -0,0 +1,93 @@
+use crate::components::post_process::{PostProcess, PostProcessShader};
+use crate::context::Context;
+
+/// Tunables of the CRT effect.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CrtParams {
+    /// How much darker the scanlines are, from `0.0` (none) to `1.0` (black).
+    pub scanline_intensity: f32,
+    /// Amount of barrel distortion, `0.0` keeps the image flat.
+    pub curvature: f32,
+}
+
+impl Default for CrtParams {
+    fn default() -> Self {
+        Self {
+            scanline_intensity: 0.25,
+            curvature: 0.05,
+        }
+    }
+}
+
+impl CrtParams {
+    /// Values passed to the shader through `post_process.params`.
+    fn shader_params(self) -> [f32; 4] {
+        [
+            self.scanline_intensity.clamp(0.0, 1.0),
+            self.curvature.max(0.0),
+            0.0,
+            0.0,
+        ]
+    }
+}
+
+/// Post-process pass that draws a texture with scanlines and curvature,
+/// built on the same interface as user provided post-process shaders.
+pub struct CrtPass {
+    params: CrtParams,
+    pass: PostProcess,
+}
+
+impl CrtPass {
+    pub fn new(context: &Context, params: CrtParams) -> Self {
+        let shader = PostProcessShader::Fragment(include_str!("crt.wgsl").to_owned());
+        let mut pass =
+            PostProcess::new(context, &shader).expect("builtin CRT shader is valid");
+        pass.set_params(params.shader_params());
+        Self { params, pass }
+    }
+
+    pub fn set_params(&mut self, params: CrtParams) {
+        self.params = params;
+        self.pass.set_params(params.shader_params());
+    }
+
+    #[inline]
+    pub fn params(&self) -> CrtParams {
+        self.params
+    }
+
+    #[inline]
+    pub(crate) fn pass_mut(&mut self) -> &mut PostProcess {
+        &mut self.pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shader_params() {
+        let params = CrtParams {
+            scanline_intensity: 2.0,
+            curvature: -1.0,
+        };
+        assert_eq!(params.shader_params(), [1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_shader_is_valid() {
+        use crate::components::post_process::PRELUDE;
+        use wgpu::naga;
+
+        let source = format!("{PRELUDE}\n{}", include_str!("crt.wgsl"));
+        let module = naga::front::wgsl::parse_str(&source).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+}
@@ -1,5 +1,9 @@
+#[cfg(feature = "bloom")]
+pub mod bloom;
 pub mod core;
+pub mod crt;
 pub mod layer;
+pub mod post_process;
 pub mod rect;
 pub mod rich_text;
 pub mod text;
@@ -0,0 +1,553 @@
+use crate::context::Context;
+use bytemuck::{Pod, Zeroable};
+use fnv::FnvHashMap;
+use std::{borrow::Cow, fmt, mem};
+
+/// WGSL declarations every post-process shader is built on: the uniforms at
+/// binding 0, the source texture at binding 1, its sampler at binding 2 and
+/// a `vs_main` drawing one full screen triangle with `VertexOutput.uv`
+/// going from `(0, 0)` top left to `(1, 1)` bottom right.
+pub const PRELUDE: &str = include_str!("prelude.wgsl");
+
+/// Source of a post-process shader.
+#[derive(Clone, Debug)]
+pub enum PostProcessShader {
+    /// WGSL defining only `fs_main`, appended to [`PRELUDE`].
+    Fragment(String),
+    /// A complete WGSL module, with `vs_main` and `fs_main` entry points and
+    /// the bindings of [`PRELUDE`].
+    Wgsl(String),
+}
+
+impl PostProcessShader {
+    fn source(&self) -> Cow<'_, str> {
+        match self {
+            PostProcessShader::Fragment(fragment) => {
+                Cow::Owned(format!("{PRELUDE}\n{fragment}"))
+            }
+            PostProcessShader::Wgsl(source) => Cow::Borrowed(source),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostProcessError {
+    /// The WGSL could not be parsed.
+    Parse(String),
+    /// The module is invalid or doesn't follow the post-process interface.
+    Validation(String),
+}
+
+impl fmt::Display for PostProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(message) => write!(f, "post-process parse error: {message}"),
+            Self::Validation(message) => {
+                write!(f, "post-process validation error: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PostProcessError {}
+
+/// Checks `source` before it reaches the device, which would only report
+/// errors through its uncaptured error handler, once the pipeline is built
+/// on the first `render`. Besides parsing and validating the module, the
+/// resources its entry points use are checked against the bindings of
+/// [`PRELUDE`].
+#[cfg(not(target_arch = "wasm32"))]
+fn validate(source: &str) -> Result<(), PostProcessError> {
+    use wgpu::naga;
+
+    let module = naga::front::wgsl::parse_str(source)
+        .map_err(|err| PostProcessError::Parse(err.emit_to_string(source)))?;
+    let info = naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .map_err(|err| PostProcessError::Validation(err.emit_to_string(source)))?;
+
+    for (name, stage) in [
+        ("vs_main", naga::ShaderStage::Vertex),
+        ("fs_main", naga::ShaderStage::Fragment),
+    ] {
+        if !module
+            .entry_points
+            .iter()
+            .any(|entry| entry.name == name && entry.stage == stage)
+        {
+            return Err(PostProcessError::Validation(format!(
+                "missing {stage:?} entry point `{name}`"
+            )));
+        }
+    }
+
+    for (index, entry) in module.entry_points.iter().enumerate() {
+        let uses = info.get_entry_point(index);
+        for (handle, global) in module.global_variables.iter() {
+            let Some(binding) = &global.binding else {
+                continue;
+            };
+            if uses[handle].is_empty() {
+                continue;
+            }
+            let name = global.name.as_deref().unwrap_or("_");
+            let inner = &module.types[global.ty].inner;
+            let matches = binding.group == 0
+                && match binding.binding {
+                    0 => {
+                        global.space == naga::AddressSpace::Uniform
+                            && inner.size(module.to_ctx()) as usize
+                                <= mem::size_of::<Uniforms>()
+                    }
+                    1 => matches!(
+                        inner,
+                        naga::TypeInner::Image {
+                            dim: naga::ImageDimension::D2,
+                            arrayed: false,
+                            class: naga::ImageClass::Sampled {
+                                kind: naga::ScalarKind::Float,
+                                multi: false,
+                            },
+                        }
+                    ),
+                    2 => matches!(inner, naga::TypeInner::Sampler { comparison: false }),
+                    _ => false,
+                };
+            if !matches {
+                return Err(PostProcessError::Validation(format!(
+                    "`{name}` at @group({}) @binding({}) of `{}` doesn't match the \
+                     post-process bindings",
+                    binding.group, binding.binding, entry.name
+                )));
+            }
+        }
+        // Nothing is bound to the vertex stage, the triangle comes from
+        // `vertex_index`
+        if entry.stage == naga::ShaderStage::Vertex
+            && entry.function.arguments.iter().any(|argument| {
+                matches!(argument.binding, Some(naga::Binding::Location { .. }))
+            })
+        {
+            return Err(PostProcessError::Validation(format!(
+                "`{}` takes vertex inputs, which post-process shaders don't get",
+                entry.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn validate(_source: &str) -> Result<(), PostProcessError> {
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Zeroable, Pod)]
+struct Uniforms {
+    resolution: [f32; 2],
+    _padding: [f32; 2],
+    params: [f32; 4],
+}
+
+struct Source {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Full screen pass drawing an intermediate texture into the final target
+/// through a custom fragment shader. Render into `source_view` first, then
+/// call `render`.
+pub struct PostProcess {
+    params: [f32; 4],
+    uniforms: wgpu::Buffer,
+    current_uniforms: Uniforms,
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    pipelines: FnvHashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+    source: Option<Source>,
+}
+
+impl PostProcess {
+    /// Validates and compiles `shader`.
+    pub fn new(
+        context: &Context,
+        shader: &PostProcessShader,
+    ) -> Result<Self, PostProcessError> {
+        let source = shader.source();
+        validate(&source)?;
+
+        let device = &context.device;
+        let current_uniforms = Uniforms::zeroed();
+        let uniforms = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("post_process::Uniforms"),
+            size: mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("post_process::BindGroupLayout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX
+                            | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+                            ),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float {
+                                filterable: true,
+                            },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(
+                            wgpu::SamplerBindingType::Filtering,
+                        ),
+                        count: None,
+                    },
+                ],
+            });
+
+        let pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("post_process::PipelineLayout"),
+                push_constant_ranges: &[],
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("post_process::Shader"),
+            source: wgpu::ShaderSource::Wgsl(source),
+        });
+
+        Ok(Self {
+            params: [0.0; 4],
+            uniforms,
+            current_uniforms,
+            sampler,
+            bind_group_layout,
+            pipeline_layout,
+            shader,
+            pipelines: FnvHashMap::default(),
+            source: None,
+        })
+    }
+
+    /// Sets the values the shader reads from `post_process.params`.
+    pub fn set_params(&mut self, params: [f32; 4]) {
+        self.params = params;
+    }
+
+    #[inline]
+    pub fn params(&self) -> [f32; 4] {
+        self.params
+    }
+
+    /// Returns the texture to render the frame into before calling `render`,
+    /// (re)creating it when the context size or `format` changed.
+    pub fn source_view(
+        &mut self,
+        ctx: &Context,
+        format: wgpu::TextureFormat,
+    ) -> &wgpu::TextureView {
+        let size = wgpu::Extent3d {
+            width: (ctx.size.width as u32).max(1),
+            height: (ctx.size.height as u32).max(1),
+            depth_or_array_layers: 1,
+        };
+        let outdated = self.source.as_ref().map_or(true, |source| {
+            source.texture.size() != size || source.texture.format() != format
+        });
+        if outdated {
+            let texture = ctx.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("post_process::Source"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            let bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("post_process::BindGroup"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniforms.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+            });
+            self.source = Some(Source {
+                texture,
+                view,
+                bind_group,
+            });
+        }
+
+        &self.source.as_ref().unwrap().view
+    }
+
+    /// Draws the content of the source texture into `view` through the
+    /// shader, blended over the target once loaded with `load`. Colors are
+    /// premultiplied, as rendered into the source and output by the shader.
+    pub fn render(
+        &mut self,
+        ctx: &Context,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        let Some(source) = &self.source else {
+            return;
+        };
+
+        let uniforms = Uniforms {
+            resolution: [
+                source.texture.width() as f32,
+                source.texture.height() as f32,
+            ],
+            _padding: [0.0; 2],
+            params: self.params,
+        };
+        if uniforms != self.current_uniforms {
+            ctx.queue
+                .write_buffer(&self.uniforms, 0, bytemuck::bytes_of(&uniforms));
+            self.current_uniforms = uniforms;
+        }
+
+        let pipeline = self.pipelines.entry(format).or_insert_with(|| {
+            ctx.device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("post_process::Pipeline"),
+                    layout: Some(&self.pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &self.shader,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &self.shader,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format,
+                            blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                    cache: None,
+                })
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("post_process::render"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, &source.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
+
+/// A full screen pass that reads the frame from its own texture, so passes
+/// can be chained: each one draws into the `source_view` of the next.
+pub(crate) trait Stage {
+    fn source_view(
+        &mut self,
+        ctx: &Context,
+        format: wgpu::TextureFormat,
+    ) -> &wgpu::TextureView;
+
+    fn render(
+        &mut self,
+        ctx: &Context,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    );
+}
+
+impl Stage for PostProcess {
+    #[inline]
+    fn source_view(
+        &mut self,
+        ctx: &Context,
+        format: wgpu::TextureFormat,
+    ) -> &wgpu::TextureView {
+        PostProcess::source_view(self, ctx, format)
+    }
+
+    #[inline]
+    fn render(
+        &mut self,
+        ctx: &Context,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        PostProcess::render(self, ctx, encoder, view, format, load)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uniforms_alignment() {
+        assert_eq!(mem::size_of::<Uniforms>() % 16, 0);
+    }
+
+    #[test]
+    fn test_validate_fragment() {
+        let shader = PostProcessShader::Fragment(String::from(
+            "@fragment
+            fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+                let color = textureSampleLevel(source, source_sampler, input.uv, 0.0);
+                return color * post_process.params.x;
+            }",
+        ));
+        assert_eq!(validate(&shader.source()), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let broken = PostProcessShader::Fragment(String::from("fn fs_main( {"));
+        assert!(matches!(
+            validate(&broken.source()),
+            Err(PostProcessError::Parse(_))
+        ));
+
+        let missing = PostProcessShader::Fragment(String::new());
+        assert_eq!(
+            validate(&missing.source()),
+            Err(PostProcessError::Validation(String::from(
+                "missing Fragment entry point `fs_main`"
+            )))
+        );
+
+        let mistyped = PostProcessShader::Fragment(String::from(
+            "@fragment
+            fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+                return input.uv;
+            }",
+        ));
+        assert!(matches!(
+            validate(&mistyped.source()),
+            Err(PostProcessError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_bindings() {
+        let module = |declarations: &str, vertex_input: &str, sample: &str| {
+            PostProcessShader::Wgsl(format!(
+                "{declarations}
+                @vertex
+                fn vs_main({vertex_input}@builtin(vertex_index) index: u32)
+                    -> @builtin(position) vec4<f32> {{
+                    return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+                }}
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {{
+                    return {sample};
+                }}"
+            ))
+        };
+        let sampled = "textureSampleLevel(source, source_sampler, vec2<f32>(0.0), 0.0)";
+        let bindings = "@group(0) @binding(1) var source: texture_2d<f32>;
+            @group(0) @binding(2) var source_sampler: sampler;";
+        assert_eq!(validate(&module(bindings, "", sampled).source()), Ok(()));
+
+        // Unused declarations aren't bound, so they don't matter
+        let unused = "@group(0) @binding(3) var<uniform> extra: vec4<f32>;";
+        let shader = module(&format!("{bindings}\n{unused}"), "", sampled);
+        assert_eq!(validate(&shader.source()), Ok(()));
+
+        let uint = "@group(0) @binding(1) var source: texture_2d<u32>;";
+        let shader = module(uint, "", "vec4<f32>(textureLoad(source, vec2<i32>(0), 0))");
+        assert_eq!(
+            validate(&shader.source()),
+            Err(PostProcessError::Validation(String::from(
+                "`source` at @group(0) @binding(1) of `fs_main` doesn't match the \
+                 post-process bindings"
+            )))
+        );
+
+        let extra = "@group(0) @binding(3) var<uniform> extra: vec4<f32>;";
+        let shader = module(extra, "", "extra");
+        assert!(matches!(
+            validate(&shader.source()),
+            Err(PostProcessError::Validation(_))
+        ));
+
+        let shader = module(bindings, "@location(0) position: vec2<f32>, ", sampled);
+        assert!(matches!(
+            validate(&shader.source()),
+            Err(PostProcessError::Validation(_))
+        ));
+    }
+}
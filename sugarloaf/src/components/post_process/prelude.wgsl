@@ -0,0 +1,32 @@
+// Interface shared by every post-process shader. Fragment snippets are
+// appended to this prelude and must define:
+//
+// @fragment
+// fn fs_main(input: VertexOutput) -> @location(0) vec4<f32>
+
+struct PostProcessInput {
+    // Size of the source texture in pixels
+    resolution: vec2<f32>,
+    _padding: vec2<f32>,
+    // Free form parameters set with `PostProcess::set_params`
+    params: vec4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> post_process: PostProcessInput;
+@group(0) @binding(1) var source: texture_2d<f32>;
+@group(0) @binding(2) var source_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// A single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
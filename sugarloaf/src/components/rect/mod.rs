@@ -1,4 +1,4 @@
-use crate::components::core::orthographic_projection;
+use crate::components::core::{orthographic_projection, IDENTITY_MATRIX};
 use crate::context::Context;
 use bytemuck::{Pod, Zeroable};
 use std::{borrow::Cow, mem};
@@ -11,32 +11,31 @@ const MAX_INSTANCES: usize = 5_000;
 struct Uniforms {
     transform: [f32; 16],
     scale: f32,
-    _padding: [f32; 3],
+    _padding: f32,
+    viewport: [f32; 2],
 }
 
 impl Uniforms {
-    fn new(transformation: [f32; 16], scale: f32) -> Uniforms {
+    fn new(transformation: [f32; 16], scale: f32, viewport: [f32; 2]) -> Uniforms {
         Self {
             transform: transformation,
             scale,
             // Ref: https://github.com/iced-rs/iced/blob/bc62013b6cde52174bf4c4286939cf170bfa7760/wgpu/src/quad.rs#LL295C6-L296C68
             // Uniforms must be aligned to their largest member,
             // this uses a mat4x4<f32> which aligns to 16, so align to that
-            _padding: [0.0; 3],
+            _padding: 0.0,
+            viewport,
         }
     }
 }
 
 impl Default for Uniforms {
     fn default() -> Self {
-        let identity_matrix: [f32; 16] = [
-            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
-            1.0,
-        ];
         Self {
-            transform: identity_matrix,
+            transform: IDENTITY_MATRIX,
             scale: 1.0,
-            _padding: [0.0; 3],
+            _padding: 0.0,
+            viewport: [1.0; 2],
         }
     }
 }
@@ -70,6 +69,43 @@ unsafe impl bytemuck::Zeroable for Rect {}
 #[allow(unsafe_code)]
 unsafe impl bytemuck::Pod for Rect {}
 
+/// A [`Rect`] in half the bytes, with its position and size normalized to
+/// the viewport and its color to `0..=1`, each in 16 bits. Only the part of
+/// the rect inside the viewport is packed.
+#[derive(Debug, PartialEq, Default, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct PackedRect {
+    pub position: [u16; 2],
+    pub size: [u16; 2],
+    pub color: [u16; 4],
+}
+
+impl PackedRect {
+    /// Packs `rect` relative to a viewport of `viewport` logical pixels.
+    pub fn new(rect: &Rect, viewport: [f32; 2]) -> Self {
+        let unorm = |value: f32, range: f32| {
+            if range <= 0. {
+                return 0;
+            }
+            ((value / range).clamp(0., 1.) * u16::MAX as f32).round() as u16
+        };
+        // Normalized positions can't go past the edges, so the rect is
+        // clipped to them instead of moved
+        let clip = |position: f32, size: f32, range: f32| {
+            let start = position.max(0.).min(range);
+            let end = (position + size).max(0.).min(range);
+            (start, (end - start).max(0.))
+        };
+        let (x, width) = clip(rect.position[0], rect.size[0], viewport[0]);
+        let (y, height) = clip(rect.position[1], rect.size[1], viewport[1]);
+        Self {
+            position: [unorm(x, viewport[0]), unorm(y, viewport[1])],
+            size: [unorm(width, viewport[0]), unorm(height, viewport[1])],
+            color: rect.color.map(|channel| unorm(channel, 1.)),
+        }
+    }
+}
+
 // TODO: Implement square
 fn create_vertices_rect() -> Vec<Vertex> {
     let vertex_data = [
@@ -103,7 +139,11 @@ pub struct RectBrush {
     bind_group: wgpu::BindGroup,
     transform: wgpu::Buffer,
     pipeline: wgpu::RenderPipeline,
+    packed_pipeline: wgpu::RenderPipeline,
     current_transform: [f32; 16],
+    viewport: [f32; 2],
+    packed: bool,
+    packed_instances: Vec<PackedRect>,
 }
 
 impl RectBrush {
@@ -111,11 +151,11 @@ impl RectBrush {
         let device = &context.device;
         let vertex_data = create_vertices_rect();
 
-        let transform = device.create_buffer(&wgpu::BufferDescriptor {
+        // Starts out valid, before the first `update_transform`
+        let transform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
-            size: mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+            contents: bytemuck::bytes_of(&Uniforms::default()),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
         });
 
         let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -173,56 +213,10 @@ impl RectBrush {
             source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("rect.wgsl"))),
         });
 
-        let vertex_buffers = [
-            wgpu::VertexBufferLayout {
-                array_stride: mem::size_of::<Vertex>() as u64,
-                step_mode: wgpu::VertexStepMode::Vertex,
-                attributes: &[wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: 0,
-                    shader_location: 0,
-                }],
-            },
-            wgpu::VertexBufferLayout {
-                array_stride: mem::size_of::<Rect>() as u64,
-                step_mode: wgpu::VertexStepMode::Instance,
-                attributes: &wgpu::vertex_attr_array!(
-                    1 => Float32x2,
-                    2 => Float32x4,
-                    3 => Float32x2,
-                ),
-            },
-        ];
-
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            cache: None,
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &vertex_buffers,
-            },
-            fragment: Some(wgpu::FragmentState {
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: context.format,
-                    blend: BLEND,
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                front_face: wgpu::FrontFace::Cw,
-                ..Default::default()
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState::default(),
-            multiview: None,
-        });
+        let pipeline =
+            create_pipeline(device, &pipeline_layout, &shader, context.format, false);
+        let packed_pipeline =
+            create_pipeline(device, &pipeline_layout, &shader, context.format, true);
 
         let instances = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Instances Buffer"),
@@ -239,27 +233,41 @@ impl RectBrush {
             bind_group,
             transform,
             pipeline,
-            current_transform: [0.0; 16],
+            packed_pipeline,
+            current_transform: IDENTITY_MATRIX,
+            viewport: Uniforms::default().viewport,
+            packed: false,
+            packed_instances: Vec::new(),
             instances,
         }
     }
 
+    /// Uploads rects as [`PackedRect`], halving the bandwidth of full
+    /// screen redraws at the cost of snapping them to 1/65535 of the
+    /// viewport. Colors are clamped to `0..=1`, keep it disabled for colors
+    /// brighter than SDR white. Disabled by default.
+    pub fn set_packed_instances(&mut self, enabled: bool) {
+        self.packed = enabled;
+    }
+
     #[inline]
     pub fn resize(&mut self, ctx: &mut Context) {
-        let transform: [f32; 16] =
-            orthographic_projection(ctx.size.width, ctx.size.height);
-        // device.push_error_scope(wgpu::ErrorFilter::Validation);
-        let scale = ctx.scale;
-        let queue = &mut ctx.queue;
-
-        if transform != self.current_transform {
-            let uniforms = Uniforms::new(transform, scale);
-
-            queue.write_buffer(&self.transform, 0, bytemuck::bytes_of(&uniforms));
+        self.update_transform(ctx);
+    }
 
-            self.current_transform = transform;
+    /// Writes the projection and scale of `ctx` to the uniform buffer when
+    /// they changed since the last call. `render` calls it first, so the
+    /// uniforms are current from the first frame on.
+    pub fn update_transform(&mut self, ctx: &mut Context) {
+        let uniforms = frame_uniforms(ctx.size.width, ctx.size.height, ctx.scale);
+        if uniforms.transform != self.current_transform
+            || uniforms.viewport != self.viewport
+        {
+            ctx.queue
+                .write_buffer(&self.transform, 0, bytemuck::bytes_of(&uniforms));
+            self.current_transform = uniforms.transform;
+            self.viewport = uniforms.viewport;
         }
-        // queue.write_buffer(&self.transform, 0, bytemuck::cast_slice(&IDENTITY_MATRIX));
     }
 
     #[inline]
@@ -278,7 +286,13 @@ impl RectBrush {
             return;
         }
 
-        rpass.set_pipeline(&self.pipeline);
+        self.update_transform(ctx);
+
+        if self.packed {
+            rpass.set_pipeline(&self.packed_pipeline);
+        } else {
+            rpass.set_pipeline(&self.pipeline);
+        }
         rpass.set_bind_group(0, &self.bind_group, &[]);
         rpass.set_index_buffer(self.index_buf.slice(..), wgpu::IndexFormat::Uint16);
         rpass.set_vertex_buffer(0, self.vertex_buf.slice(..));
@@ -289,9 +303,19 @@ impl RectBrush {
             let end = (i + MAX_INSTANCES).min(total);
             let amount = end - i;
 
-            let instance_bytes = bytemuck::cast_slice(&instances[i..end]);
-
-            queue.write_buffer(&self.instances, 0, instance_bytes);
+            if self.packed {
+                self.packed_instances.clear();
+                self.packed_instances.extend(
+                    instances[i..end]
+                        .iter()
+                        .map(|rect| PackedRect::new(rect, self.viewport)),
+                );
+                let instance_bytes = bytemuck::cast_slice(&self.packed_instances);
+                queue.write_buffer(&self.instances, 0, instance_bytes);
+            } else {
+                let instance_bytes = bytemuck::cast_slice(&instances[i..end]);
+                queue.write_buffer(&self.instances, 0, instance_bytes);
+            }
             rpass.draw_indexed(0..self.index_count as u32, 0, 0..amount as u32);
 
             i += MAX_INSTANCES;
@@ -300,3 +324,165 @@ impl RectBrush {
         // queue.submit(Some(encoder.finish()));
     }
 }
+
+/// Uniforms for a target of `width` by `height` physical pixels at `scale`.
+fn frame_uniforms(width: f32, height: f32, scale: f32) -> Uniforms {
+    let viewport = [width / scale, height / scale];
+    Uniforms::new(orthographic_projection(width, height), scale, viewport)
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    packed: bool,
+) -> wgpu::RenderPipeline {
+    let (entry_point, instance_buffer) = if packed {
+        (
+            "vs_packed",
+            wgpu::VertexBufferLayout {
+                array_stride: mem::size_of::<PackedRect>() as u64,
+                step_mode: wgpu::VertexStepMode::Instance,
+                attributes: &wgpu::vertex_attr_array!(
+                    1 => Unorm16x2,
+                    2 => Unorm16x2,
+                    3 => Unorm16x4,
+                ),
+            },
+        )
+    } else {
+        (
+            "vs_main",
+            wgpu::VertexBufferLayout {
+                array_stride: mem::size_of::<Rect>() as u64,
+                step_mode: wgpu::VertexStepMode::Instance,
+                attributes: &wgpu::vertex_attr_array!(
+                    1 => Float32x2,
+                    2 => Float32x4,
+                    3 => Float32x2,
+                ),
+            },
+        )
+    };
+    let vertex_buffers = [
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<Vertex>() as u64,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x2,
+                offset: 0,
+                shader_location: 0,
+            }],
+        },
+        instance_buffer,
+    ];
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        cache: None,
+        label: None,
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            module: shader,
+            entry_point,
+            buffers: &vertex_buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: BLEND,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            front_face: wgpu::FrontFace::Cw,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed_rect() {
+        let rect = Rect {
+            position: [100., 50.],
+            color: [1.0, 0.5, 0.0, 1.0],
+            size: [200., 400.],
+        };
+        let packed = PackedRect::new(&rect, [400., 800.]);
+        assert_eq!(mem::size_of::<PackedRect>() * 2, mem::size_of::<Rect>());
+        assert_eq!(packed.position, [16384, 4096]);
+        assert_eq!(packed.size, [32768, 32768]);
+        assert_eq!(packed.color, [65535, 32768, 0, 65535]);
+
+        // Unpacked the way the shader does, within a hundredth of a pixel
+        let unpack = |value: u16, range: f32| value as f32 / u16::MAX as f32 * range;
+        assert!((unpack(packed.position[0], 400.) - 100.).abs() < 0.01);
+        assert!((unpack(packed.size[1], 800.) - 400.).abs() < 0.01);
+
+        let offscreen = Rect {
+            position: [-10., 900.],
+            ..rect
+        };
+        assert_eq!(
+            PackedRect::new(&offscreen, [400., 800.]).position,
+            [0, 65535]
+        );
+
+        // Partly left of the viewport, only the visible part is kept
+        let clipped = PackedRect::new(
+            &Rect {
+                position: [-10., 50.],
+                size: [30., 400.],
+                ..rect
+            },
+            [400., 800.],
+        );
+        assert_eq!(clipped.position[0], 0);
+        assert!((unpack(clipped.size[0], 400.) - 20.).abs() < 0.01);
+        assert_eq!(clipped.size[1], packed.size[1]);
+        assert_eq!(clipped.color, packed.color);
+    }
+
+    #[test]
+    fn test_first_frame_has_a_valid_transform() {
+        let clip = |transform: &[f32; 16], [x, y]: [f32; 2]| {
+            [
+                transform[0] * x + transform[4] * y + transform[12],
+                transform[1] * x + transform[5] * y + transform[13],
+            ]
+        };
+        // Never all zeros, which collapses every rect to a point
+        assert_eq!(Uniforms::default().transform, IDENTITY_MATRIX);
+
+        let uniforms = frame_uniforms(800., 600., 2.);
+        assert_eq!(uniforms.viewport, [400., 300.]);
+        assert_eq!(clip(&uniforms.transform, [0., 0.]), [-1., 1.]);
+        assert_eq!(clip(&uniforms.transform, [800., 600.]), [1., -1.]);
+    }
+
+    #[test]
+    fn test_rect_shader_is_valid() {
+        use wgpu::naga;
+
+        let module = naga::front::wgsl::parse_str(include_str!("rect.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+        assert_eq!(mem::size_of::<Uniforms>(), 80);
+    }
+}
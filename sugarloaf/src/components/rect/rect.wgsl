@@ -1,6 +1,8 @@
 struct Globals {
     transform: mat4x4<f32>,
     scale: f32,
+    // Logical size the packed instances are relative to
+    viewport: vec2<f32>,
 }
 
 @group(0) @binding(0) var<uniform> globals: Globals;
@@ -10,12 +12,11 @@ struct VertexOutput {
     @location(0) color: vec4<f32>,
 };
 
-@vertex
-fn vs_main(
-    @location(0) vertex_position: vec2<f32>,
-    @location(1) in_pos: vec2<f32>,
-    @location(2) color: vec4<f32>,
-    @location(3) size: vec2<f32>,
+fn quad(
+    vertex_position: vec2<f32>,
+    in_pos: vec2<f32>,
+    color: vec4<f32>,
+    size: vec2<f32>,
 ) -> VertexOutput {
     var output: VertexOutput;
 
@@ -34,6 +35,33 @@ fn vs_main(
     return output;
 }
 
+@vertex
+fn vs_main(
+    @location(0) vertex_position: vec2<f32>,
+    @location(1) in_pos: vec2<f32>,
+    @location(2) color: vec4<f32>,
+    @location(3) size: vec2<f32>,
+) -> VertexOutput {
+    return quad(vertex_position, in_pos, color, size);
+}
+
+// Same as `vs_main` for `PackedRect` instances, whose position and size
+// are normalized to the viewport
+@vertex
+fn vs_packed(
+    @location(0) vertex_position: vec2<f32>,
+    @location(1) in_pos: vec2<f32>,
+    @location(2) size: vec2<f32>,
+    @location(3) color: vec4<f32>,
+) -> VertexOutput {
+    return quad(
+        vertex_position,
+        in_pos * globals.viewport,
+        color,
+        size * globals.viewport,
+    );
+}
+
 @fragment
 fn fs_main(output: VertexOutput) -> @location(0) vec4<f32> {
     return output.color;
@@ -11,6 +11,9 @@
 
 use crate::components::rich_text::image_cache::TextureId;
 use bytemuck::{Pod, Zeroable};
+use rustc_hash::{FxHashMap, FxHasher};
+use std::hash::Hasher;
+use std::ops::Range;
 
 /// Batch geometry vertex.
 #[repr(C)]
@@ -22,7 +25,7 @@ pub struct Vertex {
 }
 
 /// Rectangle with floating point coordinates.
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
 pub struct Rect {
     pub x: f32,
     pub y: f32,
@@ -40,6 +43,83 @@ impl Rect {
             height,
         }
     }
+
+    /// Aligns the rectangle to physical pixels, `scale` being the physical
+    /// pixels per unit of the rectangle: its position is rounded to the
+    /// nearest pixel and its size with `snap_size`, so thin lines stay sharp
+    /// and keep the same thickness wherever they are.
+    pub fn snap(&self, scale: f32) -> Rect {
+        Rect::new(
+            (self.x * scale).round() / scale,
+            (self.y * scale).round() / scale,
+            snap_size(self.width * scale) / scale,
+            snap_size(self.height * scale) / scale,
+        )
+    }
+
+    /// Returns the smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Returns the parts of the rectangle outside of `other`, as up to four
+    /// rectangles: full width bands above and below it, then the sides.
+    pub fn subtract(&self, other: &Rect) -> Vec<Rect> {
+        let (right, bottom) = (self.x + self.width, self.y + self.height);
+        let left = other.x.max(self.x);
+        let top = other.y.max(self.y);
+        let other_right = (other.x + other.width).min(right);
+        let other_bottom = (other.y + other.height).min(bottom);
+        if other_right <= left || other_bottom <= top {
+            return vec![*self];
+        }
+        [
+            Rect::new(self.x, self.y, self.width, top - self.y),
+            Rect::new(self.x, other_bottom, self.width, bottom - other_bottom),
+            Rect::new(self.x, top, left - self.x, other_bottom - top),
+            Rect::new(other_right, top, right - other_right, other_bottom - top),
+        ]
+        .into_iter()
+        .filter(|rect| rect.width > 0. && rect.height > 0.)
+        .collect()
+    }
+}
+
+/// Rounds a size in physical pixels to whole pixels, keeping at least one
+/// for anything not empty. Ties round down, so a 1px line at 1.5x takes one
+/// physical pixel rather than two.
+#[inline]
+pub fn snap_size(size: f32) -> f32 {
+    if size <= 0. {
+        return 0.;
+    }
+    (size - 0.5).ceil().max(1.)
+}
+
+/// Distances from each edge of an image, in pixels, such as the fixed
+/// borders of a nine-slice image.
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct Insets {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+impl Insets {
+    /// Creates insets of `value` on every edge.
+    pub fn uniform(value: f32) -> Self {
+        Self {
+            left: value,
+            top: value,
+            right: value,
+            bottom: value,
+        }
+    }
 }
 
 impl From<[f32; 4]> for Rect {
@@ -110,6 +190,16 @@ impl Batch {
         true
     }
 
+    /// Adds an untextured quad, see `push_quad`.
+    #[inline]
+    fn add_quad(&mut self, points: &[[f32; 2]; 4], depth: f32, color: &[f32; 4]) -> bool {
+        if !self.vertices.is_empty() && self.subpix {
+            return false;
+        }
+        self.push_quad(points, depth, 0., color, None);
+        true
+    }
+
     #[inline]
     fn push_rect(
         &mut self,
@@ -123,36 +213,35 @@ impl Batch {
         let y = rect.y;
         let w = rect.width;
         let h = rect.height;
+        let points = [[x, y], [x, y + h], [x + w, y + h], [x + w, y]];
+        self.push_quad(&points, depth, flags, color, coords);
+    }
+
+    /// Pushes a quad given its top left, bottom left, bottom right and top
+    /// right corners, in that order.
+    #[inline]
+    fn push_quad(
+        &mut self,
+        points: &[[f32; 2]; 4],
+        depth: f32,
+        flags: f32,
+        color: &[f32; 4],
+        coords: Option<&[f32; 4]>,
+    ) {
         const DEFAULT_COORDS: [f32; 4] = [0., 0., 1., 1.];
         let coords = coords.unwrap_or(&DEFAULT_COORDS);
         let l = coords[0];
         let t = coords[1];
         let r = coords[2];
         let b = coords[3];
-        let verts = [
-            Vertex {
-                pos: [x, y, depth, flags],
-                color: *color,
-                uv: [l, t],
-            },
-            Vertex {
-                pos: [x, y + h, depth, flags],
-                color: *color,
-                uv: [l, b],
-            },
-            Vertex {
-                pos: [x + w, y + h, depth, flags],
-                color: *color,
-                uv: [r, b],
-            },
-            Vertex {
-                pos: [x + w, y, depth, flags],
-                color: *color,
-                uv: [r, t],
-            },
-        ];
+        let uvs = [[l, t], [l, b], [r, b], [r, t]];
         let base = self.vertices.len() as u32;
-        self.vertices.extend_from_slice(&verts);
+        self.vertices
+            .extend(points.iter().zip(uvs).map(|(point, uv)| Vertex {
+                pos: [point[0], point[1], depth, flags],
+                color: *color,
+                uv,
+            }));
         self.indices.extend_from_slice(&[
             base,
             base + 1,
@@ -164,9 +253,10 @@ impl Batch {
     }
 
     #[inline]
-    fn build_display_list(&self, list: &mut DisplayList) {
+    fn build_display_list(&self, list: &mut DisplayList, transparent: bool) {
         let first_vertex = list.vertices.len() as u32;
         let first_index = list.indices.len() as u32;
+        let first_command = list.commands.len() as u32;
         list.vertices.extend_from_slice(&self.vertices);
         list.indices
             .extend(self.indices.iter().map(|i| *i + first_vertex));
@@ -176,33 +266,137 @@ impl Batch {
         if let Some(tex) = self.image {
             list.commands.push(Command::BindTexture(1, tex));
         }
-        list.indices_to_draw
-            .push((first_index, first_index + self.indices.len() as u32));
+        let pipeline = match (self.subpix, transparent) {
+            (true, _) => Pipeline::Subpixel,
+            (false, true) => Pipeline::Transparent,
+            (false, false) => Pipeline::Opaque,
+        };
+        list.draws.push(Draw {
+            pipeline,
+            vertices: first_vertex..list.vertices.len() as u32,
+            indices: first_index..list.indices.len() as u32,
+            commands: first_command..list.commands.len() as u32,
+            mask: self.mask,
+            image: self.image,
+        });
     }
 }
 
 pub struct BatchManager {
     batches: Vec<Batch>,
     opaque: Vec<Batch>,
+    highlight: Vec<Batch>,
     transparent: Vec<Batch>,
+    overlay: Vec<Batch>,
+    /// Vertices and indices of each batch of the previous frame, per list in
+    /// the order above, see `take_batch`.
+    previous: [Vec<(usize, usize)>; 4],
+    /// Greatest depth added since `reset`, where overlays are drawn.
+    max_depth: f32,
 }
 
+const OPAQUE: usize = 0;
+const HIGHLIGHT: usize = 1;
+const TRANSPARENT: usize = 2;
+const OVERLAY: usize = 3;
+
 impl BatchManager {
     pub fn new() -> Self {
         Self {
             batches: Vec::new(),
             opaque: Vec::new(),
+            highlight: Vec::new(),
             transparent: Vec::new(),
+            overlay: Vec::new(),
+            previous: Default::default(),
+            max_depth: 0.0,
+        }
+    }
+
+    /// Creates a manager with room for `rects` rects in both the opaque and
+    /// the transparent list.
+    pub fn with_capacity(rects: usize) -> Self {
+        let mut batches = Self::new();
+        batches.reserve(rects);
+        batches
+    }
+
+    /// Makes sure the first opaque and transparent batches can hold `rects`
+    /// rects each without reallocating. Later frames size their batches
+    /// after the previous one instead.
+    fn reserve(&mut self, rects: usize) {
+        while self.batches.len() < 2 {
+            self.batches.push(Batch::default());
+        }
+        let len = self.batches.len();
+        for batch in &mut self.batches[len - 2..] {
+            batch.vertices.reserve(rects * 4);
+            batch.indices.reserve(rects * 6);
+        }
+    }
+
+    /// Makes sure the batches plain rects go to can hold `opaque` opaque and
+    /// `transparent` transparent rects without reallocating.
+    pub fn reserve_rects(&mut self, opaque: usize, transparent: usize) {
+        for (list, rects) in [
+            (&mut self.opaque, opaque),
+            (&mut self.transparent, transparent),
+        ] {
+            if rects == 0 {
+                continue;
+            }
+            // Plain rects go to the first batch that isn't subpixel, or to a
+            // new one from the pool
+            match list
+                .iter_mut()
+                .find(|batch| batch.vertices.is_empty() || !batch.subpix)
+            {
+                Some(batch) => {
+                    batch.vertices.reserve(rects * 4);
+                    batch.indices.reserve(rects * 6);
+                }
+                None => {
+                    if let Some(batch) = self.batches.last_mut() {
+                        batch.vertices.reserve(rects * 4);
+                        batch.indices.reserve(rects * 6);
+                    }
+                }
+            }
         }
     }
 
+    /// Recycles every batch into the free pool. Batches are cleared, not
+    /// dropped, so their storage is reused by the next frame, and how much
+    /// each held is kept so the batch taking its place reserves as much.
     #[inline]
     pub fn reset(&mut self) {
+        for (previous, list) in self.previous.iter_mut().zip([
+            &self.opaque,
+            &self.highlight,
+            &self.transparent,
+            &self.overlay,
+        ]) {
+            previous.clear();
+            previous.extend(
+                list.iter()
+                    .map(|batch| (batch.vertices.len(), batch.indices.len())),
+            );
+        }
         self.batches.append(&mut self.opaque);
+        self.batches.append(&mut self.highlight);
         self.batches.append(&mut self.transparent);
+        self.batches.append(&mut self.overlay);
         for batch in &mut self.batches {
             batch.clear();
         }
+        self.max_depth = 0.0;
+    }
+
+    /// Greatest depth of the rects and quads added since `reset`, overlays
+    /// excluded.
+    #[inline]
+    pub fn max_depth(&self) -> f32 {
+        self.max_depth
     }
 
     #[inline]
@@ -215,6 +409,7 @@ impl BatchManager {
         mask: TextureId,
         subpix: bool,
     ) {
+        self.max_depth = self.max_depth.max(depth);
         for batch in &mut self.transparent {
             if batch.add_rect(rect, depth, color, Some(coords), None, Some(mask), subpix)
             {
@@ -242,6 +437,7 @@ impl BatchManager {
         image: TextureId,
         has_alpha: bool,
     ) {
+        self.max_depth = self.max_depth.max(depth);
         let transparent = has_alpha || color[3] != 1.0;
         if transparent {
             for batch in &mut self.transparent {
@@ -285,6 +481,7 @@ impl BatchManager {
 
     #[inline]
     pub fn add_rect(&mut self, rect: &Rect, depth: f32, color: &[f32; 4]) {
+        self.max_depth = self.max_depth.max(depth);
         let transparent = color[3] != 1.0;
         if transparent {
             for batch in &mut self.transparent {
@@ -303,33 +500,105 @@ impl BatchManager {
             .add_rect(rect, depth, color, None, None, None, false);
     }
 
+    /// Adds an untextured quad given its top left, bottom left, bottom right
+    /// and top right corners, for shapes that aren't axis aligned.
+    #[inline]
+    pub fn add_quad(&mut self, points: &[[f32; 2]; 4], depth: f32, color: &[f32; 4]) {
+        self.max_depth = self.max_depth.max(depth);
+        let transparent = color[3] != 1.0;
+        let batches = if transparent {
+            &mut self.transparent
+        } else {
+            &mut self.opaque
+        };
+        for batch in batches {
+            if batch.add_quad(points, depth, color) {
+                return;
+            }
+        }
+        self.alloc_batch(transparent).add_quad(points, depth, color);
+    }
+
+    /// Adds a rect that is submitted after every opaque batch and before the
+    /// transparent ones, so it covers backgrounds but stays beneath glyphs.
+    #[inline]
+    pub fn add_highlight_rect(&mut self, rect: &Rect, depth: f32, color: &[f32; 4]) {
+        self.max_depth = self.max_depth.max(depth);
+        if let Some(batch) = self.highlight.last_mut() {
+            if batch.add_rect(rect, depth, color, None, None, None, false) {
+                return;
+            }
+        }
+        let mut batch = self.take_batch(HIGHLIGHT, self.highlight.len());
+        batch.add_rect(rect, depth, color, None, None, None, false);
+        self.highlight.push(batch);
+    }
+
+    /// Adds a rect that is submitted after every opaque and transparent
+    /// batch, so it covers anything drawn in the same frame.
+    #[inline]
+    pub fn add_overlay_rect(&mut self, rect: &Rect, depth: f32, color: &[f32; 4]) {
+        if let Some(batch) = self.overlay.last_mut() {
+            if batch.add_rect(rect, depth, color, None, None, None, false) {
+                return;
+            }
+        }
+        let mut batch = self.take_batch(OVERLAY, self.overlay.len());
+        batch.add_rect(rect, depth, color, None, None, None, false);
+        self.overlay.push(batch);
+    }
+
     #[inline]
     pub fn build_display_list(&self, list: &mut DisplayList) {
         for batch in &self.opaque {
             if batch.vertices.is_empty() {
                 continue;
             }
-            batch.build_display_list(list);
+            batch.build_display_list(list, false);
+        }
+        for batch in &self.highlight {
+            if batch.vertices.is_empty() {
+                continue;
+            }
+            batch.build_display_list(list, true);
         }
         for batch in &self.transparent {
             if batch.vertices.is_empty() {
                 continue;
             }
-            batch.build_display_list(list);
+            batch.build_display_list(list, true);
+        }
+        for batch in &self.overlay {
+            if batch.vertices.is_empty() {
+                continue;
+            }
+            batch.build_display_list(list, true);
         }
     }
 
     #[inline]
     fn alloc_batch(&mut self, transparent: bool) -> &mut Batch {
-        let batch = self.batches.pop().unwrap_or_default();
         if transparent {
+            let batch = self.take_batch(TRANSPARENT, self.transparent.len());
             self.transparent.push(batch);
             self.transparent.last_mut().unwrap()
         } else {
+            let batch = self.take_batch(OPAQUE, self.opaque.len());
             self.opaque.push(batch);
             self.opaque.last_mut().unwrap()
         }
     }
+
+    /// Takes a batch from the pool for position `index` of `list`, with
+    /// room for what the batch there held in the previous frame.
+    fn take_batch(&mut self, list: usize, index: usize) -> Batch {
+        let mut batch = self.batches.pop().unwrap_or_default();
+        if let Some(&(vertices, indices)) = self.previous[list].get(index) {
+            batch.vertices.reserve(vertices);
+            batch.indices.reserve(indices);
+        }
+        batch
+    }
 }
 
 /// Resources and commands for drawing a composition.
@@ -337,7 +606,7 @@ impl BatchManager {
 pub struct DisplayList {
     vertices: Vec<Vertex>,
     indices: Vec<u32>,
-    indices_to_draw: Vec<(u32, u32)>,
+    draws: Vec<Draw>,
     commands: Vec<Command>,
 }
 
@@ -354,10 +623,11 @@ impl DisplayList {
         &self.vertices
     }
 
-    /// Returns the buffered indices to draw.
+    /// Returns the draws of the display list, in the order they have to be
+    /// submitted.
     #[inline]
-    pub fn indices_to_draw(&self) -> &[(u32, u32)] {
-        &self.indices_to_draw
+    pub fn draws(&self) -> &[Draw] {
+        &self.draws
     }
 
     /// Returns the buffered indices for the display list.
@@ -372,19 +642,523 @@ impl DisplayList {
         &self.commands
     }
 
+    /// Renders the batched geometry as an SVG document. Every quad becomes a
+    /// `rect` filled with its vertex color, or a `polygon` when it isn't axis
+    /// aligned; image and mask quads are approximated as filled shapes tagged
+    /// with a `data-kind` attribute.
+    pub fn to_svg(&self) -> String {
+        let mut width: f32 = 0.;
+        let mut height: f32 = 0.;
+        let mut body = String::new();
+        for quad in self.vertices.chunks_exact(4) {
+            let bounds = quad_bounds(quad);
+            width = width.max(bounds.x + bounds.width);
+            height = height.max(bounds.y + bounds.height);
+
+            let kind = match quad[0].pos[3] as i32 {
+                1 => "image",
+                2 => "mask",
+                3 => "image-mask",
+                _ => "rect",
+            };
+            let [r, g, b, a] = quad[0].color;
+            let fill = format!(
+                "fill=\"rgb({},{},{})\" fill-opacity=\"{a}\" data-kind=\"{kind}\"",
+                (r * 255.).round() as u8,
+                (g * 255.).round() as u8,
+                (b * 255.).round() as u8,
+            );
+            let is_rect = quad[0].pos[0] == quad[1].pos[0]
+                && quad[2].pos[0] == quad[3].pos[0]
+                && quad[0].pos[1] == quad[3].pos[1]
+                && quad[1].pos[1] == quad[2].pos[1];
+            if is_rect {
+                let Rect {
+                    x,
+                    y,
+                    width: w,
+                    height: h,
+                } = bounds;
+                body.push_str(&format!(
+                    "  <rect x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{h}\" {fill}/>\n"
+                ));
+            } else {
+                let points = quad
+                    .iter()
+                    .map(|vertex| format!("{},{}", vertex.pos[0], vertex.pos[1]))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                body.push_str(&format!("  <polygon points=\"{points}\" {fill}/>\n"));
+            }
+        }
+
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n{body}</svg>\n"
+        )
+    }
+
+    /// Renders the batched quads as text for golden file tests, one line per
+    /// quad with its kind, bounds, depth, color and textures. Lines are
+    /// sorted so changes in batching don't show up as differences, values go
+    /// through `quantize`, and textures are numbered in the order they are
+    /// first drawn since their ids are allocated process wide.
+    pub fn to_snapshot(&self) -> String {
+        let mut textures: Vec<TextureId> = Vec::new();
+        let mut texture = |id: Option<TextureId>| match id {
+            Some(id) => {
+                let index = textures.iter().position(|t| *t == id).unwrap_or_else(|| {
+                    textures.push(id);
+                    textures.len() - 1
+                });
+                format!("t{index}")
+            }
+            None => "-".into(),
+        };
+        let mut lines = Vec::with_capacity(self.vertices.len() / 4);
+        for draw in &self.draws {
+            let quads =
+                &self.vertices[draw.vertices.start as usize..draw.vertices.end as usize];
+            let (image, mask) = (texture(draw.image), texture(draw.mask));
+            for quad in quads.chunks_exact(4) {
+                let kind = match quad[0].pos[3] as i32 {
+                    1 => "image",
+                    2 => "mask",
+                    3 => "image-mask",
+                    _ => "rect",
+                };
+                let bounds = quad_bounds(quad);
+                let [r, g, b, a] = quad[0].color.map(quantize);
+                lines.push(format!(
+                    "{kind} x={} y={} w={} h={} z={} color={r},{g},{b},{a} image={image} mask={mask}",
+                    quantize(bounds.x),
+                    quantize(bounds.y),
+                    quantize(bounds.width),
+                    quantize(bounds.height),
+                    quantize(quad[0].pos[2]),
+                ));
+            }
+        }
+        lines.sort();
+        let mut snapshot = lines.join("\n");
+        snapshot.push('\n');
+        snapshot
+    }
+
+    /// Returns the region covering every quad that was added, removed or
+    /// modified compared to `previous`, or `None` if both lists draw the
+    /// same quads. Quads that only moved between batches are not damage.
+    pub fn damage(&self, previous: &DisplayList) -> Option<Rect> {
+        // Quads present in `previous` count up, the ones in `self` count down,
+        // anything not balanced out at the end has changed
+        let mut quads: FxHashMap<u64, (i32, Rect)> = FxHashMap::default();
+        for (list, step) in [(previous, 1), (self, -1)] {
+            for quad in list.vertices.chunks_exact(4) {
+                let mut hasher = FxHasher::default();
+                for vertex in quad {
+                    hasher.write(bytemuck::bytes_of(vertex));
+                }
+                quads
+                    .entry(hasher.finish())
+                    .or_insert_with(|| (0, quad_bounds(quad)))
+                    .0 += step;
+            }
+        }
+
+        quads
+            .values()
+            .filter(|(count, _)| *count != 0)
+            .map(|(_, bounds)| *bounds)
+            .reduce(|damage, bounds| damage.union(&bounds))
+    }
+
     /// Clears the display list.
     #[inline]
     pub fn clear(&mut self) {
         self.vertices.clear();
         self.indices.clear();
         self.commands.clear();
-        self.indices_to_draw.clear();
+        self.draws.clear();
+    }
+}
+
+/// Rounds `value` to hundredths, so float noise from layout doesn't change
+/// snapshots. Negative zero becomes zero.
+pub fn quantize(value: f32) -> f32 {
+    let value = (value * 100.).round() / 100.;
+    if value == 0. {
+        0.
+    } else {
+        value
+    }
+}
+
+/// Axis aligned bounds of the four vertices of a quad.
+#[inline]
+fn quad_bounds(quad: &[Vertex]) -> Rect {
+    let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+    for vertex in quad {
+        for i in 0..2 {
+            min[i] = min[i].min(vertex.pos[i]);
+            max[i] = max[i].max(vertex.pos[i]);
+        }
     }
+    Rect::new(min[0], min[1], max[0] - min[0], max[1] - min[1])
 }
 
-/// Command in a display list.
+/// Command in a display list. Commands are recorded for the draw that
+/// follows them, see `Draw::commands`.
 #[derive(Copy, Clone, Debug)]
 pub enum Command {
     /// Bind a texture at the specified slot.
     BindTexture(u32, TextureId),
 }
+
+/// Blending a draw expects from the pipeline drawing it. Renderers other
+/// than `RichTextBrush` pick their pipeline from `Draw::pipeline`. The
+/// variants are stable; a new kind of draw gets a new variant rather than
+/// changing what an existing one means.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Pipeline {
+    /// Fully opaque geometry, which may be drawn without blending and in
+    /// any order.
+    Opaque,
+    /// Geometry with alpha, blended premultiplied over what was drawn
+    /// before, in the order of the draws.
+    Transparent,
+    /// Glyph masks with per-channel coverage, blended with dual source
+    /// blending like `Transparent` ones.
+    Subpixel,
+}
+
+/// A batch of a display list, drawn with a single indexed draw call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Draw {
+    pub pipeline: Pipeline,
+    /// Range of `DisplayList::vertices` used by the draw.
+    pub vertices: Range<u32>,
+    /// Range of `DisplayList::indices` to draw. Indices point into the whole
+    /// vertex list, not into `vertices`.
+    pub indices: Range<u32>,
+    /// Range of `DisplayList::commands` to run before the draw.
+    pub commands: Range<u32>,
+    /// Mask texture sampled by the draw.
+    pub mask: Option<TextureId>,
+    /// Color texture sampled by the draw.
+    pub image: Option<TextureId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_retains_capacity() {
+        let mut batches = BatchManager::with_capacity(64);
+        let capacity = |batches: &BatchManager| {
+            batches
+                .batches
+                .iter()
+                .map(|b| (b.vertices.capacity(), b.indices.capacity()))
+                .collect::<Vec<_>>()
+        };
+        let reserved = capacity(&batches);
+        assert!(reserved.iter().all(|&(v, i)| v >= 64 * 4 && i >= 64 * 6));
+
+        for frame in 0..3 {
+            for i in 0..64 {
+                let rect = Rect::new(i as f32, 0., 1., 1.);
+                batches.add_rect(&rect, 0.0, &[1.0, 1.0, 1.0, 1.0]);
+                batches.add_rect(&rect, 0.0, &[1.0, 1.0, 1.0, 0.5]);
+            }
+            assert!(
+                batches.batches.is_empty(),
+                "frame {frame} allocated a batch"
+            );
+            batches.reset();
+            assert_eq!(capacity(&batches), reserved);
+        }
+    }
+
+    #[test]
+    fn test_batches_are_sized_after_the_previous_frame() {
+        let mut batches = BatchManager::new();
+        let opaque = [1.0, 1.0, 1.0, 1.0];
+        for i in 0..200 {
+            batches.add_rect(&Rect::new(i as f32, 0., 1., 1.), 0.0, &opaque);
+        }
+        batches.add_rect(&Rect::new(0., 0., 1., 1.), 0.0, &[1.0, 1.0, 1.0, 0.5]);
+        batches.reset();
+
+        // The pool hands out the small transparent batch first, which has
+        // to grow to what the opaque one held
+        batches.add_rect(&Rect::new(0., 0., 1., 1.), 0.0, &opaque);
+        assert!(batches.opaque[0].vertices.capacity() >= 200 * 4);
+        assert!(batches.opaque[0].indices.capacity() >= 200 * 6);
+    }
+
+    #[test]
+    fn test_display_list_to_svg() {
+        let mut batches = BatchManager::new();
+        batches.add_rect(&Rect::new(0., 0., 10., 20.), 0.0, &[1.0, 0.0, 0.0, 1.0]);
+        batches.add_mask_rect(
+            &Rect::new(10., 0., 5., 5.),
+            0.0,
+            &[0.0, 0.0, 1.0, 1.0],
+            &[0., 0., 1., 1.],
+            TextureId(1),
+            true,
+        );
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+
+        let svg = list.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("viewBox=\"0 0 15 20\""));
+        assert!(svg.contains(
+            "<rect x=\"0\" y=\"0\" width=\"10\" height=\"20\" fill=\"rgb(255,0,0)\" fill-opacity=\"1\" data-kind=\"rect\"/>"
+        ));
+        assert!(
+            svg.contains("fill=\"rgb(0,0,255)\" fill-opacity=\"1\" data-kind=\"mask\"")
+        );
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+
+    #[test]
+    fn test_snap_at_fractional_scale() {
+        // A 1px line at 1.5x, between two physical pixels
+        let line = Rect::new(10.2, 20.1, 100., 1.).snap(1.5);
+        let physical = [line.x, line.y, line.width, line.height].map(|v| v * 1.5);
+        for (value, expected) in physical.into_iter().zip([15., 30., 150., 1.]) {
+            assert!((value - expected).abs() < 1e-4, "{physical:?}");
+        }
+        assert_eq!(snap_size(2.5), 2.);
+        assert_eq!(snap_size(2.6), 3.);
+        assert_eq!(snap_size(0.2), 1.);
+        assert_eq!(snap_size(0.), 0.);
+    }
+
+    #[test]
+    fn test_display_list_snapshot() {
+        let mut batches = BatchManager::new();
+        batches.add_mask_rect(
+            &Rect::new(10.004, 0., 5., 5.),
+            0.0,
+            &[0.0, 0.0, 1.0, 1.0],
+            &[0., 0., 1., 1.],
+            TextureId(7),
+            true,
+        );
+        batches.add_rect(&Rect::new(0., -0.001, 10., 20.), 0.0, &[1.0, 0.0, 0.0, 1.0]);
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+
+        assert_eq!(
+            list.to_snapshot(),
+            "mask x=10 y=0 w=5 h=5 z=0 color=0,0,1,1 image=- mask=t0\n\
+             rect x=0 y=0 w=10 h=20 z=0 color=1,0,0,1 image=- mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_rect_subtract() {
+        let rect = Rect::new(0., 0., 10., 10.);
+        let parts = rect.subtract(&Rect::new(2., 4., 3., 2.));
+        assert_eq!(
+            parts,
+            [
+                Rect::new(0., 0., 10., 4.),
+                Rect::new(0., 6., 10., 4.),
+                Rect::new(0., 4., 2., 2.),
+                Rect::new(5., 4., 5., 2.),
+            ]
+        );
+        let area: f32 = parts.iter().map(|part| part.width * part.height).sum();
+        assert_eq!(area, 100. - 6.);
+
+        // Clipped to the rect, or outside of it
+        assert_eq!(
+            rect.subtract(&Rect::new(-5., -5., 10., 20.)),
+            [Rect::new(5., 0., 5., 10.)]
+        );
+        assert_eq!(rect.subtract(&Rect::new(20., 0., 5., 5.)), [rect]);
+        assert!(rect.subtract(&rect).is_empty());
+    }
+
+    #[test]
+    fn test_overlay_rects_are_submitted_last() {
+        let mut batches = BatchManager::new();
+        batches.add_rect(&Rect::new(0., 0., 10., 10.), 0.0, &[1.0, 0.0, 0.0, 0.5]);
+        batches.add_overlay_rect(
+            &Rect::new(0., 0., 10., 10.),
+            0.0,
+            &[0.0, 0.0, 0.0, 0.3],
+        );
+        batches.add_rect(&Rect::new(0., 0., 10., 10.), 0.0, &[0.0, 1.0, 0.0, 1.0]);
+        batches.add_rect(&Rect::new(0., 0., 10., 10.), 0.0, &[0.0, 0.0, 1.0, 0.5]);
+
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+        let last = list.vertices().last().unwrap();
+        assert_eq!(last.color, [0.0, 0.0, 0.0, 0.3]);
+    }
+
+    #[test]
+    fn test_highlight_rects_are_between_opaque_and_transparent() {
+        let mut batches = BatchManager::new();
+        let rect = Rect::new(0., 0., 10., 10.);
+        batches.add_mask_rect(
+            &rect,
+            0.0,
+            &[0.0, 0.0, 1.0, 1.0],
+            &[0., 0., 1., 1.],
+            TextureId(1),
+            true,
+        );
+        batches.add_highlight_rect(&rect, 0.0, &[1.0, 1.0, 0.0, 0.5]);
+        batches.add_rect(&rect, 0.0, &[1.0, 0.0, 0.0, 1.0]);
+
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+        let colors: Vec<_> = list.vertices().iter().step_by(4).map(|v| v.color).collect();
+        assert_eq!(
+            colors,
+            vec![
+                [1.0, 0.0, 0.0, 1.0],
+                [1.0, 1.0, 0.0, 0.5],
+                [0.0, 0.0, 1.0, 1.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display_list_draws() {
+        let mut batches = BatchManager::new();
+        let rect = Rect::new(0., 0., 10., 10.);
+        batches.add_mask_rect(
+            &rect,
+            0.0,
+            &[0.0, 0.0, 1.0, 1.0],
+            &[0., 0., 1., 1.],
+            TextureId(1),
+            true,
+        );
+        batches.add_rect(&rect, 0.0, &[1.0, 0.0, 0.0, 0.5]);
+        batches.add_rect(&rect, 0.0, &[1.0, 0.0, 0.0, 1.0]);
+        batches.add_rect(&rect, 0.0, &[0.0, 1.0, 0.0, 1.0]);
+
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+        let draws = list.draws();
+        assert_eq!(
+            draws,
+            &[
+                Draw {
+                    pipeline: Pipeline::Opaque,
+                    vertices: 0..8,
+                    indices: 0..12,
+                    commands: 0..0,
+                    mask: None,
+                    image: None,
+                },
+                Draw {
+                    pipeline: Pipeline::Subpixel,
+                    vertices: 8..12,
+                    indices: 12..18,
+                    commands: 0..1,
+                    mask: Some(TextureId(1)),
+                    image: None,
+                },
+                Draw {
+                    pipeline: Pipeline::Transparent,
+                    vertices: 12..16,
+                    indices: 18..24,
+                    commands: 1..1,
+                    mask: None,
+                    image: None,
+                },
+            ]
+        );
+        assert!(matches!(
+            list.commands()[draws[1].commands.start as usize],
+            Command::BindTexture(0, TextureId(1))
+        ));
+        // Indices of a draw stay within its vertices
+        for draw in draws {
+            let indices =
+                &list.indices()[draw.indices.start as usize..draw.indices.end as usize];
+            assert!(indices.iter().all(|i| draw.vertices.contains(i)));
+        }
+    }
+
+    #[test]
+    fn test_display_list_damage() {
+        let build = |rects: &[(Rect, [f32; 4])]| {
+            let mut batches = BatchManager::new();
+            for (rect, color) in rects {
+                batches.add_rect(rect, 0.0, color);
+            }
+            let mut list = DisplayList::new();
+            batches.build_display_list(&mut list);
+            list
+        };
+
+        let red = [1.0, 0.0, 0.0, 1.0];
+        let blue = [0.0, 0.0, 1.0, 1.0];
+        let previous = build(&[
+            (Rect::new(0., 0., 10., 10.), red),
+            (Rect::new(10., 0., 10., 10.), red),
+            (Rect::new(20., 0., 10., 10.), red),
+        ]);
+
+        // Same quads in a different order
+        let current = build(&[
+            (Rect::new(20., 0., 10., 10.), red),
+            (Rect::new(0., 0., 10., 10.), red),
+            (Rect::new(10., 0., 10., 10.), red),
+        ]);
+        assert!(current.damage(&previous).is_none());
+
+        // A single recolored cell
+        let current = build(&[
+            (Rect::new(0., 0., 10., 10.), red),
+            (Rect::new(10., 0., 10., 10.), blue),
+            (Rect::new(20., 0., 10., 10.), red),
+        ]);
+        let damage = current.damage(&previous).unwrap();
+        assert_eq!(
+            (damage.x, damage.y, damage.width, damage.height),
+            (10., 0., 10., 10.)
+        );
+
+        // A removed cell and an added one
+        let current = build(&[
+            (Rect::new(10., 0., 10., 10.), red),
+            (Rect::new(20., 0., 10., 10.), red),
+            (Rect::new(0., 20., 5., 5.), red),
+        ]);
+        let damage = current.damage(&previous).unwrap();
+        assert_eq!(
+            (damage.x, damage.y, damage.width, damage.height),
+            (0., 0., 10., 25.)
+        );
+    }
+
+    #[test]
+    fn test_quads_use_their_bounds() {
+        let mut batches = BatchManager::new();
+        let points = [[10., 0.], [0., 10.], [20., 30.], [30., 20.]];
+        batches.add_quad(&points, 0.0, &[1.0, 0.0, 0.0, 1.0]);
+        let mut list = DisplayList::new();
+        batches.build_display_list(&mut list);
+
+        let svg = list.to_svg();
+        assert!(svg.contains("viewBox=\"0 0 30 30\""));
+        assert!(svg.contains("<polygon points=\"10,0 0,10 20,30 30,20\""));
+
+        let damage = list.damage(&DisplayList::new()).unwrap();
+        assert_eq!(
+            (damage.x, damage.y, damage.width, damage.height),
+            (0., 0., 30., 30.)
+        );
+    }
+}
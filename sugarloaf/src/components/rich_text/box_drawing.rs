@@ -0,0 +1,447 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Geometry for box-drawing and block characters (U+2500 to U+259F), drawn
+//! as rects snapped to the pixel grid so lines join across cells without
+//! seams. Arcs, diagonals and the double lines mixed with single ones are
+//! left to the font.
+
+use crate::components::rich_text::batch::Rect;
+
+const LIGHT: u8 = 1;
+const HEAVY: u8 = 2;
+
+/// Weights of the up, right, down and left arms of U+2500 to U+254B. The
+/// dashed lines in between are drawn by `dashes` and have no arms.
+#[rustfmt::skip]
+const LINES: [[u8; 4]; 0x4C] = [
+    [0, 1, 0, 1], [0, 2, 0, 2], [1, 0, 1, 0], [2, 0, 2, 0],
+    [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0],
+    [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0],
+    [0, 1, 1, 0], [0, 2, 1, 0], [0, 1, 2, 0], [0, 2, 2, 0],
+    [0, 0, 1, 1], [0, 0, 1, 2], [0, 0, 2, 1], [0, 0, 2, 2],
+    [1, 1, 0, 0], [1, 2, 0, 0], [2, 1, 0, 0], [2, 2, 0, 0],
+    [1, 0, 0, 1], [1, 0, 0, 2], [2, 0, 0, 1], [2, 0, 0, 2],
+    [1, 1, 1, 0], [1, 2, 1, 0], [2, 1, 1, 0], [1, 1, 2, 0],
+    [2, 1, 2, 0], [2, 2, 1, 0], [1, 2, 2, 0], [2, 2, 2, 0],
+    [1, 0, 1, 1], [1, 0, 1, 2], [2, 0, 1, 1], [1, 0, 2, 1],
+    [2, 0, 2, 1], [2, 0, 1, 2], [1, 0, 2, 2], [2, 0, 2, 2],
+    [0, 1, 1, 1], [0, 1, 1, 2], [0, 2, 1, 1], [0, 2, 1, 2],
+    [0, 1, 2, 1], [0, 1, 2, 2], [0, 2, 2, 1], [0, 2, 2, 2],
+    [1, 1, 0, 1], [1, 1, 0, 2], [1, 2, 0, 1], [1, 2, 0, 2],
+    [2, 1, 0, 1], [2, 1, 0, 2], [2, 2, 0, 1], [2, 2, 0, 2],
+    [1, 1, 1, 1], [1, 1, 1, 2], [1, 2, 1, 1], [1, 2, 1, 2],
+    [2, 1, 1, 1], [1, 1, 2, 1], [2, 1, 2, 1], [2, 1, 1, 2],
+    [2, 2, 1, 1], [1, 1, 2, 2], [1, 2, 2, 1], [2, 2, 1, 2],
+    [1, 2, 2, 2], [2, 1, 2, 2], [2, 2, 2, 1], [2, 2, 2, 2],
+];
+
+/// Arms of the half lines U+2574 to U+257F.
+#[rustfmt::skip]
+const HALF_LINES: [[u8; 4]; 12] = [
+    [0, 0, 0, 1], [1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0],
+    [0, 0, 0, 2], [2, 0, 0, 0], [0, 2, 0, 0], [0, 0, 2, 0],
+    [0, 2, 0, 1], [1, 0, 2, 0], [0, 1, 0, 2], [2, 0, 1, 0],
+];
+
+/// Positions used by double lines, along either axis: the cell start, the
+/// outer line, the center, the inner line and the cell end.
+const START: u8 = 0;
+const OUTER: u8 = 1;
+const INNER: u8 = 3;
+const END: u8 = 4;
+
+/// Segment of a double line: whether it is horizontal, its position across
+/// and where it starts and ends along its axis.
+type Segment = (bool, u8, u8, u8);
+
+const H: bool = true;
+const V: bool = false;
+
+fn double_lines(ch: char) -> Option<&'static [Segment]> {
+    let segments: &'static [Segment] = match ch {
+        '═' => &[(H, OUTER, START, END), (H, INNER, START, END)],
+        '║' => &[(V, OUTER, START, END), (V, INNER, START, END)],
+        '╔' => &[
+            (H, OUTER, OUTER, END),
+            (H, INNER, INNER, END),
+            (V, OUTER, OUTER, END),
+            (V, INNER, INNER, END),
+        ],
+        '╗' => &[
+            (H, OUTER, START, INNER),
+            (H, INNER, START, OUTER),
+            (V, INNER, OUTER, END),
+            (V, OUTER, INNER, END),
+        ],
+        '╚' => &[
+            (H, INNER, OUTER, END),
+            (H, OUTER, INNER, END),
+            (V, OUTER, START, INNER),
+            (V, INNER, START, OUTER),
+        ],
+        '╝' => &[
+            (H, INNER, START, INNER),
+            (H, OUTER, START, OUTER),
+            (V, INNER, START, INNER),
+            (V, OUTER, START, OUTER),
+        ],
+        '╠' => &[
+            (V, OUTER, START, END),
+            (V, INNER, START, OUTER),
+            (V, INNER, INNER, END),
+            (H, OUTER, INNER, END),
+            (H, INNER, INNER, END),
+        ],
+        '╣' => &[
+            (V, INNER, START, END),
+            (V, OUTER, START, OUTER),
+            (V, OUTER, INNER, END),
+            (H, OUTER, START, OUTER),
+            (H, INNER, START, OUTER),
+        ],
+        '╦' => &[
+            (H, OUTER, START, END),
+            (H, INNER, START, OUTER),
+            (H, INNER, INNER, END),
+            (V, OUTER, INNER, END),
+            (V, INNER, INNER, END),
+        ],
+        '╩' => &[
+            (H, INNER, START, END),
+            (H, OUTER, START, OUTER),
+            (H, OUTER, INNER, END),
+            (V, OUTER, START, OUTER),
+            (V, INNER, START, OUTER),
+        ],
+        '╬' => &[
+            (H, OUTER, START, OUTER),
+            (H, OUTER, INNER, END),
+            (H, INNER, START, OUTER),
+            (H, INNER, INNER, END),
+            (V, OUTER, START, OUTER),
+            (V, OUTER, INNER, END),
+            (V, INNER, START, OUTER),
+            (V, INNER, INNER, END),
+        ],
+        _ => return None,
+    };
+    Some(segments)
+}
+
+/// Number of dashes, orientation and weight of the dashed lines.
+fn dashes(ch: char) -> Option<(u8, bool, u8)> {
+    Some(match ch {
+        '┄' => (3, H, LIGHT),
+        '┅' => (3, H, HEAVY),
+        '┆' => (3, V, LIGHT),
+        '┇' => (3, V, HEAVY),
+        '┈' => (4, H, LIGHT),
+        '┉' => (4, H, HEAVY),
+        '┊' => (4, V, LIGHT),
+        '┋' => (4, V, HEAVY),
+        '╌' => (2, H, LIGHT),
+        '╍' => (2, H, HEAVY),
+        '╎' => (2, V, LIGHT),
+        '╏' => (2, V, HEAVY),
+        _ => return None,
+    })
+}
+
+fn arms(ch: char) -> Option<[u8; 4]> {
+    match ch as u32 {
+        code @ 0x2500..=0x254B if dashes(ch).is_none() => {
+            Some(LINES[(code - 0x2500) as usize])
+        }
+        code @ 0x2574..=0x257F => Some(HALF_LINES[(code - 0x2574) as usize]),
+        _ => None,
+    }
+}
+
+/// Returns true when `ch` is drawn with rects rather than the font.
+pub fn is_supported(ch: char) -> bool {
+    arms(ch).is_some()
+        || dashes(ch).is_some()
+        || double_lines(ch).is_some()
+        || ('\u{2580}'..='\u{259F}').contains(&ch)
+}
+
+/// Cell snapped to whole pixels, so that neighbours share their edges.
+#[derive(Copy, Clone)]
+struct Cell {
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl Cell {
+    fn x(&self, fraction: f32) -> f32 {
+        (self.x0 + (self.x1 - self.x0) * fraction).round()
+    }
+
+    fn y(&self, fraction: f32) -> f32 {
+        (self.y0 + (self.y1 - self.y0) * fraction).round()
+    }
+}
+
+fn push(out: &mut Vec<(Rect, f32)>, x0: f32, y0: f32, x1: f32, y1: f32, alpha: f32) {
+    if x1 > x0 && y1 > y0 {
+        out.push((Rect::new(x0, y0, x1 - x0, y1 - y0), alpha));
+    }
+}
+
+/// Start of a stroke of `width` centered on `center`.
+#[inline]
+fn stroke(center: f32, width: f32) -> f32 {
+    (center - width / 2.).round()
+}
+
+/// Appends the rects drawing `ch` in `cell` with lines `thickness` pixels
+/// wide, along with the alpha to draw them with. Returns false when `ch`
+/// isn't supported.
+pub fn rects(ch: char, cell: Rect, thickness: f32, out: &mut Vec<(Rect, f32)>) -> bool {
+    let cell = Cell {
+        x0: cell.x.round(),
+        y0: cell.y.round(),
+        x1: (cell.x + cell.width).round(),
+        y1: (cell.y + cell.height).round(),
+    };
+    let width = |weight: u8| thickness * weight as f32;
+
+    if let Some(arms) = arms(ch) {
+        lines(arms, cell, width, out);
+    } else if let Some((count, horizontal, weight)) = dashes(ch) {
+        let w = width(weight);
+        for i in 0..count {
+            let from = i as f32 / count as f32;
+            let to = (i as f32 + 0.75) / count as f32;
+            if horizontal {
+                let y = stroke(cell.y(0.5), w);
+                push(out, cell.x(from), y, cell.x(to), y + w, 1.0);
+            } else {
+                let x = stroke(cell.x(0.5), w);
+                push(out, x, cell.y(from), x + w, cell.y(to), 1.0);
+            }
+        }
+    } else if let Some(segments) = double_lines(ch) {
+        let (cx, cy) = (cell.x(0.5), cell.y(0.5));
+        let position = |position: u8, start: f32, center: f32, end: f32| match position {
+            START => start,
+            OUTER => stroke(center, thickness) - thickness,
+            INNER => stroke(center, thickness) + thickness,
+            _ => end,
+        };
+        for (horizontal, across, from, to) in segments.iter().copied() {
+            // Positions are where strokes start, horizontal segments cover the
+            // corners they turn at and vertical ones start past them
+            let extend = |position: u8| match position {
+                START | END => 0.,
+                _ => thickness,
+            };
+            if horizontal {
+                let y = position(across, cell.y0, cy, cell.y1);
+                let x0 = position(from, cell.x0, cx, cell.x1);
+                let x1 = position(to, cell.x0, cx, cell.x1) + extend(to);
+                push(out, x0, y, x1, y + thickness, 1.0);
+            } else {
+                let x = position(across, cell.x0, cx, cell.x1);
+                let y0 = position(from, cell.y0, cy, cell.y1) + extend(from);
+                let y1 = position(to, cell.y0, cy, cell.y1);
+                push(out, x, y0, x + thickness, y1, 1.0);
+            }
+        }
+    } else {
+        return blocks(ch, cell, out);
+    }
+
+    true
+}
+
+fn lines(
+    arms: [u8; 4],
+    cell: Cell,
+    width: impl Fn(u8) -> f32,
+    out: &mut Vec<(Rect, f32)>,
+) {
+    let [up, right, down, left] = arms;
+    let (cx, cy) = (cell.x(0.5), cell.y(0.5));
+    let vertical = width(up.max(down));
+    let horizontal = width(left.max(right));
+    // Horizontal arms cover the center, vertical arms stop at their edges
+    let vx = stroke(cx, vertical);
+    let hy = stroke(cy, horizontal);
+
+    if left > 0 {
+        let w = width(left);
+        let y = stroke(cy, w);
+        let end = if vertical > 0. { vx + vertical } else { cx };
+        push(out, cell.x0, y, end, y + w, 1.0);
+    }
+    if right > 0 {
+        let w = width(right);
+        let y = stroke(cy, w);
+        let start = match (left > 0, vertical > 0.) {
+            (true, true) => vx + vertical,
+            (false, true) => vx,
+            _ => cx,
+        };
+        push(out, start, y, cell.x1, y + w, 1.0);
+    }
+    if up > 0 {
+        let w = width(up);
+        let x = stroke(cx, w);
+        let end = if horizontal > 0. { hy } else { cy };
+        push(out, x, cell.y0, x + w, end, 1.0);
+    }
+    if down > 0 {
+        let w = width(down);
+        let x = stroke(cx, w);
+        let start = if horizontal > 0. { hy + horizontal } else { cy };
+        push(out, x, start, x + w, cell.y1, 1.0);
+    }
+}
+
+fn blocks(ch: char, cell: Cell, out: &mut Vec<(Rect, f32)>) -> bool {
+    let Cell { x0, y0, x1, y1 } = cell;
+    let (cx, cy) = (cell.x(0.5), cell.y(0.5));
+    match ch as u32 {
+        0x2580 => push(out, x0, y0, x1, cy, 1.0),
+        // Lower one eighth to seven eighths
+        code @ 0x2581..=0x2587 => {
+            let eighths = (code - 0x2580) as f32;
+            push(out, x0, cell.y(1. - eighths / 8.), x1, y1, 1.0);
+        }
+        0x2588 => push(out, x0, y0, x1, y1, 1.0),
+        // Left seven eighths to one eighth
+        code @ 0x2589..=0x258F => {
+            let eighths = (0x2590 - code) as f32;
+            push(out, x0, y0, cell.x(eighths / 8.), y1, 1.0);
+        }
+        0x2590 => push(out, cx, y0, x1, y1, 1.0),
+        // Light, medium and dark shades
+        code @ 0x2591..=0x2593 => {
+            push(out, x0, y0, x1, y1, (code - 0x2590) as f32 * 0.25);
+        }
+        0x2594 => push(out, x0, y0, x1, cell.y(1. / 8.), 1.0),
+        0x2595 => push(out, cell.x(7. / 8.), y0, x1, y1, 1.0),
+        code @ 0x2596..=0x259F => {
+            const UPPER_LEFT: u8 = 1;
+            const UPPER_RIGHT: u8 = 2;
+            const LOWER_LEFT: u8 = 4;
+            const LOWER_RIGHT: u8 = 8;
+            let quadrants = [
+                LOWER_LEFT,
+                LOWER_RIGHT,
+                UPPER_LEFT,
+                UPPER_LEFT | LOWER_LEFT | LOWER_RIGHT,
+                UPPER_LEFT | LOWER_RIGHT,
+                UPPER_LEFT | UPPER_RIGHT | LOWER_LEFT,
+                UPPER_LEFT | UPPER_RIGHT | LOWER_RIGHT,
+                UPPER_RIGHT,
+                UPPER_RIGHT | LOWER_LEFT,
+                UPPER_RIGHT | LOWER_LEFT | LOWER_RIGHT,
+            ][(code - 0x2596) as usize];
+            for (quadrant, (qx0, qy0, qx1, qy1)) in [
+                (UPPER_LEFT, (x0, y0, cx, cy)),
+                (UPPER_RIGHT, (cx, y0, x1, cy)),
+                (LOWER_LEFT, (x0, cy, cx, y1)),
+                (LOWER_RIGHT, (cx, cy, x1, y1)),
+            ] {
+                if quadrants & quadrant != 0 {
+                    push(out, qx0, qy0, qx1, qy1, 1.0);
+                }
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(ch: char, cell: Rect) -> Vec<(f32, f32, f32, f32, f32)> {
+        let mut out = Vec::new();
+        assert!(rects(ch, cell, 1., &mut out));
+        out.iter()
+            .map(|(rect, alpha)| (rect.x, rect.y, rect.width, rect.height, *alpha))
+            .collect()
+    }
+
+    #[test]
+    fn test_horizontal_line_spans_cell() {
+        let cell = Rect::new(10., 0., 8., 16.);
+        assert_eq!(
+            draw('─', cell),
+            vec![(10., 8., 4., 1., 1.), (14., 8., 4., 1., 1.)]
+        );
+        assert_eq!(
+            draw('━', cell),
+            vec![(10., 7., 4., 2., 1.), (14., 7., 4., 2., 1.)]
+        );
+    }
+
+    #[test]
+    fn test_adjacent_cells_share_edges() {
+        // Fractional cell widths are snapped the same way on both sides
+        let width = 7.6;
+        let first = draw('─', Rect::new(0., 0., width, 16.));
+        let second = draw('─', Rect::new(width, 0., width, 16.));
+        let end = first[1].0 + first[1].2;
+        assert_eq!(end, second[0].0);
+    }
+
+    #[test]
+    fn test_cross_has_no_gaps_or_overlaps() {
+        let cell = Rect::new(0., 0., 8., 16.);
+        let rects = draw('┼', cell);
+        let area: f32 = rects.iter().map(|(_, _, w, h, _)| w * h).sum();
+        // One full row and one full column, sharing a single pixel
+        assert_eq!(area, 8. + 16. - 1.);
+    }
+
+    #[test]
+    fn test_corner_joins() {
+        let cell = Rect::new(0., 0., 8., 16.);
+        // Right arm from the vertical stroke, down arm below the horizontal
+        assert_eq!(
+            draw('┌', cell),
+            vec![(4., 8., 4., 1., 1.), (4., 9., 1., 7., 1.)]
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let cell = Rect::new(0., 0., 8., 16.);
+        assert_eq!(draw('█', cell), vec![(0., 0., 8., 16., 1.)]);
+        assert_eq!(draw('▄', cell), vec![(0., 8., 8., 8., 1.)]);
+        assert_eq!(draw('▌', cell), vec![(0., 0., 4., 16., 1.)]);
+        assert_eq!(draw('▒', cell), vec![(0., 0., 8., 16., 0.5)]);
+        assert_eq!(
+            draw('▚', cell),
+            vec![(0., 0., 4., 8., 1.), (4., 8., 4., 8., 1.)]
+        );
+    }
+
+    #[test]
+    fn test_double_corner() {
+        let cell = Rect::new(0., 0., 8., 16.);
+        let rects = draw('╔', cell);
+        assert_eq!(rects.len(), 4);
+        // The outer horizontal line starts on the outer vertical one
+        assert_eq!(rects[0].0, rects[2].0);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut out = Vec::new();
+        assert!(!is_supported('╭'));
+        assert!(!is_supported('╱'));
+        assert!(!rects('╭', Rect::new(0., 0., 8., 16.), 1., &mut out));
+        assert!(out.is_empty());
+    }
+}
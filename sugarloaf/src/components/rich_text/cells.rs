@@ -0,0 +1,75 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Cell spans derived from code points, for callers that don't compute
+//! them. Gated behind the `cell_widths` feature.
+
+use unicode_width::UnicodeWidthChar;
+
+/// Derives how many cells text covers from the Unicode width of its code
+/// points, so wide CJK characters and emoji take two cells.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CellWidths {
+    /// Width of a cell in pixels.
+    pub cell_width: f32,
+    /// Treats East Asian ambiguous width characters, like `±` or `Ω`, as
+    /// wide, as terminals set up for CJK locales do.
+    pub ambiguous_wide: bool,
+}
+
+impl CellWidths {
+    pub fn new(cell_width: f32) -> Self {
+        Self {
+            cell_width,
+            ambiguous_wide: false,
+        }
+    }
+
+    /// Cells taken by `ch`. Control characters take none, combining marks
+    /// none either since they share the cell of their base.
+    #[inline]
+    pub fn cells(&self, ch: char) -> usize {
+        let width = if self.ambiguous_wide {
+            ch.width_cjk()
+        } else {
+            ch.width()
+        };
+        width.unwrap_or(0)
+    }
+
+    /// Cells taken by `text`.
+    pub fn span(&self, text: &str) -> usize {
+        text.chars().map(|ch| self.cells(ch)).sum()
+    }
+
+    /// Width in pixels of the cells taken by `text`.
+    #[inline]
+    pub fn width(&self, text: &str) -> f32 {
+        self.span(text) as f32 * self.cell_width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_spans() {
+        let widths = CellWidths::new(10.);
+        assert_eq!(widths.span("ab"), 2);
+        assert_eq!(widths.span("漢字"), 4);
+        assert_eq!(widths.span("e\u{301}"), 1);
+        assert_eq!(widths.width("a漢"), 30.);
+    }
+
+    #[test]
+    fn test_ambiguous_width() {
+        let mut widths = CellWidths::new(10.);
+        assert_eq!(widths.cells('±'), 1);
+        widths.ambiguous_wide = true;
+        assert_eq!(widths.cells('±'), 2);
+        assert_eq!(widths.cells('a'), 1);
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Color policies applied to text runs before they are drawn.
+
+/// Bright variants of the eight base ANSI colors (indices 8 to 15), as
+/// defined by xterm.
+pub const DEFAULT_BRIGHT_PALETTE: [[f32; 4]; 8] = [
+    [0.498, 0.498, 0.498, 1.0],
+    [1.0, 0.0, 0.0, 1.0],
+    [0.0, 1.0, 0.0, 1.0],
+    [1.0, 1.0, 0.0, 1.0],
+    [0.361, 0.361, 1.0, 1.0],
+    [1.0, 0.0, 1.0, 1.0],
+    [0.0, 1.0, 1.0, 1.0],
+    [1.0, 1.0, 1.0, 1.0],
+];
+
+/// Returns the bright variant of `color` when it is one of the eight base
+/// ANSI colors, keeping its alpha. Any other color is returned unchanged.
+#[inline]
+pub fn bright_variant(
+    color: [f32; 4],
+    ansi_index: Option<u8>,
+    bright_palette: &[[f32; 4]; 8],
+) -> [f32; 4] {
+    match ansi_index {
+        Some(index) if index < 8 => {
+            let bright = bright_palette[index as usize];
+            [bright[0], bright[1], bright[2], color[3]]
+        }
+        _ => color,
+    }
+}
+
+/// Relative luminance of an sRGB color, as defined by WCAG.
+#[inline]
+pub fn relative_luminance(color: [f32; 4]) -> f32 {
+    0.2126 * to_linear(color[0])
+        + 0.7152 * to_linear(color[1])
+        + 0.0722 * to_linear(color[2])
+}
+
+/// WCAG contrast ratio between two colors, from `1.0` to `21.0`.
+#[inline]
+pub fn contrast_ratio(a: [f32; 4], b: [f32; 4]) -> f32 {
+    let (a, b) = (relative_luminance(a), relative_luminance(b));
+    let (lighter, darker) = if a > b { (a, b) } else { (b, a) };
+    (lighter + 0.05) / (darker + 0.05)
+}
+
+/// Moves `foreground` towards white or black, whichever contrasts more with
+/// `background`, until their contrast ratio reaches `min_ratio`. The result
+/// is as close to the original color as possible; when the ratio can't be
+/// reached, the extreme is returned. Alpha is kept.
+pub fn ensure_contrast(
+    foreground: [f32; 4],
+    background: [f32; 4],
+    min_ratio: f32,
+) -> [f32; 4] {
+    if contrast_ratio(foreground, background) >= min_ratio {
+        return foreground;
+    }
+    let target = if contrast_ratio([1.0, 1.0, 1.0, 1.0], background)
+        >= contrast_ratio([0.0, 0.0, 0.0, 1.0], background)
+    {
+        1.0
+    } else {
+        0.0
+    };
+    let mix = |t: f32| {
+        let channel = |c: f32| c + (target - c) * t;
+        [
+            channel(foreground[0]),
+            channel(foreground[1]),
+            channel(foreground[2]),
+            foreground[3],
+        ]
+    };
+    let (mut low, mut high) = (0.0, 1.0);
+    if contrast_ratio(mix(high), background) < min_ratio {
+        return mix(high);
+    }
+    for _ in 0..16 {
+        let mid = (low + high) / 2.;
+        if contrast_ratio(mix(mid), background) >= min_ratio {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+    mix(high)
+}
+
+/// Color space colors are defined in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    /// Wide gamut space of most recent displays. It shares the sRGB transfer
+    /// function and white point, with more saturated primaries.
+    DisplayP3,
+}
+
+impl ColorSpace {
+    /// Converts a gamma encoded `color` from `self` to `target`, clamping it
+    /// to the gamut of `target`. Alpha is kept.
+    pub fn convert(self, color: [f32; 4], target: ColorSpace) -> [f32; 4] {
+        if self == target {
+            return color;
+        }
+        let linear = self.convert_linear(map_rgb(color, to_linear), target);
+        map_rgb(linear, |c| from_linear(c.clamp(0., 1.)))
+    }
+
+    /// Same as `convert` without clamping, for extended range targets:
+    /// colors out of the gamut of `target` keep components below 0.0 or past
+    /// 1.0, with the transfer function mirrored for negative ones as in
+    /// scRGB and extended sRGB.
+    pub fn convert_extended(self, color: [f32; 4], target: ColorSpace) -> [f32; 4] {
+        if self == target {
+            return color;
+        }
+        let linear = self
+            .convert_linear(map_rgb(color, |c| c.signum() * to_linear(c.abs())), target);
+        map_rgb(linear, |c| c.signum() * from_linear(c.abs()))
+    }
+
+    /// Converts a linear `color` from `self` to `target`.
+    fn convert_linear(self, color: [f32; 4], target: ColorSpace) -> [f32; 4] {
+        let matrix = match (self, target) {
+            (ColorSpace::Srgb, ColorSpace::DisplayP3) => SRGB_TO_DISPLAY_P3,
+            (ColorSpace::DisplayP3, ColorSpace::Srgb) => DISPLAY_P3_TO_SRGB,
+            _ => return color,
+        };
+        let channel =
+            |row: [f32; 3]| row[0] * color[0] + row[1] * color[1] + row[2] * color[2];
+        [
+            channel(matrix[0]),
+            channel(matrix[1]),
+            channel(matrix[2]),
+            color[3],
+        ]
+    }
+}
+
+#[inline]
+fn map_rgb(color: [f32; 4], f: impl Fn(f32) -> f32) -> [f32; 4] {
+    [f(color[0]), f(color[1]), f(color[2]), color[3]]
+}
+
+/// Linear sRGB to linear Display P3, both with a D65 white point.
+const SRGB_TO_DISPLAY_P3: [[f32; 3]; 3] = [
+    [0.822_462_1, 0.177_538, 0.0],
+    [0.033_194_2, 0.966_805_8, 0.0],
+    [0.017_082_7, 0.072_397_4, 0.910_519_9],
+];
+
+/// Linear Display P3 to linear sRGB.
+const DISPLAY_P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.224_940_1, -0.224_940_4, 0.0],
+    [-0.042_056_9, 1.042_057_1, 0.0],
+    [-0.019_637_6, -0.078_636_1, 1.098_273_5],
+];
+
+/// sRGB transfer function, shared by Display P3.
+#[inline]
+fn to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[inline]
+fn from_linear(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Color matrix applied to every fragment drawn by the rich text brush.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ColorFilter {
+    /// Leaves colors untouched.
+    #[default]
+    None,
+    Grayscale,
+    Sepia,
+    /// Simulates the lack of red cones.
+    Protanopia,
+    /// Simulates the lack of green cones.
+    Deuteranopia,
+    /// Simulates the lack of blue cones.
+    Tritanopia,
+    /// Column-major matrix multiplied with the RGBA color.
+    Custom([f32; 16]),
+}
+
+impl ColorFilter {
+    /// Returns the column-major matrix for the filter. Simulations use the
+    /// full severity matrices from Machado et al. (2009).
+    pub fn matrix(&self) -> [f32; 16] {
+        match self {
+            ColorFilter::None => from_rows([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]),
+            ColorFilter::Grayscale => from_rows([[0.2126, 0.7152, 0.0722]; 3]),
+            ColorFilter::Sepia => from_rows([
+                [0.393, 0.769, 0.189],
+                [0.349, 0.686, 0.168],
+                [0.272, 0.534, 0.131],
+            ]),
+            ColorFilter::Protanopia => from_rows([
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ]),
+            ColorFilter::Deuteranopia => from_rows([
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ]),
+            ColorFilter::Tritanopia => from_rows([
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ]),
+            ColorFilter::Custom(matrix) => *matrix,
+        }
+    }
+}
+
+/// Builds a column-major 4x4 matrix from the rows of an RGB matrix,
+/// leaving alpha untouched.
+fn from_rows(rows: [[f32; 3]; 3]) -> [f32; 16] {
+    let mut matrix = [0.; 16];
+    for (row, values) in rows.iter().enumerate() {
+        for (column, value) in values.iter().enumerate() {
+            matrix[column * 4 + row] = *value;
+        }
+    }
+    matrix[15] = 1.;
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(matrix: [f32; 16], color: [f32; 4]) -> [f32; 4] {
+        let mut out = [0.; 4];
+        for (row, value) in out.iter_mut().enumerate() {
+            *value = (0..4)
+                .map(|column| matrix[column * 4 + row] * color[column])
+                .sum();
+        }
+        out
+    }
+
+    #[test]
+    fn test_color_filter() {
+        use crate::components::core::IDENTITY_MATRIX;
+
+        assert_eq!(ColorFilter::None.matrix(), IDENTITY_MATRIX);
+        let color = [0.9, 0.2, 0.1, 0.5];
+        assert_eq!(apply(ColorFilter::None.matrix(), color), color);
+
+        let gray = apply(ColorFilter::Grayscale.matrix(), color);
+        assert_eq!(gray[0], gray[1]);
+        assert_eq!(gray[1], gray[2]);
+        assert_eq!(gray[3], 0.5);
+
+        // Simulations keep white white and alpha untouched
+        for filter in [
+            ColorFilter::Protanopia,
+            ColorFilter::Deuteranopia,
+            ColorFilter::Tritanopia,
+        ] {
+            let white = apply(filter.matrix(), [1.0, 1.0, 1.0, 0.5]);
+            assert!(
+                white[..3].iter().all(|c| (c - 1.0).abs() < 0.01),
+                "{filter:?}"
+            );
+            assert_eq!(white[3], 0.5);
+        }
+    }
+
+    #[test]
+    fn test_color_space_conversion() {
+        let assert_close = |actual: [f32; 4], expected: [f32; 4]| {
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a - e).abs() < 0.002, "{actual:?} != {expected:?}");
+            }
+        };
+        let srgb_red = [1.0, 0.0, 0.0, 0.5];
+        let p3 = ColorSpace::Srgb.convert(srgb_red, ColorSpace::DisplayP3);
+        assert_close(p3, [0.9175, 0.2003, 0.1387, 0.5]);
+        // sRGB colors are inside P3 and survive the round trip
+        assert_close(
+            ColorSpace::DisplayP3.convert(p3, ColorSpace::Srgb),
+            srgb_red,
+        );
+
+        // P3 red is out of the sRGB gamut and gets clamped
+        let p3_red = [1.0, 0.0, 0.0, 1.0];
+        let clamped = ColorSpace::DisplayP3.convert(p3_red, ColorSpace::Srgb);
+        assert_close(clamped, [1.0, 0.0, 0.0, 1.0]);
+
+        for space in [ColorSpace::Srgb, ColorSpace::DisplayP3] {
+            assert_eq!(space.convert(p3_red, space), p3_red);
+        }
+
+        // Unless extended, where it goes past the sRGB primaries and back
+        let extended = ColorSpace::DisplayP3.convert_extended(p3_red, ColorSpace::Srgb);
+        assert!(extended[0] > 1.0 && extended[1] < 0.0 && extended[2] < 0.0);
+        assert_close(
+            ColorSpace::Srgb.convert_extended(extended, ColorSpace::DisplayP3),
+            p3_red,
+        );
+    }
+
+    #[test]
+    fn test_contrast_ratio() {
+        let white = [1.0, 1.0, 1.0, 1.0];
+        let black = [0.0, 0.0, 0.0, 1.0];
+        assert!((contrast_ratio(white, black) - 21.0).abs() < 0.01);
+        assert!((contrast_ratio(black, white) - 21.0).abs() < 0.01);
+        assert_eq!(contrast_ratio(white, white), 1.0);
+    }
+
+    #[test]
+    fn test_ensure_contrast() {
+        let background = [0.2, 0.2, 0.2, 1.0];
+        let foreground = [0.3, 0.3, 0.3, 0.8];
+
+        let adjusted = ensure_contrast(foreground, background, 4.5);
+        assert!(contrast_ratio(adjusted, background) >= 4.5);
+        // Lightened on a dark background, barely past the threshold
+        assert!(adjusted[0] > foreground[0]);
+        assert!(contrast_ratio(adjusted, background) < 4.6);
+        assert_eq!(adjusted[3], 0.8);
+
+        // Colors that already contrast enough are untouched
+        let white = [1.0, 1.0, 1.0, 1.0];
+        assert_eq!(ensure_contrast(white, background, 4.5), white);
+
+        // An unreachable ratio gives the extreme
+        assert_eq!(
+            ensure_contrast(foreground, background, 25.0),
+            [1.0, 1.0, 1.0, 0.8]
+        );
+    }
+
+    #[test]
+    fn test_bright_variant() {
+        let red = [0.8, 0.0, 0.0, 0.5];
+        assert_eq!(
+            bright_variant(red, Some(1), &DEFAULT_BRIGHT_PALETTE),
+            [1.0, 0.0, 0.0, 0.5]
+        );
+        // Already bright, 256 colors and true colors are left alone
+        assert_eq!(bright_variant(red, Some(9), &DEFAULT_BRIGHT_PALETTE), red);
+        assert_eq!(bright_variant(red, Some(196), &DEFAULT_BRIGHT_PALETTE), red);
+        assert_eq!(bright_variant(red, None, &DEFAULT_BRIGHT_PALETTE), red);
+    }
+}
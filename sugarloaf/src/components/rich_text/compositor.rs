@@ -11,25 +11,58 @@
 
 use crate::components::rich_text::batch::BatchManager;
 pub use crate::components::rich_text::batch::{
-    // Command, DisplayList, Pipeline, Rect, Vertex,
-    Command,
-    DisplayList,
-    Rect,
-    Vertex,
+    quantize, snap_size, Command, DisplayList, Draw, Insets, Pipeline, Rect, Vertex,
+};
+use crate::components::rich_text::box_drawing;
+#[cfg(feature = "cell_widths")]
+use crate::components::rich_text::cells::CellWidths;
+use crate::components::rich_text::color::{
+    bright_variant, ensure_contrast, ColorSpace, DEFAULT_BRIGHT_PALETTE,
+};
+use crate::components::rich_text::cursor::CursorBlink;
+use crate::components::rich_text::image_cache::{
+    premultiply, GlyphCache, GlyphCacheSession, GlyphEntry, ImageCache, ImageData,
+    PixelFormat,
 };
 pub use crate::components::rich_text::image_cache::{
-    AddImage,
-    ImageId,
-    ImageLocation,
-    TextureEvent,
-    TextureId,
-    // AddImage, Epoch, ImageData, ImageId, ImageLocation, TextureEvent, TextureId,
+    AddImage, GlyphCacheStats, ImageId, ImageLocation, TextureEvent, TextureId,
 };
-use crate::components::rich_text::image_cache::{GlyphCache, ImageCache};
+use crate::components::rich_text::iterm2::ITerm2File;
+use crate::components::rich_text::kitty::{
+    kitty_depth, KittyDelete, KittyImage, KittyPlacement,
+};
+use crate::components::rich_text::missing_glyph;
+use crate::components::rich_text::powerline;
+#[cfg(feature = "sixel")]
+use crate::components::rich_text::sixel::SixelImage;
 use crate::components::rich_text::text::*;
 use crate::SugarCursor;
 
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use swash::{FontRef, GlyphId};
+
+/// Cells of a default 80x24 terminal, used to pre-size the batches.
+const EXPECTED_CELLS: usize = 80 * 24;
+
+/// Width of the caret cursor in logical pixels.
+const CARET_WIDTH: f32 = 3.0;
+
+/// How long an animation can go without being advanced before its frames
+/// are freed, see `advance_animation`.
+const ANIMATION_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Depth between consecutive `next_depth` calls, a power of two so every
+/// step is exact in `f32` and survives a `Depth32Float` buffer.
+pub const DEPTH_STEP: f32 = 1.0 / 65536.0;
+
+/// Depth of the shadow of a glyph at `depth`, a step beneath it. At `0.0`
+/// they share the depth and the shadow stays beneath by being drawn first.
+#[inline]
+fn shadow_depth(depth: f32) -> f32 {
+    (depth - DEPTH_STEP).max(0.0)
+}
 
 pub struct ComposedRect {
     rect: Rect,
@@ -37,53 +70,640 @@ pub struct ComposedRect {
     color: [f32; 4],
     has_alpha: bool,
     image: TextureId,
+    /// Depth of the glyph when it differs from its run, see `Glyph::with_depth`.
+    depth: Option<f32>,
+}
+
+/// How a background image is scaled to the viewport.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Fills the viewport ignoring the aspect ratio.
+    #[default]
+    Stretch,
+    /// Scales to fit entirely inside the viewport, centered.
+    Contain,
+    /// Scales to cover the whole viewport, cropping the overflow.
+    Cover,
+    /// Repeats the image at its natural size from the top left corner.
+    Tile,
+}
+
+/// Colors replacing the ones of every text run, like the high contrast
+/// themes of operating systems.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ForcedColors {
+    pub foreground: [f32; 4],
+    pub background: [f32; 4],
+    pub cursor: [f32; 4],
+    pub selection_foreground: [f32; 4],
+    pub selection_background: [f32; 4],
+}
+
+impl ForcedColors {
+    /// Forces `foreground` over `background`, with the cursor drawn in the
+    /// foreground color and the selection inverted.
+    pub fn new(foreground: [f32; 4], background: [f32; 4]) -> Self {
+        Self {
+            foreground,
+            background,
+            cursor: foreground,
+            selection_foreground: background,
+            selection_background: foreground,
+        }
+    }
+
+    /// Returns `style` with its colors replaced, geometry is untouched.
+    fn apply<'a>(&self, style: &TextRunStyle<'a>) -> TextRunStyle<'a> {
+        let mut style = *style;
+        let (foreground, background) = if style.selected {
+            (self.selection_foreground, self.selection_background)
+        } else {
+            (self.foreground, self.background)
+        };
+        style.color = foreground;
+        style.ansi_index = None;
+        if style.selected || style.background_color.is_some() {
+            style.background_color = Some(background);
+        }
+        style.default_background = self.background;
+        style.cursor = match style.cursor {
+            SugarCursor::Block(_) => SugarCursor::Block(self.cursor),
+            SugarCursor::Caret(_) => SugarCursor::Caret(self.cursor),
+            cursor => cursor,
+        };
+        if let Some(strikethrough) = &mut style.strikethrough {
+            strikethrough.color = strikethrough.color.map(|_| foreground);
+        }
+        if let Some(underline) = &mut style.underline {
+            underline.color = if underline.cursor {
+                self.cursor
+            } else {
+                foreground
+            };
+        }
+        style
+    }
+}
+
+/// A `TextRunStyle` with the compositor settings applied (forced colors,
+/// color space, bright bold and minimum contrast) and its glyph cache slot
+/// looked up, from `Compositor::resolve_style`. Keep it across frames for
+/// runs that don't change.
+///
+/// Changing any of those settings, or the scale, makes it out of date and
+/// `Compositor::draw_resolved` resolves it again. Changing the run itself,
+/// e.g. its font size, is done with `set_font_size` or by resolving a new
+/// style.
+#[derive(Copy, Clone)]
+pub struct ResolvedStyle<'a> {
+    source: TextRunStyle<'a>,
+    style: TextRunStyle<'a>,
+    color: [f32; 4],
+    font_slot: usize,
+    generation: u64,
+}
+
+impl<'a> ResolvedStyle<'a> {
+    /// The style as given to `resolve_style`.
+    #[inline]
+    pub fn source(&self) -> &TextRunStyle<'a> {
+        &self.source
+    }
+
+    /// Changes the font size, the style is resolved again when next drawn.
+    #[allow(unused)]
+    pub fn set_font_size(&mut self, font_size: f32) {
+        self.source.font_size = font_size;
+        self.generation = u64::MAX;
+    }
 }
 
 pub enum CachedRect {
     Image(ComposedRect),
     Mask(ComposedRect),
+    /// The shadow of a glyph mask, drawn just beneath the glyph's depth.
+    Shadow(ComposedRect),
     Standard((Rect, [f32; 4])),
+    /// The cursor of a run, which dim overlays leave uncovered.
+    Cursor((Rect, [f32; 4])),
+}
+
+/// Colors of search matches, see `Compositor::draw_search_match`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HighlightColors {
+    /// The match the search is currently on.
+    pub current_match: [f32; 4],
+    /// Every other match.
+    pub other_match: [f32; 4],
+}
+
+impl Default for HighlightColors {
+    fn default() -> Self {
+        Self {
+            current_match: [1.0, 0.6, 0.0, 1.0],
+            other_match: [1.0, 0.85, 0.0, 0.5],
+        }
+    }
+}
+
+/// Direction a divider drawn by `Compositor::draw_divider` runs in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DividerOrientation {
+    /// Between panes stacked on top of each other.
+    Horizontal,
+    /// Between panes side by side.
+    Vertical,
+}
+
+/// Colors of pane dividers, see `Compositor::draw_divider`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DividerColors {
+    /// Dividers along the focused pane.
+    pub active: [f32; 4],
+    /// Every other divider.
+    pub inactive: [f32; 4],
+}
+
+impl Default for DividerColors {
+    fn default() -> Self {
+        Self {
+            active: [0.6, 0.6, 0.6, 1.0],
+            inactive: [0.3, 0.3, 0.3, 1.0],
+        }
+    }
+}
+
+/// Shape of a cursor drawn by `Compositor::draw_animated_cursor`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CursorShape {
+    #[default]
+    Block,
+    Caret,
+    Underline,
 }
 
+/// Batches glyphs, rects and images into a `DisplayList`.
+///
+/// # Depth and ordering
+///
+/// Every draw call takes a `depth`, but by default it does not control
+/// layering: the pipeline is created without a depth buffer
+/// (`depth_stencil: None`). Callers should pass `0.0` unless
+/// `DepthMode::Tested` is enabled on the brush, in which case draws with a
+/// greater depth (within `0.0..=1.0`) occlude lower ones. `next_depth`
+/// hands out increasing depths in call order for callers that just want
+/// later draws on top.
+///
+/// Otherwise layering is decided by submission order, in four groups:
+///
+/// 1. Opaque draws: rects with alpha `1.0` and images without alpha, which
+///    includes the background image.
+/// 2. Highlights, from `draw_highlight`.
+/// 3. Transparent draws: glyph masks, color glyphs and translucent rects.
+/// 4. Overlays, such as `draw_dim_overlay`, at the greatest depth of the
+///    frame so they also win the depth test.
+///
+/// Each group is drawn entirely before the next one. Inside a group, a draw
+/// is appended to the first batch that can hold it (same textures and
+/// subpixel mode), so two draws are only guaranteed to keep their call order
+/// when they end up in the same batch.
 pub struct Compositor {
     images: ImageCache,
     glyphs: GlyphCache,
     batches: BatchManager,
     intercepts: Vec<(f32, f32)>,
+    background_opacity: f32,
+    background_image: Option<(ImageId, Fit)>,
+    vertical_subpixel_bias: f32,
+    scale: f32,
+    glyph_run: Vec<Glyph>,
+    bold_is_bright: bool,
+    bright_palette: [[f32; 4]; 8],
+    min_contrast: Option<f32>,
+    forced_colors: Option<ForcedColors>,
+    box_drawing: bool,
+    powerline: Vec<char>,
+    builtin_glyphs: HashMap<u64, HashMap<GlyphId, char>>,
+    kitty_images: HashMap<u32, ImageId>,
+    builtin_rects: Vec<(Rect, f32)>,
+    missing_glyph: Option<MissingGlyphStyle>,
+    missing_glyph_rects: Vec<Rect>,
+    hinting: Hinting,
+    center_glyphs: bool,
+    tab_width: usize,
+    highlight_colors: HighlightColors,
+    divider_colors: DividerColors,
+    pixel_snapping: bool,
+    cursor_blink: Option<CursorBlink>,
+    cursor_visible: bool,
+    /// Whether the target displays colors out of the sRGB gamut.
+    extended_range: bool,
+    /// Cursors drawn since `begin`, cut out of the overlays by `finish`.
+    cursor_rects: Vec<Rect>,
+    overlays: Vec<(Rect, [f32; 4])>,
+    next_depth: f32,
+    /// Bumped by every setting `resolve_style` depends on.
+    style_generation: u64,
+    #[cfg(feature = "cell_widths")]
+    cell_widths: Option<CellWidths>,
 }
 
 impl Compositor {
     /// Creates a new compositor.
+    #[allow(unused)]
     pub fn new(max_texture_size: u16) -> Self {
+        Self::with_atlas_size(max_texture_size, max_texture_size)
+    }
+
+    /// Creates a new compositor whose glyph atlases start at `initial_size`
+    /// and grow toward `max_size` as glyphs are added, see
+    /// `take_atlas_resized`.
+    pub fn with_atlas_size(initial_size: u16, max_size: u16) -> Self {
         Self {
-            images: ImageCache::new(max_texture_size),
+            images: ImageCache::with_sizes(initial_size, max_size),
             glyphs: GlyphCache::new(),
-            batches: BatchManager::new(),
+            batches: BatchManager::with_capacity(EXPECTED_CELLS),
             intercepts: Vec::new(),
+            background_opacity: 1.0,
+            background_image: None,
+            vertical_subpixel_bias: 0.5,
+            scale: 1.0,
+            glyph_run: Vec::new(),
+            bold_is_bright: false,
+            bright_palette: DEFAULT_BRIGHT_PALETTE,
+            min_contrast: None,
+            forced_colors: None,
+            box_drawing: true,
+            powerline: Vec::new(),
+            builtin_glyphs: HashMap::new(),
+            kitty_images: HashMap::new(),
+            builtin_rects: Vec::new(),
+            missing_glyph: Some(MissingGlyphStyle::default()),
+            missing_glyph_rects: Vec::new(),
+            hinting: Hinting::default(),
+            center_glyphs: false,
+            tab_width: TabStops::DEFAULT_WIDTH,
+            highlight_colors: HighlightColors::default(),
+            divider_colors: DividerColors::default(),
+            pixel_snapping: false,
+            cursor_blink: None,
+            cursor_visible: true,
+            extended_range: false,
+            cursor_rects: Vec::new(),
+            overlays: Vec::new(),
+            next_depth: 0.0,
+            style_generation: 0,
+            #[cfg(feature = "cell_widths")]
+            cell_widths: None,
+        }
+    }
+
+    /// Derives the width of runs drawn with `draw_text` from their code
+    /// points, `None` takes it from the rect like `draw_glyphs`.
+    #[cfg(feature = "cell_widths")]
+    #[allow(unused)]
+    pub fn set_cell_widths(&mut self, cell_widths: Option<CellWidths>) {
+        self.cell_widths = cell_widths;
+    }
+
+    /// Fits glyph outlines to the pixel grid with `hinting`, see
+    /// `Hinting` for how it plays with subpixel positions. Glyphs are
+    /// cached per mode, switching back and forth doesn't rasterize them
+    /// again. `Hinting::Slight` by default.
+    #[allow(unused)]
+    pub fn set_hinting(&mut self, hinting: Hinting) {
+        self.hinting = hinting;
+    }
+
+    /// Darkens the stems of outline glyphs at small sizes with
+    /// `intensity`, 1.0 matching FreeType, see
+    /// `GlyphCache::set_stem_darkening`. Disabled by default.
+    #[allow(unused)]
+    pub fn set_stem_darkening(&mut self, enabled: bool, intensity: f32) {
+        self.glyphs
+            .set_stem_darkening(&mut self.images, enabled, intensity);
+    }
+
+    /// Draws box-drawing and block characters (U+2500 to U+259F) with rects
+    /// sized to the cell instead of the font's glyphs, so borders don't
+    /// blur or leave seams between cells. Enabled by default.
+    pub fn set_box_drawing(&mut self, enabled: bool) {
+        self.box_drawing = enabled;
+    }
+
+    /// Draws a box in place of glyphs missing from the font (glyph 0) in
+    /// horizontal runs, styled by `style`. `None` draws the font's own
+    /// `.notdef` glyph instead.
+    #[allow(unused)]
+    pub fn set_missing_glyph_style(&mut self, style: Option<MissingGlyphStyle>) {
+        self.missing_glyph = style;
+    }
+
+    /// Blinks the cursors drawn by `draw_glyphs` with `blink`, or keeps them
+    /// visible with `None`. Visibility only changes on `update_cursor_blink`.
+    pub fn set_cursor_blink(&mut self, blink: Option<CursorBlink>) {
+        self.cursor_blink = blink;
+        self.cursor_visible = true;
+    }
+
+    #[inline]
+    pub fn cursor_blink_mut(&mut self) -> Option<&mut CursorBlink> {
+        self.cursor_blink.as_mut()
+    }
+
+    /// Resolves whether cursors are drawn in the frame at `now`, returning
+    /// true when that changed since the last update.
+    pub fn update_cursor_blink(&mut self, now: Instant) -> bool {
+        let visible = self
+            .cursor_blink
+            .map_or(true, |blink| blink.is_visible(now));
+        let changed = visible != self.cursor_visible;
+        self.cursor_visible = visible;
+        changed
+    }
+
+    /// Sets the number of cells between tab stops, used when laying out runs
+    /// that contain tabs. 0 gives tabs the width of a single cell.
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width;
+    }
+
+    #[inline]
+    pub fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+
+    /// Centers glyphs in lines taller than their font, for the runs of
+    /// layouts drawn by the brush, see `TextRunStyle::glyph_height`.
+    /// Disabled by default, the extra space goes below the glyphs.
+    pub fn set_center_glyphs(&mut self, enabled: bool) {
+        self.center_glyphs = enabled;
+    }
+
+    #[inline]
+    pub fn center_glyphs(&self) -> bool {
+        self.center_glyphs
+    }
+
+    /// Draws the powerline separator `ch` (see [`powerline::GLYPHS`]) with
+    /// primitives sized to the cell instead of the font's glyph. Disabled by
+    /// default for every separator, fonts with good ones can keep them.
+    pub fn set_powerline(&mut self, ch: char, enabled: bool) {
+        if !powerline::is_supported(ch) {
+            return;
+        }
+        self.powerline.retain(|c| *c != ch);
+        if enabled {
+            self.powerline.push(ch);
+        }
+    }
+
+    /// Draws bold runs of the eight base ANSI colors with their bright
+    /// variant.
+    pub fn set_bold_is_bright(&mut self, enabled: bool) {
+        self.bold_is_bright = enabled;
+        self.style_generation += 1;
+    }
+
+    /// Sets the bright variants of the eight base ANSI colors.
+    pub fn set_bright_palette(&mut self, palette: [[f32; 4]; 8]) {
+        self.bright_palette = palette;
+        self.style_generation += 1;
+    }
+
+    /// Sets the minimum contrast ratio between glyphs and the background of
+    /// their run. Glyph colors are lightened or darkened at draw time when
+    /// needed, runs without a background are left alone.
+    pub fn set_min_contrast(&mut self, min_contrast: Option<f32>) {
+        self.min_contrast = min_contrast;
+        self.style_generation += 1;
+    }
+
+    /// Overrides the colors of every run while keeping its geometry and
+    /// decorations, `None` restores the run colors.
+    pub fn set_forced_colors(&mut self, forced_colors: Option<ForcedColors>) {
+        self.forced_colors = forced_colors;
+        self.style_generation += 1;
+    }
+
+    /// Sets whether the target is an extended range one, see
+    /// `context::is_extended_range`. Display P3 runs keep their colors out of
+    /// the sRGB gamut on those instead of being clamped to it.
+    pub fn set_extended_range(&mut self, extended_range: bool) {
+        self.extended_range = extended_range;
+        self.style_generation += 1;
+    }
+
+    /// Sets the scale factor used to size cursors.
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+        self.style_generation += 1;
+    }
+
+    #[inline]
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Resolves a cursor thickness given in logical pixels to physical
+    /// pixels, so cursors look the same on 1x and 2x displays.
+    #[inline]
+    fn cursor_thickness(&self, thickness: f32) -> f32 {
+        if self.pixel_snapping {
+            return snap_size(thickness * self.scale);
+        }
+        (thickness * self.scale).round().max(1.)
+    }
+
+    /// Aligns cursors and underlines to physical pixels with `Rect::snap`,
+    /// so thin ones don't blur over two pixels at fractional positions or
+    /// scales. Disabled by default.
+    #[allow(unused)]
+    pub fn set_pixel_snapping(&mut self, enabled: bool) {
+        self.pixel_snapping = enabled;
+    }
+
+    /// Snaps `rect` to physical pixels when pixel snapping is enabled.
+    #[inline]
+    fn snap(&self, rect: Rect) -> Rect {
+        if self.pixel_snapping {
+            rect.snap(1.0)
+        } else {
+            rect
         }
     }
 
+    /// Scales the alpha of run backgrounds without affecting glyphs, so a
+    /// translucent window still shows crisp text.
+    pub fn set_background_opacity(&mut self, opacity: f32) {
+        self.background_opacity = opacity.clamp(0.0, 1.0);
+    }
+
+    /// Sets the bias added to a glyph's y position before it is snapped to
+    /// the pixel grid. Glyphs are rasterized at whole pixels vertically so
+    /// the baseline stays put across lines with fractional positions; the
+    /// default of `0.5` rounds to the nearest pixel.
+    pub fn set_vertical_subpixel_bias(&mut self, bias: f32) {
+        self.vertical_subpixel_bias = bias;
+    }
+
     /// Advances the epoch for the compositor and clears all batches.
     pub fn begin(&mut self) {
         // TODO: Write a better prune system that doesn't rely on epoch
         // self.glyphs.prune(&mut self.images);
         self.batches.reset();
+        self.glyphs.begin_frame();
+        self.cursor_rects.clear();
+        self.overlays.clear();
+        self.next_depth = 0.0;
+    }
+
+    /// Returns a depth greater than the one returned by the previous call
+    /// since `begin`, starting at `0.0` and going up by `DEPTH_STEP`, so
+    /// with `DepthMode::Tested` each draw covers the ones drawn before it.
+    /// It stops at `1.0` after 65536 calls, where further draws share the
+    /// same depth and fall back to submission order.
+    ///
+    /// Manual depths can be mixed in: a draw at `0.5` covers every draw
+    /// handed a depth up to `0.5` and is covered by those after it, e.g.
+    /// kitty images placed with `kitty_depth`. Depths aren't handed out
+    /// around manual ones, so passing `0.0` by hand puts a draw under
+    /// everything layered with `next_depth`.
+    #[allow(unused)]
+    pub fn next_depth(&mut self) -> f32 {
+        let depth = self.next_depth;
+        self.next_depth = (depth + DEPTH_STEP).min(1.0);
+        depth
+    }
+
+    /// Glyph cache activity since the last `begin`.
+    #[inline]
+    pub fn glyph_cache_stats(&self) -> GlyphCacheStats {
+        self.glyphs.frame_stats()
+    }
+
+    /// Glyph cache activity since the compositor was created.
+    #[inline]
+    pub fn total_glyph_cache_stats(&self) -> GlyphCacheStats {
+        self.glyphs.total_stats()
+    }
+
+    /// Rasterizes the glyphs of a run ahead of `draw_glyphs`, in parallel,
+    /// so a frame following a font or size change doesn't rasterize every
+    /// glyph on screen one after the other. Glyphs are placed as in
+    /// `draw_glyphs`, so the same cache entries are hit when drawing.
+    #[allow(unused)]
+    pub fn warm_glyphs<I>(&mut self, style: &TextRunStyle, glyphs: I)
+    where
+        I: Iterator,
+        I::Item: Borrow<Glyph>,
+    {
+        let size_scale = style.vertical_align.scale();
+        let baseline_shift =
+            style.vertical_align.baseline_shift(style.font_size) + centering(style);
+        let glyphs: Vec<_> = glyphs
+            .map(|glyph| {
+                let glyph = glyph.borrow();
+                let y = (glyph.y + baseline_shift + self.vertical_subpixel_bias).floor();
+                (glyph.id, glyph.x, y)
+            })
+            .collect();
+        self.glyphs
+            .session(
+                &mut self.images,
+                style.font,
+                style.font_coords,
+                style.font_size * size_scale,
+                style.presentation,
+                self.hinting,
+            )
+            .warm_glyphs_parallel(&glyphs);
     }
 
     /// Builds a display list for the current batched geometry and enumerates
     /// all texture events with the specified closure.
     pub fn finish(&mut self, list: &mut DisplayList, events: impl FnMut(TextureEvent)) {
         self.images.drain_events(events);
+        // Overlays go at the greatest depth of the frame so they also cover
+        // everything beneath them with `DepthMode::Tested`
+        let depth = self.batches.max_depth();
+        for (rect, color) in &self.overlays {
+            let mut parts = vec![*rect];
+            for cursor in &self.cursor_rects {
+                parts = parts
+                    .iter()
+                    .flat_map(|part| part.subtract(cursor))
+                    .collect();
+            }
+            for part in &parts {
+                self.batches.add_overlay_rect(part, depth, color);
+            }
+        }
         self.batches.build_display_list(list);
     }
 }
 
 /// Image management.
 impl Compositor {
-    /// Adds an image to the compositor.
+    /// Adds an image to the compositor. RGBA data with alpha is expected
+    /// with straight alpha and premultiplied before it is cached.
     #[allow(unused)]
     pub fn add_image(&mut self, request: AddImage) -> Option<ImageId> {
-        self.images.allocate(request)
+        if request.format != PixelFormat::Rgba8 || !request.has_alpha {
+            return self.images.allocate(request);
+        }
+        let ImageData::Borrowed(data) = request.data;
+        let data = premultiply(data);
+        self.images.allocate(AddImage {
+            data: ImageData::Borrowed(&data),
+            ..request
+        })
+    }
+
+    /// Adds the frames of an animated image, each with how long it is shown,
+    /// like `add_image`. The returned id draws the frame picked by
+    /// `advance_animation`, the first one until then.
+    #[allow(unused)]
+    pub fn add_animation(&mut self, frames: &[(AddImage, Duration)]) -> Option<ImageId> {
+        let data: Vec<Vec<u8>> = frames
+            .iter()
+            .map(|(request, _)| {
+                let ImageData::Borrowed(data) = request.data;
+                if request.format == PixelFormat::Rgba8 && request.has_alpha {
+                    premultiply(data)
+                } else {
+                    data.to_vec()
+                }
+            })
+            .collect();
+        let frames: Vec<(AddImage, Duration)> = frames
+            .iter()
+            .zip(&data)
+            .map(|((request, duration), data)| {
+                let request = AddImage {
+                    data: ImageData::Borrowed(data),
+                    ..*request
+                };
+                (request, *duration)
+            })
+            .collect();
+        self.images.allocate_animation(&frames)
+    }
+
+    /// Selects the frame of an animated image to draw at `now`, and frees
+    /// the frames of animations that weren't advanced for
+    /// `ANIMATION_IDLE_TIMEOUT`, which are no longer on screen. Returns
+    /// whether the frame changed, so the caller knows to redraw.
+    #[allow(unused)]
+    pub fn advance_animation(&mut self, image: ImageId, now: Instant) -> bool {
+        let changed = self.images.advance_animation(image, now);
+        let evicted = self
+            .images
+            .evict_idle_animations(now, ANIMATION_IDLE_TIMEOUT);
+        if evicted > 0 {
+            trace_event!(debug, "rich_text: evicted {evicted} idle animations");
+        }
+        changed
     }
 
     /// Returns the image associated with the specified identifier.
@@ -92,22 +712,409 @@ impl Compositor {
         self.images.get(image)
     }
 
+    /// Sets an image to be drawn behind everything else. The image must have
+    /// been added with `add_image`.
+    pub fn set_background_image(&mut self, image: ImageId, fit: Fit) {
+        self.background_image = Some((image, fit));
+    }
+
+    /// Removes the background image.
+    pub fn clear_background_image(&mut self) {
+        self.background_image = None;
+    }
+
     /// Removes the image from the compositor.
     #[allow(unused)]
     pub fn remove_image(&mut self, image: ImageId) -> bool {
+        trace_event!(trace, ?image, "rich_text: image removed");
         self.images.deallocate(image).is_some()
     }
+
+    /// Share of the atlas space freed between live glyphs and images, see
+    /// `defragment_atlas`.
+    #[inline]
+    pub fn atlas_fragmentation(&self) -> f32 {
+        self.images.fragmentation()
+    }
+
+    /// Returns whether the atlases grew since the last call. Rects drawn
+    /// before, including the ones already batched for the current frame,
+    /// have stale texture coordinates and must be drawn again.
+    pub fn take_atlas_resized(&mut self) -> bool {
+        self.images.take_resized()
+    }
+
+    /// Repacks the live glyphs and images into fresh atlases, so large
+    /// glyphs fit again after removals scattered the free space. Image ids
+    /// stay valid, the new atlases are uploaded by the next `finish`. Call it
+    /// between frames, and drop any rects cached from previous draws since
+    /// their texture coordinates are stale. Returns false, changing nothing,
+    /// when the glyphs don't fit in fresh atlases.
+    pub fn defragment_atlas(&mut self) -> bool {
+        trace_span!(debug, "defragment_atlas");
+        self.images.defragment()
+    }
 }
 
 /// Drawing.
 impl Compositor {
-    /// Draws a rectangle with the specified depth and color.
+    /// Draws a rectangle with the specified depth and color. See the
+    /// `Compositor` docs for how draws are layered.
     #[allow(unused)]
     pub fn draw_rect(&mut self, rect: impl Into<Rect>, depth: f32, color: &[f32; 4]) {
         self.batches.add_rect(&rect.into(), depth, color);
     }
 
-    /// Draws an image with the specified rectangle, depth and color.
+    /// Draws the outline of `rect` with corners rounded by `radius`. The
+    /// `thickness` is in logical pixels, scaled like cursors are.
+    #[allow(unused)]
+    pub fn draw_rect_outline(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        thickness: f32,
+        radius: f32,
+        color: &[f32; 4],
+    ) {
+        let thickness = self.cursor_thickness(thickness);
+        for rect in outline_rects(&rect.into(), radius, thickness) {
+            self.batches.add_rect(&rect, depth, color);
+        }
+    }
+
+    /// Draws many rectangles at once, like calling `draw_rect` for each in
+    /// order, with room for all of them reserved up front. Consecutive rects
+    /// of the same color touching on the same row, like the cell backgrounds
+    /// of a line, are merged into one.
+    #[allow(unused)]
+    pub fn draw_rects(&mut self, rects: &[(Rect, [f32; 4])], depth: f32) {
+        let transparent = rects.iter().filter(|(_, color)| color[3] != 1.0).count();
+        self.batches
+            .reserve_rects(rects.len() - transparent, transparent);
+
+        let mut pending: Option<(Rect, [f32; 4])> = None;
+        for (rect, color) in rects {
+            if let Some((last, last_color)) = &mut pending {
+                if last_color == color
+                    && (last.y - rect.y).abs() < 0.01
+                    && (last.height - rect.height).abs() < 0.01
+                    && (last.x + last.width - rect.x).abs() < 0.01
+                {
+                    last.width = rect.x + rect.width - last.x;
+                    continue;
+                }
+            }
+            if let Some((last, last_color)) = pending.replace((*rect, *color)) {
+                self.draw_rect(last, depth, &last_color);
+            }
+        }
+        if let Some((last, last_color)) = pending {
+            self.draw_rect(last, depth, &last_color);
+        }
+    }
+
+    /// Highlights cells, e.g. search matches, above their background and
+    /// beneath their text. Adjacent cells are merged so a match spanning
+    /// several cells, or lines, is drawn as a single rect.
+    #[allow(unused)]
+    pub fn draw_highlight(&mut self, rects: &[Rect], color: &[f32; 4]) {
+        for rect in coalesce(rects) {
+            self.batches.add_highlight_rect(&rect, 0.0, color);
+        }
+    }
+
+    /// Highlights the cells of a search match with the current or other match
+    /// color.
+    #[allow(unused)]
+    pub fn draw_search_match(&mut self, rects: &[Rect], current: bool) {
+        let color = if current {
+            self.highlight_colors.current_match
+        } else {
+            self.highlight_colors.other_match
+        };
+        self.draw_highlight(rects, &color);
+    }
+
+    /// Sets the colors used by `draw_search_match`.
+    #[allow(unused)]
+    pub fn set_highlight_colors(&mut self, colors: HighlightColors) {
+        self.highlight_colors = colors;
+    }
+
+    /// Draws a straight line from `start` to `end`, `width` thick and
+    /// centered on them, with square ends that stop at the endpoints. Axis
+    /// aligned lines are drawn as rects, others as a rotated quad. Nothing is
+    /// drawn for a zero length or width.
+    #[allow(unused)]
+    pub fn draw_line(
+        &mut self,
+        start: [f32; 2],
+        end: [f32; 2],
+        width: f32,
+        depth: f32,
+        color: &[f32; 4],
+    ) {
+        let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length == 0. || width <= 0. {
+            return;
+        }
+        let half = width / 2.;
+        if dx == 0. || dy == 0. {
+            let (x, y) = (start[0].min(end[0]), start[1].min(end[1]));
+            let rect = if dy == 0. {
+                Rect::new(x, y - half, dx.abs(), width)
+            } else {
+                Rect::new(x - half, y, width, dy.abs())
+            };
+            self.batches.add_rect(&rect, depth, color);
+            return;
+        }
+        // Half the width along the normal of the line
+        let (nx, ny) = (-dy / length * half, dx / length * half);
+        let points = [
+            [start[0] - nx, start[1] - ny],
+            [start[0] + nx, start[1] + ny],
+            [end[0] + nx, end[1] + ny],
+            [end[0] - nx, end[1] - ny],
+        ];
+        self.batches.add_quad(&points, depth, color);
+    }
+
+    /// Draws a divider between panes, starting at `position` and running
+    /// `length` pixels along `orientation`, centered on the boundary. The
+    /// `thickness` is in logical pixels, scaled like cursors are and rounded
+    /// to whole physical pixels, and the divider is snapped to the pixel grid
+    /// so it stays crisp at any scale. Dividers along the focused pane use
+    /// the active color, see `set_divider_colors`.
+    #[allow(unused)]
+    pub fn draw_divider(
+        &mut self,
+        orientation: DividerOrientation,
+        position: [f32; 2],
+        length: f32,
+        thickness: f32,
+        active: bool,
+    ) {
+        let color = if active {
+            self.divider_colors.active
+        } else {
+            self.divider_colors.inactive
+        };
+        let thickness = self.cursor_thickness(thickness);
+        let [x, y] = position;
+        let half = thickness / 2.;
+        let rect = match orientation {
+            DividerOrientation::Horizontal => Rect::new(x, y - half, length, thickness),
+            DividerOrientation::Vertical => Rect::new(x - half, y, thickness, length),
+        }
+        .snap(1.0);
+        if rect.width > 0. && rect.height > 0. {
+            self.draw_rect(rect, 0.0, &color);
+        }
+    }
+
+    /// Sets the colors used by `draw_divider`.
+    #[allow(unused)]
+    pub fn set_divider_colors(&mut self, colors: DividerColors) {
+        self.divider_colors = colors;
+    }
+
+    /// Draws a selection from the cell `start` to the cell `end`, both
+    /// included, as `(column, row)` in a grid `columns` wide whose top left
+    /// cell is `grid`. Lines between the first and the last are selected
+    /// whole, and the outer corners of the shape are rounded by `radius`
+    /// pixels while the inner ones stay square. The shape is drawn without
+    /// overlaps, so translucent colors blend once.
+    #[allow(unused)]
+    pub fn draw_selection_span(
+        &mut self,
+        start: (u16, u16),
+        end: (u16, u16),
+        columns: u16,
+        grid: Rect,
+        color: &[f32; 4],
+        radius: f32,
+    ) {
+        let (start, end) = if (start.1, start.0) <= (end.1, end.0) {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        let columns = columns as f32;
+        let row = |first: u16, last: u16, from: f32, to: f32| {
+            Rect::new(
+                grid.x + from * grid.width,
+                grid.y + first as f32 * grid.height,
+                (to - from) * grid.width,
+                (last - first + 1) as f32 * grid.height,
+            )
+        };
+        let (first, last) = (start.1, end.1);
+        let mut rows = vec![];
+        if first == last {
+            rows.push(row(first, first, start.0 as f32, end.0 as f32 + 1.));
+        } else {
+            rows.push(row(first, first, start.0 as f32, columns));
+            if last - first > 1 {
+                rows.push(row(first + 1, last - 1, 0., columns));
+            }
+            rows.push(row(last, last, 0., end.0 as f32 + 1.));
+        }
+
+        // A corner is outer unless the row next to it continues the shape
+        // past it
+        let covers = |other: Option<&Rect>, x: f32, right: bool| {
+            other.is_some_and(|other| {
+                let (left, end) = (other.x, other.x + other.width);
+                if right {
+                    left < x && x <= end
+                } else {
+                    left <= x && x < end
+                }
+            })
+        };
+        for (index, rect) in rows.iter().enumerate() {
+            let above = index.checked_sub(1).and_then(|index| rows.get(index));
+            let below = rows.get(index + 1);
+            let right = rect.x + rect.width;
+            let corners = [
+                !covers(above, rect.x, false),
+                !covers(above, right, true),
+                !covers(below, right, true),
+                !covers(below, rect.x, false),
+            ];
+            for quad in rounded_quads(rect, radius, corners) {
+                self.batches.add_quad(&quad, 0.0, color);
+            }
+        }
+    }
+
+    /// Draws a cursor moving from the cell `from` to the cell `to`, `t` of
+    /// the way through the animation. The corners leading the move get there
+    /// first, so the cursor stretches into a trail toward `to` and then
+    /// catches up; `t` is driven by the host and clamped to `0.0..=1.0`.
+    /// Like any rect, call it after the lines it moves over so it covers
+    /// their backgrounds, and leave the cursor of their style disabled.
+    #[allow(unused)]
+    pub fn draw_animated_cursor(
+        &mut self,
+        from: Rect,
+        to: Rect,
+        t: f32,
+        color: &[f32; 4],
+        shape: CursorShape,
+    ) {
+        let from = self.cursor_rect(from, shape);
+        let to = self.cursor_rect(to, shape);
+        let points = smear(&from, &to, t);
+        let [top_left, bottom_left, bottom_right, top_right] = points;
+        if top_left[0] == bottom_left[0]
+            && top_right[0] == bottom_right[0]
+            && top_left[1] == top_right[1]
+            && bottom_left[1] == bottom_right[1]
+        {
+            let rect = Rect::new(
+                top_left[0],
+                top_left[1],
+                bottom_right[0] - top_left[0],
+                bottom_right[1] - top_left[1],
+            );
+            self.batches.add_rect(&rect, 0.0, color);
+        } else {
+            self.batches.add_quad(&points, 0.0, color);
+        }
+        // Overlays are cut by the bounds of the quad
+        let (mut left, mut top) = (f32::INFINITY, f32::INFINITY);
+        let (mut right, mut bottom) = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for [x, y] in points {
+            (left, right) = (left.min(x), right.max(x));
+            (top, bottom) = (top.min(y), bottom.max(y));
+        }
+        self.cursor_rects
+            .push(Rect::new(left, top, right - left, bottom - top));
+    }
+
+    /// The part of `cell` covered by a cursor of `shape`.
+    fn cursor_rect(&self, cell: Rect, shape: CursorShape) -> Rect {
+        match shape {
+            CursorShape::Block => cell,
+            CursorShape::Caret => Rect::new(
+                cell.x,
+                cell.y,
+                self.cursor_thickness(CARET_WIDTH),
+                cell.height,
+            ),
+            CursorShape::Underline => {
+                let thickness = self.cursor_thickness(1.0);
+                Rect::new(
+                    cell.x,
+                    cell.y + cell.height - thickness,
+                    cell.width,
+                    thickness,
+                )
+            }
+        }
+    }
+
+    /// Darkens a pane region, e.g. an unfocused split, by drawing a
+    /// translucent rect of `color` with the given `alpha` over it. The
+    /// overlay is submitted by `finish` after every other draw of the frame
+    /// so it sits above text and backgrounds, with the cursors drawn in the
+    /// frame cut out of it so they keep their color.
+    ///
+    /// With `DepthMode::Tested` the overlay is drawn at the greatest depth
+    /// used in the frame, so it also covers text and images layered above
+    /// `0.0`, e.g. kitty images at `kitty_depth`. Cursors stay above it
+    /// whatever their depth since they are cut out rather than covered.
+    #[allow(unused)]
+    pub fn draw_dim_overlay(
+        &mut self,
+        rect: impl Into<Rect>,
+        color: &[f32; 4],
+        alpha: f32,
+    ) {
+        let color = [color[0], color[1], color[2], alpha.clamp(0.0, 1.0)];
+        self.overlays.push((rect.into(), color));
+    }
+
+    /// Draws the background image, if any, to fill `viewport`. Must be called
+    /// right after `begin` so it ends up beneath any other draw.
+    pub fn draw_background_image(&mut self, viewport: Rect) {
+        let Some((image, fit)) = self.background_image else {
+            return;
+        };
+        let (Some(location), Some((width, height))) =
+            (self.images.get(image), self.images.size(image))
+        else {
+            return;
+        };
+
+        let (min, max) = (location.min, location.max);
+        for (rect, uv) in fit_image(fit, viewport, width as f32, height as f32) {
+            let coords = [
+                min.0 + uv[0] * (max.0 - min.0),
+                min.1 + uv[1] * (max.1 - min.1),
+                min.0 + uv[2] * (max.0 - min.0),
+                min.1 + uv[3] * (max.1 - min.1),
+            ];
+            // Added to the opaque list regardless of alpha, since opaque
+            // batches are submitted before transparent ones.
+            self.batches.add_image_rect(
+                &rect,
+                0.0,
+                &[1.0, 1.0, 1.0, 1.0],
+                &coords,
+                location.texture_id,
+                false,
+            );
+        }
+    }
+
+    /// Draws an image with the specified rectangle, depth and color. Every
+    /// sampled pixel is multiplied by `color`, an sRGB color like the ones of
+    /// `draw_rect`: white draws the image as it is, a lower alpha fades it
+    /// and another color tints it, e.g. a white icon drawn red comes out red.
     #[allow(unused)]
     pub fn draw_image(
         &mut self,
@@ -128,37 +1135,339 @@ impl Compositor {
         }
     }
 
-    pub fn draw_glyphs_from_cache(&mut self, cache: &Vec<CachedRect>, depth: f32) {
-        for val in cache {
-            match val {
-                CachedRect::Image(data) => {
-                    self.batches.add_image_rect(
-                        &data.rect,
-                        depth,
-                        &data.color,
-                        &data.coords,
-                        data.image,
-                        data.has_alpha,
-                    );
-                }
-                CachedRect::Mask(data) => {
-                    self.batches.add_mask_rect(
-                        &data.rect,
-                        depth,
-                        &data.color,
-                        &data.coords,
-                        data.image,
-                        data.has_alpha,
-                    );
-                }
+    /// Draws an image stretched over `rect` as nine slices: the corners,
+    /// `insets` in size, are kept as they are, the edges are stretched along
+    /// them and the middle both ways. Corners are shrunk evenly when `rect`
+    /// is smaller than them. Returns false, drawing nothing, when the image
+    /// is unknown or the insets don't fit in it. `color` tints the image as
+    /// in `draw_image`.
+    #[allow(unused)]
+    pub fn draw_image_9slice(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        color: &[f32; 4],
+        image: ImageId,
+        insets: Insets,
+    ) -> bool {
+        let Some((width, height)) = self.images.size(image) else {
+            return false;
+        };
+        let (width, height) = (width as f32, height as f32);
+        let Insets {
+            left,
+            top,
+            right,
+            bottom,
+        } = insets;
+        if left.min(top).min(right).min(bottom) < 0.
+            || left + right > width
+            || top + bottom > height
+        {
+            log::warn!(
+                "rich_text::compositor: insets {insets:?} exceed the {width}x{height} image"
+            );
+            return false;
+        }
+        let Some(location) = self.images.get(image) else {
+            return false;
+        };
+
+        let rect = rect.into();
+        // Both the source and target of each slice, along one axis
+        let slices = |start: f32, size: f32, low: f32, high: f32, extent: f32| {
+            let scale = if low + high > size {
+                size / (low + high)
+            } else {
+                1.
+            };
+            let target = [0., low * scale, size - high * scale, size].map(|v| start + v);
+            let source = [0., low, extent - high, extent].map(|v| v / extent);
+            (target, source)
+        };
+        let (xs, us) = slices(rect.x, rect.width, left, right, width);
+        let (ys, vs) = slices(rect.y, rect.height, top, bottom, height);
+        let (min, max) = (location.min, location.max);
+        let u = |v: f32| min.0 + (max.0 - min.0) * v;
+        let v = |v: f32| min.1 + (max.1 - min.1) * v;
+        for row in 0..3 {
+            for column in 0..3 {
+                let target = Rect::new(
+                    xs[column],
+                    ys[row],
+                    xs[column + 1] - xs[column],
+                    ys[row + 1] - ys[row],
+                );
+                if target.width <= 0. || target.height <= 0. {
+                    continue;
+                }
+                let coords =
+                    [u(us[column]), v(vs[row]), u(us[column + 1]), v(vs[row + 1])];
+                self.batches.add_image_rect(
+                    &target,
+                    depth,
+                    color,
+                    &coords,
+                    location.texture_id,
+                    image.has_alpha(),
+                );
+            }
+        }
+        true
+    }
+
+    /// Draws a Sixel image, added with `add_image`, from the top left corner
+    /// of the cell at `(column, row)` and at its size in pixels, so it isn't
+    /// resampled. Returns the columns and rows of cells it covers, for moving
+    /// the cursor past it.
+    #[cfg(feature = "sixel")]
+    #[allow(unused)]
+    pub fn draw_sixel(
+        &mut self,
+        image: ImageId,
+        sixel: &SixelImage,
+        (column, row): (u16, u16),
+        (cell_width, cell_height): (f32, f32),
+        depth: f32,
+    ) -> (u16, u16) {
+        let rect = Rect::new(
+            column as f32 * cell_width,
+            row as f32 * cell_height,
+            sixel.width as f32,
+            sixel.height as f32,
+        );
+        self.draw_image(rect, depth, &[1.0, 1.0, 1.0, 1.0], image);
+        sixel.cells(cell_width, cell_height)
+    }
+
+    /// Adds an image transmitted with the Kitty graphics protocol under its
+    /// protocol id, replacing the image sent before with the same id.
+    #[allow(unused)]
+    pub fn add_kitty_image(&mut self, id: u32, image: &KittyImage) -> Option<ImageId> {
+        let image = self.add_image(image.as_add_image())?;
+        if let Some(previous) = self.kitty_images.insert(id, image) {
+            self.remove_image(previous);
+        }
+        Some(image)
+    }
+
+    /// Draws the Kitty image with protocol id `id` at the cell `(column,
+    /// row)`, scaled to the columns and rows of the placement, or at its size
+    /// in pixels when they are zero. The z-index is mapped to the depth with
+    /// `kitty_depth`. Returns the columns and rows of cells it covers, or
+    /// `None` for unknown ids.
+    #[allow(unused)]
+    pub fn draw_kitty_image(
+        &mut self,
+        id: u32,
+        placement: &KittyPlacement,
+        (column, row): (u16, u16),
+        (cell_width, cell_height): (f32, f32),
+    ) -> Option<(u16, u16)> {
+        let image = *self.kitty_images.get(&id)?;
+        let (width, height) = self.images.size(image)?;
+        let span = |cells: u16, pixels: u16, offset: u16, cell: f32| {
+            if cells > 0 {
+                (cells, cells as f32 * cell)
+            } else {
+                let size = pixels as f32;
+                (((size + offset as f32) / cell).ceil() as u16, size)
+            }
+        };
+        let (columns, width) =
+            span(placement.columns, width, placement.x_offset, cell_width);
+        let (rows, height) =
+            span(placement.rows, height, placement.y_offset, cell_height);
+        let rect = Rect::new(
+            column as f32 * cell_width + placement.x_offset as f32,
+            row as f32 * cell_height + placement.y_offset as f32,
+            width,
+            height,
+        );
+        let depth = kitty_depth(placement.z_index);
+        self.draw_image(rect, depth, &[1.0, 1.0, 1.0, 1.0], image);
+        Some((columns, rows))
+    }
+
+    /// Removes the Kitty images matched by a delete command. Returns whether
+    /// any was removed.
+    #[allow(unused)]
+    pub fn delete_kitty_images(&mut self, delete: KittyDelete) -> bool {
+        let removed: Vec<ImageId> = match delete {
+            KittyDelete::All => {
+                self.kitty_images.drain().map(|(_, image)| image).collect()
+            }
+            KittyDelete::Id(id) => self.kitty_images.remove(&id).into_iter().collect(),
+        };
+        for image in &removed {
+            self.remove_image(*image);
+        }
+        !removed.is_empty()
+    }
+
+    /// Draws an iTerm2 inline image, added with `add_image`, from the top
+    /// left corner of the cell at `(column, row)`, sized by the width, height
+    /// and aspect ratio options of `file`. `session` is the size in pixels
+    /// of the grid, which percentages refer to. Returns the columns and rows
+    /// of cells it covers.
+    #[allow(unused)]
+    pub fn draw_iterm2_image(
+        &mut self,
+        image: ImageId,
+        file: &ITerm2File,
+        (column, row): (u16, u16),
+        (cell_width, cell_height): (f32, f32),
+        session: (f32, f32),
+        depth: f32,
+    ) -> Option<(u16, u16)> {
+        let (width, height) = self.images.size(image)?;
+        let (width, height) = file.size(
+            (width as f32, height as f32),
+            (cell_width, cell_height),
+            session,
+        );
+        let rect = Rect::new(
+            column as f32 * cell_width,
+            row as f32 * cell_height,
+            width,
+            height,
+        );
+        self.draw_image(rect, depth, &[1.0, 1.0, 1.0, 1.0], image);
+        Some((
+            (width / cell_width).ceil() as u16,
+            (height / cell_height).ceil() as u16,
+        ))
+    }
+
+    pub fn draw_glyphs_from_cache(&mut self, cache: &Vec<CachedRect>, depth: f32) {
+        for val in cache {
+            match val {
+                CachedRect::Image(data) => {
+                    self.batches.add_image_rect(
+                        &data.rect,
+                        data.depth.unwrap_or(depth),
+                        &data.color,
+                        &data.coords,
+                        data.image,
+                        data.has_alpha,
+                    );
+                }
+                CachedRect::Mask(data) => {
+                    self.batches.add_mask_rect(
+                        &data.rect,
+                        data.depth.unwrap_or(depth),
+                        &data.color,
+                        &data.coords,
+                        data.image,
+                        data.has_alpha,
+                    );
+                }
+                CachedRect::Shadow(data) => {
+                    self.batches.add_mask_rect(
+                        &data.rect,
+                        shadow_depth(data.depth.unwrap_or(depth)),
+                        &data.color,
+                        &data.coords,
+                        data.image,
+                        data.has_alpha,
+                    );
+                }
                 CachedRect::Standard((rect, bg_color)) => {
                     self.batches.add_rect(rect, depth, bg_color);
                 }
+                CachedRect::Cursor((rect, cursor_color)) => {
+                    self.batches.add_rect(rect, depth, cursor_color);
+                    self.cursor_rects.push(*rect);
+                }
+            }
+        }
+    }
+
+    /// Resolves the colors and font of `style` once, for runs drawn with
+    /// `draw_resolved` frame after frame.
+    pub fn resolve_style<'a>(&mut self, style: &TextRunStyle<'a>) -> ResolvedStyle<'a> {
+        let source = *style;
+        let mut style = match self.forced_colors {
+            Some(forced_colors) => forced_colors.apply(style),
+            None => *style,
+        };
+        // Targets are sRGB, wgpu can't tag them otherwise, but extended range
+        // ones display components below 0.0 and past 1.0, i.e. wider gamuts
+        if style.color_space != ColorSpace::Srgb {
+            style = convert_style(&style, ColorSpace::Srgb, self.extended_range);
+        }
+        let mut color = if self.bold_is_bright && style.bold {
+            bright_variant(style.color, style.ansi_index, &self.bright_palette)
+        } else {
+            style.color
+        };
+        let background = style
+            .background_color
+            .or((style.default_background[3] > 0.).then_some(style.default_background));
+        if let (Some(min_contrast), Some(background)) = (self.min_contrast, background) {
+            color = ensure_contrast(color, background, min_contrast);
+        }
+        ResolvedStyle {
+            source,
+            style,
+            color,
+            font_slot: self
+                .glyphs
+                .font_slot(style.font.key.value(), style.font_coords),
+            generation: self.style_generation,
+        }
+    }
+
+    /// Returns whether `style` is still up to date, see `ResolvedStyle`.
+    #[inline]
+    #[allow(unused)]
+    pub fn is_resolved(&self, style: &ResolvedStyle) -> bool {
+        style.generation == self.style_generation
+    }
+
+    /// Draws a text run of `text`, like `draw_glyphs` but with the width of
+    /// the run and of the cursor, on its first character, derived from the
+    /// cells `text` covers. Sizes backgrounds, underlines and cursors over
+    /// wide characters without the caller computing cell spans.
+    #[cfg(feature = "cell_widths")]
+    #[allow(unused)]
+    pub fn draw_text<I>(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        style: &TextRunStyle,
+        text: &str,
+        glyphs: I,
+    ) -> Vec<CachedRect>
+    where
+        I: Iterator,
+        I::Item: Borrow<Glyph>,
+    {
+        let mut rect = rect.into();
+        let mut style = *style;
+        if let Some(widths) = self.cell_widths {
+            rect.width = widths.width(text);
+            if style.cursor != SugarCursor::Disabled {
+                style.cursor_width = text
+                    .chars()
+                    .next()
+                    .map(|ch| widths.cells(ch).max(1) as f32 * widths.cell_width);
             }
         }
+        self.draw_glyphs(rect, depth, &style, glyphs)
     }
 
-    /// Draws a text run.
+    /// Draws a text run at `depth`, or at the depth of each glyph for those
+    /// that have one, see `Glyph::with_depth`. Backgrounds and decorations
+    /// span the run and stay at `depth`. The rects returned keep per glyph
+    /// depths, `draw_glyphs_from_cache` replays the rest at the depth it is
+    /// given.
+    ///
+    /// `style.font_size` may be fractional. Glyphs are rasterized at that
+    /// exact size but cached in half pixel buckets: zooming smoothly from
+    /// 12 to 14 adds at most five sets of glyphs to the atlas, and a size
+    /// reuses the glyphs of another up to a quarter pixel off when both
+    /// fall in the same bucket.
     pub fn draw_glyphs<I>(
         &mut self,
         rect: impl Into<Rect>,
@@ -171,65 +1480,304 @@ impl Compositor {
         I: Iterator,
         I::Item: Borrow<Glyph>,
     {
+        let mut style = self.resolve_style(style);
+        self.draw_resolved(rect, depth, &mut style, glyphs)
+    }
+
+    /// Draws a text run with a style from `resolve_style`, resolving it again
+    /// first when it is out of date.
+    pub fn draw_resolved<I>(
+        &mut self,
+        rect: impl Into<Rect>,
+        depth: f32,
+        resolved: &mut ResolvedStyle,
+        glyphs: I,
+    ) -> Vec<CachedRect>
+    where
+        I: Iterator,
+        I::Item: Borrow<Glyph>,
+    {
+        if !self.is_resolved(resolved) {
+            *resolved = self.resolve_style(&resolved.source);
+        }
+        let style = &resolved.style;
         let rect = rect.into();
+        let size_scale = style.vertical_align.scale();
+        let baseline_shift =
+            style.vertical_align.baseline_shift(style.font_size) + centering(style);
+        let underline_cursor = style.underline.is_some_and(|underline| underline.cursor);
         let (underline, underline_offset, underline_size, underline_color) =
             match style.underline {
-                Some(underline) => (
+                // The underline cursor is hidden while blinking off
+                Some(underline) if !underline_cursor || self.cursor_visible => (
                     true,
-                    underline.offset.round() as i32,
-                    underline.size.round().max(1.),
+                    underline.offset.map(|offset| offset * size_scale),
+                    underline.size.map(|size| {
+                        if underline_cursor {
+                            self.cursor_thickness(size)
+                        } else {
+                            (size * size_scale).round().max(1.)
+                        }
+                    }),
                     underline.color,
                 ),
-                _ => (false, 0, 0., [0.0, 0.0, 0.0, 0.0]),
+                _ => (false, None, None, [0.0, 0.0, 0.0, 0.0]),
             };
-        if underline {
-            self.intercepts.clear();
-        }
-        let mut session = self.glyphs.session(
+        let caret_width = self.cursor_thickness(CARET_WIDTH);
+        // Intercepts are in absolute coordinates and only meaningful for the
+        // run being drawn, never let them leak into the next one
+        self.intercepts.clear();
+        let vertical = style.writing_mode == WritingMode::Vertical;
+        let rtl = !vertical && style.direction == TextDirection::RightToLeft;
+        let missing_glyph = self.missing_glyph.filter(|_| !vertical).map(|missing| {
+            let color = missing.color.unwrap_or(resolved.color);
+            (missing, self.cursor_thickness(missing.thickness), color)
+        });
+        // Builtin glyphs are shaped for cells of a row
+        let builtin_glyphs =
+            if !vertical && (self.box_drawing || !self.powerline.is_empty()) {
+                let glyphs = self
+                    .builtin_glyphs
+                    .entry(style.font.key.value())
+                    .or_insert_with(|| builtin_glyphs(&style.font));
+                Some(&*glyphs)
+            } else {
+                None
+            };
+        let is_builtin = |ch: char| {
+            if powerline::is_supported(ch) {
+                self.powerline.contains(&ch)
+            } else {
+                self.box_drawing
+            }
+        };
+        self.builtin_rects.clear();
+        self.missing_glyph_rects.clear();
+        let mut session = self.glyphs.session_with_slot(
             &mut self.images,
             style.font,
-            style.font_coords,
-            style.font_size,
+            resolved.font_slot,
+            style.font_size * size_scale,
+            style.presentation,
+            self.hinting,
         );
+        // Offsets and sizes the caller left out come from the font
+        let (font_offset, font_size) = session.underline_metrics();
+        let underline_offset = underline_offset.unwrap_or(font_offset).round() as i32;
+        let underline_size = underline_size.unwrap_or_else(|| font_size.round().max(1.));
         let mut result = Vec::new();
-        let subpx_bias = (0.125, 0.);
-        let color = style.color;
+        let subpx_bias = (0.125, self.vertical_subpixel_bias);
+        let outline = style
+            .outline
+            .map(|(width, color)| (width.round().clamp(1., 8.) as u8, color));
+        let color = resolved.color;
         let x = rect.x;
-        for g in glyphs {
-            let glyph = g.borrow();
-            let entry = session.get(glyph.id, glyph.x, glyph.y);
+        let mut run = std::mem::take(&mut self.glyph_run);
+        run.clear();
+        run.extend(glyphs.map(|g| *g.borrow()));
+        // Kerning moves the glyphs that follow each pair, so the run grows or
+        // shrinks by the accumulated adjustment
+        let width = if vertical {
+            stack_vertically(&session, &mut run, rect.x + style.line_height / 2., rect.y)
+        } else {
+            let width = if style.proportional {
+                place_by_advance(&session, &mut run, x)
+            } else {
+                rect.width
+            };
+            let width = if style.kerning {
+                width + apply_kerning(&session, &mut run)
+            } else {
+                width
+            };
+            attach_marks(&session, &mut run);
+            if rtl {
+                mirror_run(&mut run, x, width);
+            }
+            width
+        };
+        // Area covered by `length` of the run from `start`, along the row or
+        // down the column
+        let span = |start: f32, length: f32| {
+            if vertical {
+                Rect::new(rect.x, start, style.line_height, length)
+            } else {
+                Rect::new(start, style.topline, length, style.line_height)
+            }
+        };
+        let start = if vertical { rect.y } else { x };
+        // The background covers the whole run, so like the cursor it is
+        // emitted once; translucent copies per glyph would blend together
+        if let Some(mut bg_color) = style.background_color {
+            bg_color[3] *= self.background_opacity;
+            let rect = span(start, width);
+            self.batches.add_rect(&rect, depth, &bg_color);
+            result.push(CachedRect::Standard((rect, bg_color)));
+        }
+        // Horizontal extent of the glyphs with ink
+        let mut ink: Option<(f32, f32)> = None;
+        for (index, glyph) in run.iter().enumerate() {
+            let glyph_depth = glyph.depth.unwrap_or(depth);
+            if let Some(ch) = builtin_glyphs
+                .and_then(|glyphs| glyphs.get(&glyph.id).copied())
+                .filter(|ch| is_builtin(*ch))
+            {
+                // The cell spans up to the next glyph, or the end of the run
+                let end = run.get(index + 1).map_or(x + width, |next| next.x);
+                let cell =
+                    Rect::new(glyph.x, style.topline, end - glyph.x, style.line_height);
+                let thickness = (cell.width / 8.).round().max(1.);
+                if !box_drawing::rects(ch, cell, thickness, &mut self.builtin_rects) {
+                    powerline::rects(ch, cell, thickness, &mut self.builtin_rects);
+                }
+                ink = extend_ink(ink, cell.x, cell.x + cell.width);
+                continue;
+            }
+            if let Some((missing, thickness, _)) = missing_glyph.filter(|_| glyph.id == 0)
+            {
+                let end = run.get(index + 1).map_or(x + width, |next| next.x);
+                let cell =
+                    Rect::new(glyph.x, style.topline, end - glyph.x, style.line_height);
+                let frame = missing_glyph::frame(cell);
+                self.missing_glyph_rects
+                    .extend(outline_rects(&frame, 0., thickness));
+                if let Some(code_point) =
+                    glyph.code_point.filter(|_| missing.show_code_point)
+                {
+                    missing_glyph::code_point_rects(
+                        code_point,
+                        frame,
+                        thickness,
+                        &mut self.missing_glyph_rects,
+                    );
+                }
+                ink = extend_ink(ink, frame.x, frame.x + frame.width);
+                continue;
+            }
+
+            let y = (glyph.y + baseline_shift + subpx_bias.1).floor();
+            let px = (glyph.x + subpx_bias.0).floor();
+            let (entry, px) = match (session.get(glyph.id, glyph.x, y), style.glyph_span)
+            {
+                (Some(entry), Some(glyph_span)) if !vertical => {
+                    let size = style.font_size * size_scale;
+                    let (entry, px) = fit_to_span(
+                        &mut session,
+                        glyph.id,
+                        (px, y),
+                        entry,
+                        (glyph_span, style.line_height),
+                        size,
+                    );
+                    (Some(entry), px)
+                }
+                (entry, _) => (entry, px),
+            };
+            // Outlines are rasterized at the font size, rescaled glyphs go
+            // without
+            let outline = outline.filter(|_| style.glyph_span.is_none() || vertical);
             if let Some(entry) = entry {
                 if let Some(img) = session.get_image(entry.image) {
-                    let gx = (glyph.x + subpx_bias.0).floor() + entry.left as f32;
-                    let gy = (glyph.y + subpx_bias.1).floor() - entry.top as f32;
+                    let gx = px + entry.left as f32;
+                    if entry.has_ink {
+                        ink = extend_ink(ink, gx, gx + entry.width as f32);
+                    }
+                    let rect = Rect::new(
+                        gx,
+                        y - entry.top as f32,
+                        entry.width as f32,
+                        entry.height as f32,
+                    );
+                    let coords = [img.min.0, img.min.1, img.max.0, img.max.1];
 
-                    if entry.is_bitmap {
-                        let rect =
-                            Rect::new(gx, gy, entry.width as f32, entry.height as f32);
+                    // Color glyphs are drawn as they are, coverage masks
+                    // (outlines and alpha bitmaps) are tinted with the run color
+                    if entry.is_color {
                         let color = [1.0, 1.0, 1.0, 1.0];
-                        let coords = [img.min.0, img.min.1, img.max.0, img.max.1];
+                        let has_alpha = entry.image.has_alpha();
                         self.batches.add_image_rect(
                             &rect,
-                            depth,
+                            glyph_depth,
                             &color,
                             &coords,
                             img.texture_id,
-                            entry.image.has_alpha(),
+                            has_alpha,
                         );
                         result.push(CachedRect::Image(ComposedRect {
                             rect,
                             color,
                             coords,
                             image: img.texture_id,
-                            has_alpha: entry.image.has_alpha(),
+                            has_alpha,
+                            depth: glyph.depth,
                         }));
                     } else {
-                        let rect =
-                            Rect::new(gx, gy, entry.width as f32, entry.height as f32);
-                        let coords = [img.min.0, img.min.1, img.max.0, img.max.1];
+                        // The shadow goes beneath the glyph, and first so that
+                        // submission order agrees without depth testing
+                        if let Some(shadow) = style.shadow {
+                            let rect = Rect::new(
+                                rect.x + shadow.offset.0 - shadow.spread,
+                                rect.y + shadow.offset.1 - shadow.spread,
+                                rect.width + shadow.spread * 2.,
+                                rect.height + shadow.spread * 2.,
+                            );
+                            self.batches.add_mask_rect(
+                                &rect,
+                                shadow_depth(glyph_depth),
+                                &shadow.color,
+                                &coords,
+                                img.texture_id,
+                                true,
+                            );
+                            result.push(CachedRect::Shadow(ComposedRect {
+                                rect,
+                                color: shadow.color,
+                                coords,
+                                image: img.texture_id,
+                                has_alpha: true,
+                                depth: glyph.depth,
+                            }));
+                        }
+                        if let Some((outline_color, outline_entry, outline_img)) =
+                            outline.as_ref().and_then(|(outline, color)| {
+                                let entry = session
+                                    .get_outlined(glyph.id, glyph.x, y, *outline)?;
+                                let img = session.get_image(entry.image)?;
+                                Some((*color, entry, img))
+                            })
+                        {
+                            let rect = Rect::new(
+                                px + outline_entry.left as f32,
+                                y - outline_entry.top as f32,
+                                outline_entry.width as f32,
+                                outline_entry.height as f32,
+                            );
+                            let coords = [
+                                outline_img.min.0,
+                                outline_img.min.1,
+                                outline_img.max.0,
+                                outline_img.max.1,
+                            ];
+                            self.batches.add_mask_rect(
+                                &rect,
+                                glyph_depth,
+                                &outline_color,
+                                &coords,
+                                outline_img.texture_id,
+                                true,
+                            );
+                            result.push(CachedRect::Mask(ComposedRect {
+                                rect,
+                                color: outline_color,
+                                coords,
+                                image: outline_img.texture_id,
+                                has_alpha: true,
+                                depth: glyph.depth,
+                            }));
+                        }
                         self.batches.add_mask_rect(
                             &rect,
-                            depth,
+                            glyph_depth,
                             &color,
                             &coords,
                             img.texture_id,
@@ -241,41 +1789,14 @@ impl Compositor {
                             coords,
                             image: img.texture_id,
                             has_alpha: true,
+                            depth: glyph.depth,
                         }));
                     }
 
-                    if let Some(bg_color) = style.background_color {
-                        let rect = Rect::new(
-                            rect.x,
-                            style.topline,
-                            rect.width,
-                            style.line_height,
-                        );
-                        self.batches.add_rect(&rect, depth, &bg_color);
-                        result.push(CachedRect::Standard((rect, bg_color)));
-                    }
-
-                    match style.cursor {
-                        SugarCursor::Block(cursor_color) => {
-                            let rect = Rect::new(
-                                rect.x,
-                                style.topline,
-                                rect.width,
-                                style.line_height,
-                            );
-                            self.batches.add_rect(&rect, depth, &cursor_color);
-                            result.push(CachedRect::Standard((rect, cursor_color)));
-                        }
-                        SugarCursor::Caret(cursor_color) => {
-                            let rect =
-                                Rect::new(rect.x, style.topline, 3.0, style.line_height);
-                            self.batches.add_rect(&rect, depth, &cursor_color);
-                            result.push(CachedRect::Standard((rect, cursor_color)));
-                        }
-                        _ => {}
-                    }
-
-                    if underline && entry.top - underline_offset < entry.height as i32 {
+                    if underline
+                        && !vertical
+                        && entry.top - underline_offset < entry.height as i32
+                    {
                         if let Some(mut desc_ink) = entry.desc.range() {
                             desc_ink.0 += gx;
                             desc_ink.1 += gx;
@@ -285,29 +1806,2773 @@ impl Compositor {
                 }
             }
         }
-        if underline {
+        // A ligature is one glyph over several cells, so the cursor is placed
+        // on its cell of the run instead of at the glyph
+        let (offset, cell_width) = match style.cursor_cell {
+            Some((cell, cells)) if cells > 0 => {
+                let cell_width = width / cells as f32;
+                (cell_width * cell.min(cells - 1) as f32, Some(cell_width))
+            }
+            _ => (0., None),
+        };
+        let cursor_width = style.cursor_width.or(cell_width).unwrap_or(width);
+        // Right to left runs count cells from their right edge, where the
+        // caret sits before the character
+        let (cursor_start, caret_start) = if rtl {
+            let cursor_start = start + width - offset - cursor_width;
+            (cursor_start, cursor_start + cursor_width - caret_width)
+        } else {
+            (start + offset, start + offset)
+        };
+        // The cursor covers the whole run, so it is emitted once rather than
+        // per glyph; overlapping translucent cursors would otherwise double-blend
+        let cursor = match style.cursor {
+            _ if !self.cursor_visible => None,
+            SugarCursor::Block(cursor_color) => {
+                Some((span(cursor_start, cursor_width), cursor_color))
+            }
+            SugarCursor::Caret(cursor_color) => {
+                Some((span(caret_start, caret_width), cursor_color))
+            }
+            _ => None,
+        };
+        if let Some((rect, cursor_color)) = cursor {
+            let rect = self.snap(rect);
+            self.batches.add_rect(&rect, depth, &cursor_color);
+            self.cursor_rects.push(rect);
+            result.push(CachedRect::Cursor((rect, cursor_color)));
+        }
+        // Builtin glyphs are solid rects like backgrounds, so they go after
+        // every background of the run and the cursor, as glyphs would be drawn
+        // on top
+        for (rect, alpha) in &self.builtin_rects {
+            let mut color = color;
+            color[3] *= alpha;
+            self.batches.add_rect(rect, depth, &color);
+            result.push(CachedRect::Standard((*rect, color)));
+        }
+        if let Some((_, _, color)) = missing_glyph {
+            for rect in &self.missing_glyph_rects {
+                self.batches.add_rect(rect, depth, &color);
+                result.push(CachedRect::Standard((*rect, color)));
+            }
+        }
+        if underline && vertical {
+            let rect = self.snap(Rect::new(
+                rect.x + style.line_height - underline_size,
+                start,
+                underline_size,
+                width,
+            ));
+            self.batches.add_rect(&rect, depth, &underline_color);
+            result.push(CachedRect::Standard((rect, underline_color)));
+        } else if underline {
             for range in self.intercepts.iter_mut() {
                 range.0 -= 1.;
                 range.1 += 1.;
             }
-            let mut ux = x;
-            let uy = style.baseline - underline_offset as f32;
+            // Trailing whitespace is on the left of right to left runs
+            let (mut ux, end) = match ink {
+                _ if !style.underline_skip_trailing_whitespace => (x, x + width),
+                Some((left, _)) if rtl => (left.max(x), x + width),
+                Some((_, right)) => (x, right.min(x + width)),
+                None => (x, x),
+            };
+            let uy = style.baseline + baseline_shift - underline_offset as f32;
             for range in self.intercepts.iter() {
                 if ux < range.0 {
-                    let rect = Rect::new(ux, uy, range.0 - ux, underline_size);
+                    let rect = self.snap(Rect::new(ux, uy, range.0 - ux, underline_size));
                     self.batches.add_rect(&rect, depth, &underline_color);
                     result.push(CachedRect::Standard((rect, underline_color)));
                 }
                 ux = range.1;
             }
-            let end = x + rect.width;
             if ux < end {
-                let rect = Rect::new(ux, uy, end - ux, underline_size);
+                let rect = self.snap(Rect::new(ux, uy, end - ux, underline_size));
                 self.batches.add_rect(&rect, depth, &underline_color);
                 result.push(CachedRect::Standard((rect, underline_color)));
             }
         }
+        // Strikes cross the ink, so unlike underlines they skip no intercepts
+        if let Some(strikethrough) = style.strikethrough {
+            let size = (strikethrough.size * size_scale).round().max(1.);
+            let color = strikethrough.color.unwrap_or(resolved.color);
+            let rect = if vertical {
+                Rect::new(rect.x + (style.line_height - size) / 2., start, size, width)
+            } else {
+                let y =
+                    style.baseline + baseline_shift - strikethrough.offset * size_scale;
+                Rect::new(x, y, width, size)
+            };
+            let rect = self.snap(rect);
+            self.batches.add_rect(&rect, depth, &color);
+            result.push(CachedRect::Standard((rect, color)));
+        }
+        if let Some(frame) = style.frame {
+            let bounds = self.snap(span(start, width));
+            let radius = match frame {
+                Frame::Framed => 0.,
+                Frame::Encircled => bounds.width.min(bounds.height) / 2.,
+            };
+            let thickness = self.cursor_thickness(1.);
+            for rect in outline_rects(&bounds, radius, thickness) {
+                self.batches.add_rect(&rect, depth, &resolved.color);
+                result.push(CachedRect::Standard((rect, resolved.color)));
+            }
+        }
 
+        self.glyph_run = run;
         result
     }
 }
+
+/// Merges cells that touch on the same row, then rows that span the same
+/// columns, so highlights don't overlap or leave hairline gaps.
+fn coalesce(rects: &[Rect]) -> Vec<Rect> {
+    // Cells come from the same grid, only float noise separates neighbours
+    let touches = |a: f32, b: f32| (a - b).abs() < 0.01;
+
+    let mut cells = rects.to_vec();
+    cells.sort_by(|a, b| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)));
+    let mut rows: Vec<Rect> = Vec::with_capacity(cells.len());
+    for cell in cells {
+        match rows.last_mut() {
+            Some(last)
+                if touches(last.y, cell.y)
+                    && touches(last.height, cell.height)
+                    && touches(last.x + last.width, cell.x) =>
+            {
+                last.width = cell.x + cell.width - last.x;
+            }
+            _ => rows.push(cell),
+        }
+    }
+
+    rows.sort_by(|a, b| {
+        a.x.total_cmp(&b.x)
+            .then(a.width.total_cmp(&b.width))
+            .then(a.y.total_cmp(&b.y))
+    });
+    let mut merged: Vec<Rect> = Vec::with_capacity(rows.len());
+    for row in rows {
+        match merged.last_mut() {
+            Some(last)
+                if touches(last.x, row.x)
+                    && touches(last.width, row.width)
+                    && touches(last.y + last.height, row.y) =>
+            {
+                last.height = row.y + row.height - last.y;
+            }
+            _ => merged.push(row),
+        }
+    }
+    merged
+}
+
+/// Splits the outline of `rect`, `thickness` wide inside of it and with
+/// corners rounded by `radius`, into rects. Like `rounded_quads`, the arcs
+/// are stacked bands of a pixel or so.
+fn outline_rects(rect: &Rect, radius: f32, thickness: f32) -> Vec<Rect> {
+    let thickness = thickness.min(rect.width / 2.).min(rect.height / 2.);
+    let radius = radius.min(rect.width / 2.).min(rect.height / 2.);
+    let (right, bottom) = (rect.x + rect.width, rect.y + rect.height);
+    if radius <= 0. {
+        let side = rect.height - thickness * 2.;
+        return vec![
+            Rect::new(rect.x, rect.y, rect.width, thickness),
+            Rect::new(rect.x, rect.y + thickness, thickness, side),
+            Rect::new(right - thickness, rect.y + thickness, thickness, side),
+            Rect::new(rect.x, bottom - thickness, rect.width, thickness),
+        ];
+    }
+
+    // How far an arc of `radius` is from the side, `distance` pixels from
+    // the edge
+    let inset = |radius: f32, distance: f32| {
+        if distance >= radius {
+            return 0.;
+        }
+        let d = radius - distance;
+        radius - (radius * radius - d * d).max(0.).sqrt()
+    };
+    let inner = (radius - thickness).max(0.);
+    let mut rects = Vec::new();
+    // Rows of the outline from `top` to `top + height`, `distance` pixels
+    // from the closest edge, are solid until they reach the hole
+    let mut row = |top: f32, height: f32, distance: f32| {
+        let outer = inset(radius, distance);
+        if distance < thickness {
+            rects.push(Rect::new(
+                rect.x + outer,
+                top,
+                rect.width - outer * 2.,
+                height,
+            ));
+        } else {
+            let side = (thickness + inset(inner, distance - thickness) - outer).max(0.);
+            rects.push(Rect::new(rect.x + outer, top, side, height));
+            rects.push(Rect::new(right - outer - side, top, side, height));
+        }
+    };
+    let curved = radius.max(thickness);
+    let bands = (curved.ceil() as usize).clamp(1, 16);
+    let step = curved / bands as f32;
+    for band in 0..bands {
+        let from = band as f32 * step;
+        row(rect.y + from, step, from + step / 2.);
+    }
+    if bottom - curved > rect.y + curved {
+        row(rect.y + curved, rect.height - curved * 2., curved);
+    }
+    for band in (0..bands).rev() {
+        let from = band as f32 * step;
+        row(bottom - from - step, step, from + step / 2.);
+    }
+    rects
+}
+
+/// Splits `rect` into quads, as top left, bottom left, bottom right and top
+/// right points, that trace its `corners` (top left, top right, bottom right
+/// and bottom left) rounded by `radius`. The arcs are stacked bands of a
+/// pixel or so, which is smooth enough for small radii.
+fn rounded_quads(rect: &Rect, radius: f32, corners: [bool; 4]) -> Vec<[[f32; 2]; 4]> {
+    let quad = |top: f32, bottom: f32, insets: [f32; 4]| {
+        let [top_left, bottom_left, bottom_right, top_right] = insets;
+        let right = rect.x + rect.width;
+        [
+            [rect.x + top_left, top],
+            [rect.x + bottom_left, bottom],
+            [right - bottom_right, bottom],
+            [right - top_right, top],
+        ]
+    };
+    let radius = radius.min(rect.width / 2.).min(rect.height / 2.);
+    let [top_left, top_right, bottom_right, bottom_left] = corners;
+    let rounded_top = top_left || top_right;
+    let rounded_bottom = bottom_left || bottom_right;
+    if radius <= 0. || !(rounded_top || rounded_bottom) {
+        return vec![quad(rect.y, rect.y + rect.height, [0.; 4])];
+    }
+
+    // How far the arc is from the side, `distance` pixels from the edge
+    let inset = |distance: f32| {
+        let d = radius - distance;
+        radius - (radius * radius - d * d).max(0.).sqrt()
+    };
+    let bands = (radius.ceil() as usize).clamp(1, 8);
+    let step = radius / bands as f32;
+    let mut quads = Vec::with_capacity(bands * 2 + 1);
+    let (mut top, mut bottom) = (rect.y, rect.y + rect.height);
+    if rounded_top {
+        for band in 0..bands {
+            let (from, to) = (band as f32 * step, (band + 1) as f32 * step);
+            let side = |rounded: bool, at: f32| if rounded { inset(at) } else { 0. };
+            quads.push(quad(
+                rect.y + from,
+                rect.y + to,
+                [
+                    side(top_left, from),
+                    side(top_left, to),
+                    side(top_right, to),
+                    side(top_right, from),
+                ],
+            ));
+        }
+        top += radius;
+    }
+    if rounded_bottom {
+        bottom -= radius;
+    }
+    quads.push(quad(top, bottom, [0.; 4]));
+    if rounded_bottom {
+        let edge = rect.y + rect.height;
+        for band in (0..bands).rev() {
+            let (from, to) = ((band + 1) as f32 * step, band as f32 * step);
+            let side = |rounded: bool, at: f32| if rounded { inset(at) } else { 0. };
+            quads.push(quad(
+                edge - from,
+                edge - to,
+                [
+                    side(bottom_left, from),
+                    side(bottom_left, to),
+                    side(bottom_right, to),
+                    side(bottom_right, from),
+                ],
+            ));
+        }
+    }
+    quads
+}
+
+/// Corners of a cursor smeared from `from` to `to`, `t` of the way through
+/// its animation, as top left, bottom left, bottom right and top right. A
+/// corner's progress eases out when it leads the move and eases in when it
+/// trails, so corners across the move stay aligned and a straight move
+/// remains a rect.
+fn smear(from: &Rect, to: &Rect, t: f32) -> [[f32; 2]; 4] {
+    const SIGNS: [[f32; 2]; 4] = [[-1., -1.], [-1., 1.], [1., 1.], [1., -1.]];
+    let corners = |rect: &Rect| {
+        SIGNS.map(|[sx, sy]| {
+            [
+                rect.x + rect.width * (sx + 1.) / 2.,
+                rect.y + rect.height * (sy + 1.) / 2.,
+            ]
+        })
+    };
+
+    let t = t.clamp(0., 1.);
+    let head = 1. - (1. - t) * (1. - t);
+    let tail = t * t;
+    let dx = (to.x + to.width / 2.) - (from.x + from.width / 2.);
+    let dy = (to.y + to.height / 2.) - (from.y + from.height / 2.);
+    let length = dx.abs() + dy.abs();
+
+    let (from, to) = (corners(from), corners(to));
+    let mut points = [[0.; 2]; 4];
+    for (i, [sx, sy]) in SIGNS.into_iter().enumerate() {
+        // From -1 for the corner trailing the move to 1 for the leading one
+        let lead = if length > 0. {
+            (sx * dx + sy * dy) / length
+        } else {
+            0.
+        };
+        let progress = tail + (head - tail) * (lead + 1.) / 2.;
+        points[i] = [
+            from[i][0] + (to[i][0] - from[i][0]) * progress,
+            from[i][1] + (to[i][1] - from[i][1]) * progress,
+        ];
+    }
+    points
+}
+
+/// Maps the glyphs of `font` to the characters that have builtin geometry.
+fn builtin_glyphs(font: &FontRef) -> HashMap<GlyphId, char> {
+    let charmap = font.charmap();
+    ('\u{2500}'..='\u{259F}')
+        .filter(|ch| box_drawing::is_supported(*ch))
+        .chain(powerline::GLYPHS)
+        .filter_map(|ch| match charmap.map(ch) {
+            0 => None,
+            id => Some((id, ch)),
+        })
+        .collect()
+}
+
+/// Returns `ink` grown to cover `start..end`.
+#[inline]
+fn extend_ink(ink: Option<(f32, f32)>, start: f32, end: f32) -> Option<(f32, f32)> {
+    Some(ink.map_or((start, end), |(left, right)| {
+        (left.min(start), right.max(end))
+    }))
+}
+
+/// Nudges each glyph by the kerning accumulated from the pairs before it and
+/// returns the total adjustment.
+fn apply_kerning(session: &GlyphCacheSession, glyphs: &mut [Glyph]) -> f32 {
+    let mut offset = 0.;
+    for i in 1..glyphs.len() {
+        offset += session.kerning(glyphs[i - 1].id, glyphs[i].id);
+        glyphs[i].x += offset;
+    }
+    offset
+}
+
+/// Converts every color of `style` to `target`, unclamped when `extended`,
+/// see `ColorSpace::convert_extended`.
+fn convert_style<'a>(
+    style: &TextRunStyle<'a>,
+    target: ColorSpace,
+    extended: bool,
+) -> TextRunStyle<'a> {
+    let source = style.color_space;
+    let convert = |color: [f32; 4]| {
+        if extended {
+            source.convert_extended(color, target)
+        } else {
+            source.convert(color, target)
+        }
+    };
+    let mut style = *style;
+    style.color = convert(style.color);
+    style.background_color = style.background_color.map(convert);
+    style.default_background = convert(style.default_background);
+    style.cursor = match style.cursor {
+        SugarCursor::Block(color) => SugarCursor::Block(convert(color)),
+        SugarCursor::Caret(color) => SugarCursor::Caret(convert(color)),
+        SugarCursor::Underline(color) => SugarCursor::Underline(convert(color)),
+        cursor => cursor,
+    };
+    if let Some(underline) = &mut style.underline {
+        underline.color = convert(underline.color);
+    }
+    if let Some(strikethrough) = &mut style.strikethrough {
+        strikethrough.color = strikethrough.color.map(convert);
+    }
+    if let Some(shadow) = &mut style.shadow {
+        shadow.color = convert(shadow.color);
+    }
+    if let Some((_, color)) = &mut style.outline {
+        *color = convert(*color);
+    }
+    style.color_space = target;
+    style
+}
+
+/// Moves combining marks, glyphs with no advance, that were placed past the
+/// end of the base glyph before them back to it. Marks are drawn from the
+/// pen position after their base, so they stack over it instead of sitting
+/// in a cell of their own. Marks already positioned over the base by the
+/// shaper keep their place.
+fn attach_marks(session: &GlyphCacheSession, glyphs: &mut [Glyph]) {
+    let mut base_end = None;
+    for glyph in glyphs {
+        let advance = session.advance(glyph.id);
+        if advance != 0. {
+            base_end = Some(glyph.x + advance);
+        } else if let Some(end) = base_end {
+            glyph.x = glyph.x.min(end);
+        }
+    }
+}
+
+/// Returns how far the glyphs of `style` are moved down to center their
+/// box in the line, see `TextRunStyle::glyph_height`, in whole pixels so the
+/// baseline stays sharp.
+fn centering(style: &TextRunStyle) -> f32 {
+    match style.glyph_height {
+        Some(height) if style.writing_mode == WritingMode::Horizontal => {
+            ((style.line_height - height).max(0.) / 2.).round()
+        }
+        _ => 0.,
+    }
+}
+
+/// Rescales `entry`, the glyph `id` drawn with its pen at `px` and `y`, for
+/// its ink to fill `area`, a width and a height, keeping its aspect ratio.
+/// Returns the glyph and the pen position centering it across the width.
+fn fit_to_span(
+    session: &mut GlyphCacheSession,
+    id: u16,
+    (px, y): (f32, f32),
+    entry: GlyphEntry,
+    (width, height): (f32, f32),
+    size: f32,
+) -> (GlyphEntry, f32) {
+    if entry.width == 0 || entry.height == 0 {
+        return (entry, px);
+    }
+    let scale = (width / entry.width as f32).min(height / entry.height as f32);
+    // Close enough sizes keep the glyph as it is rather than rasterizing it
+    // again for a pixel. Embedded bitmaps, already scaled from the nearest
+    // strike, only get blurrier scaled up and are kept when they fit
+    let close = (scale - 1.).abs() * (entry.width.max(entry.height) as f32) < 1.;
+    let entry = if close || (entry.strike.is_some() && scale >= 1.) {
+        entry
+    } else {
+        session.get_sized(id, 0., y, size * scale).unwrap_or(entry)
+    };
+    let offset = ((width - entry.width as f32) / 2.).floor();
+    (entry, px + offset - entry.left as f32)
+}
+
+/// Mirrors `glyphs`, laid out in logical order from `x`, across the run
+/// `width` wide so the first one ends at its right edge. Each glyph spans up
+/// to the next one, and they are left in visual order.
+fn mirror_run(glyphs: &mut [Glyph], x: f32, width: f32) {
+    let mut end = x + width;
+    for glyph in glyphs.iter_mut().rev() {
+        let start = glyph.x;
+        glyph.x = x + (x + width - end);
+        end = start;
+    }
+    glyphs.reverse();
+}
+
+/// Places `glyphs` one after the other from `x` by their advance, and
+/// returns the width of the run.
+fn place_by_advance(session: &GlyphCacheSession, glyphs: &mut [Glyph], x: f32) -> f32 {
+    let mut pen = x;
+    for glyph in glyphs {
+        glyph.x = pen;
+        pen += session.advance(glyph.id);
+    }
+    pen - x
+}
+
+/// Places `glyphs` upright one below the other, starting at `top` and
+/// centered on `center`, and returns the length of the column.
+fn stack_vertically(
+    session: &GlyphCacheSession,
+    glyphs: &mut [Glyph],
+    center: f32,
+    top: f32,
+) -> f32 {
+    let mut y = top;
+    for glyph in glyphs {
+        let (advance, origin, width) = session.vertical_metrics(glyph.id);
+        glyph.x = center - width / 2.;
+        glyph.y = y + origin;
+        y += advance;
+    }
+    y - top
+}
+
+/// Computes the rects covering `viewport` for an image of `width` x `height`
+/// pixels, along with the normalized image coordinates each of them samples.
+fn fit_image(fit: Fit, viewport: Rect, width: f32, height: f32) -> Vec<(Rect, [f32; 4])> {
+    if width <= 0. || height <= 0. || viewport.width <= 0. || viewport.height <= 0. {
+        return vec![];
+    }
+
+    const FULL: [f32; 4] = [0., 0., 1., 1.];
+    match fit {
+        Fit::Stretch => vec![(viewport, FULL)],
+        Fit::Contain => {
+            let scale = (viewport.width / width).min(viewport.height / height);
+            let (w, h) = (width * scale, height * scale);
+            let rect = Rect::new(
+                viewport.x + (viewport.width - w) / 2.,
+                viewport.y + (viewport.height - h) / 2.,
+                w,
+                h,
+            );
+            vec![(rect, FULL)]
+        }
+        Fit::Cover => {
+            let scale = (viewport.width / width).max(viewport.height / height);
+            let crop_x = (1. - viewport.width / (width * scale)) / 2.;
+            let crop_y = (1. - viewport.height / (height * scale)) / 2.;
+            vec![(viewport, [crop_x, crop_y, 1. - crop_x, 1. - crop_y])]
+        }
+        Fit::Tile => {
+            let mut rects = Vec::new();
+            let mut y = 0.;
+            while y < viewport.height {
+                let h = height.min(viewport.height - y);
+                let mut x = 0.;
+                while x < viewport.width {
+                    let w = width.min(viewport.width - x);
+                    rects.push((
+                        Rect::new(viewport.x + x, viewport.y + y, w, h),
+                        [0., 0., w / width, h / height],
+                    ));
+                    x += width;
+                }
+                y += height;
+            }
+            rects
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::rich_text::color::contrast_ratio;
+    use crate::components::rich_text::iterm2::ITerm2Image;
+    use crate::components::rich_text::kitty::KittyCommand;
+
+    const FONT: &[u8] =
+        include_bytes!("../../font/resources/CascadiaMono/CascadiaMonoPL-Regular.ttf");
+
+    fn font() -> FontRef<'static> {
+        FontRef::from_index(FONT, 0).unwrap()
+    }
+
+    fn glyph_id(font: &FontRef, ch: char) -> u16 {
+        font.charmap().map(ch)
+    }
+
+    fn style(font: FontRef) -> TextRunStyle {
+        TextRunStyle {
+            font,
+            font_coords: &[],
+            font_size: 16.,
+            color: [1.0, 1.0, 1.0, 1.0],
+            background_color: None,
+            default_background: [0.0; 4],
+            baseline: 20.,
+            topline: 4.,
+            line_height: 20.,
+            glyph_height: None,
+            advance: 30.,
+            underline: None,
+            underline_skip_trailing_whitespace: false,
+            strikethrough: None,
+            frame: None,
+            cursor: SugarCursor::Disabled,
+            cursor_width: None,
+            cursor_cell: None,
+            shadow: None,
+            outline: None,
+            vertical_align: VerticalAlign::Baseline,
+            kerning: false,
+            proportional: false,
+            glyph_span: None,
+            ligatures: true,
+            presentation: Presentation::Auto,
+            bold: false,
+            ansi_index: None,
+            selected: false,
+            writing_mode: WritingMode::Horizontal,
+            direction: TextDirection::LeftToRight,
+            color_space: ColorSpace::Srgb,
+        }
+    }
+
+    fn glyphs(font: &FontRef, text: &str) -> Vec<Glyph> {
+        text.chars()
+            .enumerate()
+            .map(|(i, ch)| Glyph::new(glyph_id(font, ch), i as f32 * 10., 20.))
+            .collect()
+    }
+
+    fn standard_rects(rects: &[CachedRect]) -> Vec<(Rect, [f32; 4])> {
+        rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Standard((rect, color))
+                | CachedRect::Cursor((rect, color)) => Some((*rect, *color)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_background_opacity_only_affects_backgrounds() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        comp.set_background_opacity(0.5);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "abc").iter(),
+        );
+
+        let backgrounds = standard_rects(&rects);
+        assert_eq!(backgrounds.len(), 1);
+        assert_eq!(backgrounds[0].1[3], 0.5);
+        assert!(rects.iter().all(|r| match r {
+            CachedRect::Mask(data) => data.color[3] == 1.0,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_background_opacity_blends_once_per_run() {
+        use crate::components::rich_text::tests::{assert_pixel, Offscreen};
+
+        let font = font();
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        let glyphs = glyphs(&font, "   ");
+
+        // Half transparent over every cell of the run, not once per glyph
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.set_background_opacity(0.5);
+            comp.draw_glyphs(Rect::new(0., 20., 30., 1.), 0.0, &style, glyphs.iter());
+        });
+        assert_pixel(&pixels, 2, 8, [0, 0, 128, 128]);
+        assert_pixel(&pixels, 12, 8, [0, 0, 128, 128]);
+    }
+
+    #[test]
+    fn test_fit_image() {
+        let viewport = Rect::new(0., 0., 200., 100.);
+
+        let rects = fit_image(Fit::Stretch, viewport, 50., 50.);
+        assert_eq!(rects.len(), 1);
+        assert_eq!(rects[0].1, [0., 0., 1., 1.]);
+
+        let rects = fit_image(Fit::Contain, viewport, 50., 50.);
+        let (rect, _) = rects[0];
+        assert_eq!(
+            (rect.x, rect.y, rect.width, rect.height),
+            (50., 0., 100., 100.)
+        );
+
+        let rects = fit_image(Fit::Cover, viewport, 50., 50.);
+        let (rect, uv) = rects[0];
+        assert_eq!((rect.width, rect.height), (200., 100.));
+        assert_eq!(uv, [0., 0.25, 1., 0.75]);
+
+        let rects = fit_image(Fit::Tile, viewport, 64., 64.);
+        assert_eq!(rects.len(), 8);
+        let (last, uv) = rects[7];
+        assert_eq!(
+            (last.x, last.y, last.width, last.height),
+            (192., 64., 8., 36.)
+        );
+        assert_eq!(uv, [0., 0., 8. / 64., 36. / 64.]);
+    }
+
+    #[test]
+    fn test_shadow_is_drawn_beneath_each_glyph() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.shadow = Some(Shadow {
+            offset: (1., 2.),
+            spread: 0.5,
+            color: [0.0, 0.0, 0.0, 0.5],
+        });
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.5,
+            &style,
+            glyphs(&font, "ab").iter(),
+        );
+
+        let masks: Vec<&ComposedRect> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) | CachedRect::Shadow(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(masks.len(), 4);
+        assert!(matches!(rects[0], CachedRect::Shadow(_)));
+        for pair in masks.chunks(2) {
+            let (shadow, glyph) = (pair[0], pair[1]);
+            assert_eq!(shadow.color, [0.0, 0.0, 0.0, 0.5]);
+            assert_eq!(glyph.color, style.color);
+            assert_eq!(shadow.rect.x, glyph.rect.x + 0.5);
+            assert_eq!(shadow.rect.y, glyph.rect.y + 1.5);
+            assert_eq!(shadow.rect.width, glyph.rect.width + 1.);
+            assert_eq!(shadow.coords, glyph.coords);
+        }
+
+        // Shadows sit a step beneath their glyph, replayed runs too
+        for replay in [false, true] {
+            comp.begin();
+            if replay {
+                comp.draw_glyphs_from_cache(&rects, 0.5);
+            } else {
+                comp.draw_glyphs(
+                    Rect::new(0., 20., 30., 1.),
+                    0.5,
+                    &style,
+                    glyphs(&font, "ab").iter(),
+                );
+            }
+            let mut list = DisplayList::new();
+            comp.finish(&mut list, |_| {});
+            let mut depths: Vec<f32> = list.vertices().iter().map(|v| v.pos[2]).collect();
+            depths.sort_by(f32::total_cmp);
+            depths.dedup();
+            assert_eq!(depths, [0.5 - DEPTH_STEP, 0.5]);
+        }
+    }
+
+    #[test]
+    fn test_outline_is_dilated_from_coverage() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.outline = Some((2., [1.0, 0.0, 0.0, 1.0]));
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "a").iter(),
+        );
+
+        let masks: Vec<&ComposedRect> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(masks.len(), 2);
+        let (outline, glyph) = (masks[0], masks[1]);
+        assert_eq!(outline.color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(outline.rect.x, glyph.rect.x - 2.);
+        assert_eq!(outline.rect.y, glyph.rect.y - 2.);
+        assert_eq!(outline.rect.width, glyph.rect.width + 4.);
+        assert_eq!(outline.rect.height, glyph.rect.height + 4.);
+        // A separate atlas entry holds the dilated coverage
+        assert_ne!(outline.coords, glyph.coords);
+    }
+
+    #[test]
+    fn test_vertical_run_is_stacked_down_a_column() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.writing_mode = WritingMode::Vertical;
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: [1.0, 0.0, 0.0, 1.0],
+            cursor: false,
+        });
+
+        let rects = comp.draw_glyphs(
+            Rect::new(100., 40., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "abc").iter(),
+        );
+
+        let masks: Vec<Rect> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) => Some(data.rect),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(masks.len(), 3);
+        for pair in masks.windows(2) {
+            assert!(pair[1].y > pair[0].y + pair[0].height / 2.);
+        }
+        for mask in &masks {
+            assert!(mask.x >= 100. && mask.x + mask.width <= 100. + style.line_height);
+        }
+
+        let metrics = font.glyph_metrics(&[]).scale(16.);
+        let length: f32 = "abc"
+            .chars()
+            .map(|ch| metrics.advance_height(glyph_id(&font, ch)))
+            .sum();
+        let standard = standard_rects(&rects);
+        let (background, _) = standard[0];
+        assert_eq!((background.x, background.y), (100., 40.));
+        assert_eq!(background.width, style.line_height);
+        assert!((background.height - length).abs() < 0.01);
+        // The underline runs along the right side of the column
+        let (side_line, color) = *standard.last().unwrap();
+        assert_eq!(color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(side_line.x, 100. + style.line_height - 1.);
+        assert_eq!((side_line.width, side_line.height), (1., background.height));
+    }
+
+    #[test]
+    fn test_display_p3_runs_keep_their_gamut_on_extended_range_targets() {
+        use crate::components::rich_text::tests::{half_to_f32, Offscreen};
+
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let draw = |comp: &mut Compositor, color, color_space| {
+            let mut style = style(font);
+            style.color = color;
+            style.color_space = color_space;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "a").iter(),
+            );
+            match &rects[..] {
+                [CachedRect::Mask(data)] => data.color,
+                _ => panic!("expected a single glyph"),
+            }
+        };
+        let assert_close = |actual: [f32; 4], expected: [f32; 4]| {
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a - e).abs() < 0.005, "{actual:?} != {expected:?}");
+            }
+        };
+
+        // sRGB colors are drawn as they are
+        let srgb_red = [1.0, 0.0, 0.0, 1.0];
+        assert_eq!(draw(&mut comp, srgb_red, ColorSpace::Srgb), srgb_red);
+        // sRGB red given in P3 comes out as sRGB red
+        let p3 = ColorSpace::Srgb.convert(srgb_red, ColorSpace::DisplayP3);
+        assert_close(draw(&mut comp, p3, ColorSpace::DisplayP3), srgb_red);
+
+        // P3 red is out of the sRGB gamut, clamped to it on SDR targets
+        let p3_red = [1.0, 0.0, 0.0, 1.0];
+        assert_close(draw(&mut comp, p3_red, ColorSpace::DisplayP3), srgb_red);
+        // and kept past it on extended range ones
+        comp.set_extended_range(true);
+        let extended = draw(&mut comp, p3_red, ColorSpace::DisplayP3);
+        assert!(extended[0] > 1.0 && extended[1] < 0.0 && extended[2] < 0.0);
+
+        // Rendered, P3 red is linear sRGB past the red primary, distinct
+        // from its clamp
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba16Float);
+        let pixels = target.render(wgpu::Color::BLACK, |comp| {
+            for (x, color_space) in [(0., ColorSpace::DisplayP3), (8., ColorSpace::Srgb)]
+            {
+                let mut style = style(font);
+                style.background_color = Some(p3_red);
+                style.color_space = color_space;
+                comp.draw_glyphs(
+                    Rect::new(x, 20., 8., 1.),
+                    0.0,
+                    &style,
+                    glyphs(&font, " ").iter(),
+                );
+            }
+        });
+        let pixel = |x: usize, y: usize| -> [f32; 4] {
+            let i = (y * 16 + x) * 8;
+            std::array::from_fn(|c| {
+                half_to_f32(u16::from_le_bytes([
+                    pixels[i + c * 2],
+                    pixels[i + c * 2 + 1],
+                ]))
+            })
+        };
+        assert_close(pixel(2, 8), [1.2249, -0.0421, -0.0196, 1.0]);
+        assert_close(pixel(12, 8), srgb_red);
+    }
+
+    #[test]
+    fn test_resolved_style_is_reused_until_out_of_date() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.2, 0.2, 0.2, 1.0]);
+        style.color = [0.25, 0.25, 0.25, 1.0];
+        comp.set_min_contrast(Some(4.5));
+
+        let mut resolved = comp.resolve_style(&style);
+        let draw = |comp: &mut Compositor, resolved: &mut ResolvedStyle| {
+            let rects = comp.draw_resolved(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                resolved,
+                glyphs(&font, "a").iter(),
+            );
+            rects
+                .iter()
+                .find_map(|r| match r {
+                    CachedRect::Mask(data) => Some((data.rect, data.color)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let (rect, color) = draw(&mut comp, &mut resolved);
+        assert!(contrast_ratio(color, [0.2, 0.2, 0.2, 1.0]) >= 4.5);
+        let (again, same_color) = draw(&mut comp, &mut resolved);
+        assert_eq!(
+            (again.x, again.y, again.height),
+            (rect.x, rect.y, rect.height)
+        );
+        assert_eq!(same_color, color);
+        assert!(comp.is_resolved(&resolved));
+
+        // Settings the style depends on make it out of date
+        comp.set_min_contrast(None);
+        assert!(!comp.is_resolved(&resolved));
+        let (_, color) = draw(&mut comp, &mut resolved);
+        assert_eq!(color, style.color);
+        assert!(comp.is_resolved(&resolved));
+
+        // So does a new font size, which rasterizes a bigger glyph
+        resolved.set_font_size(32.);
+        let (bigger, _) = draw(&mut comp, &mut resolved);
+        assert!(bigger.height > rect.height);
+        assert_eq!(resolved.source().font_size, 32.);
+    }
+
+    // Per glyph cost of draw_glyphs once every glyph is cached, as for a
+    // static screen. Run with `cargo test --workspace --release bench_draw_glyphs --
+    // --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_draw_glyphs() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        let line = glyphs(
+            &font,
+            &"The quick brown fox jumps over the lazy dog ".repeat(4),
+        );
+        let rect = Rect::new(0., 20., line.len() as f32 * 10., 1.);
+        comp.draw_glyphs(rect, 0.0, &style, line.iter());
+
+        const FRAMES: usize = 200;
+        const LINES: usize = 50;
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            comp.begin();
+            for _ in 0..LINES {
+                std::hint::black_box(comp.draw_glyphs(rect, 0.0, &style, line.iter()));
+            }
+        }
+        let glyphs = FRAMES * LINES * line.len();
+        println!(
+            "draw_glyphs: {:.1} ns per glyph",
+            start.elapsed().as_nanos() as f64 / glyphs as f64
+        );
+    }
+
+    #[test]
+    fn test_cursor_is_drawn_once_per_run() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let cursor_color = [1.0, 0.0, 0.0, 0.5];
+        let mut style = style(font);
+
+        for (cursor, width) in [
+            (SugarCursor::Block(cursor_color), 30.),
+            (SugarCursor::Caret(cursor_color), 3.),
+        ] {
+            style.cursor = cursor;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "abc").iter(),
+            );
+
+            let cursors: Vec<_> = standard_rects(&rects)
+                .into_iter()
+                .filter(|(_, color)| *color == cursor_color)
+                .collect();
+            assert_eq!(cursors.len(), 1);
+            let (rect, _) = cursors[0];
+            assert_eq!(
+                (rect.x, rect.y, rect.width, rect.height),
+                (0., 4., width, 20.)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cursor_blink_hides_cursors() {
+        use std::time::Duration;
+
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        let mut style = style(font);
+        let count_cursors = |style: &TextRunStyle, comp: &mut Compositor| {
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                style,
+                glyphs(&font, "a").iter(),
+            );
+            standard_rects(&rects)
+                .into_iter()
+                .filter(|(_, color)| *color == cursor_color)
+                .count()
+        };
+
+        let start = Instant::now();
+        let interval = Duration::from_millis(500);
+        comp.set_cursor_blink(Some(CursorBlink::new(interval, start)));
+        assert!(!comp.update_cursor_blink(start));
+        assert!(comp.update_cursor_blink(start + interval));
+
+        style.cursor = SugarCursor::Block(cursor_color);
+        assert_eq!(count_cursors(&style, &mut comp), 0);
+        style.cursor = SugarCursor::Disabled;
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-1.),
+            size: Some(1.),
+            color: cursor_color,
+            cursor: true,
+        });
+        assert_eq!(count_cursors(&style, &mut comp), 0);
+
+        // Typing shows the cursor again
+        comp.cursor_blink_mut()
+            .unwrap()
+            .cursor_moved(start + interval);
+        assert!(comp.update_cursor_blink(start + interval));
+        assert!(count_cursors(&style, &mut comp) > 0);
+    }
+
+    #[test]
+    fn test_warmed_glyphs_are_drawn_from_the_cache() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let glyphs = glyphs(&font, "hello world");
+        let mut list = DisplayList::new();
+
+        comp.warm_glyphs(&style, glyphs.iter());
+        let mut uploads = 0;
+        comp.finish(&mut list, |_| uploads += 1);
+        assert!(uploads > 0);
+
+        comp.begin();
+        comp.draw_glyphs(Rect::new(0., 20., 110., 1.), 0.0, &style, glyphs.iter());
+        let mut uploads = 0;
+        comp.finish(&mut list, |_| uploads += 1);
+        assert_eq!(uploads, 0);
+        assert!(!list.vertices().is_empty());
+    }
+
+    #[test]
+    fn test_glyph_top_is_stable_across_fractional_y() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let id = glyph_id(&font, 'g');
+
+        let tops: Vec<f32> = [20.0, 20.1, 20.25, 20.4, 19.6, 19.75, 19.9]
+            .iter()
+            .map(|&y| {
+                let rects = comp.draw_glyphs(
+                    Rect::new(0., 20., 10., 1.),
+                    0.0,
+                    &style,
+                    [Glyph::new(id, 0., y)].iter(),
+                );
+                match rects.first() {
+                    Some(CachedRect::Mask(data)) => data.rect.y,
+                    _ => panic!("expected a mask glyph"),
+                }
+            })
+            .collect();
+
+        assert!(tops.iter().all(|top| *top == tops[0]), "{tops:?}");
+    }
+
+    #[test]
+    fn test_intercepts_do_not_leak_between_runs() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let underline_color = [0.0, 1.0, 0.0, 1.0];
+        let mut underlined = style(font);
+        underlined.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: underline_color,
+            cursor: false,
+        });
+        let plain = style(font);
+        let rect = Rect::new(0., 20., 30., 1.);
+        let underlines = |rects: &[CachedRect]| {
+            standard_rects(rects)
+                .into_iter()
+                .filter(|(_, color)| *color == underline_color)
+                .count()
+        };
+
+        // Descenders split the underline around their ink
+        let rects = comp.draw_glyphs(rect, 0.0, &underlined, glyphs(&font, "gjp").iter());
+        assert!(underlines(&rects) > 1);
+
+        let rects = comp.draw_glyphs(rect, 0.0, &plain, glyphs(&font, "gjp").iter());
+        assert_eq!(underlines(&rects), 0);
+
+        // Without descenders the underline is a single span
+        let rects = comp.draw_glyphs(rect, 0.0, &underlined, glyphs(&font, "ace").iter());
+        assert_eq!(underlines(&rects), 1);
+    }
+
+    #[test]
+    fn test_cursor_thickness_follows_scale() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        comp.set_scale(2.0);
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        let mut style = style(font);
+        style.cursor = SugarCursor::Caret(cursor_color);
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "a").iter(),
+        );
+        let carets: Vec<_> = standard_rects(&rects)
+            .into_iter()
+            .filter(|(_, color)| *color == cursor_color)
+            .collect();
+        assert_eq!(carets.len(), 1);
+        assert_eq!(carets[0].0.width, 6.);
+
+        // The underline cursor
+        style.cursor = SugarCursor::Disabled;
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-1.),
+            size: Some(1.),
+            color: cursor_color,
+            cursor: true,
+        });
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "a").iter(),
+        );
+        let underlines: Vec<_> = standard_rects(&rects)
+            .into_iter()
+            .filter(|(_, color)| *color == cursor_color)
+            .collect();
+        assert!(!underlines.is_empty());
+        assert!(underlines.iter().all(|(rect, _)| rect.height == 2.));
+    }
+
+    #[test]
+    fn test_proportional_runs_use_font_advances() {
+        const SANS: &[u8] =
+            include_bytes!("../../../resources/test-fonts/DejaVuSans.ttf");
+        let font = FontRef::from_index(SANS, 0).unwrap();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.proportional = true;
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+
+        let rects = comp.draw_glyphs(
+            Rect::new(100., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "iW").iter(),
+        );
+
+        let metrics = font.glyph_metrics(&[]).scale(16.);
+        let advance = |ch| metrics.advance_width(glyph_id(&font, ch));
+        assert!(advance('i') < 6. && advance('W') > 12.);
+        let masks: Vec<Rect> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) => Some(data.rect),
+                _ => None,
+            })
+            .collect();
+        // The W starts right after the narrow i rather than a cell later
+        assert!(masks[1].x < 100. + advance('i') + 1., "{:?}", masks[1]);
+        let (background, _) = standard_rects(&rects)[0];
+        assert_eq!(background.x, 100.);
+        assert!((background.width - advance('i') - advance('W')).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_cursor_lands_on_a_cell_of_a_ligature() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        style.cursor = SugarCursor::Block(cursor_color);
+        style.cursor_width = Some(10.);
+        // A single glyph standing for a ligature over three 10px cells
+        style.cursor_cell = Some((1, 3));
+
+        let rects = comp.draw_glyphs(
+            Rect::new(100., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "=").iter(),
+        );
+
+        let cursors: Vec<Rect> = standard_rects(&rects)
+            .into_iter()
+            .filter(|(_, color)| *color == cursor_color)
+            .map(|(rect, _)| rect)
+            .collect();
+        assert_eq!(cursors.len(), 1);
+        assert_eq!((cursors[0].x, cursors[0].width), (110., 10.));
+
+        // Without a width of its own the cursor covers just its cell
+        style.cursor_width = None;
+        style.cursor_cell = Some((2, 3));
+        let rects = comp.draw_glyphs(
+            Rect::new(100., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "=").iter(),
+        );
+        let (cursor, _) = standard_rects(&rects)
+            .into_iter()
+            .find(|(_, color)| *color == cursor_color)
+            .unwrap();
+        assert_eq!((cursor.x, cursor.width), (120., 10.));
+    }
+
+    #[test]
+    fn test_right_to_left_runs_are_mirrored() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let masks = |rects: &[CachedRect]| -> Vec<f32> {
+            rects
+                .iter()
+                .filter_map(|r| match r {
+                    CachedRect::Mask(data) => Some(data.rect.x),
+                    _ => None,
+                })
+                .collect()
+        };
+        let rect = Rect::new(0., 20., 30., 1.);
+        let mirrored = comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "cba").iter());
+
+        style.direction = TextDirection::RightToLeft;
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        style.cursor = SugarCursor::Block(cursor_color);
+        style.cursor_width = Some(10.);
+        style.cursor_cell = Some((0, 3));
+        let rects = comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "abc").iter());
+
+        // The first logical glyph ends at the right edge of the run
+        assert_eq!(masks(&rects), masks(&mirrored));
+        let cursor = |rects: &[CachedRect]| {
+            standard_rects(rects)
+                .into_iter()
+                .find(|(_, color)| *color == cursor_color)
+                .unwrap()
+                .0
+        };
+        let block = cursor(&rects);
+        assert_eq!((block.x, block.width), (20., 10.));
+
+        style.cursor = SugarCursor::Caret(cursor_color);
+        let rects = comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "abc").iter());
+        let caret = cursor(&rects);
+        assert_eq!(caret.x + caret.width, 30.);
+    }
+
+    #[test]
+    fn test_combining_marks_stack_over_their_base() {
+        const SANS: &[u8] =
+            include_bytes!("../../../resources/test-fonts/DejaVuSans.ttf");
+        let font = FontRef::from_index(SANS, 0).unwrap();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let metrics = font.glyph_metrics(&[]).scale(16.);
+        let (base, mark) = (glyph_id(&font, 'e'), glyph_id(&font, '\u{301}'));
+        assert_eq!(metrics.advance_width(mark), 0.);
+
+        // The acute arrives in a cell of its own, two cells after the e
+        let glyphs = [Glyph::new(base, 0., 20.), Glyph::new(mark, 20., 20.)];
+        let rects =
+            comp.draw_glyphs(Rect::new(0., 20., 30., 1.), 0.0, &style, glyphs.iter());
+
+        let masks: Vec<Rect> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) => Some(data.rect),
+                _ => None,
+            })
+            .collect();
+        let (e, acute) = (masks[0], masks[1]);
+        assert!(
+            acute.x < e.x + e.width && acute.x + acute.width > e.x,
+            "{e:?} {acute:?}"
+        );
+        assert!(acute.x + acute.width <= metrics.advance_width(base) + 1.);
+    }
+
+    #[test]
+    fn test_next_depth_layers_by_call_order() {
+        let mut comp = Compositor::new(2048);
+        comp.begin();
+        assert_eq!(comp.next_depth(), 0.0);
+        assert_eq!(comp.next_depth(), DEPTH_STEP);
+        let mut last = DEPTH_STEP;
+        for _ in 0..70_000 {
+            let depth = comp.next_depth();
+            assert!(depth > last || depth == 1.0);
+            last = depth;
+        }
+        assert_eq!(last, 1.0);
+
+        comp.begin();
+        assert_eq!(comp.next_depth(), 0.0);
+    }
+
+    #[test]
+    fn test_glyphs_can_override_the_run_depth() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        let glyphs = [
+            Glyph::new(glyph_id(&font, 'a'), 0., 20.),
+            Glyph::new(glyph_id(&font, 'b'), 10., 20.).with_depth(0.75),
+        ];
+
+        let depths = |list: &DisplayList| {
+            let mut depths: Vec<f32> = list.vertices().iter().map(|v| v.pos[2]).collect();
+            depths.sort_by(f32::total_cmp);
+            depths.dedup();
+            depths
+        };
+
+        comp.begin();
+        let cache =
+            comp.draw_glyphs(Rect::new(0., 20., 20., 1.), 0.25, &style, glyphs.iter());
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(depths(&list), [0.25, 0.75]);
+
+        // Replays keep the glyph depth and move the rest to the new depth
+        comp.begin();
+        comp.draw_glyphs_from_cache(&cache, 0.5);
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(depths(&list), [0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_glyph_cache_stats() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let rect = Rect::new(0., 20., 20., 1.);
+
+        comp.begin();
+        comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "aa").iter());
+        let frame = GlyphCacheStats {
+            hits: 1,
+            misses: 1,
+            insertions: 1,
+        };
+        assert_eq!(comp.glyph_cache_stats(), frame);
+        assert_eq!(frame.miss_rate(), 0.5);
+
+        comp.begin();
+        assert_eq!(comp.glyph_cache_stats(), GlyphCacheStats::default());
+        comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "aa").iter());
+        assert_eq!(comp.glyph_cache_stats().hits, 2);
+        assert_eq!(
+            comp.total_glyph_cache_stats(),
+            GlyphCacheStats {
+                hits: 3,
+                misses: 1,
+                insertions: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_glyphs_are_fitted_to_their_span() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let mask = |comp: &mut Compositor, style: &TextRunStyle| {
+            let rects = comp.draw_glyphs(
+                Rect::new(100., 20., 20., 1.),
+                0.0,
+                style,
+                glyphs(&font, "W")
+                    .iter()
+                    .map(|glyph| Glyph { x: 100., ..*glyph }),
+            );
+            rects
+                .iter()
+                .find_map(|r| match r {
+                    CachedRect::Mask(data) => Some(data.rect),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let plain = mask(&mut comp, &style);
+
+        // Two cells, the glyph grows until it hits the line height
+        style.glyph_span = Some(20.);
+        let wide = mask(&mut comp, &style);
+        assert!(wide.width > plain.width * 1.3, "{plain:?} {wide:?}");
+        assert!(wide.height <= style.line_height);
+        let center = wide.x + wide.width / 2.;
+        assert!((center - 110.).abs() <= 1., "{wide:?}");
+
+        // Half a cell, the glyph shrinks to fit, give or take the pixel
+        // rasterization rounds out to
+        style.glyph_span = Some(5.);
+        let narrow = mask(&mut comp, &style);
+        assert!(narrow.width <= 6., "{narrow:?}");
+        assert!(narrow.x >= 99. && narrow.x + narrow.width <= 106.);
+    }
+
+    #[cfg(feature = "cell_widths")]
+    #[test]
+    fn test_draw_text_sizes_runs_by_cells() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        style.cursor = SugarCursor::Block(cursor_color);
+        comp.set_cell_widths(Some(CellWidths::new(10.)));
+
+        // One cell per character in the rect, the first one is wide
+        let rects = comp.draw_text(
+            Rect::new(0., 20., 20., 1.),
+            0.0,
+            &style,
+            "漢a",
+            glyphs(&font, "漢a").iter(),
+        );
+        let rects = standard_rects(&rects);
+        let (cursor, _) = rects.iter().find(|(_, c)| *c == cursor_color).unwrap();
+        assert_eq!(cursor.width, 20.);
+        let (background, _) = rects.iter().find(|(_, c)| *c != cursor_color).unwrap();
+        assert_eq!(background.width, 30.);
+    }
+
+    #[cfg(feature = "sixel")]
+    #[test]
+    fn test_draw_sixel_on_the_grid() {
+        let mut comp = Compositor::new(2048);
+        let sixel = SixelImage::decode(b"#1;2;100;0;0!25~-!25~").unwrap();
+        let image = comp.add_image(sixel.as_add_image()).unwrap();
+
+        let cells = comp.draw_sixel(image, &sixel, (2, 1), (10., 20.), 0.0);
+        assert_eq!(cells, (3, 1));
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "image x=20 y=20 w=25 h=12 z=0 color=1,1,1,1 image=t0 mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_kitty_images_are_placed_on_the_grid() {
+        let mut comp = Compositor::new(2048);
+        let (command, payload) =
+            KittyCommand::parse(b"a=T,f=32,s=1,v=1,i=3;/wAA/w==").unwrap();
+        let image = KittyImage::decode(&command, &payload).unwrap();
+        comp.add_kitty_image(command.id, &image).unwrap();
+
+        let placement = KittyPlacement {
+            columns: 2,
+            rows: 1,
+            x_offset: 2,
+            z_index: 0,
+            ..KittyPlacement::default()
+        };
+        let cells = comp.draw_kitty_image(3, &placement, (1, 2), (10., 20.));
+        assert_eq!(cells, Some((2, 1)));
+        let cells =
+            comp.draw_kitty_image(3, &KittyPlacement::default(), (0, 0), (10., 20.));
+        assert_eq!(cells, Some((1, 1)));
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "image x=0 y=0 w=1 h=1 z=0.5 color=1,1,1,1 image=t0 mask=-\n\
+             image x=12 y=40 w=20 h=20 z=0.5 color=1,1,1,1 image=t0 mask=-\n"
+        );
+
+        assert!(comp.delete_kitty_images(KittyDelete::Id(3)));
+        assert!(!comp.delete_kitty_images(KittyDelete::All));
+        assert_eq!(
+            comp.draw_kitty_image(3, &placement, (0, 0), (10., 20.)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_iterm2_images_keep_their_aspect_ratio() {
+        let mut comp = Compositor::new(2048);
+        let pixels = vec![255; 40 * 20 * 4];
+        let image = ITerm2Image {
+            width: 40,
+            height: 20,
+            pixels,
+        };
+        let image = comp.add_image(image.as_add_image()).unwrap();
+        let file = ITerm2File::parse(b"File=width=2;height=2;inline=1:").unwrap();
+
+        let cells =
+            comp.draw_iterm2_image(image, &file, (1, 1), (10., 20.), (800., 600.), 0.0);
+        assert_eq!(cells, Some((2, 1)));
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "image x=10 y=20 w=20 h=10 z=0 color=1,1,1,1 image=t0 mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_animations_step_through_their_frames() {
+        let mut comp = Compositor::new(2048);
+        let red = [255, 0, 0, 255].repeat(4);
+        let blue = [0, 0, 255, 255].repeat(4);
+        let frame = |data| AddImage {
+            format: PixelFormat::Rgba8,
+            width: 2,
+            height: 2,
+            has_alpha: true,
+            evictable: true,
+            data: ImageData::Borrowed(data),
+        };
+        let image = comp
+            .add_animation(&[
+                (frame(&red), Duration::from_millis(100)),
+                (frame(&blue), Duration::from_millis(50)),
+            ])
+            .unwrap();
+        let first = comp.get_image(image).unwrap().min;
+
+        let start = Instant::now();
+        assert!(!comp.advance_animation(image, start));
+        assert!(comp.advance_animation(image, start + Duration::from_millis(120)));
+        let second = comp.get_image(image).unwrap().min;
+        assert_ne!(first, second);
+        // Loops back to the first frame
+        assert!(comp.advance_animation(image, start + Duration::from_millis(160)));
+        assert_eq!(comp.get_image(image).unwrap().min, first);
+
+        // Once idle, only the first frame is kept, as a still image
+        let later = start + Duration::from_secs(10);
+        let still = comp.add_image(frame(&red)).unwrap();
+        assert!(!comp.advance_animation(still, later));
+        assert!(!comp.advance_animation(image, later + Duration::from_millis(120)));
+        assert_eq!(comp.get_image(image).unwrap().min, first);
+        assert!(comp.remove_image(image));
+    }
+
+    #[test]
+    fn test_nine_slice_keeps_corners() {
+        let mut comp = Compositor::new(2048);
+        let data = vec![255; 30 * 30 * 4];
+        let image = comp
+            .add_image(AddImage {
+                format: PixelFormat::Rgba8,
+                width: 30,
+                height: 30,
+                has_alpha: false,
+                evictable: true,
+                data: ImageData::Borrowed(&data),
+            })
+            .unwrap();
+        let white = [1.0, 1.0, 1.0, 1.0];
+        assert!(!comp.draw_image_9slice(
+            Rect::new(0., 0., 100., 50.),
+            0.0,
+            &white,
+            image,
+            Insets {
+                left: 20.,
+                right: 20.,
+                ..Insets::default()
+            },
+        ));
+        assert!(comp.draw_image_9slice(
+            Rect::new(0., 0., 100., 50.),
+            0.0,
+            &white,
+            image,
+            Insets::uniform(10.),
+        ));
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        let snapshot = list.to_snapshot();
+        let sizes: Vec<&str> = snapshot
+            .lines()
+            .map(|line| {
+                let start = line.find(" x=").unwrap() + 1;
+                let end = line.find(" z=").unwrap();
+                &line[start..end]
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                "x=0 y=0 w=10 h=10",
+                "x=0 y=10 w=10 h=30",
+                "x=0 y=40 w=10 h=10",
+                "x=10 y=0 w=80 h=10",
+                "x=10 y=10 w=80 h=30",
+                "x=10 y=40 w=80 h=10",
+                "x=90 y=0 w=10 h=10",
+                "x=90 y=10 w=10 h=30",
+                "x=90 y=40 w=10 h=10",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_draw_image_passes_its_tint() {
+        use crate::components::rich_text::tests::{assert_pixel, Offscreen};
+
+        let white = vec![255; 4 * 4 * 4];
+        let draw = |comp: &mut Compositor| {
+            let icon = comp
+                .add_image(AddImage {
+                    format: PixelFormat::Rgba8,
+                    width: 4,
+                    height: 4,
+                    has_alpha: true,
+                    evictable: true,
+                    data: ImageData::Borrowed(&white),
+                })
+                .unwrap();
+            comp.draw_image(Rect::new(0., 0., 4., 4.), 0.0, &[1.0, 0.0, 0.0, 1.0], icon);
+        };
+
+        let mut comp = Compositor::new(2048);
+        draw(&mut comp);
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "image x=0 y=0 w=4 h=4 z=0 color=1,0,0,1 image=t0 mask=-\n"
+        );
+
+        // A white icon drawn red comes out red
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        let pixels = target.render(wgpu::Color::TRANSPARENT, draw);
+        assert_pixel(&pixels, 2, 2, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 6, 2, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_dim_overlay_leaves_the_cursor_undimmed() {
+        use crate::components::rich_text::tests::{assert_pixel, Offscreen};
+        use crate::components::rich_text::{DepthMode, RichTextBrushConfig};
+
+        let font = font();
+        let mut style = style(font);
+        style.cursor = SugarCursor::Block([1.0, 0.0, 0.0, 1.0]);
+        let glyphs = glyphs(&font, " ");
+        let overlay = |comp: &mut Compositor| {
+            comp.draw_dim_overlay(
+                Rect::new(0., 0., 16., 16.),
+                &[0.0, 0.0, 0.0, 1.0],
+                0.5,
+            );
+        };
+
+        // Cut out of the overlay, whether the run is drawn or replayed
+        let mut comp = Compositor::new(2048);
+        let rects =
+            comp.draw_glyphs(Rect::new(0., 20., 8., 1.), 0.0, &style, glyphs.iter());
+        overlay(&mut comp);
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        let drawn = list.to_snapshot();
+        assert!(!drawn.contains("x=0 y=0 w=16 h=16"), "{drawn}");
+        comp.begin();
+        comp.draw_glyphs_from_cache(&rects, 0.0);
+        overlay(&mut comp);
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(list.to_snapshot(), drawn);
+
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        let pixels = target.render(wgpu::Color::WHITE, |comp| {
+            comp.draw_glyphs(Rect::new(0., 20., 8., 1.), 0.0, &style, glyphs.iter());
+            overlay(comp);
+        });
+        assert_pixel(&pixels, 2, 8, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 12, 8, [128, 128, 128, 255]);
+
+        // Depth tested, the overlay still covers draws above `0.0`, e.g. a
+        // kitty image, and the cursor drawn at their depth
+        let config = RichTextBrushConfig {
+            depth_mode: DepthMode::Tested,
+            ..RichTextBrushConfig::default()
+        };
+        let mut target =
+            Offscreen::with_config(wgpu::TextureFormat::Rgba8Unorm, &config, (16, 16));
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.draw_rect(Rect::new(0., 0., 16., 16.), 0.5, &[1.0, 1.0, 1.0, 1.0]);
+            comp.draw_glyphs(Rect::new(0., 20., 8., 1.), 0.5, &style, glyphs.iter());
+            overlay(comp);
+        });
+        assert_pixel(&pixels, 2, 8, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 12, 8, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn test_subpixel_blend() {
+        use crate::components::rich_text::tests::{assert_pixel, Offscreen};
+        use crate::components::rich_text::RichTextBrushConfig;
+
+        let config = RichTextBrushConfig {
+            subpixel_text: true,
+            ..Default::default()
+        };
+        let mut target =
+            Offscreen::with_config(wgpu::TextureFormat::Rgba8Unorm, &config, (16, 16));
+        let gray = wgpu::Color {
+            r: 0.2,
+            g: 0.4,
+            b: 0.6,
+            a: 1.0,
+        };
+        let pixels = target.render(gray, |comp| {
+            let mask = |comp: &mut Compositor, coverage: [u8; 4]| {
+                let id = comp
+                    .add_image(AddImage {
+                        format: PixelFormat::Rgba8,
+                        width: 1,
+                        height: 1,
+                        has_alpha: false,
+                        evictable: false,
+                        data: ImageData::Borrowed(&coverage),
+                    })
+                    .unwrap();
+                let image = comp.images.get(id).unwrap();
+                let coords = [image.min.0, image.min.1, image.max.0, image.max.1];
+                (image.texture_id, coords)
+            };
+            // White text whose left edge only covers the blue subpixel
+            let (texture, coords) = mask(comp, [0, 0, 255, 255]);
+            comp.batches.add_mask_rect(
+                &Rect::new(2., 2., 1., 1.),
+                0.,
+                &[1.0, 1.0, 1.0, 1.0],
+                &coords,
+                texture,
+                true,
+            );
+            // Full coverage of every subpixel matches BLEND, i.e. the rect
+            let (texture, coords) = mask(comp, [255, 255, 255, 255]);
+            let color = [1.0, 0.5, 0.0, 0.5];
+            comp.batches.add_mask_rect(
+                &Rect::new(6., 2., 1., 1.),
+                0.,
+                &color,
+                &coords,
+                texture,
+                true,
+            );
+            comp.draw_rect(Rect::new(10., 2., 1., 1.), 0., &color);
+        });
+
+        // Without dual-source blending masks fall back to the coverage of
+        // their first channel, which the blue subpixel edge leaves untouched
+        let edge = if target.subpixel_text() {
+            [51, 102, 255, 255]
+        } else {
+            [51, 102, 153, 255]
+        };
+        assert_pixel(&pixels, 2, 2, edge);
+        assert_pixel(&pixels, 6, 2, [153, 115, 77, 255]);
+        assert_pixel(&pixels, 10, 2, [153, 115, 77, 255]);
+    }
+
+    #[test]
+    fn test_draw_rects_merges_runs_of_a_line() {
+        let mut comp = Compositor::new(2048);
+        let blue = [0.0, 0.0, 1.0, 1.0];
+        let red = [1.0, 0.0, 0.0, 0.5];
+        let cell = |column: f32, row: f32| Rect::new(column * 10., row * 20., 10., 20.);
+        comp.draw_rects(
+            &[
+                (cell(0., 0.), blue),
+                (cell(1., 0.), blue),
+                (cell(2., 0.), blue),
+                (cell(3., 0.), red),
+                (cell(5., 0.), red),
+                (cell(0., 1.), blue),
+            ],
+            0.0,
+        );
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "rect x=0 y=0 w=30 h=20 z=0 color=0,0,1,1 image=- mask=-\n\
+             rect x=0 y=20 w=10 h=20 z=0 color=0,0,1,1 image=- mask=-\n\
+             rect x=30 y=0 w=10 h=20 z=0 color=1,0,0,0.5 image=- mask=-\n\
+             rect x=50 y=0 w=10 h=20 z=0 color=1,0,0,0.5 image=- mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_draw_line() {
+        let mut comp = Compositor::new(2048);
+        let color = [1.0, 1.0, 1.0, 1.0];
+        comp.draw_line([40., 10.], [10., 10.], 2., 0.0, &color);
+        comp.draw_line([5., 0.], [5., 30.], 1., 0.0, &color);
+        // Nothing for a point or no width
+        comp.draw_line([7., 7.], [7., 7.], 2., 0.0, &color);
+        comp.draw_line([0., 0.], [9., 9.], 0., 0.0, &color);
+        // A diagonal is a rotated quad, whose bounds include its thickness
+        comp.draw_line([0., 0.], [30., 40.], 10., 0.0, &color);
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "rect x=-4 y=-3 w=38 h=46 z=0 color=1,1,1,1 image=- mask=-\n\
+             rect x=10 y=9 w=30 h=2 z=0 color=1,1,1,1 image=- mask=-\n\
+             rect x=4.5 y=0 w=1 h=30 z=0 color=1,1,1,1 image=- mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_dividers_are_snapped_to_pixels() {
+        let mut comp = Compositor::new(2048);
+        comp.set_scale(1.5);
+        comp.set_divider_colors(DividerColors {
+            active: [1.0, 1.0, 1.0, 1.0],
+            inactive: [0.5, 0.5, 0.5, 1.0],
+        });
+        comp.draw_divider(DividerOrientation::Vertical, [100.3, 0.4], 50., 1., true);
+        comp.draw_divider(
+            DividerOrientation::Horizontal,
+            [0., 60.75],
+            99.6,
+            0.5,
+            false,
+        );
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_eq!(
+            list.to_snapshot(),
+            "rect x=0 y=60 w=100 h=1 z=0 color=0.5,0.5,0.5,1 image=- mask=-\n\
+             rect x=99 y=0 w=2 h=50 z=0 color=1,1,1,1 image=- mask=-\n"
+        );
+    }
+
+    #[test]
+    fn test_strikethrough_has_its_own_color() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.color = [1.0, 1.0, 1.0, 1.0];
+        let mut strike = |color| {
+            style.strikethrough = Some(Strikethrough {
+                offset: 6.,
+                size: 2.,
+                color,
+            });
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 20., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "ab").iter(),
+            );
+            standard_rects(&rects)
+                .into_iter()
+                .map(|(rect, color)| ([rect.x, rect.y, rect.width, rect.height], color))
+                .collect::<Vec<_>>()
+        };
+
+        let muted = [0.5, 0.5, 0.5, 1.0];
+        assert_eq!(strike(Some(muted)), [([0., 14., 20., 2.], muted)]);
+        // Unset, it follows the text
+        assert_eq!(strike(None), [([0., 14., 20., 2.], [1.0, 1.0, 1.0, 1.0])]);
+    }
+
+    #[test]
+    fn test_missing_glyphs_are_boxed() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.color = [0.5, 0.5, 0.5, 1.0];
+        style.line_height = 40.;
+        let glyphs = [Glyph::new(0, 0., 20.).with_code_point('\u{E0}')];
+        let draw = |comp: &mut Compositor| {
+            let rects =
+                comp.draw_glyphs(Rect::new(0., 20., 40., 1.), 0.0, &style, glyphs.iter());
+            standard_rects(&rects)
+        };
+
+        // A thin box in the color of the text, inset from the cell
+        let boxed = draw(&mut comp);
+        assert_eq!(boxed.len(), 4);
+        assert!(boxed
+            .iter()
+            .all(|(_, color)| *color == [0.5, 0.5, 0.5, 1.0]));
+        let (top, _) = boxed[0];
+        assert_eq!([top.x, top.y, top.width, top.height], [4., 8., 32., 1.]);
+
+        let magenta = [1.0, 0.0, 1.0, 1.0];
+        comp.set_missing_glyph_style(Some(MissingGlyphStyle {
+            thickness: 2.,
+            show_code_point: true,
+            color: Some(magenta),
+        }));
+        let loud = draw(&mut comp);
+        assert!(loud.len() > 4);
+        assert!(loud.iter().all(|(_, color)| *color == magenta));
+        assert_eq!(loud[0].0.height, 2.);
+
+        comp.set_missing_glyph_style(None);
+        assert!(draw(&mut comp).is_empty());
+    }
+
+    #[test]
+    fn test_frames_outline_the_run() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let mut frame = |frame| {
+            style.frame = Some(frame);
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 40., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "    ").iter(),
+            );
+            standard_rects(&rects)
+                .into_iter()
+                .map(|(rect, _)| [rect.x, rect.y, rect.width, rect.height])
+                .collect::<Vec<_>>()
+        };
+
+        // The run spans 0..40 by 4..24
+        assert_eq!(
+            frame(Frame::Framed),
+            [
+                [0., 4., 40., 1.],
+                [0., 5., 1., 18.],
+                [39., 5., 1., 18.],
+                [0., 23., 40., 1.],
+            ]
+        );
+        let encircled = frame(Frame::Encircled);
+        let inside = |[x, y, width, height]: [f32; 4]| {
+            x >= 0. && y >= 4. && x + width <= 40. && y + height <= 24.
+        };
+        assert!(encircled.iter().all(|rect| inside(*rect)));
+        // The ends are rounded and the middle is left empty
+        let covers = |px: f32, py: f32| {
+            encircled.iter().any(|[x, y, width, height]| {
+                (*x..x + width).contains(&px) && (*y..y + height).contains(&py)
+            })
+        };
+        assert!(covers(20., 4.5) && covers(20., 23.5));
+        assert!(covers(0.5, 14.) && covers(39.5, 14.));
+        assert!(!covers(0.5, 4.5) && !covers(39.5, 23.5));
+        assert!(!covers(20., 14.));
+    }
+
+    #[test]
+    fn test_underline_can_skip_trailing_whitespace() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let color = [1.0, 0.0, 0.0, 1.0];
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color,
+            cursor: false,
+        });
+        let mut underline_end = |skip| {
+            style.underline_skip_trailing_whitespace = skip;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "-  ").iter(),
+            );
+            standard_rects(&rects)
+                .into_iter()
+                .filter(|(_, c)| *c == color)
+                .map(|(rect, _)| rect.x + rect.width)
+                .fold(0., f32::max)
+        };
+
+        assert_eq!(underline_end(false), 30.);
+        let end = underline_end(true);
+        assert!(end > 0. && end <= 10., "{end}");
+    }
+
+    #[test]
+    fn test_underline_falls_back_to_font_metrics() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let color = [1.0, 0.0, 0.0, 1.0];
+        let mut underline = |offset, size| {
+            style.underline = Some(UnderlineStyle {
+                offset,
+                size,
+                color,
+                cursor: false,
+            });
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                &style,
+                // Spaces leave no ink to skip
+                glyphs(&font, "   ").iter(),
+            );
+            let underlines: Vec<_> = standard_rects(&rects)
+                .into_iter()
+                .filter(|(_, c)| *c == color)
+                .map(|(rect, _)| (rect.y, rect.height))
+                .collect();
+            assert_eq!(underlines.len(), 1);
+            underlines[0]
+        };
+
+        let metrics = font.metrics(&[]).scale(16.);
+        assert_eq!(
+            underline(None, None),
+            (
+                20. - metrics.underline_offset.round(),
+                metrics.stroke_size.round().max(1.)
+            )
+        );
+        // What the caller gives wins
+        assert_eq!(underline(Some(-2.), Some(3.)), (22., 3.));
+        assert_eq!(underline(Some(-2.), None).0, 22.);
+    }
+
+    #[test]
+    fn test_pixel_snapping_keeps_thin_lines_crisp() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        comp.set_scale(1.5);
+        comp.set_pixel_snapping(true);
+        let underline_color = [0.0, 1.0, 0.0, 1.0];
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        let mut style = style(font);
+        style.cursor = SugarCursor::Caret(cursor_color);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: underline_color,
+            cursor: false,
+        });
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0.4, 20.3, 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "a").iter(),
+        );
+        for (rect, _) in standard_rects(&rects) {
+            for value in [rect.x, rect.y, rect.width, rect.height] {
+                assert_eq!(value, value.round(), "{rect:?}");
+            }
+        }
+        let (underline, _) = standard_rects(&rects)
+            .into_iter()
+            .find(|(_, color)| *color == underline_color)
+            .unwrap();
+        assert_eq!(underline.height, 1.);
+        // A 3px caret is 4.5 physical pixels, rounded down
+        let (caret, _) = standard_rects(&rects)
+            .into_iter()
+            .find(|(_, color)| *color == cursor_color)
+            .unwrap();
+        assert_eq!(caret.width, 4.);
+    }
+
+    #[test]
+    fn test_selection_span_rounds_outer_corners() {
+        let mut comp = Compositor::new(2048);
+        let color = [0.0, 0.0, 1.0, 0.5];
+        let grid = Rect::new(0., 0., 10., 20.);
+        let bounds = |comp: &mut Compositor| {
+            let mut list = DisplayList::new();
+            comp.finish(&mut list, |_| {});
+            comp.begin();
+            list.to_snapshot()
+                .lines()
+                .map(|line| {
+                    let values: Vec<f32> = line
+                        .split(' ')
+                        .skip(1)
+                        .take(4)
+                        .map(|pair| pair[2..].parse().unwrap())
+                        .collect();
+                    Rect::new(values[0], values[1], values[2], values[3])
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // From the fourth cell of the first line to the second of the third,
+        // given in reverse
+        comp.draw_selection_span((1, 2), (3, 0), 10, grid, &color, 0.);
+        let rects = bounds(&mut comp);
+        assert_eq!(rects.len(), 3);
+        let mut spans: Vec<_> = rects
+            .iter()
+            .map(|r| (r.x, r.y, r.x + r.width, r.y + r.height))
+            .collect();
+        spans.sort_by(|a, b| a.1.total_cmp(&b.1));
+        assert_eq!(
+            spans,
+            [
+                (30., 0., 100., 20.),
+                (0., 20., 100., 40.),
+                (0., 40., 20., 60.)
+            ]
+        );
+
+        comp.draw_selection_span((3, 0), (1, 2), 10, grid, &color, 4.);
+        let rects = bounds(&mut comp);
+        let at = |y: f32| rects.iter().filter(move |r| r.y <= y && y < r.y + r.height);
+        // Outer corners are cut, inner ones stay square
+        assert!(at(0.).all(|r| r.x > 30. && r.x + r.width < 100.));
+        assert!(at(19.5).any(|r| r.x == 30.));
+        assert!(at(20.).any(|r| r.x > 0. && r.x + r.width == 100.));
+        assert!(at(39.5).any(|r| r.x == 0. && r.x + r.width < 100.));
+        assert!(at(40.).any(|r| r.x == 0. && r.x + r.width == 20.));
+        assert!(at(59.5).all(|r| r.x > 0. && r.x + r.width < 20.));
+    }
+
+    #[test]
+    fn test_superscript_and_subscript() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let underline_color = [0.0, 1.0, 0.0, 1.0];
+        let mut style = style(font);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(2.),
+            color: underline_color,
+            cursor: false,
+        });
+
+        let mut draw = |vertical_align| {
+            style.vertical_align = vertical_align;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 10., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "x").iter(),
+            );
+            let glyph = rects
+                .iter()
+                .find_map(|r| match r {
+                    CachedRect::Mask(data) => Some(data.rect),
+                    _ => None,
+                })
+                .unwrap();
+            let underline = standard_rects(&rects)
+                .into_iter()
+                .find(|(_, color)| *color == underline_color)
+                .unwrap()
+                .0;
+            (glyph, underline)
+        };
+
+        let (glyph, underline) = draw(VerticalAlign::Baseline);
+        let (sup, sup_underline) = draw(VerticalAlign::Superscript);
+        let (sub, sub_underline) = draw(VerticalAlign::Subscript);
+
+        assert!(sup.height < glyph.height && sub.height < glyph.height);
+        assert!(sup.y + sup.height < glyph.y + glyph.height);
+        assert!(sub.y + sub.height > glyph.y + glyph.height);
+        assert!(sup_underline.y < underline.y && sub_underline.y > underline.y);
+        assert!(sup_underline.height < underline.height);
+    }
+
+    #[test]
+    fn test_block_cursor_spans_the_grapheme() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let cursor_color = [1.0, 0.0, 0.0, 1.0];
+        let mut style = style(font);
+        style.cursor = SugarCursor::Block(cursor_color);
+        // A family emoji is one wide grapheme but five glyphs once shaped
+        // without an emoji font
+        style.cursor_width = Some(20.);
+        let text = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+
+        let rects = comp.draw_glyphs(
+            Rect::new(0., 20., 50., 1.),
+            0.0,
+            &style,
+            glyphs(&font, text).iter(),
+        );
+        let cursors: Vec<_> = standard_rects(&rects)
+            .into_iter()
+            .filter(|(_, color)| *color == cursor_color)
+            .collect();
+        assert_eq!(cursors.len(), 1);
+        assert_eq!(cursors[0].0.width, 20.);
+    }
+
+    #[test]
+    fn test_bold_is_bright() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.color = [0.8, 0.0, 0.0, 1.0];
+        style.ansi_index = Some(1);
+        style.bold = true;
+        let draw = |comp: &mut Compositor| {
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 10., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "a").iter(),
+            );
+            match rects.first() {
+                Some(CachedRect::Mask(data)) => data.color,
+                _ => panic!("expected a mask glyph"),
+            }
+        };
+
+        assert_eq!(draw(&mut comp), [0.8, 0.0, 0.0, 1.0]);
+        comp.set_bold_is_bright(true);
+        assert_eq!(draw(&mut comp), DEFAULT_BRIGHT_PALETTE[1]);
+    }
+
+    #[test]
+    fn test_min_contrast() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        comp.set_min_contrast(Some(4.5));
+        let mut style = style(font);
+        style.color = [0.3, 0.3, 0.3, 1.0];
+        let mut draw = |background| {
+            style.background_color = background;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 10., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "a").iter(),
+            );
+            rects
+                .iter()
+                .find_map(|r| match r {
+                    CachedRect::Mask(data) => Some(data.color),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        assert_eq!(draw(None), [0.3, 0.3, 0.3, 1.0]);
+        let background = [0.2, 0.2, 0.2, 1.0];
+        assert!(contrast_ratio(draw(Some(background)), background) >= 4.5);
+    }
+
+    #[test]
+    fn test_min_contrast_against_the_default_background() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        comp.set_min_contrast(Some(4.5));
+        let mut style = style(font);
+        style.color = [0.3, 0.3, 0.3, 1.0];
+        let mut draw = |default_background| {
+            style.default_background = default_background;
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 10., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "a").iter(),
+            );
+            rects
+                .iter()
+                .find_map(|r| match r {
+                    CachedRect::Mask(data) => Some(data.color),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        // Transparent by default, which keeps the run color
+        assert_eq!(draw([0.0; 4]), [0.3, 0.3, 0.3, 1.0]);
+        let background = [0.2, 0.2, 0.2, 1.0];
+        assert!(contrast_ratio(draw(background), background) >= 4.5);
+    }
+
+    #[test]
+    fn test_forced_colors() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let forced = ForcedColors::new([1.0, 1.0, 0.0, 1.0], [0.0, 0.0, 0.0, 1.0]);
+        let mut style = style(font);
+        style.color = [0.5, 0.5, 0.5, 1.0];
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        style.cursor = SugarCursor::Block([1.0, 0.0, 0.0, 1.0]);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: [0.0, 1.0, 0.0, 1.0],
+            cursor: false,
+        });
+        let draw = |comp: &mut Compositor, style: &TextRunStyle| {
+            comp.draw_glyphs(
+                Rect::new(0., 20., 10., 1.),
+                0.0,
+                style,
+                glyphs(&font, "a").iter(),
+            )
+        };
+        let unforced = draw(&mut comp, &style);
+
+        comp.set_forced_colors(Some(forced));
+        let rects = draw(&mut comp, &style);
+        let masks: Vec<_> = rects
+            .iter()
+            .filter_map(|r| match r {
+                CachedRect::Mask(data) => Some(data.color),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(masks, vec![forced.foreground]);
+        let colors: Vec<_> = standard_rects(&rects).iter().map(|r| r.1).collect();
+        assert!(colors.contains(&forced.background));
+        assert!(colors.contains(&forced.cursor));
+        // Decorations and geometry are kept
+        assert_eq!(rects.len(), unforced.len());
+
+        style.selected = true;
+        let rects = draw(&mut comp, &style);
+        let colors: Vec<_> = standard_rects(&rects).iter().map(|r| r.1).collect();
+        assert!(colors.contains(&forced.selection_background));
+    }
+
+    #[test]
+    fn test_box_drawing_is_drawn_with_rects() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        let draw = |comp: &mut Compositor| {
+            comp.draw_glyphs(
+                Rect::new(0., 20., 30., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "─a─").iter(),
+            )
+        };
+
+        let rects = draw(&mut comp);
+        let masks = rects
+            .iter()
+            .filter(|r| matches!(r, CachedRect::Mask(_)))
+            .count();
+        assert_eq!(masks, 1);
+        let lines: Vec<_> = standard_rects(&rects)
+            .into_iter()
+            .filter(|(_, color)| *color == style.color)
+            .map(|(rect, _)| (rect.x, rect.width))
+            .collect();
+        // Each line spans its whole cell, drawn after the backgrounds
+        assert_eq!(lines, vec![(0., 5.), (5., 5.), (20., 5.), (25., 5.)]);
+        let last_background = rects
+            .iter()
+            .rposition(|r| matches!(r, CachedRect::Standard((_, c)) if *c != style.color))
+            .unwrap();
+        let first_line = rects
+            .iter()
+            .position(|r| matches!(r, CachedRect::Standard((_, c)) if *c == style.color))
+            .unwrap();
+        assert!(last_background < first_line);
+
+        comp.set_box_drawing(false);
+        let rects = draw(&mut comp);
+        let masks = rects
+            .iter()
+            .filter(|r| matches!(r, CachedRect::Mask(_)))
+            .count();
+        assert_eq!(masks, 3);
+    }
+
+    #[test]
+    fn test_powerline_is_opt_in_per_code_point() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let draw = |comp: &mut Compositor| {
+            let rects = comp.draw_glyphs(
+                Rect::new(0., 20., 20., 1.),
+                0.0,
+                &style,
+                glyphs(&font, "\u{E0B0}\u{E0B2}").iter(),
+            );
+            let masks = rects
+                .iter()
+                .filter(|r| matches!(r, CachedRect::Mask(_)))
+                .count();
+            (masks, standard_rects(&rects))
+        };
+
+        let (masks, rects) = draw(&mut comp);
+        assert_eq!(masks, 2);
+        assert!(rects.is_empty());
+
+        comp.set_powerline('\u{E0B0}', true);
+        let (masks, rects) = draw(&mut comp);
+        assert_eq!(masks, 1);
+        // Filled row by row over the first cell only
+        assert!(!rects.is_empty());
+        assert!(rects
+            .iter()
+            .all(|(rect, _)| rect.x >= 0. && rect.x + rect.width <= 10.));
+        let rows = rects.iter().filter(|(rect, _)| rect.x == 0.).count();
+        assert_eq!(rows, style.line_height as usize);
+    }
+
+    #[test]
+    fn test_highlight_coalesces_adjacent_cells() {
+        let cells = [
+            Rect::new(20., 0., 10., 20.),
+            Rect::new(0., 0., 10., 20.),
+            Rect::new(10., 0., 10., 20.),
+            // A separate match further on the row
+            Rect::new(50., 0., 10., 20.),
+            // Full rows stack into one rect
+            Rect::new(0., 20., 30., 20.),
+            Rect::new(0., 40., 30., 20.),
+        ];
+        let rects: Vec<_> = coalesce(&cells)
+            .iter()
+            .map(|r| (r.x, r.y, r.width, r.height))
+            .collect();
+        assert_eq!(rects, vec![(0., 0., 30., 60.), (50., 0., 10., 20.)]);
+    }
+
+    #[test]
+    fn test_search_match_colors() {
+        let mut comp = Compositor::new(2048);
+        let colors = HighlightColors::default();
+        comp.draw_search_match(&[Rect::new(0., 0., 10., 20.)], true);
+        comp.draw_search_match(&[Rect::new(10., 0., 10., 20.)], false);
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        let drawn: Vec<_> = list.vertices().iter().step_by(4).map(|v| v.color).collect();
+        assert_eq!(drawn, vec![colors.current_match, colors.other_match]);
+    }
+
+    #[test]
+    fn test_smear_interpolates_between_cells() {
+        let from = Rect::new(0., 0., 10., 20.);
+        let to = Rect::new(100., 40., 10., 20.);
+        assert_eq!(
+            smear(&from, &to, 0.),
+            [[0., 0.], [0., 20.], [10., 20.], [10., 0.]]
+        );
+        assert_eq!(
+            smear(&from, &to, 1.),
+            [[100., 40.], [100., 60.], [110., 60.], [110., 40.]]
+        );
+
+        // Moving down and right, the bottom right corner leads and the top
+        // left one trails
+        let [top_left, _, bottom_right, _] = smear(&from, &to, 0.5);
+        assert!(bottom_right[0] - 10. > top_left[0]);
+        assert!(bottom_right[1] - 20. > top_left[1]);
+    }
+
+    /// Compares `snapshot` with the golden file `name` in `resources/golden`,
+    /// or rewrites the file when `SUGARLOAF_BLESS` is set.
+    fn assert_golden(name: &str, snapshot: &str) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/golden")
+            .join(name);
+        if std::env::var_os("SUGARLOAF_BLESS").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, snapshot).unwrap();
+            return;
+        }
+        let golden = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!("{} is missing, run with SUGARLOAF_BLESS=1", path.display())
+        });
+        assert_eq!(
+            snapshot,
+            golden.replace("\r\n", "\n"),
+            "{} differs, run with SUGARLOAF_BLESS=1 to update it",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn test_draw_glyphs_matches_golden_file() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        style.background_color = Some([0.0, 0.0, 0.5, 1.0]);
+        style.cursor = SugarCursor::Block([1.0, 1.0, 1.0, 0.5]);
+        style.cursor_width = Some(10.);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: [1.0, 0.0, 0.0, 1.0],
+            cursor: false,
+        });
+        comp.draw_glyphs(
+            Rect::new(0., 20., 30., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "a=b").iter(),
+        );
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        assert_golden("draw_glyphs.snap", &list.to_snapshot());
+    }
+
+    #[test]
+    fn test_stem_darkening_rasterizes_glyphs_again() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let rect = Rect::new(0., 20., 10., 1.);
+        comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "l").iter());
+
+        comp.begin();
+        comp.set_stem_darkening(true, 1.);
+        comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "l").iter());
+        assert_eq!(comp.glyph_cache_stats().misses, 1);
+
+        // Same setting, glyphs stay cached
+        comp.begin();
+        comp.set_stem_darkening(true, 1.);
+        comp.draw_glyphs(rect, 0.0, &style, glyphs(&font, "l").iter());
+        assert_eq!(comp.glyph_cache_stats().hits, 1);
+    }
+
+    #[test]
+    fn test_glyphs_center_in_a_double_line_height() {
+        let font = font();
+        let metrics = font.metrics(&[]).scale(16.);
+        let height = metrics.ascent + metrics.descent;
+        let mut style = style(font);
+        style.topline = 0.;
+        style.baseline = metrics.ascent.round();
+        style.line_height = (height * 2.).round();
+        style.background_color = Some([0.0, 0.0, 1.0, 1.0]);
+        style.underline = Some(UnderlineStyle {
+            offset: Some(-2.),
+            size: Some(1.),
+            color: [1.0, 0.0, 0.0, 1.0],
+            cursor: false,
+        });
+        let draw = |style: &TextRunStyle, glyph_height: Option<f32>| {
+            let mut comp = Compositor::new(2048);
+            let style = TextRunStyle {
+                glyph_height,
+                ..*style
+            };
+            let glyphs = [Glyph::new(glyph_id(&font, 'H'), 0., style.baseline)];
+            let rects =
+                comp.draw_glyphs(Rect::new(0., 0., 10., 1.), 0.0, &style, glyphs.iter());
+            let glyph = rects
+                .iter()
+                .find_map(|rect| match rect {
+                    CachedRect::Mask(data) => Some(data.rect),
+                    _ => None,
+                })
+                .unwrap();
+            let standard = standard_rects(&rects);
+            let background = standard[0].0;
+            let underline = standard.last().unwrap().0;
+            (glyph, background, underline)
+        };
+
+        // The extra space is left below the glyphs
+        let (top, background, underline) = draw(&style, None);
+        assert!(top.y < style.line_height / 4.);
+        // Centered, glyph and underline go down by half of it, the
+        // background still fills the line
+        let (centered, centered_background, centered_underline) =
+            draw(&style, Some(height));
+        let shift = ((style.line_height - height) / 2.).round();
+        assert_eq!(centered.y - top.y, shift);
+        assert_eq!(centered_underline.y - underline.y, shift);
+        assert_eq!(
+            [background.y, background.height],
+            [centered_background.y, centered_background.height]
+        );
+        assert_eq!(centered_background.height, style.line_height);
+        let middle = centered.y + centered.height / 2.;
+        assert!((middle - style.line_height / 2.).abs() < height / 4.);
+
+        // Lines no taller than the glyphs leave them in place
+        style.line_height = height;
+        let (top, ..) = draw(&style, None);
+        assert_eq!(draw(&style, Some(height)).0.y, top.y);
+    }
+
+    #[test]
+    fn test_hinting_modes_are_cached_apart() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let style = style(font);
+        let rect = Rect::new(0., 20., 10., 1.);
+        let draw = |comp: &mut Compositor, x: f32| {
+            let glyph = Glyph::new(glyph_id(&font, 'e'), x, 20.);
+            comp.draw_glyphs(rect, 0.0, &style, [glyph].iter());
+        };
+
+        for hinting in [Hinting::None, Hinting::Slight, Hinting::Full] {
+            comp.set_hinting(hinting);
+            draw(&mut comp, 0.);
+        }
+        assert_eq!(comp.glyph_cache_stats().misses, 3);
+
+        // Fully hinted glyphs drop their subpixel offset, slightly hinted
+        // ones keep it
+        comp.begin();
+        draw(&mut comp, 0.5);
+        assert_eq!(comp.glyph_cache_stats().hits, 1);
+        comp.set_hinting(Hinting::Slight);
+        draw(&mut comp, 0.5);
+        assert_eq!(comp.glyph_cache_stats().misses, 1);
+    }
+
+    #[test]
+    fn test_zoom_through_fractional_font_sizes() {
+        let font = font();
+        let mut comp = Compositor::new(2048);
+        let mut style = style(font);
+        let zoom = |comp: &mut Compositor, style: &mut TextRunStyle| {
+            let mut widths = Vec::new();
+            for step in 0..=4 {
+                style.font_size = 12. + step as f32 * 0.5;
+                let rects = comp.draw_glyphs(
+                    Rect::new(0., 20., 10., 1.),
+                    0.0,
+                    style,
+                    glyphs(&font, "M").iter(),
+                );
+                let width = rects
+                    .iter()
+                    .find_map(|rect| match rect {
+                        CachedRect::Image(data) | CachedRect::Mask(data) => {
+                            Some(data.rect.width)
+                        }
+                        _ => None,
+                    })
+                    .unwrap();
+                widths.push(width);
+            }
+            widths
+        };
+
+        // Every half pixel step is a bucket of its own, rasterized once
+        let widths = zoom(&mut comp, &mut style);
+        assert_eq!(comp.glyph_cache_stats().misses, 5);
+        assert!(widths.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(widths[0] < widths[4]);
+
+        comp.begin();
+        assert_eq!(zoom(&mut comp, &mut style), widths);
+        assert_eq!(comp.glyph_cache_stats().misses, 0);
+        assert_eq!(comp.glyph_cache_stats().hits, 5);
+
+        // Sizes in between land in the bucket of the nearest half pixel
+        style.font_size = 12.2;
+        comp.draw_glyphs(
+            Rect::new(0., 20., 10., 1.),
+            0.0,
+            &style,
+            glyphs(&font, "M").iter(),
+        );
+        assert_eq!(comp.glyph_cache_stats().misses, 0);
+    }
+
+    #[test]
+    fn test_animated_cursor_stretches_along_a_row() {
+        let mut comp = Compositor::new(2048);
+        let color = [1.0, 1.0, 1.0, 1.0];
+        comp.draw_animated_cursor(
+            Rect::new(0., 0., 10., 20.),
+            Rect::new(100., 0., 10., 20.),
+            0.5,
+            &color,
+            CursorShape::Block,
+        );
+
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        let svg = list.to_svg();
+        // A single rect, its head ahead of the midpoint and its tail behind
+        assert_eq!(list.vertices().len(), 4);
+        assert!(svg.contains("<rect x=\"25\" y=\"0\" width=\"60\" height=\"20\""));
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Compute pass dropping the quads outside the viewport before they are
+//! drawn. It compacts the index buffer of a display list and writes the
+//! index count of each draw into its indirect arguments, so the draws that
+//! follow only process visible quads.
+
+use crate::components::rich_text::batch::{Draw, Rect};
+use std::borrow::Cow;
+
+/// Storage buffers bound by `cull.wgsl`.
+const STORAGE_BUFFERS: u32 = 5;
+
+pub struct GpuCulling {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    params: wgpu::Buffer,
+    draws: Option<wgpu::Buffer>,
+    culled: Option<wgpu::Buffer>,
+}
+
+impl GpuCulling {
+    /// Returns whether `device` runs compute shaders with enough storage
+    /// buffers for the pass, which downlevel devices like WebGL don't.
+    pub fn is_supported(device: &wgpu::Device) -> bool {
+        let limits = device.limits();
+        limits.max_storage_buffers_per_shader_stage >= STORAGE_BUFFERS
+            && limits.max_compute_invocations_per_workgroup >= 64
+            && limits.max_compute_workgroups_per_dimension > 0
+    }
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("rich_text::Culling"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    storage(1, true),
+                    storage(2, true),
+                    storage(3, true),
+                    storage(4, false),
+                    storage(5, false),
+                ],
+            });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("rich_text::Culling"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("rich_text::Culling"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("cull.wgsl"))),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("rich_text::Culling"),
+            layout: Some(&layout),
+            module: &shader,
+            entry_point: "cs_main",
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            cache: None,
+        });
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("rich_text::Culling params"),
+            size: std::mem::size_of::<[f32; 4]>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            pipeline,
+            bind_group_layout,
+            params,
+            draws: None,
+            culled: None,
+        }
+    }
+
+    /// Culls the quads of `draws` outside `bounds` into `culled`, returning
+    /// whether the pass ran. `vertices` and `indices` hold the display
+    /// list, and `args` the indirect arguments of every draw, whose index
+    /// counts are rewritten. The pass is submitted right away, so it runs
+    /// before the render pass drawing the list.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cull(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &wgpu::Buffer,
+        indices: &wgpu::Buffer,
+        args: &wgpu::Buffer,
+        draws: &[Draw],
+        bounds: Rect,
+    ) -> bool {
+        if draws.is_empty() {
+            return false;
+        }
+        let ranges = draw_ranges(draws);
+        let ranges: &[u8] = bytemuck::cast_slice(&ranges);
+        let draws_buffer = grow(
+            device,
+            &mut self.draws,
+            ranges.len() as u64,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        );
+        queue.write_buffer(draws_buffer, 0, ranges);
+        let culled = grow(
+            device,
+            &mut self.culled,
+            indices.size(),
+            wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDEX
+                | wgpu::BufferUsages::COPY_SRC,
+        );
+        let params = [
+            bounds.x,
+            bounds.y,
+            bounds.x + bounds.width,
+            bounds.y + bounds.height,
+        ];
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&params));
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("rich_text::Culling"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                self.params.as_entire_binding(),
+                vertices.as_entire_binding(),
+                indices.as_entire_binding(),
+                draws_buffer.as_entire_binding(),
+                args.as_entire_binding(),
+                culled.as_entire_binding(),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(binding, resource)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource,
+            })
+            .collect::<Vec<_>>(),
+        });
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("rich_text::Culling"),
+            });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("rich_text::Culling"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            // One workgroup per draw, which keeps its quads in order
+            pass.dispatch_workgroups(draws.len() as u32, 1, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+        true
+    }
+
+    /// Index buffer holding the quads kept by the last `cull`.
+    #[inline]
+    pub fn culled(&self) -> Option<&wgpu::Buffer> {
+        self.culled.as_ref()
+    }
+}
+
+/// Returns `buffer`, replaced with a larger one first when it can't hold
+/// `size` bytes.
+fn grow<'a>(
+    device: &wgpu::Device,
+    buffer: &'a mut Option<wgpu::Buffer>,
+    size: u64,
+    usage: wgpu::BufferUsages,
+) -> &'a wgpu::Buffer {
+    if buffer.as_ref().map_or(true, |buffer| buffer.size() < size) {
+        trace_event!(debug, "rich_text: culling buffer grew to {size} bytes");
+        *buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("rich_text::Culling"),
+            size: size.next_power_of_two().max(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage,
+            mapped_at_creation: false,
+        }));
+    }
+    buffer.as_ref().unwrap()
+}
+
+/// First index and index count of each draw, as read by `cull.wgsl`.
+fn draw_ranges(draws: &[Draw]) -> Vec<[u32; 2]> {
+    draws
+        .iter()
+        .map(|draw| [draw.indices.start, draw.indices.len() as u32])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::rich_text::batch::{Pipeline, Vertex};
+    use crate::context::Context;
+    use crate::sugarloaf::{SugarloafRenderer, SugarloafWindowSize};
+    use wgpu::util::DeviceExt;
+
+    const VERTEX_FLOATS: usize =
+        std::mem::size_of::<Vertex>() / std::mem::size_of::<f32>();
+
+    #[test]
+    fn test_cull_shader_is_valid() {
+        use wgpu::naga;
+
+        let module = naga::front::wgsl::parse_str(include_str!("cull.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cull_shader_matches_the_vertex_layout() {
+        let source = include_str!("cull.wgsl");
+        let floats = std::mem::size_of::<Vertex>() / std::mem::size_of::<f32>();
+        assert!(source.contains(&format!("const VERTEX_FLOATS: u32 = {floats}u;")));
+        let words = std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>() / 4;
+        assert!(source.contains(&format!("const ARGS_WORDS: u32 = {words}u;")));
+    }
+
+    // Copies `buffer` back from the GPU as words
+    fn read_words(context: &Context, buffer: &wgpu::Buffer) -> Vec<u32> {
+        let staging = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        context.queue.submit(Some(encoder.finish()));
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        context.device.poll(wgpu::Maintain::Wait);
+        let words = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        words
+    }
+
+    #[test]
+    fn test_cull_keeps_the_visible_quads_in_order() {
+        let context = futures::executor::block_on(Context::new_headless(
+            SugarloafWindowSize {
+                width: 16.,
+                height: 16.,
+            },
+            wgpu::TextureFormat::Rgba8Unorm,
+            SugarloafRenderer::default(),
+        ))
+        .expect("the GPU tests need a wgpu adapter, e.g. Mesa's llvmpipe");
+        assert!(GpuCulling::is_supported(&context.device));
+
+        // Three quads, the middle one right of the viewport
+        let mut vertices = vec![0f32; 12 * VERTEX_FLOATS];
+        for (quad, x) in [0., 20., 8.].into_iter().enumerate() {
+            for (corner, (dx, dy)) in [(0., 0.), (4., 0.), (4., 4.), (0., 4.)]
+                .into_iter()
+                .enumerate()
+            {
+                let vertex = (quad * 4 + corner) * VERTEX_FLOATS;
+                vertices[vertex] = x + dx;
+                vertices[vertex + 1] = dy;
+            }
+        }
+        let indices: Vec<u32> = (0..3)
+            .flat_map(|quad| [0, 1, 2, 2, 3, 0].map(|i| quad * 4 + i))
+            .collect();
+        let storage = |contents: &[u8], usage| {
+            context
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents,
+                    usage: wgpu::BufferUsages::STORAGE | usage,
+                })
+        };
+        let vertices =
+            storage(bytemuck::cast_slice(&vertices), wgpu::BufferUsages::empty());
+        let indices = storage(bytemuck::cast_slice(&indices), wgpu::BufferUsages::INDEX);
+        let args = storage(
+            bytemuck::cast_slice(&[18u32, 1, 0, 0, 0]),
+            wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_SRC,
+        );
+        let draw = Draw {
+            pipeline: Pipeline::Opaque,
+            vertices: 0..12,
+            indices: 0..18,
+            commands: 0..0,
+            mask: None,
+            image: None,
+        };
+
+        let mut culling = GpuCulling::new(&context.device);
+        assert!(culling.cull(
+            &context.device,
+            &context.queue,
+            &vertices,
+            &indices,
+            &args,
+            &[draw],
+            Rect::new(0., 0., 16., 16.),
+        ));
+        assert_eq!(read_words(&context, &args), [12, 1, 0, 0, 0]);
+        let culled = read_words(&context, culling.culled().unwrap());
+        assert_eq!(culled[..12], [0, 1, 2, 2, 3, 0, 8, 9, 10, 10, 11, 8]);
+    }
+
+    #[test]
+    fn test_draw_ranges() {
+        let draw = |indices: std::ops::Range<u32>| Draw {
+            pipeline: Pipeline::Opaque,
+            vertices: 0..0,
+            indices,
+            commands: 0..0,
+            mask: None,
+            image: None,
+        };
+        assert_eq!(
+            draw_ranges(&[draw(0..12), draw(12..18)]),
+            [[0, 12], [12, 6]]
+        );
+    }
+}
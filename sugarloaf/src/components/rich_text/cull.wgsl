@@ -0,0 +1,90 @@
+// Compacts the quads of each draw that overlap the viewport into a new
+// index buffer and writes how many indices are left into the arguments of
+// its indirect draw. A workgroup walks the quads of one draw in order, so
+// the compacted quads keep the order they are blended in.
+
+struct Params {
+    // Left, top, right and bottom of the viewport, in vertex coordinates
+    bounds: vec4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Vertices as plain floats, `VERTEX_FLOATS` each, position first
+@group(0) @binding(1) var<storage, read> vertices: array<f32>;
+@group(0) @binding(2) var<storage, read> indices: array<u32>;
+// First index and index count of each draw
+@group(0) @binding(3) var<storage, read> draws: array<vec2<u32>>;
+// `DrawIndexedIndirectArgs` of each draw, `ARGS_WORDS` each
+@group(0) @binding(4) var<storage, read_write> args: array<u32>;
+@group(0) @binding(5) var<storage, read_write> culled: array<u32>;
+
+const LANES: u32 = 64u;
+const VERTEX_FLOATS: u32 = 10u;
+const ARGS_WORDS: u32 = 5u;
+const QUAD_INDICES: u32 = 6u;
+
+var<workgroup> offsets: array<u32, LANES>;
+var<workgroup> written: u32;
+
+fn visible(first: u32) -> bool {
+    var low = vec2<f32>(3.4e38);
+    var high = vec2<f32>(-3.4e38);
+    for (var i = 0u; i < QUAD_INDICES; i++) {
+        let vertex = indices[first + i] * VERTEX_FLOATS;
+        let position = vec2<f32>(vertices[vertex], vertices[vertex + 1u]);
+        low = min(low, position);
+        high = max(high, position);
+    }
+    return high.x > params.bounds.x && low.x < params.bounds.z
+        && high.y > params.bounds.y && low.y < params.bounds.w;
+}
+
+@compute @workgroup_size(64)
+fn cs_main(
+    @builtin(workgroup_id) group: vec3<u32>,
+    @builtin(local_invocation_index) lane: u32,
+) {
+    let draw = draws[group.x];
+    let quads = draw.y / QUAD_INDICES;
+    if lane == 0u {
+        written = 0u;
+    }
+    workgroupBarrier();
+
+    let chunks = (quads + LANES - 1u) / LANES;
+    for (var chunk = 0u; chunk < chunks; chunk++) {
+        let quad = chunk * LANES + lane;
+        let first = draw.x + quad * QUAD_INDICES;
+        let keep = quad < quads && visible(first);
+        offsets[lane] = select(0u, 1u, keep);
+        workgroupBarrier();
+
+        // Inclusive prefix sum of the quads kept in the chunk
+        for (var step = 1u; step < LANES; step *= 2u) {
+            var sum = offsets[lane];
+            if lane >= step {
+                sum += offsets[lane - step];
+            }
+            workgroupBarrier();
+            offsets[lane] = sum;
+            workgroupBarrier();
+        }
+
+        let base = written;
+        if keep {
+            let slot = draw.x + (base + offsets[lane] - 1u) * QUAD_INDICES;
+            for (var i = 0u; i < QUAD_INDICES; i++) {
+                culled[slot + i] = indices[first + i];
+            }
+        }
+        workgroupBarrier();
+        if lane == LANES - 1u {
+            written = base + offsets[lane];
+        }
+        workgroupBarrier();
+    }
+
+    if lane == 0u {
+        args[group.x * ARGS_WORDS] = written * QUAD_INDICES;
+    }
+}
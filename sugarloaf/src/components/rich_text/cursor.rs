@@ -0,0 +1,122 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Cursor blink timing, so every host blinks with the same phase.
+
+use std::time::{Duration, Instant};
+
+/// Blink phase of a cursor. The cursor is visible for `interval`, hidden for
+/// the next `interval`, and so on from the last reset.
+///
+/// Times are passed in rather than read from the clock, which keeps the
+/// phase consistent across everything drawn in a frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CursorBlink {
+    /// How long the cursor stays visible, then hidden. Zero never hides it.
+    pub interval: Duration,
+    /// Restarts the blink with the cursor visible whenever it moves, so it
+    /// doesn't disappear while typing.
+    pub reset_on_move: bool,
+    start: Instant,
+}
+
+impl CursorBlink {
+    /// Interval used by most terminals and toolkits.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Starts blinking at `now` with the cursor visible.
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            reset_on_move: true,
+            start: now,
+        }
+    }
+
+    /// Restarts the blink at `now` with the cursor visible.
+    #[inline]
+    pub fn reset(&mut self, now: Instant) {
+        self.start = now;
+    }
+
+    /// Tells the blink the cursor moved at `now`, restarting it if
+    /// `reset_on_move` is set.
+    #[inline]
+    pub fn cursor_moved(&mut self, now: Instant) {
+        if self.reset_on_move {
+            self.reset(now);
+        }
+    }
+
+    /// Returns whether the cursor should be drawn at `now`.
+    pub fn is_visible(&self, now: Instant) -> bool {
+        self.phases(now) % 2 == 0
+    }
+
+    /// Returns when the cursor next shows or hides after `now`, for hosts
+    /// scheduling the next redraw. `None` when the cursor never hides.
+    pub fn next_change(&self, now: Instant) -> Option<Instant> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        let phases = self.phases(now) + 1;
+        Some(self.start + self.interval * phases)
+    }
+
+    /// Number of whole intervals elapsed between the last reset and `now`.
+    #[inline]
+    fn phases(&self, now: Instant) -> u32 {
+        if self.interval.is_zero() {
+            return 0;
+        }
+        let elapsed = now.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.interval.as_nanos()).min(u32::MAX as u128) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn test_blink_phase() {
+        let start = Instant::now();
+        let blink = CursorBlink::new(INTERVAL, start);
+        assert!(blink.is_visible(start));
+        assert!(blink.is_visible(start + Duration::from_millis(499)));
+        assert!(!blink.is_visible(start + INTERVAL));
+        assert!(blink.is_visible(start + INTERVAL * 2));
+        assert_eq!(
+            blink.next_change(start + Duration::from_millis(700)),
+            Some(start + INTERVAL * 2)
+        );
+    }
+
+    #[test]
+    fn test_moving_resets_the_blink() {
+        let start = Instant::now();
+        let hidden = start + Duration::from_millis(600);
+        let mut blink = CursorBlink::new(INTERVAL, start);
+        assert!(!blink.is_visible(hidden));
+        blink.cursor_moved(hidden);
+        assert!(blink.is_visible(hidden));
+        assert!(blink.is_visible(hidden + Duration::from_millis(499)));
+
+        blink.reset_on_move = false;
+        let hidden = hidden + INTERVAL;
+        blink.cursor_moved(hidden);
+        assert!(!blink.is_visible(hidden));
+    }
+
+    #[test]
+    fn test_zero_interval_never_hides() {
+        let start = Instant::now();
+        let blink = CursorBlink::new(Duration::ZERO, start);
+        assert!(blink.is_visible(start + Duration::from_secs(3)));
+        assert_eq!(blink.next_change(start), None);
+    }
+}
@@ -6,6 +6,8 @@ pub struct AtlasAllocator {
     lines: Vec<Line>,
     slots: Vec<Slot>,
     free_slot: u32,
+    /// Area of the free slots of fragmented shelves, see `fragmented_area`.
+    fragmented: u64,
 }
 
 impl AtlasAllocator {
@@ -18,6 +20,7 @@ impl AtlasAllocator {
             lines: Vec::new(),
             slots: Vec::new(),
             free_slot: !0,
+            fragmented: 0,
         }
     }
 
@@ -26,6 +29,13 @@ impl AtlasAllocator {
         self.width
     }
 
+    /// Returns the area of the slots freed in the middle of shelves, which
+    /// only fit images as tall as the shelf and as wide as the slot.
+    #[inline]
+    pub fn fragmented_area(&self) -> u64 {
+        self.fragmented
+    }
+
     /// Returns the height of the atlas.
     // pub fn height(&self) -> u16 {
     // self.height
@@ -84,6 +94,7 @@ impl AtlasAllocator {
                 let x = slot.x;
                 let end = (x as u32 + padded_width as u32).min(self.width as u32);
                 let slot_end = slot.end();
+                self.fragmented -= (end - x as u32) as u64 * line.height as u64;
                 if end != slot_end {
                     slot.x = end as u16;
                     slot.width = (slot_end - end) as u16;
@@ -141,6 +152,7 @@ impl AtlasAllocator {
             // additional slot for the remaining space if any.
             let slot_index = self.allocate_slot(x, actual_width)?;
             let remaining = self.width() - offset as u16;
+            self.fragmented += (actual_width + remaining) as u64 * line.height as u64;
             if remaining != 0 {
                 let remaining_slot_index =
                     self.allocate_slot(offset as u16, remaining)?;
@@ -203,8 +215,11 @@ impl AtlasAllocator {
                     }
                 }
             }
+            self.fragmented += actual_width as u64 * line.height as u64;
             let first = self.slots[first_index as usize];
             if first.next == !0 && first.end() == self.width as u32 {
+                // The whole tail of the shelf is free again
+                self.fragmented -= first.width as u64 * line.height as u64;
                 self.free_slot(first_index);
                 self.lines[line_index].state = first.x as u32;
             }
@@ -1,5 +1,7 @@
 use super::atlas::*;
 use super::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(Default)]
 pub struct ImageCache {
@@ -10,14 +12,32 @@ pub struct ImageCache {
     events: Vec<Event>,
     free_entries: u32,
     free_images: u32,
+    /// Size of every atlas, doubled up to `max_texture_size` when they fill
+    /// up.
+    atlas_size: u16,
     max_texture_size: u16,
+    /// Set when the atlases grew, see `take_resized`.
+    resized: bool,
+    /// Frames of animated images, keyed by the id of their first frame.
+    animations: HashMap<ImageId, Animation>,
+    /// Area of the live atlas images, see `fragmentation`.
+    atlas_area: u64,
 }
 
 impl ImageCache {
     /// Creates a new image cache.
     #[inline]
+    #[allow(unused)]
     pub fn new(max_texture_size: u16) -> Self {
-        let max_texture_size = max_texture_size.clamp(1024, 4096);
+        Self::with_sizes(max_texture_size, max_texture_size)
+    }
+
+    /// Creates a new image cache whose atlases start at `initial_size` and
+    /// grow toward `max_texture_size` as they fill up, instead of being
+    /// allocated at full size up front.
+    pub fn with_sizes(initial_size: u16, max_texture_size: u16) -> Self {
+        let max_texture_size = max_texture_size.clamp(MIN_ATLAS_SIZE, 4096);
+        let atlas_size = initial_size.clamp(MIN_ATLAS_SIZE, max_texture_size);
         Self {
             entries: Vec::new(),
             atlases: Vec::new(),
@@ -26,7 +46,11 @@ impl ImageCache {
             events: Vec::new(),
             free_entries: END_OF_LIST,
             free_images: END_OF_LIST,
+            atlas_size,
             max_texture_size,
+            resized: false,
+            animations: HashMap::new(),
+            atlas_area: 0,
         }
     }
 
@@ -60,31 +84,39 @@ impl ImageCache {
             return ImageId::new(entry.generation, entry_index as u32, has_alpha);
         }
         let mut atlas_data = self.alloc_from_atlases(format, width, height);
-        if atlas_data.is_none() {
+        // Growing repacks what is already there, so it is tried before
+        // adding another atlas
+        while atlas_data.is_none()
+            && self.atlases.iter().any(|atlas| atlas.format == format)
+            && self.grow()
+        {
             atlas_data = self.alloc_from_atlases(format, width, height);
         }
         if atlas_data.is_none() {
-            let dim = self.max_texture_size;
-            let atlas_index = self.atlases.len();
-            if atlas_index >= MAX_ATLASES as usize {
-                return None;
-            }
-            let mut alloc = AtlasAllocator::new(dim, dim);
-            if let Some((x, y)) = alloc.allocate(width, height) {
-                let buffer = vec![0u8; dim as usize * dim as usize * 4];
-                let texture_id = TextureId::allocate();
-                self.atlases.push(Atlas {
-                    format,
-                    alloc,
-                    buffer,
-                    fresh: true,
-                    dirty: true,
-                    texture_id,
-                });
-                atlas_data = Some((atlas_index, x, y));
-            } else {
+            if self.atlases.len() >= MAX_ATLASES as usize {
                 return None;
             }
+            // Grow until the image fits a fresh atlas
+            let (alloc, x, y) = loop {
+                let dim = self.atlas_size;
+                let mut alloc = AtlasAllocator::new(dim, dim);
+                if let Some((x, y)) = alloc.allocate(width, height) {
+                    break (alloc, x, y);
+                }
+                if !self.grow() {
+                    return None;
+                }
+            };
+            let dim = self.atlas_size as usize;
+            self.atlases.push(Atlas {
+                format,
+                alloc,
+                buffer: vec![0u8; dim * dim * 4],
+                fresh: true,
+                dirty: true,
+                texture_id: TextureId::allocate(),
+            });
+            atlas_data = Some((self.atlases.len() - 1, x, y));
         }
         let (atlas_index, x, y) = atlas_data?;
         let entry_index = self.alloc_entry()?;
@@ -96,6 +128,7 @@ impl ImageCache {
         entry.y = y;
         entry.width = width;
         entry.height = height;
+        self.atlas_area += width as u64 * height as u64;
         if let Some(data) = request.data() {
             let atlas = self.atlases.get_mut(atlas_index)?;
             fill(
@@ -104,7 +137,7 @@ impl ImageCache {
                 width,
                 height,
                 data,
-                self.max_texture_size,
+                self.atlas_size,
                 &mut atlas.buffer,
                 4,
             );
@@ -116,8 +149,14 @@ impl ImageCache {
     // Evaluate if does make sense to deallocate from atlas and if yes, which case?
     // considering that a terminal uses a short/limited of glyphs compared to a wide text editor
     // if deallocate an image then is necessary to cleanup cache of draw_layout fn
-    /// Deallocates the specified image.
+    /// Deallocates the specified image, with all its frames when it is
+    /// animated.
     pub fn deallocate(&mut self, image: ImageId) -> Option<()> {
+        if let Some(animation) = self.animations.remove(&image) {
+            for (frame, _) in animation.frames.into_iter().skip(1) {
+                self.deallocate(frame);
+            }
+        }
         let entry = self.entries.get_mut(image.index())?;
         if entry.flags & ENTRY_ALLOCATED == 0 || entry.generation != image.generation() {
             return None;
@@ -131,6 +170,7 @@ impl ImageCache {
         } else {
             let atlas = self.atlases.get_mut(entry.owner as usize)?;
             atlas.alloc.deallocate(entry.x, entry.y, entry.width);
+            self.atlas_area -= entry.width as u64 * entry.height as u64;
         }
         entry.flags = 0;
         self.free_entries = image.index() as u32;
@@ -138,7 +178,12 @@ impl ImageCache {
     }
 
     /// Retrieves the image for the specified handle and updates the epoch.
+    /// Animated images resolve to their current frame.
     pub fn get(&mut self, handle: ImageId) -> Option<ImageLocation> {
+        let handle = self
+            .animations
+            .get(&handle)
+            .map_or(handle, |animation| animation.frames[animation.current].0);
         let entry = self.entries.get_mut(handle.index())?;
         if entry.flags & ENTRY_ALLOCATED == 0 || entry.generation != handle.generation() {
             return None;
@@ -154,7 +199,7 @@ impl ImageCache {
         } else {
             let atlas = self.atlases.get(entry.owner as usize)?;
             let texture_id = atlas.texture_id;
-            let s = 1. / self.max_texture_size as f32;
+            let s = 1. / self.atlas_size as f32;
             ImageLocation {
                 texture_id,
                 min: (entry.x as f32 * s, entry.y as f32 * s),
@@ -166,6 +211,101 @@ impl ImageCache {
         })
     }
 
+    /// Allocates the frames of an animated image, each shown for its
+    /// duration before the next one, looping. Returns the id of the first
+    /// frame, which `get` resolves to the frame picked by
+    /// `advance_animation`.
+    pub fn allocate_animation(
+        &mut self,
+        frames: &[(AddImage, Duration)],
+    ) -> Option<ImageId> {
+        let mut allocated = Vec::with_capacity(frames.len());
+        for (request, duration) in frames {
+            match self.allocate(*request) {
+                Some(frame) => {
+                    allocated.push((frame, (*duration).max(MIN_FRAME_DURATION)))
+                }
+                None => {
+                    for (frame, _) in allocated {
+                        self.deallocate(frame);
+                    }
+                    return None;
+                }
+            }
+        }
+        let first = allocated.first()?.0;
+        if allocated.len() > 1 {
+            self.animations.insert(
+                first,
+                Animation {
+                    frames: allocated,
+                    current: 0,
+                    started: None,
+                    advanced: None,
+                },
+            );
+        }
+        Some(first)
+    }
+
+    /// Picks the frame of an animated image to show at `now`, the clock of
+    /// the animation starting at its first call. Returns whether the frame
+    /// changed, false for still images.
+    pub fn advance_animation(&mut self, image: ImageId, now: Instant) -> bool {
+        let Some(animation) = self.animations.get_mut(&image) else {
+            return false;
+        };
+        animation.advanced = Some(now);
+        let started = *animation.started.get_or_insert(now);
+        let total: Duration =
+            animation.frames.iter().map(|(_, duration)| *duration).sum();
+        let mut elapsed =
+            now.saturating_duration_since(started).as_nanos() % total.as_nanos();
+        let mut current = 0;
+        for (index, (_, duration)) in animation.frames.iter().enumerate() {
+            if elapsed < duration.as_nanos() {
+                current = index;
+                break;
+            }
+            elapsed -= duration.as_nanos();
+        }
+        let changed = current != animation.current;
+        animation.current = current;
+        changed
+    }
+
+    /// Frees every frame but the first of the animations not advanced for
+    /// `idle`, leaving still images under the same ids. Animations take a
+    /// lot of atlas space and the ones not on screen aren't advanced, so
+    /// they are dropped early. Returns how many were evicted.
+    pub fn evict_idle_animations(&mut self, now: Instant, idle: Duration) -> usize {
+        let mut evicted = Vec::new();
+        for (image, animation) in &mut self.animations {
+            // Animations never advanced start idling now
+            let advanced = *animation.advanced.get_or_insert(now);
+            if now.saturating_duration_since(advanced) >= idle {
+                evicted.push(*image);
+            }
+        }
+        for image in &evicted {
+            if let Some(animation) = self.animations.remove(image) {
+                for (frame, _) in animation.frames.into_iter().skip(1) {
+                    self.deallocate(frame);
+                }
+            }
+        }
+        evicted.len()
+    }
+
+    /// Returns the width and height in pixels of the specified image.
+    pub fn size(&self, image: ImageId) -> Option<(u16, u16)> {
+        let entry = self.entries.get(image.index())?;
+        if entry.flags & ENTRY_ALLOCATED == 0 || entry.generation != image.generation() {
+            return None;
+        }
+        Some((entry.width, entry.height))
+    }
+
     /// Returns true if the image is valid.
     pub fn is_valid(&self, image: ImageId) -> bool {
         if let Some(entry) = self.entries.get(image.index()) {
@@ -175,6 +315,155 @@ impl ImageCache {
         }
     }
 
+    /// Share of the atlas space, live images and slots freed between them,
+    /// that is free, from `0.0` to `1.0`. Freed slots only fit images no
+    /// larger than what they held, so a high ratio means large images may
+    /// no longer fit despite the free space. Both areas are kept up to date
+    /// as images come and go, so this is cheap enough to check every frame.
+    pub fn fragmentation(&self) -> f32 {
+        let fragmented: u64 = self
+            .atlases
+            .iter()
+            .map(|atlas| atlas.alloc.fragmented_area())
+            .sum();
+        if fragmented == 0 {
+            return 0.;
+        }
+        fragmented as f32 / (fragmented + self.atlas_area) as f32
+    }
+
+    /// Repacks the live atlas images into fresh atlases, tallest first, and
+    /// destroys the old ones. Image ids stay valid and resolve to their new
+    /// location; the new atlases are uploaded by the next `drain_events`.
+    /// Returns false, leaving the atlases untouched, when the images don't
+    /// fit.
+    pub fn defragment(&mut self) -> bool {
+        self.repack(self.atlas_size)
+    }
+
+    /// Doubles the size of the atlases, up to `max_texture_size`, moving the
+    /// images over like `defragment`. Returns false when they can't grow.
+    fn grow(&mut self) -> bool {
+        if self.atlas_size >= self.max_texture_size {
+            return false;
+        }
+        let size = self.atlas_size.saturating_mul(2).min(self.max_texture_size);
+        // With no atlas yet, the first one is simply created larger
+        if self.atlases.is_empty() {
+            self.atlas_size = size;
+            return true;
+        }
+        if !self.repack(size) {
+            return false;
+        }
+        trace_event!(debug, "rich_text: atlases grew to {size}");
+        self.resized = true;
+        true
+    }
+
+    /// Returns whether the atlases grew since the last call. Texture
+    /// coordinates of images drawn before are stale once they did.
+    pub fn take_resized(&mut self) -> bool {
+        std::mem::take(&mut self.resized)
+    }
+
+    /// Size of the atlases.
+    #[allow(unused)]
+    #[inline]
+    pub fn atlas_size(&self) -> u16 {
+        self.atlas_size
+    }
+
+    /// Moves the live atlas images into fresh `dim` sized atlases, see
+    /// `defragment`.
+    fn repack(&mut self, dim: u16) -> bool {
+        let mut live: Vec<usize> = (0..self.entries.len())
+            .filter(|&i| {
+                self.entries[i].flags & (ENTRY_ALLOCATED | ENTRY_STANDALONE)
+                    == ENTRY_ALLOCATED
+            })
+            .collect();
+        live.sort_by_key(|&i| {
+            let entry = &self.entries[i];
+            (
+                std::cmp::Reverse(entry.height),
+                std::cmp::Reverse(entry.width),
+            )
+        });
+
+        // Place everything before moving any pixel, so a failure leaves the
+        // cache as it was
+        let mut allocators: Vec<(PixelFormat, AtlasAllocator)> = Vec::new();
+        let mut placements = Vec::with_capacity(live.len());
+        for &i in &live {
+            let entry = &self.entries[i];
+            let format = self.atlases[entry.owner as usize].format;
+            let placed = allocators
+                .iter_mut()
+                .enumerate()
+                .find_map(|(owner, alloc)| {
+                    if alloc.0 != format {
+                        return None;
+                    }
+                    let (x, y) = alloc.1.allocate(entry.width, entry.height)?;
+                    Some((owner, x, y))
+                });
+            let placed = match placed {
+                Some(placed) => placed,
+                None => {
+                    if allocators.len() >= MAX_ATLASES as usize {
+                        return false;
+                    }
+                    let mut alloc = AtlasAllocator::new(dim, dim);
+                    let Some((x, y)) = alloc.allocate(entry.width, entry.height) else {
+                        return false;
+                    };
+                    allocators.push((format, alloc));
+                    (allocators.len() - 1, x, y)
+                }
+            };
+            placements.push(placed);
+        }
+
+        let mut atlases: Vec<Atlas> = allocators
+            .into_iter()
+            .map(|(format, alloc)| Atlas {
+                format,
+                alloc,
+                buffer: vec![0u8; dim as usize * dim as usize * 4],
+                fresh: true,
+                dirty: true,
+                texture_id: TextureId::allocate(),
+            })
+            .collect();
+        let source_pitch = self.atlas_size as usize * 4;
+        let pitch = dim as usize * 4;
+        for (&i, (owner, x, y)) in live.iter().zip(placements) {
+            let entry = &mut self.entries[i];
+            let source = &self.atlases[entry.owner as usize].buffer;
+            let target = &mut atlases[owner].buffer;
+            let row = entry.width as usize * 4;
+            for line in 0..entry.height as usize {
+                let from =
+                    (entry.y as usize + line) * source_pitch + entry.x as usize * 4;
+                let to = (y as usize + line) * pitch + x as usize * 4;
+                target[to..to + row].copy_from_slice(&source[from..from + row]);
+            }
+            entry.owner = owner as u16;
+            entry.x = x;
+            entry.y = y;
+        }
+
+        for atlas in std::mem::replace(&mut self.atlases, atlases) {
+            // Never uploaded, so there is no texture to destroy yet
+            if !atlas.fresh {
+                self.events.push(Event::DestroyTexture(atlas.texture_id));
+            }
+        }
+        self.atlas_size = dim;
+        true
+    }
+
     /// Updates an image with the specified data.
     // pub fn update(&mut self, handle: ImageId, data: &[u8]) -> Option<()> {
     //     let entry = self.entries.get_mut(handle.index())?;
@@ -252,8 +541,8 @@ impl ImageCache {
                 f(TextureEvent::CreateTexture {
                     id: atlas.texture_id,
                     format: atlas.format,
-                    width: self.max_texture_size,
-                    height: self.max_texture_size,
+                    width: self.atlas_size,
+                    height: self.atlas_size,
                     data: Some(&atlas.buffer),
                 });
             } else {
@@ -262,8 +551,8 @@ impl ImageCache {
                     format: atlas.format,
                     x: 0,
                     y: 0,
-                    width: self.max_texture_size,
-                    height: self.max_texture_size,
+                    width: self.atlas_size,
+                    height: self.atlas_size,
                     data: &atlas.buffer,
                 })
             }
@@ -402,6 +691,13 @@ struct Atlas {
     texture_id: TextureId,
 }
 
+struct Animation {
+    frames: Vec<(ImageId, Duration)>,
+    current: usize,
+    started: Option<Instant>,
+    advanced: Option<Instant>,
+}
+
 struct Standalone {
     texture_id: TextureId,
     used: bool,
@@ -477,3 +773,136 @@ fn fill(
     }
     Some(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate(cache: &mut ImageCache, size: u16, value: u8) -> ImageId {
+        let data = vec![value; size as usize * size as usize * 4];
+        cache
+            .allocate(AddImage {
+                format: PixelFormat::Rgba8,
+                width: size,
+                height: size,
+                has_alpha: true,
+                evictable: true,
+                data: ImageData::Borrowed(&data),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_atlas_grows_before_adding_atlases() {
+        let mut cache = ImageCache::with_sizes(256, 1024);
+        assert_eq!(cache.atlas_size(), 256);
+        // Four 100px images fill a 256px atlas
+        let images: Vec<_> = (0..5u8)
+            .map(|i| (allocate(&mut cache, 100, i), i))
+            .collect();
+        assert_eq!(cache.atlas_size(), 512);
+        assert_eq!(cache.atlases.len(), 1);
+        assert!(cache.take_resized());
+        assert!(!cache.take_resized());
+
+        for (image, value) in &images {
+            let location = cache.get(*image).unwrap();
+            let entry = &cache.entries[image.index()];
+            let row = (entry.y as usize * 512 + entry.x as usize) * 4;
+            let buffer = &cache.atlases[0].buffer;
+            assert!(buffer[row..row + 100 * 4].iter().all(|v| v == value));
+            assert_eq!(location.min.0 * 512., entry.x as f32);
+        }
+        let mut sizes = vec![];
+        cache.drain_events(|event| {
+            if let TextureEvent::CreateTexture { width, height, .. } = event {
+                sizes.push((width, height));
+            }
+        });
+        assert_eq!(sizes, vec![(512, 512)]);
+
+        // Past the maximum size, another atlas is added
+        for i in 0..100 {
+            allocate(&mut cache, 100, i);
+        }
+        assert_eq!(cache.atlas_size(), 1024);
+        assert_eq!(cache.atlases.len(), 2);
+    }
+
+    #[test]
+    fn test_fragmentation_follows_allocations() {
+        let mut cache = ImageCache::new(1024);
+        // One shelf of four images, 17px each with their padding
+        let images: Vec<_> = (0..4u8).map(|i| allocate(&mut cache, 16, i)).collect();
+        let live = 16. * 16.;
+        assert_eq!(cache.fragmentation(), 0.);
+
+        // Freeing in the middle leaves the slot and the tail of the shelf
+        cache.deallocate(images[1]).unwrap();
+        let free = (17. + 956.) * 17.;
+        assert_eq!(cache.fragmentation(), free / (free + live * 3.));
+
+        // The freed slot is reused
+        let image = allocate(&mut cache, 16, 4);
+        let free = 956. * 17.;
+        assert_eq!(cache.fragmentation(), free / (free + live * 4.));
+
+        // A shelf freed up to its end is no longer fragmented
+        for image in [images[0], image, images[2], images[3]] {
+            cache.deallocate(image).unwrap();
+        }
+        assert_eq!(cache.fragmentation(), 0.);
+        assert_eq!(cache.atlas_area, 0);
+    }
+
+    #[test]
+    fn test_defragment_keeps_live_images() {
+        let mut cache = ImageCache::new(1024);
+        let images: Vec<_> = (0..128u8)
+            .map(|i| (allocate(&mut cache, 16, i), i))
+            .collect();
+        cache.drain_events(|_| {});
+        let old_texture = cache.get(images[0].0).unwrap().texture_id;
+
+        for (image, value) in &images {
+            if value % 2 == 0 {
+                cache.deallocate(*image).unwrap();
+            }
+        }
+        assert!(cache.fragmentation() > 0.4);
+
+        assert!(cache.defragment());
+        assert_eq!(cache.fragmentation(), 0.);
+        let s = cache.atlas_size as f32;
+        for (image, value) in images.iter().filter(|(_, value)| value % 2 == 1) {
+            let location = cache.get(*image).unwrap();
+            assert_ne!(location.texture_id, old_texture);
+            assert_eq!(cache.size(*image), Some((16, 16)));
+            // Every pixel of the image moved along with it
+            let entry = &cache.entries[image.index()];
+            let atlas = &cache.atlases[entry.owner as usize];
+            assert_eq!(atlas.texture_id, location.texture_id);
+            assert_eq!(
+                (entry.x, entry.y),
+                ((location.min.0 * s) as u16, (location.min.1 * s) as u16)
+            );
+            for y in entry.y..entry.y + 16 {
+                let row = (y as usize * 1024 + entry.x as usize) * 4;
+                assert!(atlas.buffer[row..row + 16 * 4].iter().all(|v| v == value));
+            }
+        }
+
+        let mut destroyed = vec![];
+        let mut created = vec![];
+        cache.drain_events(|event| match event {
+            TextureEvent::DestroyTexture(id) => destroyed.push(id),
+            TextureEvent::CreateTexture { id, data, .. } => {
+                assert!(data.is_some());
+                created.push(id);
+            }
+            TextureEvent::UpdateTexture { .. } => {}
+        });
+        assert_eq!(destroyed, vec![old_texture]);
+        assert_eq!(created, vec![cache.get(images[1].0).unwrap().texture_id]);
+    }
+}
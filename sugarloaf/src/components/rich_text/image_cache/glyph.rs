@@ -1,64 +1,253 @@
 use super::cache::ImageCache;
-use super::PixelFormat;
+use super::{premultiply, PixelFormat};
 use super::{AddImage, ImageData, ImageId, ImageLocation};
+use crate::components::rich_text::text::{Hinting, Presentation};
 use core::borrow::Borrow;
 use core::hash::{Hash, Hasher};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use swash::scale::{
     image::{Content, Image as GlyphImage},
     *,
 };
 use swash::zeno::{Format, Vector};
-use swash::FontRef;
+use swash::{FontRef, GlyphMetrics, Metrics};
 
 // const IS_MACOS: bool = cfg!(target_os = "macos");
 
-const SOURCES: &[Source] = &[
+const SOURCES: [Source; 4] = [
     Source::ColorOutline(0),
     Source::ColorBitmap(StrikeWith::BestFit),
-    // Source::Bitmap(StrikeWith::ExactSize),
+    Source::Bitmap(StrikeWith::ExactSize),
     Source::Outline,
 ];
 
+/// Monochrome sources first, for text presentation.
+const TEXT_SOURCES: [Source; 4] = [
+    Source::Bitmap(StrikeWith::ExactSize),
+    Source::Outline,
+    Source::ColorOutline(0),
+    Source::ColorBitmap(StrikeWith::BestFit),
+];
+
+/// Embedded bitmap strike a glyph was taken from, see `GlyphEntry::strike`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Strike {
+    /// Index of the strike among the color or alpha strikes of the font.
+    pub index: u32,
+    /// Size the strike was drawn for, in pixels per em.
+    pub ppem: u16,
+    /// From the color strikes (`CBDT`, `sbix`) rather than the alpha ones.
+    pub color: bool,
+}
+
+/// Size buckets per pixel of font size. Glyphs are rasterized at the exact
+/// size of their session but cached per bucket, so fractional sizes are
+/// drawn as asked while a smooth zoom adds at most two sets of glyphs to
+/// the atlas per pixel it goes through. Sizes sharing a bucket reuse the
+/// glyphs of whichever was drawn first, up to a quarter pixel off.
+const SIZE_BUCKETS_PER_PX: f32 = 2.;
+
+/// Returns the bucket glyphs of `size` are cached under.
+#[inline]
+fn size_bucket(size: f32) -> u16 {
+    (size * SIZE_BUCKETS_PER_PX)
+        .round()
+        .clamp(1., u16::MAX as f32) as u16
+}
+
+/// FreeType's default stem darkening curve for CFF fonts, as stem widths
+/// against how much they are widened, both in pixels.
+const STEM_DARKENING: [(f32, f32); 4] =
+    [(0.5, 0.4), (1.0, 0.275), (1.667, 0.275), (2.333, 0.)];
+
+/// Width of the stems darkening is computed for, in ems. A tenth of the em
+/// is typical of regular weights.
+const STEM_WIDTH: f32 = 0.1;
+
+/// Returns how many pixels the outlines of glyphs `size` pixels per em are
+/// widened by, scaled by `intensity`. Stems under half a pixel get the most
+/// and darkening fades out past 23 pixels per em.
+fn stem_darkening(size: f32, intensity: f32) -> f32 {
+    let stem = size * STEM_WIDTH;
+    let amount = match STEM_DARKENING.iter().position(|(width, _)| stem < *width) {
+        Some(0) => STEM_DARKENING[0].1,
+        Some(index) => {
+            let (x0, y0) = STEM_DARKENING[index - 1];
+            let (x1, y1) = STEM_DARKENING[index];
+            y0 + (y1 - y0) * (stem - x0) / (x1 - x0)
+        }
+        None => 0.,
+    };
+    amount * intensity.max(0.)
+}
+
+/// Glyph cache activity, see `GlyphCache::frame_stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GlyphCacheStats {
+    /// Glyphs drawn from the cache.
+    pub hits: u64,
+    /// Glyphs that had to be rasterized, whether drawn or warmed ahead.
+    pub misses: u64,
+    /// Glyphs added to the atlas. Falls behind `misses` when the atlas is
+    /// out of room.
+    pub insertions: u64,
+}
+
+impl GlyphCacheStats {
+    /// Share of lookups that missed the cache, zero without lookups.
+    pub fn miss_rate(&self) -> f32 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.;
+        }
+        self.misses as f32 / lookups as f32
+    }
+}
+
+impl std::ops::Add for GlyphCacheStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            insertions: self.insertions + other.insertions,
+        }
+    }
+}
+
 pub struct GlyphCache {
     scx: ScaleContext,
-    fonts: HashMap<FontKey, FontEntry>,
+    /// Builds the scalers of `GlyphCacheSession::get_sized`, apart from
+    /// `scx` which the scaler of the session holds on to.
+    sized_scx: ScaleContext,
+    /// Entries are never removed, so the slots handed out by `font_slot`
+    /// stay valid.
+    fonts: Vec<FontEntry>,
+    slots: HashMap<FontKey, usize>,
     img: GlyphImage,
+    frame: GlyphCacheStats,
+    /// Activity of the frames before the current one.
+    previous: GlyphCacheStats,
+    /// Intensity outlines are darkened with, see `set_stem_darkening`.
+    stem_darkening: Option<f32>,
 }
 
 impl GlyphCache {
     pub fn new() -> Self {
         GlyphCache {
             scx: ScaleContext::new(),
-            fonts: HashMap::default(),
+            sized_scx: ScaleContext::new(),
+            fonts: Vec::new(),
+            slots: HashMap::default(),
             img: GlyphImage::new(),
+            frame: GlyphCacheStats::default(),
+            previous: GlyphCacheStats::default(),
+            stem_darkening: None,
         }
     }
 
+    /// Widens the outlines of glyphs at small sizes, more the smaller they
+    /// are, like FreeType's stem darkening, so thin fonts don't look faded
+    /// without subpixel rendering. `intensity` scales the widening, 1.0
+    /// matches FreeType. Glyphs cached so far are removed from `images`
+    /// when the setting changes, so they are rasterized again. Bitmaps and
+    /// color glyphs are left as they are. Disabled by default.
+    pub fn set_stem_darkening(
+        &mut self,
+        images: &mut ImageCache,
+        enabled: bool,
+        intensity: f32,
+    ) {
+        let stem_darkening = enabled.then_some(intensity);
+        if stem_darkening == self.stem_darkening {
+            return;
+        }
+        self.stem_darkening = stem_darkening;
+        for font in &mut self.fonts {
+            for (_, glyph) in font.glyphs.drain() {
+                images.deallocate(glyph.image);
+            }
+        }
+    }
+
+    /// Starts counting the activity of a new frame.
+    pub fn begin_frame(&mut self) {
+        self.previous = self.previous + self.frame;
+        self.frame = GlyphCacheStats::default();
+    }
+
+    /// Activity since the last `begin_frame`.
+    #[inline]
+    pub fn frame_stats(&self) -> GlyphCacheStats {
+        self.frame
+    }
+
+    /// Activity since the cache was created.
+    #[inline]
+    pub fn total_stats(&self) -> GlyphCacheStats {
+        self.previous + self.frame
+    }
+
+    /// Opens a session rasterizing glyphs of `font` at `size`. Glyphs are
+    /// cached apart for each presentation and hinting mode.
     pub fn session<'a>(
         &'a mut self,
         images: &'a mut ImageCache,
         font: FontRef<'a>,
         coords: &[i16],
         size: f32,
+        presentation: Presentation,
+        hinting: Hinting,
+    ) -> GlyphCacheSession<'a> {
+        let slot = self.font_slot(font.key.value(), coords);
+        self.session_with_slot(images, font, slot, size, presentation, hinting)
+    }
+
+    /// Returns the slot holding the glyphs of font `id` with `coords`, to
+    /// open sessions with `session_with_slot` without looking it up again.
+    pub fn font_slot(&mut self, id: u64, coords: &[i16]) -> usize {
+        if let Some(slot) = self.slots.get(&(id, Coords::Ref(coords))) {
+            return *slot;
+        }
+        let key = FontKey {
+            key: (id, Coords::new(coords)),
+        };
+        self.fonts.push(FontEntry::default());
+        self.slots.insert(key, self.fonts.len() - 1);
+        self.fonts.len() - 1
+    }
+
+    /// Same as `session` for a slot returned by `font_slot` for `font`.
+    pub fn session_with_slot<'a>(
+        &'a mut self,
+        images: &'a mut ImageCache,
+        font: FontRef<'a>,
+        slot: usize,
+        size: f32,
+        presentation: Presentation,
+        hinting: Hinting,
     ) -> GlyphCacheSession<'a> {
-        // let quant_size = (size * 32.) as u16;
-        let quant_size = size as u16;
-        let entry = get_entry(&mut self.fonts, font.key.value(), coords);
-        let scaler = self
-            .scx
-            .builder(font)
-            // .hint(!IS_MACOS)
-            .hint(true)
-            .size(size)
-            // .normalized_coords(coords)
-            .build();
+        let quant_size = size_bucket(size);
+        let entry = &mut self.fonts[slot];
+        let metrics = *entry.metrics.get_or_insert_with(|| font.metrics(&[]));
+        let scaler = build_scaler(&mut self.scx, font, size, hinting);
         GlyphCacheSession {
             entry,
             images,
             scaler,
+            sized_scx: &mut self.sized_scx,
             scaled_image: &mut self.img,
+            metrics: metrics.scale(size),
+            glyph_metrics: font.glyph_metrics(&[]).scale(size),
+            stats: &mut self.frame,
             quant_size,
+            font,
+            size,
+            presentation,
+            hinting,
+            stem_darkening: self.stem_darkening,
         }
     }
 
@@ -80,38 +269,42 @@ impl GlyphCache {
 
     #[allow(unused)]
     pub fn clear_evicted(&mut self, images: &mut ImageCache) {
-        self.fonts.retain(|_, entry| {
+        for entry in &mut self.fonts {
             entry.glyphs.retain(|_, g| images.is_valid(g.image));
-            !entry.glyphs.is_empty()
-        });
-    }
-}
-
-fn get_entry<'a>(
-    fonts: &'a mut HashMap<FontKey, FontEntry>,
-    id: u64,
-    coords: &[i16],
-) -> &'a mut FontEntry {
-    let key = (id, Coords::Ref(coords));
-    if let Some(entry) = fonts.get_mut(&key) {
-        // Remove this unsafe when Rust learns that early returns should not
-        // hold a borrow until the end of the function, or HashMap gets an
-        // entry API that accepts borrowed keys (in which case, the double
-        // lookup here can be removed altogether)
-        return unsafe { core::mem::transmute::<&mut FontEntry, &mut FontEntry>(entry) };
-    }
-    let key = FontKey {
-        key: (id, Coords::new(coords)),
-    };
-    fonts.entry(key).or_default()
+        }
+    }
+}
+
+fn build_scaler<'a>(
+    scx: &'a mut ScaleContext,
+    font: FontRef<'a>,
+    size: f32,
+    hinting: Hinting,
+) -> Scaler<'a> {
+    scx.builder(font)
+        // .hint(!IS_MACOS)
+        .hint(hinting != Hinting::None)
+        .size(size)
+        // .normalized_coords(coords)
+        .build()
 }
 
 pub struct GlyphCacheSession<'a> {
     entry: &'a mut FontEntry,
     images: &'a mut ImageCache,
     scaler: Scaler<'a>,
+    sized_scx: &'a mut ScaleContext,
     scaled_image: &'a mut GlyphImage,
+    /// Metrics of the font at the size of the session.
+    metrics: Metrics,
+    glyph_metrics: GlyphMetrics<'a>,
+    stats: &'a mut GlyphCacheStats,
     quant_size: u16,
+    font: FontRef<'a>,
+    size: f32,
+    presentation: Presentation,
+    hinting: Hinting,
+    stem_darkening: Option<f32>,
 }
 
 impl<'a> GlyphCacheSession<'a> {
@@ -119,62 +312,384 @@ impl<'a> GlyphCacheSession<'a> {
         self.images.get(image)
     }
 
+    /// Returns the horizontal kerning in pixels between `left` and `right`
+    /// from the font's `kern` table, or zero when there is none.
+    pub fn kerning(&self, left: u16, right: u16) -> f32 {
+        let Some(value) = self
+            .font
+            .table(swash::tag_from_bytes(b"kern"))
+            .and_then(|kern| kern_pair(kern, left, right))
+        else {
+            return 0.;
+        };
+        let units_per_em = self.metrics.units_per_em;
+        if units_per_em == 0 {
+            return 0.;
+        }
+        value as f32 * self.size / units_per_em as f32
+    }
+
+    /// Returns the offset of the font's underline above the baseline,
+    /// negative below it, and its thickness, in pixels.
+    pub fn underline_metrics(&self) -> (f32, f32) {
+        (self.metrics.underline_offset, self.metrics.stroke_size)
+    }
+
+    /// Returns the horizontal advance of `id` in pixels.
+    pub fn advance(&self, id: u16) -> f32 {
+        self.glyph_metrics.advance_width(id)
+    }
+
+    /// Returns the vertical advance of `id` in pixels, the distance from
+    /// the top of its em box to its baseline, and its horizontal advance.
+    /// Fonts without vertical metrics get synthesized ones.
+    pub fn vertical_metrics(&self, id: u16) -> (f32, f32, f32) {
+        (
+            self.glyph_metrics.advance_height(id),
+            self.glyph_metrics.vertical_origin(id),
+            self.glyph_metrics.advance_width(id),
+        )
+    }
+
     pub fn get(&mut self, id: u16, x: f32, y: f32) -> Option<GlyphEntry> {
-        let subpx = [SubpixelOffset::quantize(x), SubpixelOffset::quantize(y)];
+        self.get_outlined(id, x, y, 0)
+    }
+
+    /// Same as `get` but the rasterized coverage is dilated by `outline`
+    /// pixels on every side, so it can be drawn beneath the glyph as a
+    /// stroke. Color glyphs are never dilated.
+    pub fn get_outlined(
+        &mut self,
+        id: u16,
+        x: f32,
+        y: f32,
+        outline: u8,
+    ) -> Option<GlyphEntry> {
+        let key = self.key(id, x, y, outline);
+        if let Some(entry) = self.entry.glyphs.get(&key) {
+            if self.images.is_valid(entry.image) {
+                self.stats.hits += 1;
+                return Some(*entry);
+            }
+        }
+        self.stats.misses += 1;
+        let raster = rasterize(
+            &mut self.scaler,
+            self.scaled_image,
+            self.font,
+            self.size,
+            key,
+            self.presentation,
+            self.stem_darkening,
+        )?;
+        insert(self.images, self.entry, self.stats, key, &raster)
+    }
+
+    /// Same as `get` but rasterized at `size`, whole pixels only, rather
+    /// than at the size of the session, for glyphs scaled to fit a given
+    /// area. Meant for the odd glyph, misses build a scaler for `size`.
+    pub fn get_sized(
+        &mut self,
+        id: u16,
+        x: f32,
+        y: f32,
+        size: f32,
+    ) -> Option<GlyphEntry> {
+        let size = size.floor().clamp(1., u16::MAX as f32);
         let key = GlyphKey {
-            id,
-            subpx,
-            size: self.quant_size,
+            size: size_bucket(size),
+            ..self.key(id, x, y, 0)
         };
         if let Some(entry) = self.entry.glyphs.get(&key) {
             if self.images.is_valid(entry.image) {
+                self.stats.hits += 1;
                 return Some(*entry);
             }
         }
-        self.scaled_image.data.clear();
-        // let embolden = if IS_MACOS { 0.25 } else { 0. };
-        if Render::new(SOURCES)
-            .format(Format::CustomSubpixel([0.3, 0., -0.3]))
-            // .format(Format::Alpha)
-            .offset(Vector::new(subpx[0].to_f32(), subpx[1].to_f32()))
-            // .embolden(embolden)
-            // .transform(if cache_key.flags.contains(CacheKeyFlags::FAKE_ITALIC) {
-            //     Some(Transform::skew(
-            //         Angle::from_degrees(14.0),
-            //         Angle::from_degrees(0.0),
-            //     ))
-            // } else {
-            //     None
-            // })
-            .render_into(&mut self.scaler, id, self.scaled_image)
+        self.stats.misses += 1;
+        let mut scaler = build_scaler(self.sized_scx, self.font, size, self.hinting);
+        let raster = rasterize(
+            &mut scaler,
+            self.scaled_image,
+            self.font,
+            size,
+            key,
+            self.presentation,
+            self.stem_darkening,
+        )?;
+        insert(self.images, self.entry, self.stats, key, &raster)
+    }
+
+    /// Rasterizes `glyphs`, as glyph id and position like in `get`, across
+    /// the rayon thread pool, then adds them to the atlas from this thread.
+    /// Meant for the first frame after a font or size change, where every
+    /// glyph on screen misses the cache; cached glyphs are skipped. Falls
+    /// back to rasterizing one glyph at a time on wasm.
+    pub fn warm_glyphs_parallel(&mut self, glyphs: &[(u16, f32, f32)]) {
+        let mut seen = HashSet::with_capacity(glyphs.len());
+        let mut pending = Vec::new();
+        for &(id, x, y) in glyphs {
+            let key = self.key(id, x, y, 0);
+            let cached = self
+                .entry
+                .glyphs
+                .get(&key)
+                .is_some_and(|entry| self.images.is_valid(entry.image));
+            if !cached && seen.insert(key) {
+                pending.push(key);
+            }
+        }
+        self.stats.misses += pending.len() as u64;
+
+        #[cfg(not(target_arch = "wasm32"))]
         {
-            let p = self.scaled_image.placement;
-            let w = p.width as u16;
-            let h = p.height as u16;
-            let req = AddImage {
-                format: PixelFormat::Rgba8,
-                width: w,
-                height: h,
-                has_alpha: true,
-                evictable: true,
-                data: ImageData::Borrowed(&self.scaled_image.data),
-            };
-            let image = self.images.allocate(req)?;
-            let entry = GlyphEntry {
-                left: p.left,
-                top: p.top,
-                width: w,
-                height: h,
-                image,
-                is_bitmap: self.scaled_image.content == Content::Color,
-                desc: DescenderRegion::new(self.scaled_image),
-            };
-            self.entry.glyphs.insert(key, entry);
-            return Some(entry);
+            use rayon::prelude::*;
+
+            let (font, size, presentation) = (self.font, self.size, self.presentation);
+            let (hinting, stem_darkening) = (self.hinting, self.stem_darkening);
+            let rasters: Vec<_> = pending
+                .par_iter()
+                .map_init(
+                    || (ScaleContext::new(), GlyphImage::new()),
+                    |(scx, image), key| {
+                        let mut scaler = build_scaler(scx, font, size, hinting);
+                        rasterize(
+                            &mut scaler,
+                            image,
+                            font,
+                            size,
+                            *key,
+                            presentation,
+                            stem_darkening,
+                        )
+                        .map(Raster::into_owned)
+                    },
+                )
+                .collect();
+            for (key, raster) in pending.into_iter().zip(rasters) {
+                if let Some(raster) = raster {
+                    insert(self.images, self.entry, self.stats, key, &raster);
+                }
+            }
         }
 
-        None
+        #[cfg(target_arch = "wasm32")]
+        for key in pending {
+            if let Some(raster) = rasterize(
+                &mut self.scaler,
+                self.scaled_image,
+                self.font,
+                self.size,
+                key,
+                self.presentation,
+                self.stem_darkening,
+            ) {
+                insert(self.images, self.entry, self.stats, key, &raster);
+            }
+        }
+    }
+
+    #[inline]
+    fn key(&self, id: u16, x: f32, y: f32, outline: u8) -> GlyphKey {
+        // Fully hinted glyphs are only rasterized at whole pixels
+        let x = match self.hinting {
+            Hinting::Full => SubpixelOffset::Zero,
+            Hinting::None | Hinting::Slight => SubpixelOffset::quantize(x),
+        };
+        GlyphKey {
+            id,
+            subpx: [x, SubpixelOffset::quantize(y)],
+            size: self.quant_size,
+            outline,
+            presentation: self.presentation,
+            hinting: self.hinting,
+        }
+    }
+}
+
+/// A glyph rasterized on the CPU, waiting to be added to the atlas.
+struct Raster<'a> {
+    left: i32,
+    top: i32,
+    width: u32,
+    height: u32,
+    data: Cow<'a, [u8]>,
+    is_color: bool,
+    has_ink: bool,
+    strike: Option<Strike>,
+    desc: DescenderRegion,
+}
+
+impl Raster<'_> {
+    fn into_owned(self) -> Raster<'static> {
+        Raster {
+            data: Cow::Owned(self.data.into_owned()),
+            ..self
+        }
+    }
+}
+
+/// Renders the glyph of `key` into `image` and converts it to the RGBA
+/// layout of the atlas. Doesn't touch the atlas, so it can run on any thread
+/// with its own scaler and image. `font` and `size` are those of `scaler`.
+///
+/// Embedded bitmaps come from the strike nearest to `size`, so the least
+/// scaling is applied to them. Alpha strikes of fonts with outlines are
+/// only used at their exact size, outlines look better than scaled ones.
+/// Outlines are darkened with the intensity of `darkening`, if any.
+fn rasterize<'i>(
+    scaler: &mut Scaler,
+    image: &'i mut GlyphImage,
+    font: FontRef,
+    size: f32,
+    key: GlyphKey,
+    presentation: Presentation,
+    darkening: Option<f32>,
+) -> Option<Raster<'i>> {
+    let GlyphKey {
+        id, subpx, outline, ..
+    } = key;
+    trace_span!(trace, "rasterize_glyph", id);
+    image.data.clear();
+    let embolden = darkening.map_or(0., |intensity| stem_darkening(size, intensity));
+    let strikes = |strikes: swash::iter::BitmapStrikes| {
+        nearest_strike(
+            strikes.enumerate().filter_map(|(index, strike)| {
+                strike.contains(id).then_some((index as u32, strike.ppem()))
+            }),
+            size,
+        )
+    };
+    let color_strike = strikes(font.color_strikes());
+    let alpha_strike = strikes(font.alpha_strikes())
+        .filter(|(_, ppem)| !scaler.has_outlines() || *ppem == size as u16);
+    let sources = match presentation {
+        Presentation::Text => TEXT_SOURCES,
+        Presentation::Auto | Presentation::Emoji => SOURCES,
+    }
+    .map(|source| match (source, color_strike, alpha_strike) {
+        (Source::ColorBitmap(_), Some((index, _)), _) => {
+            Source::ColorBitmap(StrikeWith::Index(index))
+        }
+        (Source::Bitmap(_), _, Some((index, _))) => {
+            Source::Bitmap(StrikeWith::Index(index))
+        }
+        _ => source,
+    });
+    if !Render::new(&sources)
+        .format(Format::CustomSubpixel([0.3, 0., -0.3]))
+        // .format(Format::Alpha)
+        .offset(Vector::new(subpx[0].to_f32(), subpx[1].to_f32()))
+        .embolden(embolden)
+        // .transform(if cache_key.flags.contains(CacheKeyFlags::FAKE_ITALIC) {
+        //     Some(Transform::skew(
+        //         Angle::from_degrees(14.0),
+        //         Angle::from_degrees(0.0),
+        //     ))
+        // } else {
+        //     None
+        // })
+        .render_into(scaler, id, image)
+    {
+        return None;
     }
+
+    let strike = match image.source {
+        Source::ColorBitmap(_) => color_strike.map(|strike| (strike, true)),
+        Source::Bitmap(_) => alpha_strike.map(|strike| (strike, false)),
+        _ => None,
+    }
+    .map(|((index, ppem), color)| Strike { index, ppem, color });
+
+    let mut p = image.placement;
+    let content = image.content;
+    // Text presentation of a color only glyph keeps just its shape
+    let is_color = content == Content::Color && presentation != Presentation::Text;
+    let desc = DescenderRegion::new(image);
+    // Alpha bitmaps come with a single coverage channel, the atlas expects the
+    // same RGBA layout as subpixel masks. Masks only hold coverage, the run
+    // color is applied when drawing them
+    let data: Cow<[u8]> = match content {
+        Content::Mask => Cow::Owned(expand_mask(&image.data)),
+        Content::Color if !is_color => Cow::Owned(color_coverage(&image.data)),
+        // Embedded bitmaps are decoded with straight alpha, color outlines
+        // are already composited premultiplied by swash
+        Content::Color if matches!(image.source, Source::ColorBitmap(_)) => {
+            Cow::Owned(premultiply(&image.data))
+        }
+        _ => Cow::Borrowed(&image.data),
+    };
+    let data = if outline > 0 && !is_color {
+        let dilated = dilate(&data, p.width, p.height, outline);
+        let outline = outline as u32;
+        p.left -= outline as i32;
+        p.top += outline as i32;
+        p.width += outline * 2;
+        p.height += outline * 2;
+        Cow::Owned(dilated)
+    } else {
+        data
+    };
+    // Whitespace still rasterizes to a blank image of a pixel or two
+    let has_ink = data.iter().any(|byte| *byte != 0);
+    Some(Raster {
+        left: p.left,
+        top: p.top,
+        width: p.width,
+        height: p.height,
+        data,
+        is_color,
+        has_ink,
+        strike,
+        desc,
+    })
+}
+
+/// Returns the strike, as index and pixels per em, whose size is the
+/// nearest to `size`, the larger of two as near.
+fn nearest_strike(
+    strikes: impl Iterator<Item = (u32, u16)>,
+    size: f32,
+) -> Option<(u32, u16)> {
+    strikes.min_by(|a, b| {
+        let distance = |ppem: u16| (ppem as f32 - size).abs();
+        distance(a.1).total_cmp(&distance(b.1)).then(b.1.cmp(&a.1))
+    })
+}
+
+/// Adds `raster` to the atlas and caches it under `key`.
+fn insert(
+    images: &mut ImageCache,
+    entry: &mut FontEntry,
+    stats: &mut GlyphCacheStats,
+    key: GlyphKey,
+    raster: &Raster,
+) -> Option<GlyphEntry> {
+    let w = raster.width as u16;
+    let h = raster.height as u16;
+    let req = AddImage {
+        format: PixelFormat::Rgba8,
+        width: w,
+        height: h,
+        has_alpha: true,
+        evictable: true,
+        data: ImageData::Borrowed(&raster.data),
+    };
+    let image = images.allocate(req)?;
+    let glyph = GlyphEntry {
+        left: raster.left,
+        top: raster.top,
+        width: w,
+        height: h,
+        image,
+        is_color: raster.is_color,
+        has_ink: raster.has_ink,
+        strike: raster.strike,
+        desc: raster.desc,
+    };
+    entry.glyphs.insert(key, glyph);
+    stats.insertions += 1;
+    Some(glyph)
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -191,6 +706,8 @@ impl<'a> Borrow<(u64, Coords<'a>)> for FontKey {
 #[derive(Default)]
 struct FontEntry {
     glyphs: HashMap<GlyphKey, GlyphEntry>,
+    /// Unscaled metrics of the font, parsed by the first session.
+    metrics: Option<Metrics>,
 }
 
 #[derive(Clone, Debug)]
@@ -246,7 +763,11 @@ impl Hash for Coords<'_> {
 struct GlyphKey {
     id: u16,
     subpx: [SubpixelOffset; 2],
+    /// See `size_bucket`.
     size: u16,
+    outline: u8,
+    presentation: Presentation,
+    hinting: Hinting,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -256,10 +777,108 @@ pub struct GlyphEntry {
     pub width: u16,
     pub height: u16,
     pub image: ImageId,
-    pub is_bitmap: bool,
+    /// The glyph carries its own colors (color bitmaps and outlines) and
+    /// must not be tinted. Otherwise the image is a coverage mask, either
+    /// rasterized from an outline or taken from an alpha bitmap strike.
+    pub is_color: bool,
+    /// Some pixel of the image is covered, which whitespace glyphs aren't.
+    pub has_ink: bool,
+    /// The embedded bitmap strike the image was scaled from, none for
+    /// glyphs rasterized from outlines.
+    pub strike: Option<Strike>,
     pub desc: DescenderRegion,
 }
 
+/// Looks up the adjustment for a glyph pair in the horizontal format 0
+/// subtables of an OpenType `kern` table, in font units.
+fn kern_pair(data: &[u8], left: u16, right: u16) -> Option<i16> {
+    let u16_at = |offset: usize| -> Option<u16> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    // Only the OpenType version is supported, not Apple's 1.0
+    if u16_at(0)? != 0 {
+        return None;
+    }
+    let subtables = u16_at(2)?;
+    let mut offset = 4;
+    for _ in 0..subtables {
+        let length = u16_at(offset + 2)? as usize;
+        let coverage = u16_at(offset + 4)?;
+        let format = coverage >> 8;
+        // Horizontal, not minimum values and not cross-stream
+        if format == 0 && coverage & 0b111 == 0b001 {
+            let pairs = u16_at(offset + 6)? as usize;
+            let start = offset + 14;
+            let key = (left as u32) << 16 | right as u32;
+            let (mut low, mut high) = (0, pairs);
+            while low < high {
+                let mid = (low + high) / 2;
+                let entry = start + mid * 6;
+                let pair = (u16_at(entry)? as u32) << 16 | u16_at(entry + 2)? as u32;
+                match pair.cmp(&key) {
+                    std::cmp::Ordering::Less => low = mid + 1,
+                    std::cmp::Ordering::Greater => high = mid,
+                    std::cmp::Ordering::Equal => {
+                        return Some(u16_at(entry + 4)? as i16);
+                    }
+                }
+            }
+        }
+        if length == 0 {
+            break;
+        }
+        offset += length;
+    }
+    None
+}
+
+/// Turns an RGBA color image into a coverage mask taken from its alpha.
+fn color_coverage(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(4)
+        .flat_map(|pixel| [pixel[3]; 4])
+        .collect()
+}
+
+/// Replicates a single channel coverage mask into every RGBA channel.
+fn expand_mask(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|&coverage| [coverage; 4]).collect()
+}
+
+/// Grows the coverage of an RGBA subpixel mask by `radius` pixels in every
+/// direction. The result is `radius * 2` pixels wider and taller and every
+/// channel holds the dilated coverage.
+fn dilate(data: &[u8], width: u32, height: u32, radius: u8) -> Vec<u8> {
+    let (w, h, r) = (width as i32, height as i32, radius as i32);
+    let (out_w, out_h) = (w + r * 2, h + r * 2);
+    let coverage = |x: i32, y: i32| -> u8 {
+        if x < 0 || y < 0 || x >= w || y >= h {
+            return 0;
+        }
+        let offset = ((y * w + x) * 4) as usize;
+        data.get(offset..offset + 4)
+            .map(|px| *px.iter().max().unwrap_or(&0))
+            .unwrap_or(0)
+    };
+
+    let mut out = vec![0u8; (out_w * out_h * 4) as usize];
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let mut value = 0;
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    if dx * dx + dy * dy <= r * r {
+                        value = value.max(coverage(x - r + dx, y - r + dy));
+                    }
+                }
+            }
+            let offset = ((y * out_w + x) * 4) as usize;
+            out[offset..offset + 4].fill(value);
+        }
+    }
+    out
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct DescenderRegion {
     start: u16,
@@ -383,3 +1002,176 @@ impl SubpixelOffset {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_bucket() {
+        assert_eq!(size_bucket(12.), 24);
+        assert_eq!(size_bucket(12.2), 24);
+        assert_eq!(size_bucket(12.3), 25);
+        assert_eq!(size_bucket(12.5), 25);
+        assert_eq!(size_bucket(0.), 1);
+    }
+
+    #[test]
+    fn test_stem_darkening() {
+        assert_eq!(stem_darkening(4., 1.), 0.4);
+        assert_eq!(stem_darkening(12., 1.), 0.275);
+        assert_eq!(stem_darkening(24., 1.), 0.);
+        assert!(stem_darkening(8., 1.) > stem_darkening(20., 1.));
+        assert_eq!(stem_darkening(12., 2.), 0.55);
+    }
+
+    #[test]
+    fn test_stem_darkening_darkens_coverage() {
+        let font = FontRef::from_index(
+            include_bytes!(
+                "../../../font/resources/CascadiaMono/CascadiaMonoPL-Light.ttf"
+            ),
+            0,
+        )
+        .unwrap();
+        let coverage = |size: f32, darkening: Option<f32>| {
+            let key = GlyphKey {
+                id: font.charmap().map('l'),
+                subpx: [SubpixelOffset::Zero; 2],
+                size: size_bucket(size),
+                outline: 0,
+                presentation: Presentation::Auto,
+                hinting: Hinting::Slight,
+            };
+            let mut scx = ScaleContext::new();
+            let mut scaler = build_scaler(&mut scx, font, size, Hinting::Slight);
+            let mut image = GlyphImage::new();
+            let raster = rasterize(
+                &mut scaler,
+                &mut image,
+                font,
+                size,
+                key,
+                Presentation::Auto,
+                darkening,
+            )
+            .unwrap();
+            raster.data.iter().map(|byte| *byte as u32).sum::<u32>()
+        };
+
+        // Small glyphs come out darker, the more with more intensity
+        let before = coverage(10., None);
+        let after = coverage(10., Some(1.));
+        assert!(after > before, "{after} > {before}");
+        assert!(coverage(10., Some(2.)) > after);
+        // Large ones are left as they are
+        assert_eq!(coverage(32., Some(1.)), coverage(32., None));
+    }
+
+    #[test]
+    fn test_sessions_scale_the_cached_metrics() {
+        let font = FontRef::from_index(
+            include_bytes!(
+                "../../../font/resources/CascadiaMono/CascadiaMonoPL-Light.ttf"
+            ),
+            0,
+        )
+        .unwrap();
+        let id = font.charmap().map('m');
+        let mut cache = GlyphCache::new();
+        let mut images = ImageCache::new(1024);
+        // Sessions of the same font share its entry, at any size
+        for size in [12., 20.5] {
+            let mut session = cache.session(
+                &mut images,
+                font,
+                &[],
+                size,
+                Presentation::Auto,
+                Hinting::Slight,
+            );
+            let metrics = font.metrics(&[]).scale(size);
+            assert_eq!(
+                session.underline_metrics(),
+                (metrics.underline_offset, metrics.stroke_size)
+            );
+            let advance = font.glyph_metrics(&[]).scale(size).advance_width(id);
+            assert_eq!(session.advance(id), advance);
+            // Sized glyphs come out at their own size
+            let sized = session.get_sized(id, 0., 0., size * 2.).unwrap();
+            assert!(sized.width > session.get(id, 0., 0.).unwrap().width);
+        }
+        assert_eq!(cache.fonts.len(), 1);
+    }
+
+    #[test]
+    fn test_nearest_strike() {
+        let strikes = [(0, 12), (1, 16), (2, 24), (3, 32)];
+        assert_eq!(nearest_strike(strikes.into_iter(), 13.), Some((0, 12)));
+        assert_eq!(nearest_strike(strikes.into_iter(), 22.5), Some((2, 24)));
+        // Halfway between two strikes goes for the larger
+        assert_eq!(nearest_strike(strikes.into_iter(), 20.), Some((2, 24)));
+        assert_eq!(nearest_strike(strikes.into_iter(), 64.), Some((3, 32)));
+        assert_eq!(nearest_strike(std::iter::empty(), 12.), None);
+    }
+
+    #[test]
+    fn test_dilate() {
+        // 1x1 fully covered pixel
+        let data = [255, 128, 0, 255];
+        let out = dilate(&data, 1, 1, 1);
+        assert_eq!(out.len(), 3 * 3 * 4);
+        let at = |x: usize, y: usize| out[(y * 3 + x) * 4];
+        // Center and direct neighbours are covered, corners aren't
+        assert_eq!(at(1, 1), 255);
+        assert_eq!(at(0, 1), 255);
+        assert_eq!(at(1, 0), 255);
+        assert_eq!(at(2, 1), 255);
+        assert_eq!(at(1, 2), 255);
+        assert_eq!(at(0, 0), 0);
+        assert_eq!(at(2, 2), 0);
+    }
+
+    #[test]
+    fn test_dilate_without_radius_keeps_coverage() {
+        let data = [0, 0, 0, 0, 10, 20, 30, 40];
+        let out = dilate(&data, 2, 1, 0);
+        assert_eq!(out, vec![0, 0, 0, 0, 40, 40, 40, 40]);
+    }
+
+    #[test]
+    fn test_expand_mask() {
+        assert_eq!(expand_mask(&[0, 128]), vec![0, 0, 0, 0, 128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn test_kern_pair() {
+        let pairs: [(u16, u16, i16); 3] = [(1, 2, -50), (1, 5, 20), (3, 4, -10)];
+        let mut data = vec![];
+        for value in [0u16, 1] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        // Subtable header: version, length, coverage (format 0, horizontal)
+        let length = 14 + pairs.len() * 6;
+        for value in [0u16, length as u16, 0x0001, pairs.len() as u16, 0, 0, 0] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        for (left, right, value) in pairs {
+            data.extend_from_slice(&left.to_be_bytes());
+            data.extend_from_slice(&right.to_be_bytes());
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        assert_eq!(kern_pair(&data, 1, 2), Some(-50));
+        assert_eq!(kern_pair(&data, 1, 5), Some(20));
+        assert_eq!(kern_pair(&data, 3, 4), Some(-10));
+        assert_eq!(kern_pair(&data, 2, 1), None);
+        assert_eq!(kern_pair(&data[..10], 1, 2), None);
+    }
+
+    #[test]
+    fn test_color_coverage() {
+        let data = [255, 0, 0, 200, 0, 255, 0, 0];
+        assert_eq!(color_coverage(&data), vec![200, 200, 200, 200, 0, 0, 0, 0]);
+    }
+}
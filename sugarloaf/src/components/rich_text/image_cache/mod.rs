@@ -25,7 +25,7 @@ impl PixelFormat {
 
 pub use cache::ImageCache;
 // pub use glyph::{GlyphCache, GlyphCacheSession, GlyphEntry};
-pub use glyph::GlyphCache;
+pub use glyph::{GlyphCache, GlyphCacheSession, GlyphCacheStats, GlyphEntry};
 
 /// Identifier for a texture in GPU memory.
 #[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
@@ -39,7 +39,7 @@ impl TextureId {
 }
 
 /// Identifier for an image in a cache.
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct ImageId(u32);
 
 impl ImageId {
@@ -93,7 +93,8 @@ pub struct AddImage<'a> {
     pub has_alpha: bool,
     /// True if the cache can evict this image.
     pub evictable: bool,
-    /// The actual image data.
+    /// The actual image data. Color images are stored premultiplied, which
+    /// is what the pipeline blends, while masks only hold coverage.
     pub data: ImageData<'a>,
 }
 
@@ -124,6 +125,24 @@ impl<'a> ImageData<'a> {
     }
 }
 
+/// Multiplies the color channels of straight alpha RGBA pixels by their
+/// alpha. Sampling premultiplied pixels also keeps bilinear filtering from
+/// bleeding the color of transparent texels into the edges of an image.
+pub fn premultiply(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(4)
+        .flat_map(|pixel| {
+            let a = pixel[3] as u32;
+            let channel = |c: u8| ((c as u32 * a + 127) / 255) as u8;
+            [
+                channel(pixel[0]),
+                channel(pixel[1]),
+                channel(pixel[2]),
+                pixel[3],
+            ]
+        })
+        .collect()
+}
+
 /// Event that describes a change in an image cache.
 #[derive(Copy, Clone)]
 #[allow(clippy::enum_variant_names)]
@@ -153,6 +172,13 @@ pub enum TextureEvent<'a> {
 /// Limit on number of atlases before image allocation fails.
 const MAX_ATLASES: u16 = 256;
 
+/// Shortest time a frame of an animation is shown, as browsers do for
+/// GIFs with no or a tiny delay.
+const MIN_FRAME_DURATION: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Smallest size atlases start at.
+const MIN_ATLAS_SIZE: u16 = 256;
+
 /// Limit on number of standalone images.
 // const MAX_IMAGES: u16 = i16::MAX as u16;
 
@@ -0,0 +1,308 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Inline images of the iTerm2 `OSC 1337 ; File=` sequence, which the
+//! compositor draws with `draw_iterm2_image`. PNG is always decoded, JPEG
+//! and GIF behind the `image_jpeg` and `image_gif` features.
+
+use crate::components::rich_text::image_cache::{AddImage, ImageData, PixelFormat};
+use crate::components::rich_text::util::base64;
+use image::codecs::png::PngDecoder;
+use std::io::Cursor;
+use std::time::Duration;
+
+/// Requested width or height of an image.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ITerm2Dimension {
+    /// Size of the image itself, or scaled with the other dimension when
+    /// the aspect ratio is preserved.
+    #[default]
+    Auto,
+    /// A number of cells, `N`.
+    Cells(u32),
+    /// A number of pixels, `Npx`.
+    Pixels(u32),
+    /// A share of the width or height of the session, `N%`.
+    Percent(u32),
+}
+
+impl ITerm2Dimension {
+    fn parse(value: &str) -> Option<Self> {
+        if value == "auto" {
+            return Some(Self::Auto);
+        }
+        if let Some(pixels) = value.strip_suffix("px") {
+            return pixels.parse().ok().map(Self::Pixels);
+        }
+        if let Some(percent) = value.strip_suffix('%') {
+            return percent.parse().ok().map(Self::Percent);
+        }
+        value.parse().ok().map(Self::Cells)
+    }
+
+    fn resolve(self, cell: f32, session: f32) -> Option<f32> {
+        match self {
+            Self::Auto => None,
+            Self::Cells(cells) => Some(cells as f32 * cell),
+            Self::Pixels(pixels) => Some(pixels as f32),
+            Self::Percent(percent) => Some(session * percent as f32 / 100.),
+        }
+    }
+}
+
+/// A file sent with `File=`, with its decoded contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ITerm2File {
+    pub name: Option<String>,
+    pub width: ITerm2Dimension,
+    pub height: ITerm2Dimension,
+    /// Keeps the image from being stretched when both dimensions are set,
+    /// `preserveAspectRatio`, on by default.
+    pub preserve_aspect_ratio: bool,
+    /// Displays the file instead of downloading it, `inline=1`.
+    pub inline: bool,
+    pub data: Vec<u8>,
+}
+
+impl ITerm2File {
+    /// Parses the body of the sequence after `1337;`, that is
+    /// `File=<key>=<value>;...:<base64 contents>`.
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        let body = body.strip_prefix(b"File=")?;
+        let split = body.iter().position(|b| *b == b':')?;
+        let (args, contents) = (
+            std::str::from_utf8(&body[..split]).ok()?,
+            &body[split + 1..],
+        );
+
+        let mut file = Self {
+            name: None,
+            width: ITerm2Dimension::Auto,
+            height: ITerm2Dimension::Auto,
+            preserve_aspect_ratio: true,
+            inline: false,
+            data: base64::decode(contents)?,
+        };
+        for arg in args.split(';').filter(|arg| !arg.is_empty()) {
+            let (key, value) = arg.split_once('=')?;
+            match key {
+                "name" => {
+                    let name = base64::decode(value.as_bytes())?;
+                    file.name = String::from_utf8(name).ok();
+                }
+                "width" => file.width = ITerm2Dimension::parse(value)?,
+                "height" => file.height = ITerm2Dimension::parse(value)?,
+                "preserveAspectRatio" => file.preserve_aspect_ratio = value != "0",
+                "inline" => file.inline = value == "1",
+                _ => {}
+            }
+        }
+        Some(file)
+    }
+
+    /// Size in pixels the image is drawn at, for an image of `image` pixels
+    /// with cells of `cell` pixels in a session of `session` pixels.
+    pub fn size(
+        &self,
+        (image_width, image_height): (f32, f32),
+        (cell_width, cell_height): (f32, f32),
+        (session_width, session_height): (f32, f32),
+    ) -> (f32, f32) {
+        let width = self.width.resolve(cell_width, session_width);
+        let height = self.height.resolve(cell_height, session_height);
+        let ratio = image_width / image_height.max(1.);
+        let preserve = self.preserve_aspect_ratio;
+        match (width, height) {
+            (None, None) => (image_width, image_height),
+            (Some(width), None) if preserve => (width, width / ratio),
+            (Some(width), None) => (width, image_height),
+            (None, Some(height)) if preserve => (height * ratio, height),
+            (None, Some(height)) => (image_width, height),
+            (Some(width), Some(height)) if preserve => {
+                let scale = (width / image_width).min(height / image_height);
+                (image_width * scale, image_height * scale)
+            }
+            (Some(width), Some(height)) => (width, height),
+        }
+    }
+}
+
+/// An inline image converted to straight alpha RGBA pixels. `decode` keeps
+/// the first frame of animated images, `decode_frames` all of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ITerm2Image {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+}
+
+impl ITerm2Image {
+    /// Decodes the contents of a file, returning `None` for formats that
+    /// aren't enabled.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let format = image::guess_format(data).ok()?;
+        match format {
+            image::ImageFormat::Png => {}
+            #[cfg(feature = "image_jpeg")]
+            image::ImageFormat::Jpeg => {}
+            #[cfg(feature = "image_gif")]
+            image::ImageFormat::Gif => {}
+            _ => return None,
+        }
+        let image = image::load_from_memory_with_format(data, format)
+            .ok()?
+            .into_rgba8();
+        Some(Self {
+            width: u16::try_from(image.width()).ok()?,
+            height: u16::try_from(image.height()).ok()?,
+            pixels: image.into_raw(),
+        })
+    }
+
+    /// Decodes every frame of an animated GIF or PNG, with how long each is
+    /// shown. Other images decode to a single frame.
+    pub fn decode_frames(data: &[u8]) -> Option<Vec<(Self, Duration)>> {
+        use image::AnimationDecoder;
+
+        let frames = match image::guess_format(data).ok()? {
+            image::ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(data)).ok()?;
+                if !decoder.is_apng() {
+                    return Some(vec![(Self::decode(data)?, Duration::ZERO)]);
+                }
+                decoder.apng().into_frames()
+            }
+            #[cfg(feature = "image_gif")]
+            image::ImageFormat::Gif => {
+                image::codecs::gif::GifDecoder::new(Cursor::new(data))
+                    .ok()?
+                    .into_frames()
+            }
+            _ => return Some(vec![(Self::decode(data)?, Duration::ZERO)]),
+        };
+        frames
+            .map(|frame| {
+                let frame = frame.ok()?;
+                let duration = Duration::from(frame.delay());
+                let image = frame.into_buffer();
+                Some((
+                    Self {
+                        width: u16::try_from(image.width()).ok()?,
+                        height: u16::try_from(image.height()).ok()?,
+                        pixels: image.into_raw(),
+                    },
+                    duration,
+                ))
+            })
+            .collect()
+    }
+
+    /// Request for adding the image to the cache.
+    pub fn as_add_image(&self) -> AddImage {
+        AddImage {
+            format: PixelFormat::Rgba8,
+            width: self.width,
+            height: self.height,
+            has_alpha: true,
+            evictable: true,
+            data: ImageData::Borrowed(&self.pixels),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(image: image::RgbaImage, format: image::ImageFormat) -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_parse_iterm2_file() {
+        let file = ITerm2File::parse(
+            b"File=name=YS5wbmc=;width=10;height=50%;preserveAspectRatio=0;inline=1:aGk=",
+        )
+        .unwrap();
+        assert_eq!(file.name.as_deref(), Some("a.png"));
+        assert_eq!(file.width, ITerm2Dimension::Cells(10));
+        assert_eq!(file.height, ITerm2Dimension::Percent(50));
+        assert!(!file.preserve_aspect_ratio);
+        assert!(file.inline);
+        assert_eq!(file.data, b"hi");
+        assert!(ITerm2File::parse(b"File=width=wide:aGk=").is_none());
+    }
+
+    #[test]
+    fn test_iterm2_image_size() {
+        let mut file = ITerm2File::parse(b"File=width=4:").unwrap();
+        let (image, cell, session) = ((100., 50.), (10., 20.), (800., 600.));
+        assert_eq!(file.size(image, cell, session), (40., 20.));
+
+        file.height = ITerm2Dimension::Pixels(10);
+        assert_eq!(file.size(image, cell, session), (20., 10.));
+        file.preserve_aspect_ratio = false;
+        assert_eq!(file.size(image, cell, session), (40., 10.));
+
+        file.width = ITerm2Dimension::Auto;
+        file.height = ITerm2Dimension::Percent(50);
+        assert_eq!(file.size(image, cell, session), (100., 300.));
+    }
+
+    #[test]
+    fn test_decode_iterm2_png() {
+        let mut pixels = image::RgbaImage::new(2, 1);
+        pixels.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        pixels.put_pixel(1, 0, image::Rgba([0, 0, 255, 128]));
+        let image =
+            ITerm2Image::decode(&encode(pixels, image::ImageFormat::Png)).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels, [255, 0, 0, 255, 0, 0, 255, 128]);
+        assert!(ITerm2Image::decode(b"not an image").is_none());
+    }
+
+    #[test]
+    fn test_decode_still_image_frames() {
+        let png = encode(image::RgbaImage::new(3, 2), image::ImageFormat::Png);
+        let frames = ITerm2Image::decode_frames(&png).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].0.width, frames[0].0.height), (3, 2));
+    }
+
+    #[cfg(feature = "image_gif")]
+    #[test]
+    fn test_decode_gif_frames() {
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame};
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            for (value, ms) in [(255, 100), (0, 50)] {
+                let pixels =
+                    image::RgbaImage::from_pixel(2, 2, image::Rgba([value, 0, 0, 255]));
+                let delay = Delay::from_numer_denom_ms(ms, 1);
+                encoder
+                    .encode_frame(Frame::from_parts(pixels, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+        let frames = ITerm2Image::decode_frames(&data).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].1, Duration::from_millis(100));
+        assert_eq!(frames[1].1, Duration::from_millis(50));
+        assert_eq!(frames[0].0.pixels[..4], [255, 0, 0, 255]);
+        assert_eq!(frames[1].0.pixels[..4], [0, 0, 0, 255]);
+    }
+
+    #[cfg(not(feature = "image_gif"))]
+    #[test]
+    fn test_gif_needs_its_feature() {
+        let gif = encode(image::RgbaImage::new(1, 1), image::ImageFormat::Gif);
+        assert!(ITerm2Image::decode(&gif).is_none());
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Commands of the Kitty graphics protocol, which the compositor draws
+//! with `add_kitty_image` and `draw_kitty_image`.
+
+use crate::components::rich_text::image_cache::{AddImage, ImageData, PixelFormat};
+use crate::components::rich_text::util::base64;
+
+/// Action of a command, the `a` key.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KittyAction {
+    /// Transmits an image without displaying it.
+    #[default]
+    Transmit,
+    /// Transmits an image and places it at the cursor.
+    TransmitAndPlace,
+    /// Places an image transmitted before.
+    Place,
+    /// Deletes images.
+    Delete,
+    /// Checks whether an image could be transmitted, without storing it.
+    Query,
+}
+
+/// Format of the transmitted data, the `f` key.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KittyFormat {
+    Rgb,
+    #[default]
+    Rgba,
+    Png,
+}
+
+/// Images removed by a delete command, the `d` key. Uppercase values also
+/// free the image data, which the compositor always does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KittyDelete {
+    All,
+    Id(u32),
+}
+
+/// Where an image is drawn, relative to the cell at the cursor.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KittyPlacement {
+    /// Columns the image is scaled to, `c`, or its own width when zero.
+    pub columns: u16,
+    /// Rows the image is scaled to, `r`, or its own height when zero.
+    pub rows: u16,
+    /// Offset in pixels inside the cell, `X` and `Y`.
+    pub x_offset: u16,
+    pub y_offset: u16,
+    /// Stacking order, `z`. Images with a negative one go under text.
+    pub z_index: i32,
+}
+
+/// A command parsed from the control data of an APC `G` sequence.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KittyCommand {
+    pub action: KittyAction,
+    pub format: KittyFormat,
+    /// Image id, `i`.
+    pub id: u32,
+    /// Width and height in pixels of raw data, `s` and `v`.
+    pub width: u32,
+    pub height: u32,
+    /// More chunks of the payload follow, `m=1`.
+    pub more: bool,
+    pub placement: KittyPlacement,
+    pub delete: Option<KittyDelete>,
+}
+
+impl KittyCommand {
+    /// Parses the body of a sequence, `<key>=<value>,...;<base64 payload>`,
+    /// into the command and its decoded payload. Unknown keys are ignored.
+    pub fn parse(body: &[u8]) -> Option<(Self, Vec<u8>)> {
+        let (control, payload) = match body.iter().position(|b| *b == b';') {
+            Some(split) => (&body[..split], &body[split + 1..]),
+            None => (body, &[][..]),
+        };
+
+        let mut command = Self::default();
+        let mut delete = None;
+        for pair in control
+            .split(|b| *b == b',')
+            .filter(|pair| !pair.is_empty())
+        {
+            let [key, b'=', value @ ..] = pair else {
+                return None;
+            };
+            let number = || std::str::from_utf8(value).ok()?.parse::<i64>().ok();
+            match key {
+                b'a' => {
+                    command.action = match value {
+                        b"t" => KittyAction::Transmit,
+                        b"T" => KittyAction::TransmitAndPlace,
+                        b"p" => KittyAction::Place,
+                        b"d" => KittyAction::Delete,
+                        b"q" => KittyAction::Query,
+                        _ => return None,
+                    }
+                }
+                b'f' => {
+                    command.format = match number()? {
+                        24 => KittyFormat::Rgb,
+                        32 => KittyFormat::Rgba,
+                        100 => KittyFormat::Png,
+                        _ => return None,
+                    }
+                }
+                b'd' => delete = value.first().copied(),
+                b'i' => command.id = number()? as u32,
+                b's' => command.width = number()? as u32,
+                b'v' => command.height = number()? as u32,
+                b'm' => command.more = number()? == 1,
+                b'c' => command.placement.columns = number()? as u16,
+                b'r' => command.placement.rows = number()? as u16,
+                b'X' => command.placement.x_offset = number()? as u16,
+                b'Y' => command.placement.y_offset = number()? as u16,
+                b'z' => command.placement.z_index = number()? as i32,
+                _ => {}
+            }
+        }
+
+        if command.action == KittyAction::Delete {
+            command.delete = match delete.unwrap_or(b'a') {
+                b'a' | b'A' => Some(KittyDelete::All),
+                b'i' | b'I' => Some(KittyDelete::Id(command.id)),
+                _ => None,
+            };
+        }
+        Some((command, base64::decode(payload)?))
+    }
+}
+
+/// Joins a payload sent in chunks. Chunks after the first only carry the
+/// `m` key, so the command of the first one is kept.
+#[derive(Default)]
+pub struct KittyTransfer {
+    pending: Option<(KittyCommand, Vec<u8>)>,
+}
+
+impl KittyTransfer {
+    /// Adds a chunk, returning the command with the whole payload once the
+    /// last one arrives.
+    pub fn push(
+        &mut self,
+        command: KittyCommand,
+        payload: Vec<u8>,
+    ) -> Option<(KittyCommand, Vec<u8>)> {
+        let more = command.more;
+        let (first, data) = match self.pending.take() {
+            Some((first, mut data)) => {
+                data.extend_from_slice(&payload);
+                (first, data)
+            }
+            None => (command, payload),
+        };
+        if more {
+            self.pending = Some((first, data));
+            return None;
+        }
+        Some((KittyCommand { more, ..first }, data))
+    }
+}
+
+/// A transmitted image converted to straight alpha RGBA pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KittyImage {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+}
+
+impl KittyImage {
+    /// Decodes the payload of a transmit command.
+    pub fn decode(command: &KittyCommand, data: &[u8]) -> Option<Self> {
+        let (width, height, pixels) = match command.format {
+            KittyFormat::Png => {
+                let image =
+                    image::load_from_memory_with_format(data, image::ImageFormat::Png)
+                        .ok()?
+                        .into_rgba8();
+                (image.width(), image.height(), image.into_raw())
+            }
+            KittyFormat::Rgb | KittyFormat::Rgba => {
+                let (width, height) = (command.width, command.height);
+                let pixel = if command.format == KittyFormat::Rgb {
+                    3
+                } else {
+                    4
+                };
+                let len = (width as usize) * (height as usize) * pixel;
+                let data = data.get(..len)?;
+                let pixels = if pixel == 3 {
+                    data.chunks_exact(3)
+                        .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                        .collect()
+                } else {
+                    data.to_vec()
+                };
+                (width, height, pixels)
+            }
+        };
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(Self {
+            width: u16::try_from(width).ok()?,
+            height: u16::try_from(height).ok()?,
+            pixels,
+        })
+    }
+
+    /// Request for adding the image to the cache.
+    pub fn as_add_image(&self) -> AddImage {
+        AddImage {
+            format: PixelFormat::Rgba8,
+            width: self.width,
+            height: self.height,
+            has_alpha: true,
+            evictable: false,
+            data: ImageData::Borrowed(&self.pixels),
+        }
+    }
+}
+
+/// Maps a z-index to a depth in `0.0..=1.0`, with `0` at `0.5`, so with
+/// depth testing on, text drawn at `0.5` covers images with a negative
+/// z-index and is covered by the others. Small z-indices, the common
+/// ones, stay apart after rounding to `f32`.
+pub fn kitty_depth(z_index: i32) -> f32 {
+    let z = z_index as f64;
+    (0.5 + 0.5 * z / (z.abs() + 1024.)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kitty_command() {
+        // Two RGB pixels, red and blue
+        let (command, payload) =
+            KittyCommand::parse(b"a=T,f=24,s=2,v=1,i=7,c=3,r=1,z=-1;/wAAAAD/").unwrap();
+        assert_eq!(command.action, KittyAction::TransmitAndPlace);
+        assert_eq!(command.id, 7);
+        assert_eq!(command.placement.columns, 3);
+        assert_eq!(command.placement.z_index, -1);
+        assert_eq!(payload, [255, 0, 0, 0, 0, 255]);
+
+        let image = KittyImage::decode(&command, &payload).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels, [255, 0, 0, 255, 0, 0, 255, 255]);
+
+        let (command, _) = KittyCommand::parse(b"a=d,d=I,i=7").unwrap();
+        assert_eq!(command.delete, Some(KittyDelete::Id(7)));
+        assert!(KittyCommand::parse(b"a=x").is_none());
+    }
+
+    #[test]
+    fn test_chunked_kitty_transfer() {
+        let mut transfer = KittyTransfer::default();
+        let (first, payload) = KittyCommand::parse(b"a=t,f=24,s=2,v=1,m=1;/wAA").unwrap();
+        assert!(transfer.push(first, payload).is_none());
+        let (last, payload) = KittyCommand::parse(b"m=0;AAD/").unwrap();
+        let (command, payload) = transfer.push(last, payload).unwrap();
+        assert_eq!(command.format, KittyFormat::Rgb);
+        assert!(!command.more);
+        assert_eq!(payload, [255, 0, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_kitty_depth() {
+        assert_eq!(kitty_depth(0), 0.5);
+        assert!(kitty_depth(-1) < kitty_depth(0));
+        assert!(kitty_depth(1) < kitty_depth(2));
+        assert!(kitty_depth(i32::MIN) >= 0.0);
+        assert!(kitty_depth(i32::MAX) <= 1.0);
+    }
+}
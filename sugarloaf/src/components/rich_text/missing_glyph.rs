@@ -0,0 +1,135 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Geometry of the box drawn in place of glyphs missing from the font, with
+//! the hex code point written inside in tiny pixel digits when it is known.
+
+use crate::components::rich_text::batch::Rect;
+
+/// Digits 0 to F, 3 pixels wide and 5 tall, as a row of three bits each
+/// from the top, the leftmost pixel in the highest bit.
+#[rustfmt::skip]
+const HEX_DIGITS: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b111, 0b100, 0b100, 0b100, 0b111],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111],
+    [0b111, 0b100, 0b111, 0b100, 0b100],
+];
+
+/// Returns the box drawn in `cell`, inset so the boxes of adjacent cells
+/// don't touch.
+pub fn frame(cell: Rect) -> Rect {
+    let inset = (cell.width / 10.).round().max(1.);
+    Rect::new(
+        cell.x + inset,
+        cell.y + inset,
+        (cell.width - inset * 2.).max(0.),
+        (cell.height - inset * 2.).max(0.),
+    )
+}
+
+/// Adds the digits of `code_point` in hex to `rects`, on two rows centered
+/// in `frame` inside a border `thickness` wide. Digits are whole pixels, so
+/// nothing is added when the box is too small to fit them.
+pub fn code_point_rects(
+    code_point: char,
+    frame: Rect,
+    thickness: f32,
+    rects: &mut Vec<Rect>,
+) {
+    let code_point = code_point as u32;
+    let digits = if code_point > 0xFFFF {
+        format!("{code_point:06X}")
+    } else {
+        format!("{code_point:04X}")
+    };
+    let columns = digits.len() / 2;
+    // A pixel of space between digits, rows and the border
+    let (width, height) = ((columns * 4 - 1) as f32, 11.);
+    let padding = thickness + 1.;
+    let pixel = ((frame.width - padding * 2.) / width)
+        .min((frame.height - padding * 2.) / height)
+        .floor();
+    if pixel < 1. {
+        return;
+    }
+
+    let left = (frame.x + (frame.width - width * pixel) / 2.).round();
+    let top = (frame.y + (frame.height - height * pixel) / 2.).round();
+    for (index, digit) in digits.bytes().enumerate() {
+        let value = (digit as char).to_digit(16).unwrap_or(0) as usize;
+        let x = left + ((index % columns) * 4) as f32 * pixel;
+        let y = top + ((index / columns) * 6) as f32 * pixel;
+        for (row, bits) in HEX_DIGITS[value].iter().enumerate() {
+            let y = y + row as f32 * pixel;
+            // Runs of lit pixels in a row are a single rect
+            let mut column = 0;
+            while column < 3 {
+                if bits & (0b100 >> column) == 0 {
+                    column += 1;
+                    continue;
+                }
+                let start = column;
+                while column < 3 && bits & (0b100 >> column) != 0 {
+                    column += 1;
+                }
+                rects.push(Rect::new(
+                    x + start as f32 * pixel,
+                    y,
+                    (column - start) as f32 * pixel,
+                    pixel,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_point_rects() {
+        let frame = frame(Rect::new(0., 0., 40., 40.));
+        assert_eq!(
+            [frame.x, frame.y, frame.width, frame.height],
+            [4., 4., 32., 32.]
+        );
+
+        let mut rects = Vec::new();
+        code_point_rects('\u{E0}', frame, 1., &mut rects);
+        // 00E0 is two rows of two digits, 2 pixels per digit pixel, inside
+        // the border
+        assert!(!rects.is_empty());
+        assert!(rects.iter().all(|rect| rect.x >= 6.
+            && rect.y >= 6.
+            && rect.x + rect.width <= 34.
+            && rect.y + rect.height <= 34.));
+        let left = rects.iter().map(|rect| rect.x).fold(f32::MAX, f32::min);
+        let right = rects
+            .iter()
+            .map(|rect| rect.x + rect.width)
+            .fold(0., f32::max);
+        assert_eq!(right - left, 7. * 2.);
+
+        // Too small for a pixel per digit pixel
+        rects.clear();
+        let frame = super::frame(Rect::new(0., 0., 10., 20.));
+        code_point_rects('\u{1F600}', frame, 1., &mut rects);
+        assert!(rects.is_empty());
+    }
+}
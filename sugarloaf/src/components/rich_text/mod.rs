@@ -1,19 +1,56 @@
 mod batch;
+mod box_drawing;
+#[cfg(feature = "cell_widths")]
+pub mod cells;
+pub mod color;
 mod compositor;
+mod cull;
+pub mod cursor;
 mod image_cache;
+pub mod iterm2;
+pub mod kitty;
+mod missing_glyph;
+mod powerline;
+#[cfg(feature = "sixel")]
+pub mod sixel;
+mod staging;
 pub mod text;
 pub mod util;
 
-use crate::components::core::orthographic_projection;
-use crate::context::Context;
+#[cfg(feature = "bloom")]
+use crate::components::bloom::Bloom;
+use crate::components::core::{
+    multiply_matrix, orthographic_projection, orthographic_projection_flipped,
+    rotation_matrix, Rotation, IDENTITY_MATRIX,
+};
+use crate::components::crt::{CrtParams, CrtPass};
+use crate::components::post_process::{
+    PostProcess, PostProcessError, PostProcessShader, Stage,
+};
+use crate::context::{is_extended_range, Context};
 use crate::font::FontLibraryData;
 use crate::layout::SugarDimensions;
-use compositor::{
-    CachedRect, Command, Compositor, DisplayList, Rect, TextureEvent, TextureId, Vertex,
+use crate::SugarCursor;
+use bytemuck::{Pod, Zeroable};
+use color::{ColorFilter, ColorSpace};
+pub use compositor::{
+    quantize, AddImage, Command, CursorShape, DisplayList, DividerColors,
+    DividerOrientation, Draw, Fit, ForcedColors, GlyphCacheStats, HighlightColors,
+    ImageId, Insets, Pipeline, ResolvedStyle, TextureId, Vertex,
 };
+use compositor::{CachedRect, Compositor, Rect, TextureEvent};
+use cull::GpuCulling;
+use cursor::CursorBlink;
 use fnv::FnvHashMap;
-use std::{borrow::Cow, mem};
-use text::{Glyph, TextRunStyle, UnderlineStyle};
+pub use image_cache::{ImageData, PixelFormat};
+use staging::UploadStaging;
+pub use staging::UploadStats;
+use std::{borrow::Cow, mem, time::Instant};
+use swash::text::cluster::Whitespace;
+use text::{
+    Glyph, Hinting, Presentation, Strikethrough, TabStops, TextDirection, TextRunStyle,
+    UnderlineStyle, VerticalAlign, WritingMode,
+};
 use wgpu::util::DeviceExt;
 use wgpu::Texture;
 
@@ -22,12 +59,46 @@ use wgpu::Texture;
 // https://math.hws.edu/graphicsbook/c9/s2.html
 // https://docs.rs/wgpu/latest/wgpu/enum.VertexStepMode.html
 
+/// Clamps the initial and maximum atlas sizes asked for to `limit`, the
+/// device's `max_texture_dimension_2d`, with the initial size no larger
+/// than the maximum.
+fn atlas_size((initial_size, max_size): (u16, u16), limit: u32) -> (u16, u16) {
+    let limit = limit.min(u16::MAX as u32) as u16;
+    if initial_size > limit || max_size > limit {
+        log::warn!(
+            "atlas size {initial_size}..{max_size} exceeds the device limit of {limit}"
+        );
+    }
+    let max_size = max_size.min(limit);
+    (initial_size.min(max_size), max_size)
+}
+
+/// Atlas fragmentation over which `prepare` repacks the atlases.
+const DEFRAGMENT_THRESHOLD: f32 = 0.5;
+
+/// Premultiplied alpha blending, the shader outputs premultiplied colors.
 pub const BLEND: Option<wgpu::BlendState> = Some(wgpu::BlendState {
     color: wgpu::BlendComponent {
-        src_factor: wgpu::BlendFactor::SrcAlpha,
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+        operation: wgpu::BlendOperation::Add,
+    },
+    alpha: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
         dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
         operation: wgpu::BlendOperation::Add,
     },
+});
+
+/// Dual-source blending for subpixel text, the shader outputs premultiplied
+/// colors and the per-channel coverage as the second source. Requires
+/// `wgpu::Features::DUAL_SOURCE_BLENDING`.
+pub const SUBPIXEL_BLEND: Option<wgpu::BlendState> = Some(wgpu::BlendState {
+    color: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::OneMinusSrc1,
+        operation: wgpu::BlendOperation::Add,
+    },
     alpha: wgpu::BlendComponent {
         src_factor: wgpu::BlendFactor::One,
         dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
@@ -35,6 +106,203 @@ pub const BLEND: Option<wgpu::BlendState> = Some(wgpu::BlendState {
     },
 });
 
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Zeroable, Pod)]
+struct Uniforms {
+    transform: [f32; 16],
+    color_filter: [f32; 16],
+    dither: [f32; 16],
+}
+
+/// Column-major offsets of a 4x4 Bayer dither, spread over one step of an 8
+/// bit channel and centered on zero. All zeros when disabled.
+fn dither_matrix(enabled: bool) -> [f32; 16] {
+    const BAYER: [[f32; 4]; 4] = [
+        [0., 8., 2., 10.],
+        [12., 4., 14., 6.],
+        [3., 11., 1., 9.],
+        [15., 7., 13., 5.],
+    ];
+    let mut matrix = [0.; 16];
+    if enabled {
+        for (y, row) in BAYER.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                matrix[x * 4 + y] = ((value + 0.5) / 16. - 0.5) / 255.;
+            }
+        }
+    }
+    matrix
+}
+
+/// Controls how the `depth` passed to draw calls is used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DepthMode {
+    /// Depth is ignored and draws are layered by submission order, see the
+    /// `Compositor` docs. This is the default.
+    #[default]
+    Submission,
+    /// Depth is written to a depth buffer and a draw is hidden by any draw
+    /// with a greater depth. Equal depths fall back to submission order.
+    /// Depth values must be within `0.0..=1.0` and rendering has to go
+    /// through `render_with_encoder` or `render_to_texture`.
+    Tested,
+}
+
+/// Options a `RichTextBrush` is created with. Each one starts out as its
+/// setter's default and can still be changed later with that setter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RichTextBrushConfig {
+    /// See `RichTextBrush::set_depth_mode`.
+    pub depth_mode: DepthMode,
+    /// See `RichTextBrush::set_load_op`.
+    pub load_op: wgpu::LoadOp<wgpu::Color>,
+    /// See `RichTextBrush::set_color_filter`.
+    pub color_filter: ColorFilter,
+    /// See `RichTextBrush::enable_dither`.
+    pub dither: bool,
+    /// See `RichTextBrush::set_subpixel_text`.
+    pub subpixel_text: bool,
+    /// See `RichTextBrush::set_flip_y`.
+    pub flip_y: bool,
+    /// See `RichTextBrush::set_damage_tracking`.
+    pub damage_tracking: bool,
+    /// See `RichTextBrush::set_staged_uploads`.
+    pub staged_uploads: bool,
+    /// See `RichTextBrush::set_indirect_draws`.
+    pub indirect_draws: bool,
+    /// See `RichTextBrush::set_gpu_culling`.
+    pub gpu_culling: bool,
+}
+
+impl Default for RichTextBrushConfig {
+    fn default() -> Self {
+        Self {
+            depth_mode: DepthMode::default(),
+            load_op: wgpu::LoadOp::Load,
+            color_filter: ColorFilter::None,
+            dither: false,
+            subpixel_text: false,
+            flip_y: false,
+            damage_tracking: false,
+            staged_uploads: false,
+            indirect_draws: false,
+            gpu_culling: false,
+        }
+    }
+}
+
+/// What the device of a context supports among the optional features of
+/// the brush.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BrushCapabilities {
+    /// `wgpu::Features::DUAL_SOURCE_BLENDING`, for subpixel text.
+    pub dual_source_blending: bool,
+    /// `wgpu::Features::INDIRECT_FIRST_INSTANCE`, for indirect draws.
+    pub indirect_draws: bool,
+    /// Compute shaders with enough storage buffers for GPU culling.
+    pub gpu_culling: bool,
+    /// Whether the target holds colors past SDR white, see
+    /// `context::is_extended_range`.
+    pub extended_range: bool,
+    /// Largest side of a texture, which bounds the glyph atlases.
+    pub max_texture_size: u32,
+    /// Whether the target format can be rendered to with blending, which
+    /// the brush can't do without.
+    pub blendable_target: bool,
+}
+
+impl BrushCapabilities {
+    pub fn probe(context: &Context) -> Self {
+        let features = context.device.features();
+        Self {
+            dual_source_blending: features.contains(wgpu::Features::DUAL_SOURCE_BLENDING),
+            indirect_draws: features.contains(wgpu::Features::INDIRECT_FIRST_INSTANCE),
+            gpu_culling: GpuCulling::is_supported(&context.device),
+            extended_range: is_extended_range(context.format),
+            max_texture_size: context.device.limits().max_texture_dimension_2d,
+            blendable_target: {
+                let format = context.format.guaranteed_format_features(features);
+                format
+                    .allowed_usages
+                    .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+                    && format
+                        .flags
+                        .contains(wgpu::TextureFormatFeatureFlags::BLENDABLE)
+            },
+        }
+    }
+}
+
+/// A requirement of the brush the device of a context doesn't meet. Unlike
+/// a `BrushFallback` there is nothing to fall back to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrushError {
+    /// The target format can't be rendered to with blending, e.g.
+    /// `Rgba32Float` or integer formats.
+    UnsupportedFormat(wgpu::TextureFormat),
+}
+
+impl std::fmt::Display for BrushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat(format) => {
+                write!(f, "rich text can't blend into {format:?} targets")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BrushError {}
+
+/// A feature asked for that the device lacks, with what is used instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrushFallback {
+    /// Subpixel text without dual source blending, text stays grayscale.
+    GrayscaleText,
+    /// Indirect draws without `INDIRECT_FIRST_INSTANCE`, draws are direct.
+    DirectDraws,
+    /// GPU culling without compute support or indirect draws, every quad
+    /// is drawn.
+    NoCulling,
+    /// Atlases larger than the device allows, clamped to `used`.
+    AtlasSize { requested: u16, used: u16 },
+}
+
+impl BrushFallback {
+    /// Fallbacks taken for `config` and the atlas sizes of a context on a
+    /// device with `capabilities`.
+    pub fn resolve(
+        config: &RichTextBrushConfig,
+        requested_atlas: (u16, u16),
+        capabilities: &BrushCapabilities,
+    ) -> Vec<Self> {
+        let mut fallbacks = vec![];
+        if config.subpixel_text && !capabilities.dual_source_blending {
+            fallbacks.push(Self::GrayscaleText);
+        }
+        if config.indirect_draws && !capabilities.indirect_draws {
+            fallbacks.push(Self::DirectDraws);
+        }
+        if config.gpu_culling
+            && !(capabilities.gpu_culling
+                && capabilities.indirect_draws
+                && config.indirect_draws)
+        {
+            fallbacks.push(Self::NoCulling);
+        }
+        let (_, used) = atlas_size(requested_atlas, capabilities.max_texture_size);
+        if used < requested_atlas.1 {
+            fallbacks.push(Self::AtlasSize {
+                requested: requested_atlas.1,
+                used,
+            });
+        }
+        fallbacks
+    }
+}
+
 pub struct RichTextBrush {
     vertex_buffer: wgpu::Buffer,
     bind_group: wgpu::BindGroup,
@@ -43,30 +311,86 @@ pub struct RichTextBrush {
     mask_texture_view: wgpu::TextureView,
     transform: wgpu::Buffer,
     bind_group_layout: wgpu::BindGroupLayout,
-    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    subpixel_shader: Option<wgpu::ShaderModule>,
+    pipelines: FnvHashMap<(wgpu::TextureFormat, bool, bool), wgpu::RenderPipeline>,
+    subpixel_text: bool,
+    depth_mode: DepthMode,
+    depth_texture: Option<wgpu::Texture>,
+    load_op: wgpu::LoadOp<wgpu::Color>,
     textures: FnvHashMap<TextureId, Texture>,
     index_buffer: wgpu::Buffer,
     index_buffer_size: u64,
-    current_transform: [f32; 16],
+    current_uniforms: Uniforms,
+    color_filter: ColorFilter,
+    dither: bool,
+    crt: Option<CrtPass>,
+    post_process: Option<PostProcess>,
+    #[cfg(feature = "bloom")]
+    bloom: Option<Bloom>,
+    user_transform: [f32; 16],
+    rotation: Rotation,
+    flip_y: bool,
+    size: Option<(f32, f32)>,
     comp: Compositor,
     draw_layout_cache: DrawLayoutCache,
     dlist: DisplayList,
+    previous_dlist: DisplayList,
+    damage_tracking: bool,
+    damage: Option<Rect>,
     bind_group_needs_update: bool,
     first_run: bool,
     supported_vertex_buffer: usize,
+    staged_uploads: bool,
+    staging: UploadStaging,
+    staging_buffer: Option<wgpu::Buffer>,
+    upload_stats: UploadStats,
+    indirect_draws: bool,
+    indirect_buffer: Option<wgpu::Buffer>,
+    gpu_culling: bool,
+    culling: Option<GpuCulling>,
+    capabilities: BrushCapabilities,
 }
 
 impl RichTextBrush {
-    pub fn new(context: &Context) -> Self {
+    /// Creates a brush with `config`. Features the device of `context`
+    /// lacks fall back as `BrushFallback` describes, the brush works without
+    /// them and is returned along with the fallbacks taken. Requirements
+    /// without a fallback fail with a `BrushError` instead of in `wgpu`
+    /// validation once the brush renders.
+    pub fn new(
+        context: &Context,
+        config: &RichTextBrushConfig,
+    ) -> Result<(Self, Vec<BrushFallback>), BrushError> {
+        let capabilities = BrushCapabilities::probe(context);
+        if !capabilities.blendable_target {
+            return Err(BrushError::UnsupportedFormat(context.format));
+        }
+        let fallbacks = BrushFallback::resolve(config, context.atlas_size, &capabilities);
+        Ok((Self::with_config(context, config), fallbacks))
+    }
+
+    /// What the device the brush was created on supports, to tune its
+    /// config per machine.
+    #[inline]
+    pub fn capabilities(&self) -> BrushCapabilities {
+        self.capabilities
+    }
+
+    fn with_config(context: &Context, config: &RichTextBrushConfig) -> Self {
         let device = &context.device;
         let dlist = DisplayList::new();
         let supported_vertex_buffer = 2_000;
 
-        let current_transform =
-            orthographic_projection(context.size.width, context.size.height);
+        let current_uniforms = Uniforms {
+            transform: orthographic_projection(context.size.width, context.size.height),
+            color_filter: IDENTITY_MATRIX,
+            dither: dither_matrix(false),
+        };
         let transform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&current_transform),
+            contents: bytemuck::bytes_of(&current_uniforms),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
@@ -77,15 +401,14 @@ impl RichTextBrush {
                 entries: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: wgpu::ShaderStages::VERTEX,
+                        visibility: wgpu::ShaderStages::VERTEX
+                            | wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Buffer {
                             ty: wgpu::BufferBindingType::Uniform,
                             has_dynamic_offset: false,
-                            min_binding_size: wgpu::BufferSize::new(mem::size_of::<
-                                [f32; 16],
-                            >(
-                            )
-                                as wgpu::BufferAddress),
+                            min_binding_size: wgpu::BufferSize::new(
+                                mem::size_of::<Uniforms>() as wgpu::BufferAddress,
+                            ),
                         },
                         count: None,
                     },
@@ -216,45 +539,25 @@ impl RichTextBrush {
             ))),
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            cache: None,
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: mem::size_of::<Vertex>() as u64,
-                    // https://docs.rs/wgpu/latest/wgpu/enum.VertexStepMode.html
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array!(
-                        0 => Float32x4,
-                        1 => Float32x4,
-                        2 => Float32x2,
-                    ),
-                }],
-            },
-            fragment: Some(wgpu::FragmentState {
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: context.format,
-                    blend: BLEND,
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState::default(),
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState::default(),
-            multiview: None,
-        });
+        let mut pipelines = FnvHashMap::default();
+        pipelines.insert(
+            (context.format, false, false),
+            create_pipeline(
+                device,
+                &pipeline_layout,
+                &shader,
+                context.format,
+                false,
+                false,
+            ),
+        );
 
         let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("rich_text::Instances Buffer"),
             size: mem::size_of::<Vertex>() as u64 * supported_vertex_buffer as u64,
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            usage: wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::COPY_DST
+                | storage_usage(device),
             mapped_at_creation: false,
         });
 
@@ -263,7 +566,9 @@ impl RichTextBrush {
         let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("rich_text::Indices Buffer"),
             size: index_buffer_size,
-            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+            usage: wgpu::BufferUsages::INDEX
+                | wgpu::BufferUsages::COPY_DST
+                | storage_usage(device),
             mapped_at_creation: false,
         });
 
@@ -275,17 +580,52 @@ impl RichTextBrush {
             mask_texture_view,
             sampler,
             textures: FnvHashMap::default(),
-            comp: Compositor::new(2048),
+            comp: {
+                let limit = context.device.limits().max_texture_dimension_2d;
+                let (initial_size, max_size) = atlas_size(context.atlas_size, limit);
+                let mut comp = Compositor::with_atlas_size(initial_size, max_size);
+                comp.set_extended_range(is_extended_range(context.format));
+                comp
+            },
             draw_layout_cache: DrawLayoutCache::default(),
             dlist,
+            previous_dlist: DisplayList::new(),
+            damage_tracking: config.damage_tracking,
+            damage: None,
             bind_group,
             transform,
-            pipeline,
+            pipeline_layout,
+            shader,
+            subpixel_shader: None,
+            pipelines,
+            subpixel_text: config.subpixel_text,
+            depth_mode: config.depth_mode,
+            depth_texture: None,
+            load_op: config.load_op,
             vertex_buffer,
             first_run: true,
             bind_group_needs_update: true,
             supported_vertex_buffer,
-            current_transform,
+            staged_uploads: config.staged_uploads,
+            staging: UploadStaging::default(),
+            staging_buffer: None,
+            upload_stats: UploadStats::default(),
+            indirect_draws: config.indirect_draws,
+            indirect_buffer: None,
+            gpu_culling: config.gpu_culling,
+            capabilities: BrushCapabilities::probe(context),
+            culling: None,
+            current_uniforms,
+            color_filter: config.color_filter,
+            dither: config.dither,
+            crt: None,
+            post_process: None,
+            #[cfg(feature = "bloom")]
+            bloom: None,
+            user_transform: IDENTITY_MATRIX,
+            flip_y: config.flip_y,
+            size: None,
+            rotation: Rotation::None,
         }
     }
 
@@ -297,33 +637,57 @@ impl RichTextBrush {
     ) {
         // let start = std::time::Instant::now();
 
+        mem::swap(&mut self.dlist, &mut self.previous_dlist);
+
         if state.compositors.advanced.render_data.is_empty() {
             self.dlist.clear();
+            self.update_damage();
             return;
         }
 
-        // Render
-        self.comp.begin();
+        self.set_scale(state.current.layout.dimensions.scale);
+
+        if self.comp.atlas_fragmentation() > DEFRAGMENT_THRESHOLD
+            && self.comp.defragment_atlas()
+        {
+            self.draw_layout_cache.clear();
+        }
 
         let library = state.compositors.advanced.font_library();
         let font_library = { &library.inner.read().unwrap() };
 
-        draw_layout(
-            &mut self.comp,
-            &state.compositors.advanced.render_data,
-            state.current.layout.style.screen_position.0,
-            // TODO: Fix position
-            state.current.layout.style.screen_position.1,
-            font_library,
-            state.current.layout.dimensions,
-            &mut self.draw_layout_cache,
-        );
+        // Render, again if the atlases grew while drawing since everything
+        // drawn before has stale texture coordinates
+        loop {
+            self.comp.begin();
+            let (width, height) = self
+                .rotation
+                .content_size(state.current.layout.width, state.current.layout.height);
+            self.comp
+                .draw_background_image(Rect::new(0., 0., width, height));
+
+            draw_layout(
+                &mut self.comp,
+                &state.compositors.advanced.render_data,
+                state.current.layout.style.screen_position.0,
+                // TODO: Fix position
+                state.current.layout.style.screen_position.1,
+                font_library,
+                state.current.layout.dimensions,
+                &mut self.draw_layout_cache,
+            );
+            if !self.comp.take_atlas_resized() {
+                break;
+            }
+            self.draw_layout_cache.clear();
+        }
         self.draw_layout_cache.clean();
         // let duration = start.elapsed();
         // println!(" - rich_text::prepare::draw_layout() is: {:?}", duration);
 
         self.dlist.clear();
         self.finish_composition(ctx);
+        self.update_damage();
 
         // let duration = start.elapsed();
         // println!(" - rich_text::prepare() is: {:?}", duration);
@@ -351,6 +715,333 @@ impl RichTextBrush {
         }
     }
 
+    /// Adds an image to the compositor image cache.
+    pub fn add_image(&mut self, request: AddImage) -> Option<ImageId> {
+        self.comp.add_image(request)
+    }
+
+    /// Draws `image` beneath all text, scaled to the viewport per `fit`.
+    pub fn set_background_image(&mut self, image: ImageId, fit: Fit) {
+        self.comp.set_background_image(image, fit);
+    }
+
+    /// Removes the image set by `set_background_image`.
+    pub fn clear_background_image(&mut self) {
+        self.comp.clear_background_image();
+    }
+
+    /// Drops the rects cached for lines of the layout, for changes to how
+    /// lines are shaped that their hash doesn't capture, like the font
+    /// features of the layout.
+    pub fn clear_layout_cache(&mut self) {
+        self.draw_layout_cache.clear();
+    }
+
+    /// Scales the alpha of cell backgrounds while leaving glyphs opaque.
+    ///
+    /// Output colors are premultiplied by the shader, and destination
+    /// alpha is accumulated as `src + dst * (1 - src)`, so clearing the pass
+    /// with a transparent color keeps the window translucent. The surface
+    /// must be configured with `wgpu::CompositeAlphaMode::PreMultiplied`
+    /// for the compositor behind the window to show through correctly.
+    pub fn set_background_opacity(&mut self, opacity: f32) {
+        self.comp.set_background_opacity(opacity);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Sets the bias added to glyph y positions before they are snapped to
+    /// whole pixels, `0.5` (rounding to the nearest pixel) by default.
+    pub fn set_vertical_subpixel_bias(&mut self, bias: f32) {
+        self.comp.set_vertical_subpixel_bias(bias);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Draws bold runs of the eight base ANSI colors with their bright
+    /// variant, the classic terminal behavior.
+    pub fn set_bold_is_bright(&mut self, enabled: bool) {
+        self.comp.set_bold_is_bright(enabled);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Sets the bright ANSI colors used by `set_bold_is_bright`, defaults to
+    /// [`color::DEFAULT_BRIGHT_PALETTE`].
+    pub fn set_bright_palette(&mut self, palette: [[f32; 4]; 8]) {
+        self.comp.set_bright_palette(palette);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Sets the minimum WCAG contrast ratio between glyphs and their run
+    /// background, `None` draws colors as they are.
+    pub fn set_min_contrast(&mut self, min_contrast: Option<f32>) {
+        self.comp.set_min_contrast(min_contrast);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Forces every run to the given colors, ignoring their own ones, for
+    /// high contrast themes. `None` restores the run colors.
+    pub fn set_forced_colors(&mut self, forced_colors: Option<ForcedColors>) {
+        self.comp.set_forced_colors(forced_colors);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Packs the texture uploads of a frame, e.g. new glyphs and images, into
+    /// a staging buffer copied to the textures in one submission, rather
+    /// than writing each one to the queue separately. Disabled by default.
+    pub fn set_staged_uploads(&mut self, enabled: bool) {
+        self.staged_uploads = enabled;
+    }
+
+    /// Issues the draws of a frame with `draw_indexed_indirect`, reading
+    /// their arguments from a buffer instead of passing them from the CPU,
+    /// so a GPU pass can later cull or compact them in place. The buffer is
+    /// filled on the CPU for now. Only takes effect on devices with
+    /// `wgpu::Features::INDIRECT_FIRST_INSTANCE`. Disabled by default.
+    pub fn set_indirect_draws(&mut self, enabled: bool) {
+        self.indirect_draws = enabled;
+    }
+
+    /// Returns whether draws are issued indirectly on `ctx`, see
+    /// `set_indirect_draws`.
+    #[inline]
+    pub fn is_indirect_draws(&self, ctx: &Context) -> bool {
+        self.indirect_draws
+            && ctx
+                .device
+                .features()
+                .contains(wgpu::Features::INDIRECT_FIRST_INSTANCE)
+    }
+
+    /// Drops the quads outside the viewport in a compute pass before they
+    /// are drawn, which saves vertex work when most of a large grid is
+    /// scrolled out of view. The order of the remaining quads is kept. Only
+    /// takes effect along with `set_indirect_draws`, on devices that run
+    /// compute shaders, and without a transform or rotation. Disabled by
+    /// default.
+    pub fn set_gpu_culling(&mut self, enabled: bool) {
+        self.gpu_culling = enabled;
+    }
+
+    /// Returns whether offscreen quads are culled on `ctx`, see
+    /// `set_gpu_culling`.
+    #[inline]
+    pub fn is_gpu_culling(&self, ctx: &Context) -> bool {
+        self.gpu_culling
+            && self.is_indirect_draws(ctx)
+            && GpuCulling::is_supported(&ctx.device)
+    }
+
+    /// Blends glyph masks with the coverage of each channel instead of a
+    /// single one. Only takes effect on devices with
+    /// `wgpu::Features::DUAL_SOURCE_BLENDING`, text stays grayscale
+    /// otherwise. Disabled by default.
+    pub fn set_subpixel_text(&mut self, enabled: bool) {
+        self.subpixel_text = enabled;
+    }
+
+    /// Returns whether masks are blended per channel on `ctx`, see
+    /// `set_subpixel_text`.
+    #[inline]
+    pub fn is_subpixel_text(&self, ctx: &Context) -> bool {
+        self.subpixel_text
+            && ctx
+                .device
+                .features()
+                .contains(wgpu::Features::DUAL_SOURCE_BLENDING)
+    }
+
+    /// Texture uploads done by the last `prepare`.
+    #[inline]
+    pub fn upload_stats(&self) -> UploadStats {
+        self.upload_stats
+    }
+
+    /// Glyph cache activity of the last `prepare`. A high miss rate from
+    /// one frame to the next points at an atlas too small for the screen.
+    #[inline]
+    pub fn glyph_cache_stats(&self) -> GlyphCacheStats {
+        self.comp.glyph_cache_stats()
+    }
+
+    /// Glyph cache activity since the brush was created.
+    #[inline]
+    pub fn total_glyph_cache_stats(&self) -> GlyphCacheStats {
+        self.comp.total_glyph_cache_stats()
+    }
+
+    /// Blinks the cursor with `blink`, or keeps it visible with `None`.
+    pub fn set_cursor_blink(&mut self, blink: Option<CursorBlink>) {
+        self.comp.set_cursor_blink(blink);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Restarts the blink with the cursor visible if it is set to reset on
+    /// moves. Call it when the cursor moves or on input.
+    pub fn cursor_moved(&mut self, now: Instant) {
+        if let Some(blink) = self.comp.cursor_blink_mut() {
+            blink.cursor_moved(now);
+        }
+    }
+
+    /// Resolves whether the cursor is drawn in the frame at `now`. Call it
+    /// before rendering; returns true when the cursor just showed or hid.
+    pub fn update_cursor_blink(&mut self, now: Instant) -> bool {
+        let changed = self.comp.update_cursor_blink(now);
+        if changed {
+            self.draw_layout_cache.clear_cursors();
+        }
+        changed
+    }
+
+    /// Sets the number of cells between tab stops, 8 by default. Tabs in a
+    /// run advance to the next stop, which moves the glyphs after them and
+    /// widens the run background and underline. 0 disables the expansion.
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.comp.set_tab_width(width);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Fits glyph outlines to the pixel grid with `hinting`, see
+    /// `Hinting`. `Hinting::Slight` by default.
+    pub fn set_hinting(&mut self, hinting: Hinting) {
+        self.comp.set_hinting(hinting);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Centers glyphs vertically in lines taller than the font, as with a
+    /// line height over 1.0, instead of leaving the extra space below them.
+    /// Backgrounds and cursors still fill the line. Disabled by default.
+    pub fn set_center_glyphs(&mut self, enabled: bool) {
+        self.comp.set_center_glyphs(enabled);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Widens thin glyph stems at small sizes, more the smaller they are,
+    /// so light fonts hold up without subpixel rendering. `intensity` of
+    /// 1.0 darkens as much as FreeType. Disabled by default.
+    pub fn set_stem_darkening(&mut self, enabled: bool, intensity: f32) {
+        self.comp.set_stem_darkening(enabled, intensity);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Draws box-drawing and block characters with rects sized to the cell,
+    /// `false` falls back to the font's own glyphs.
+    pub fn set_box_drawing(&mut self, enabled: bool) {
+        self.comp.set_box_drawing(enabled);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Aligns cursors and underlines to physical pixels so thin ones stay
+    /// crisp at fractional scales. Disabled by default.
+    pub fn set_pixel_snapping(&mut self, enabled: bool) {
+        self.comp.set_pixel_snapping(enabled);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Draws the powerline separator `ch` (U+E0B0 to U+E0B7) with
+    /// primitives sized to the cell rather than the font's glyph.
+    pub fn set_powerline(&mut self, ch: char, enabled: bool) {
+        self.comp.set_powerline(ch, enabled);
+        self.draw_layout_cache.clear();
+    }
+
+    /// Sets a color matrix applied to everything the brush draws, to check
+    /// how a theme reads with color vision deficiencies for example.
+    pub fn set_color_filter(&mut self, filter: ColorFilter) {
+        self.color_filter = filter;
+    }
+
+    /// Dithers everything the brush draws, hiding the banding of gradients
+    /// and translucent fills on 8 bit targets. Has no effect on extended
+    /// range targets. Disabled by default.
+    pub fn enable_dither(&mut self, enabled: bool) {
+        self.dither = enabled;
+    }
+
+    #[inline]
+    pub fn color_filter(&self) -> ColorFilter {
+        self.color_filter
+    }
+
+    /// Sets a column-major matrix applied to every vertex in pixel space,
+    /// before the orthographic projection. Use it for rotation, flips or a
+    /// custom camera; the identity matrix keeps the default behavior.
+    ///
+    /// Glyphs are rasterized at their untransformed size, so integer
+    /// translations and right-angle rotations stay crisp while scaling
+    /// will resample the atlas. To zoom, prefer changing the font size.
+    pub fn set_transform(&mut self, matrix: [f32; 16]) {
+        self.user_transform = matrix;
+    }
+
+    /// Rotates everything drawn by the brush (glyphs, backgrounds, cursors
+    /// and decorations) clockwise onto the viewport. The rotation is applied
+    /// after the transform given to `set_transform`.
+    ///
+    /// Quarter turns swap the axes: content is laid out at
+    /// `rotation.content_size(width, height)`, e.g. the columns and lines
+    /// of a 800x600 window turned by 90 degrees fit in 600x800, and that
+    /// area is mapped onto the whole viewport.
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    /// Flips the y axis of the projection. By default the origin is at the
+    /// top left of the target and y grows downwards; when flipped the origin
+    /// is at the bottom left and y grows upwards.
+    ///
+    /// Only the projection changes, layout and glyph placement still assume
+    /// a top left origin, so the frame comes out mirrored. That is what
+    /// targets read with a bottom left origin (e.g. textures shared with
+    /// OpenGL) expect, without callers transforming every coordinate.
+    pub fn set_flip_y(&mut self, flip_y: bool) {
+        self.flip_y = flip_y;
+    }
+
+    /// Sets the scale factor from logical to physical pixels, e.g. when the
+    /// window moves to a monitor of another DPI. Cached `CachedRect`
+    /// geometry was laid out at the old scale, so it is dropped and the
+    /// next `prepare` lays everything out again; glyphs are rasterized at
+    /// their new physical size as they are cached by size. `prepare` also
+    /// follows the scale of the layout it renders.
+    pub fn set_scale(&mut self, scale: f32) {
+        if self.comp.scale() != scale {
+            self.comp.set_scale(scale);
+            self.draw_layout_cache.clear();
+        }
+    }
+
+    /// Sets the size of the target, whose projection is written to the
+    /// uniforms by the next render. The pipelines, buffers and atlases
+    /// don't depend on it and are kept. Until the first call the size comes
+    /// from the layout being rendered.
+    pub fn resize(&mut self, width: f32, height: f32) {
+        self.size = Some((width, height));
+    }
+
+    /// Returns whether the y axis is flipped, see `set_flip_y`.
+    #[inline]
+    pub fn flip_y(&self) -> bool {
+        self.flip_y
+    }
+
+    /// Returns the transform set by `set_transform`.
+    #[inline]
+    pub fn transform(&self) -> [f32; 16] {
+        self.user_transform
+    }
+
+    /// Returns the display list produced by the last `prepare`, for hosts
+    /// drawing or inspecting it with their own renderer.
+    #[inline]
+    pub fn display_list(&self) -> &DisplayList {
+        &self.dlist
+    }
+
+    /// Returns an SVG snapshot of the geometry produced by the last `prepare`.
+    pub fn to_svg(&self) -> String {
+        self.dlist.to_svg()
+    }
+
     #[inline]
     pub fn render<'pass>(
         &'pass mut self,
@@ -358,69 +1049,494 @@ impl RichTextBrush {
         state: &crate::sugarloaf::state::SugarState,
         rpass: &mut wgpu::RenderPass<'pass>,
     ) {
-        // let start = std::time::Instant::now();
-        let vertices: &[Vertex] = self.dlist.vertices();
-        let indices: &[u32] = self.dlist.indices();
+        let format = ctx.format;
+        self.render_with_format(ctx, state, rpass, format);
+    }
 
-        // There's nothing to render
-        if vertices.is_empty() {
-            return;
-        }
+    /// Renders into a standalone texture, e.g. to cache a pane that is
+    /// later composited. Creates and submits its own command encoder.
+    pub fn render_to_texture(
+        &mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        target: &wgpu::Texture,
+        clear_color: wgpu::Color,
+    ) {
+        let mut encoder = ctx
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        self.encode_pass(
+            ctx,
+            state,
+            &mut encoder,
+            target,
+            wgpu::LoadOp::Clear(clear_color),
+        );
+        ctx.queue.submit(Some(encoder.finish()));
+    }
 
-        let queue = &mut ctx.queue;
+    /// Records a render pass of its own into `encoder`, loading the target
+    /// with the operation set by `set_load_op` (`Load` by default). Unlike
+    /// `render`, this attaches a depth buffer when `DepthMode::Tested` is
+    /// enabled, so use it whenever depth testing is on.
+    pub fn render_with_encoder(
+        &mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::Texture,
+    ) {
+        let load = self.load_op;
+        self.encode_pass(ctx, state, encoder, target, load);
+    }
 
-        let transform = orthographic_projection(
-            state.current.layout.width,
-            state.current.layout.height,
-        );
-        let transform_has_changed = transform != self.current_transform;
+    /// Sets how `render_with_encoder` loads its target: `Clear(color)` paints
+    /// the terminal background in the same pass, `Load` keeps the existing
+    /// content to render as an overlay.
+    pub fn set_load_op(&mut self, load: wgpu::LoadOp<wgpu::Color>) {
+        self.load_op = load;
+    }
 
-        if transform_has_changed {
-            queue.write_buffer(&self.transform, 0, bytemuck::bytes_of(&transform));
-            self.current_transform = transform;
-        }
+    /// Shorthand for `set_load_op(wgpu::LoadOp::Clear(color))`.
+    pub fn set_clear_color(&mut self, color: wgpu::Color) {
+        self.set_load_op(wgpu::LoadOp::Clear(color));
+    }
 
-        if vertices.len() > self.supported_vertex_buffer {
-            self.vertex_buffer.destroy();
+    #[inline]
+    pub fn load_op(&self) -> wgpu::LoadOp<wgpu::Color> {
+        self.load_op
+    }
 
-            self.supported_vertex_buffer = vertices.len();
-            self.vertex_buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("sugarloaf::rich_text::Pipeline instances"),
-                size: mem::size_of::<Vertex>() as u64
-                    * self.supported_vertex_buffer as u64,
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-                mapped_at_creation: false,
-            });
+    /// Applies a CRT look (scanlines and curvature) in a separate full
+    /// screen pass: text is rendered into an intermediate texture which is
+    /// then drawn to the target. Only `render_with_encoder` and
+    /// `render_to_texture` go through it, `render` records into a pass it
+    /// doesn't own.
+    pub fn enable_crt_effect(&mut self, ctx: &Context, params: CrtParams) {
+        match &mut self.crt {
+            Some(crt) => crt.set_params(params),
+            None => self.crt = Some(CrtPass::new(ctx, params)),
         }
+    }
 
-        let vertices_bytes: &[u8] = bytemuck::cast_slice(vertices);
-        if !vertices_bytes.is_empty() {
-            queue.write_buffer(&self.vertex_buffer, 0, vertices_bytes);
-        }
+    pub fn disable_crt_effect(&mut self) {
+        self.crt = None;
+    }
 
-        let indices_raw: &[u8] = bytemuck::cast_slice(indices);
-        let indices_raw_size = indices_raw.len() as u64;
+    /// Makes bright text glow: pixels with a luminance above `threshold`
+    /// (`0.0` to `1.0`) are blurred over `radius` pixels and added back,
+    /// scaled by `intensity`. It runs before the CRT effect and custom
+    /// post-process and, like them, `render` skips it.
+    #[cfg(feature = "bloom")]
+    pub fn enable_bloom(
+        &mut self,
+        ctx: &Context,
+        threshold: f32,
+        intensity: f32,
+        radius: f32,
+    ) {
+        match &mut self.bloom {
+            Some(bloom) => bloom.set_params(threshold, intensity, radius),
+            None => self.bloom = Some(Bloom::new(ctx, threshold, intensity, radius)),
+        }
+    }
 
-        if self.index_buffer_size >= indices_raw_size {
-            queue.write_buffer(&self.index_buffer, 0, indices_raw);
-        } else {
-            self.index_buffer.destroy();
+    #[cfg(feature = "bloom")]
+    pub fn disable_bloom(&mut self) {
+        self.bloom = None;
+    }
 
-            let size = next_copy_buffer_size(indices_raw_size);
-            let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("rich_text::Indices"),
-                size,
-                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-                mapped_at_creation: true,
-            });
-            buffer.slice(..).get_mapped_range_mut()[..indices_raw.len()]
-                .copy_from_slice(indices_raw);
-            buffer.unmap();
+    /// Runs `shader` on the rendered frame, after the CRT effect when both
+    /// are enabled. See [`PostProcessShader`] for the interface the shader
+    /// has to follow; it's validated here and left unchanged on error.
+    /// `None` removes it. Like the CRT effect, `render` skips it.
+    pub fn set_post_process(
+        &mut self,
+        ctx: &Context,
+        shader: Option<PostProcessShader>,
+    ) -> Result<(), PostProcessError> {
+        self.post_process = match shader {
+            Some(shader) => Some(PostProcess::new(ctx, &shader)?),
+            None => None,
+        };
+        Ok(())
+    }
 
-            self.index_buffer = buffer;
-            self.index_buffer_size = size;
+    fn encode_pass(
+        &mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::Texture,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        let view = &target.create_view(&wgpu::TextureViewDescriptor::default());
+        let format = target.format();
+        #[cfg(feature = "bloom")]
+        let mut bloom = self.bloom.take();
+        let mut crt = self.crt.take();
+        let mut post_process = self.post_process.take();
+        let mut stages: Vec<&mut dyn Stage> = Vec::new();
+        #[cfg(feature = "bloom")]
+        if let Some(bloom) = bloom.as_mut() {
+            stages.push(bloom);
+        }
+        if let Some(crt) = crt.as_mut() {
+            stages.push(crt.pass_mut());
+        }
+        if let Some(post_process) = post_process.as_mut() {
+            stages.push(post_process);
+        }
+        if stages.is_empty() {
+            let size = (target.width(), target.height());
+            self.encode_text_pass(ctx, state, encoder, view, size, format, load);
+            return;
         }
 
+        // Intermediate textures only hold text, loading them would keep
+        // the previous frame unless damage tracking relies on it
+        let source_load = match load {
+            wgpu::LoadOp::Load if !self.damage_tracking => {
+                wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT)
+            }
+            load => load,
+        };
+        // Sources are sized like the context
+        let size = (
+            (ctx.size.width as u32).max(1),
+            (ctx.size.height as u32).max(1),
+        );
+        let source = stages[0].source_view(ctx, format);
+        self.encode_text_pass(ctx, state, encoder, source, size, format, source_load);
+
+        // Each stage draws into the source of the next one, the last one
+        // into the target
+        let last = stages.len() - 1;
+        for index in 0..last {
+            let (current, next) = stages.split_at_mut(index + 1);
+            let target = next[0].source_view(ctx, format);
+            current[index].render(
+                ctx,
+                encoder,
+                target,
+                format,
+                wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            );
+        }
+        stages[last].render(ctx, encoder, view, format, load);
+
+        #[cfg(feature = "bloom")]
+        {
+            self.bloom = bloom;
+        }
+        self.crt = crt;
+        self.post_process = post_process;
+    }
+
+    /// Renders into `view`, of `size` pixels, which the depth buffer
+    /// attached to it has to match.
+    #[allow(clippy::too_many_arguments)]
+    fn encode_text_pass(
+        &mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        size: (u32, u32),
+        format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        let depth_tested = self.depth_mode == DepthMode::Tested;
+        let depth_view = if depth_tested {
+            Some(self.depth_view(ctx, size))
+        } else {
+            None
+        };
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            timestamp_writes: None,
+            occlusion_query_set: None,
+            label: Some("rich_text::encode_pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: depth_view.as_ref().map(|view| {
+                wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0.0),
+                        store: wgpu::StoreOp::Discard,
+                    }),
+                    stencil_ops: None,
+                }
+            }),
+        });
+        self.render_pass(ctx, state, &mut rpass, format, depth_tested);
+    }
+
+    /// When enabled, rendering is restricted with a scissor to the region
+    /// that changed since the previous `prepare` and skipped entirely when
+    /// nothing changed. Only use it when the target keeps its content
+    /// between frames, e.g. a cached texture loaded with `LoadOp::Load`,
+    /// since everything outside the damage is left untouched. The damage is
+    /// transformed like the vertices, so the scissor follows `set_transform`,
+    /// `set_rotation` and `set_flip_y`.
+    pub fn set_damage_tracking(&mut self, enabled: bool) {
+        self.damage_tracking = enabled;
+    }
+
+    /// Returns the region that changed in the last `prepare`, in vertex
+    /// coordinates, or `None` if the output is identical to the previous
+    /// frame. Only computed while damage tracking is enabled, `None`
+    /// otherwise.
+    #[inline]
+    pub fn damage(&self) -> Option<Rect> {
+        self.damage
+    }
+
+    // Diffing the display lists walks both of them, only do it when the
+    // scissor relies on it
+    #[inline]
+    fn update_damage(&mut self) {
+        self.damage = if self.damage_tracking {
+            self.dlist.damage(&self.previous_dlist)
+        } else {
+            None
+        };
+    }
+
+    /// Selects whether the `depth` given to draw calls controls occlusion.
+    /// See `DepthMode`.
+    pub fn set_depth_mode(&mut self, mode: DepthMode) {
+        self.depth_mode = mode;
+    }
+
+    #[inline]
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+
+    fn depth_view(&mut self, ctx: &Context, size: (u32, u32)) -> wgpu::TextureView {
+        let needs_texture = match &self.depth_texture {
+            Some(texture) => (texture.width(), texture.height()) != size,
+            None => true,
+        };
+        if needs_texture {
+            self.depth_texture =
+                Some(ctx.device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("rich_text::Depth"),
+                    size: wgpu::Extent3d {
+                        width: size.0.max(1),
+                        height: size.1.max(1),
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: DEPTH_FORMAT,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    view_formats: &[],
+                }));
+        }
+        self.depth_texture
+            .as_ref()
+            .unwrap()
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Same as `render` but targets a render pass whose color attachment
+    /// uses `format`. Pipelines are created lazily and cached per format.
+    pub fn render_with_format<'pass>(
+        &'pass mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        rpass: &mut wgpu::RenderPass<'pass>,
+        format: wgpu::TextureFormat,
+    ) {
+        self.render_pass(ctx, state, rpass, format, false);
+    }
+
+    fn render_pass<'pass>(
+        &'pass mut self,
+        ctx: &mut Context,
+        state: &crate::sugarloaf::state::SugarState,
+        rpass: &mut wgpu::RenderPass<'pass>,
+        format: wgpu::TextureFormat,
+        depth_tested: bool,
+    ) {
+        let subpixel = self.is_subpixel_text(ctx);
+        let key = (format, depth_tested, subpixel);
+        let indirect = self.is_indirect_draws(ctx);
+        let culling = self.is_gpu_culling(ctx);
+        if let std::collections::hash_map::Entry::Vacant(entry) =
+            self.pipelines.entry(key)
+        {
+            log::info!(
+                "rich_text::create_pipeline for format {:?} (depth tested: {}, subpixel: {})",
+                format,
+                depth_tested,
+                subpixel
+            );
+            let shader = if subpixel {
+                // Only built on devices with dual-source blending, the
+                // module doesn't validate anywhere else
+                self.subpixel_shader.get_or_insert_with(|| {
+                    ctx.device
+                        .create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("rich_text::subpixel"),
+                            source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+                                subpixel_source(),
+                            )),
+                        })
+                })
+            } else {
+                &self.shader
+            };
+            entry.insert(create_pipeline(
+                &ctx.device,
+                &self.pipeline_layout,
+                shader,
+                format,
+                depth_tested,
+                subpixel,
+            ));
+        }
+
+        // let start = std::time::Instant::now();
+        let vertices: &[Vertex] = self.dlist.vertices();
+        let indices: &[u32] = self.dlist.indices();
+
+        // There's nothing to render
+        if vertices.is_empty() {
+            return;
+        }
+
+        let queue = &mut ctx.queue;
+
+        let (width, height) = self
+            .size
+            .unwrap_or((state.current.layout.width, state.current.layout.height));
+        let uniforms = Uniforms {
+            transform: frame_transform(
+                width,
+                height,
+                self.flip_y,
+                self.rotation,
+                &self.user_transform,
+            ),
+            color_filter: self.color_filter.matrix(),
+            dither: dither_matrix(self.dither),
+        };
+
+        if uniforms != self.current_uniforms {
+            queue.write_buffer(&self.transform, 0, bytemuck::bytes_of(&uniforms));
+            self.current_uniforms = uniforms;
+        }
+
+        // One vertex and one index buffer are enough: `write_buffer` copies
+        // into staging memory that wgpu copies from ahead of the draws, so
+        // writing buffers a previous frame still reads doesn't wait on the
+        // GPU. Rotating three of them measured no faster, see
+        // `bench_frame_uploads`.
+        if vertices.len() > self.supported_vertex_buffer {
+            self.vertex_buffer.destroy();
+
+            self.supported_vertex_buffer = vertices.len();
+            self.vertex_buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("sugarloaf::rich_text::Pipeline instances"),
+                size: mem::size_of::<Vertex>() as u64
+                    * self.supported_vertex_buffer as u64,
+                usage: wgpu::BufferUsages::VERTEX
+                    | wgpu::BufferUsages::COPY_DST
+                    | storage_usage(&ctx.device),
+                mapped_at_creation: false,
+            });
+        }
+
+        let vertices_bytes: &[u8] = bytemuck::cast_slice(vertices);
+        if !vertices_bytes.is_empty() {
+            queue.write_buffer(&self.vertex_buffer, 0, vertices_bytes);
+        }
+
+        let indices_raw: &[u8] = bytemuck::cast_slice(indices);
+        let indices_raw_size = indices_raw.len() as u64;
+
+        if self.index_buffer_size >= indices_raw_size {
+            queue.write_buffer(&self.index_buffer, 0, indices_raw);
+        } else {
+            self.index_buffer.destroy();
+
+            let size = next_copy_buffer_size(indices_raw_size);
+            let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("rich_text::Indices"),
+                size,
+                usage: wgpu::BufferUsages::INDEX
+                    | wgpu::BufferUsages::COPY_DST
+                    | storage_usage(&ctx.device),
+                mapped_at_creation: true,
+            });
+            buffer.slice(..).get_mapped_range_mut()[..indices_raw.len()]
+                .copy_from_slice(indices_raw);
+            buffer.unmap();
+
+            self.index_buffer = buffer;
+            self.index_buffer_size = size;
+        }
+
+        if indirect {
+            let args = indirect_args(self.dlist.draws());
+            let size = args.len() as u64;
+            if size > 0 {
+                if self
+                    .indirect_buffer
+                    .as_ref()
+                    .map_or(true, |buffer| buffer.size() < size)
+                {
+                    trace_event!(
+                        debug,
+                        "rich_text: indirect buffer grew to {size} bytes"
+                    );
+                    self.indirect_buffer =
+                        Some(ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                            label: Some("rich_text::Indirect"),
+                            size: next_copy_buffer_size(size),
+                            usage: wgpu::BufferUsages::INDIRECT
+                                | wgpu::BufferUsages::COPY_DST
+                                | storage_usage(&ctx.device),
+                            mapped_at_creation: false,
+                        }));
+                }
+                if let Some(buffer) = &self.indirect_buffer {
+                    queue.write_buffer(buffer, 0, &args);
+                }
+            }
+        }
+
+        // Quads are culled in vertex coordinates, which only match the
+        // target without a transform
+        let culled = culling
+            && self.user_transform == IDENTITY_MATRIX
+            && self.rotation == Rotation::None
+            && match (&self.indirect_buffer, &mut self.culling) {
+                (Some(args), culling) => culling
+                    .get_or_insert_with(|| GpuCulling::new(&ctx.device))
+                    .cull(
+                        &ctx.device,
+                        queue,
+                        &self.vertex_buffer,
+                        &self.index_buffer,
+                        args,
+                        self.dlist.draws(),
+                        Rect::new(0., 0., width, height),
+                    ),
+                (None, _) => false,
+            };
+
         let mut color_texture_updated: Option<&TextureId> = None;
         let mut mask_texture_updated: Option<&TextureId> = None;
 
@@ -515,15 +1631,57 @@ impl RichTextBrush {
             });
         }
 
-        rpass.set_pipeline(&self.pipeline);
+        if self.damage_tracking {
+            // Nothing changed
+            let Some(damage) = self.damage else {
+                return;
+            };
+            // The damage is in vertex coordinates, the scissor in target
+            // pixels. Damage partly behind a perspective transform has no
+            // bounds, everything is drawn then
+            let transform = &self.current_uniforms.transform;
+            if let Some(bounds) = transformed_bounds(damage, transform, width, height) {
+                match scissor_rect(bounds, width, height) {
+                    Some((x, y, w, h)) => rpass.set_scissor_rect(x, y, w, h),
+                    // The damage is offscreen
+                    None => return,
+                }
+            }
+        }
+
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            rpass.set_pipeline(pipeline);
+        }
         rpass.set_bind_group(0, &self.bind_group, &[]);
         rpass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        rpass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        let index_buffer = match &self.culling {
+            Some(culling) if culled => culling.culled().unwrap_or(&self.index_buffer),
+            _ => &self.index_buffer,
+        };
+        rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
 
         // Draw the specified range of indexed triangles.
-        for items in self.dlist.indices_to_draw() {
-            rpass.draw_indexed(items.0..items.1, 0, 0..1);
+        match &self.indirect_buffer {
+            Some(buffer) if indirect => {
+                for index in 0..self.dlist.draws().len() as u64 {
+                    rpass.draw_indexed_indirect(buffer, index * INDIRECT_ARGS_SIZE);
+                }
+            }
+            _ => {
+                for draw in self.dlist.draws() {
+                    rpass.draw_indexed(draw.indices.clone(), 0, 0..1);
+                }
+            }
         }
+        trace_event!(
+            debug,
+            draws = self.dlist.draws().len(),
+            vertices = self.dlist.vertices().len(),
+            indices = self.dlist.indices().len(),
+            indirect,
+            culled,
+            "rich_text: frame drawn"
+        );
 
         self.bind_group_needs_update = false;
         self.first_run = false;
@@ -534,6 +1692,7 @@ impl RichTextBrush {
 
     #[inline]
     fn finish_composition(&mut self, ctx: &mut Context) {
+        self.upload_stats = UploadStats::default();
         self.comp.finish(&mut self.dlist, |event| {
             match event {
                 TextureEvent::CreateTexture {
@@ -569,37 +1728,21 @@ impl RichTextBrush {
                         label: Some("rich_text::Cache"),
                         view_formats: &[],
                     });
+                    self.textures.insert(id, texture);
 
                     if let Some(data) = data {
                         self.bind_group_needs_update = true;
-                        let channels = match format {
-                            // Mask
-                            image_cache::PixelFormat::A8 => 1,
-                            // Color
-                            image_cache::PixelFormat::Rgba8 => 4,
-                        };
-
-                        ctx.queue.write_texture(
-                            // Tells wgpu where to copy the pixel data
-                            wgpu::ImageCopyTexture {
-                                texture: &texture,
-                                mip_level: 0,
-                                origin: wgpu::Origin3d::ZERO,
-                                aspect: wgpu::TextureAspect::All,
-                            },
-                            // The actual pixel data
+                        upload(
+                            ctx,
+                            &self.textures,
+                            self.staged_uploads.then_some(&mut self.staging),
+                            &mut self.upload_stats,
+                            id,
+                            format,
+                            [0, 0, width, height],
                             data,
-                            // The layout of the texture
-                            wgpu::ImageDataLayout {
-                                offset: 0,
-                                bytes_per_row: Some((width * channels).into()),
-                                rows_per_image: Some(height.into()),
-                            },
-                            texture_size,
                         );
                     }
-
-                    self.textures.insert(id, texture);
                 }
                 TextureEvent::UpdateTexture {
                     id,
@@ -611,57 +1754,157 @@ impl RichTextBrush {
                     data,
                 } => {
                     log::info!("rich_text::UpdateTexture id ({:?})", id);
-                    if let Some(texture) = self.textures.get(&id) {
+                    if self.textures.contains_key(&id) {
                         self.bind_group_needs_update = true;
-                        let texture_size = wgpu::Extent3d {
-                            width: width.into(),
-                            height: height.into(),
-                            depth_or_array_layers: 1,
-                        };
-
-                        let channels = match format {
-                            // Mask
-                            image_cache::PixelFormat::A8 => 1,
-                            // Color
-                            image_cache::PixelFormat::Rgba8 => 4,
-                        };
-
-                        ctx.queue.write_texture(
-                            // Tells wgpu where to copy the pixel data
-                            wgpu::ImageCopyTexture {
-                                texture,
-                                mip_level: 0,
-                                origin: wgpu::Origin3d {
-                                    x: u32::from(x),
-                                    y: u32::from(y),
-                                    z: 0,
-                                },
-                                aspect: wgpu::TextureAspect::All,
-                            },
-                            // The actual pixel data
+                        upload(
+                            ctx,
+                            &self.textures,
+                            self.staged_uploads.then_some(&mut self.staging),
+                            &mut self.upload_stats,
+                            id,
+                            format,
+                            [x, y, width, height],
                             data,
-                            // The layout of the texture
-                            wgpu::ImageDataLayout {
-                                offset: 0,
-                                bytes_per_row: Some((width * channels).into()),
-                                rows_per_image: Some(height.into()),
-                            },
-                            texture_size,
                         );
                     }
                 }
                 TextureEvent::DestroyTexture(id) => {
                     log::info!("rich_text::DestroyTexture id ({:?})", id);
+                    // Copies already staged for it are dropped with it
                     self.textures.remove(&id);
                 }
             }
         });
+        self.flush_staging(ctx);
+    }
+
+    /// Writes the staged uploads to the staging buffer and copies them into
+    /// their textures, all in one submission.
+    fn flush_staging(&mut self, ctx: &mut Context) {
+        if self.staging.is_empty() {
+            return;
+        }
+        let data = self.staging.data();
+        let size = data.len() as u64;
+        self.upload_stats.staged_bytes = size;
+        if self
+            .staging_buffer
+            .as_ref()
+            .map_or(true, |buffer| buffer.size() < size)
+        {
+            trace_event!(debug, "rich_text: staging buffer grew to {size} bytes");
+            self.staging_buffer =
+                Some(ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("rich_text::Staging"),
+                    size: size.next_power_of_two(),
+                    usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }));
+        }
+        let Some(buffer) = &self.staging_buffer else {
+            return;
+        };
+        ctx.queue.write_buffer(buffer, 0, data);
+
+        let mut encoder =
+            ctx.device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("rich_text::Staging"),
+                });
+        for copy in self.staging.copies() {
+            let Some(texture) = self.textures.get(&copy.texture) else {
+                continue;
+            };
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: copy.offset,
+                        bytes_per_row: Some(copy.bytes_per_row),
+                        rows_per_image: Some(copy.height),
+                    },
+                },
+                wgpu::ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: copy.x,
+                        y: copy.y,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: copy.width,
+                    height: copy.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        ctx.queue.submit(Some(encoder.finish()));
+        self.staging.clear();
+    }
+}
+
+/// Uploads `data` into the `region` (x, y, width, height) of texture `id`,
+/// right away or through `staging` when given.
+#[allow(clippy::too_many_arguments)]
+fn upload(
+    ctx: &Context,
+    textures: &FnvHashMap<TextureId, Texture>,
+    staging: Option<&mut UploadStaging>,
+    stats: &mut UploadStats,
+    id: TextureId,
+    format: image_cache::PixelFormat,
+    region: [u16; 4],
+    data: &[u8],
+) {
+    let [x, y, width, height] = region.map(u32::from);
+    let channels = match format {
+        // Mask
+        image_cache::PixelFormat::A8 => 1,
+        // Color
+        image_cache::PixelFormat::Rgba8 => 4,
+    };
+    stats.uploads += 1;
+    stats.bytes += data.len() as u64;
+
+    if let Some(staging) = staging {
+        staging.stage(id, x, y, width, height, channels, data);
+        return;
     }
+    let Some(texture) = textures.get(&id) else {
+        return;
+    };
+    ctx.queue.write_texture(
+        // Tells wgpu where to copy the pixel data
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d { x, y, z: 0 },
+            aspect: wgpu::TextureAspect::All,
+        },
+        // The actual pixel data
+        data,
+        // The layout of the texture
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(width * channels),
+            rows_per_image: Some(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
 }
 
 #[derive(Default)]
 struct DrawLayoutCache {
     inner: std::collections::HashMap<String, Vec<CachedRect>>,
+    // Lines with a cursor, redrawn when it blinks
+    cursors: std::collections::HashSet<String>,
 }
 
 impl DrawLayoutCache {
@@ -669,13 +1912,30 @@ impl DrawLayoutCache {
         self.inner.get(&id)
     }
 
-    fn insert(&mut self, id: String, data: Vec<CachedRect>) {
+    fn insert(&mut self, id: String, data: Vec<CachedRect>, has_cursor: bool) {
+        if has_cursor {
+            self.cursors.insert(id.clone());
+        } else {
+            self.cursors.remove(&id);
+        }
         self.inner.insert(id, data);
     }
 
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.cursors.clear();
+    }
+
+    /// Drops the lines with a cursor, keeping the rest of the screen.
+    fn clear_cursors(&mut self) {
+        for id in self.cursors.drain() {
+            self.inner.remove(&id);
+        }
+    }
+
     fn clean(&mut self) {
         if self.inner.len() > 1024 {
-            self.inner.clear();
+            self.clear();
         }
     }
 }
@@ -692,9 +1952,16 @@ fn draw_layout(
 ) {
     let depth = 0.0;
     let mut glyphs = Vec::new();
+    let tab_width = comp.tab_width();
+    let center_glyphs = comp.center_glyphs();
     for line in render_data.lines() {
         let hash = line.hash().unwrap_or(0);
         let mut px = x + line.offset();
+        let tab_stops = TabStops {
+            origin: px,
+            cell_width: rect.width,
+            width: tab_width,
+        };
         let id = format!("{}-{}-{}", hash, line.baseline(), px);
         if hash > 0 {
             if let Some(data) = draw_layout_cache.get(id.to_owned()) {
@@ -704,6 +1971,7 @@ fn draw_layout(
         }
 
         let mut cache = Vec::new();
+        let mut has_cursor = false;
         for run in line.runs() {
             let mut font = *run.font();
             if font == 0 {
@@ -714,15 +1982,27 @@ fn draw_layout(
             let run_x = px;
             glyphs.clear();
             for cluster in run.visual_clusters() {
+                // Tabs only move to the next stop, their glyph is usually
+                // missing from the font
+                if tab_width > 0 && cluster.info().whitespace() == Whitespace::Tab {
+                    px = tab_stops.next(px);
+                    continue;
+                }
                 for glyph in cluster.glyphs() {
                     let x = px + glyph.x;
                     let y = py - glyph.y;
                     // px += glyph.advance;
                     px += rect.width * run.char_width();
-                    glyphs.push(Glyph { id: glyph.id, x, y });
+                    let glyph = Glyph::new(glyph.id, x, y);
+                    glyphs.push(match cluster.code_point() {
+                        Some(code_point) => glyph.with_code_point(code_point),
+                        None => glyph,
+                    });
                 }
             }
             let color = run.color();
+            has_cursor |= run.cursor() != SugarCursor::Disabled
+                || (run.underline() && run.underline_cursor());
 
             let line_height = line.ascent() + line.descent() + line.leading();
             let style = TextRunStyle {
@@ -731,20 +2011,50 @@ fn draw_layout(
                 font_size: run.font_size(),
                 color,
                 cursor: run.cursor(),
+                cursor_width: match run.cursor() {
+                    SugarCursor::Disabled => None,
+                    _ => Some(rect.width * run.char_width()),
+                },
+                cursor_cell: None,
                 background_color: run.background_color(),
+                default_background: run.default_background(),
                 baseline: py,
                 topline: py - line.ascent(),
                 line_height,
+                glyph_height: center_glyphs.then(|| line.ascent() + line.descent()),
                 advance: px - run_x,
                 underline: if run.underline() {
                     Some(UnderlineStyle {
-                        offset: run.underline_offset(),
-                        size: run.underline_size(),
+                        offset: Some(run.underline_offset()),
+                        size: Some(run.underline_size()),
                         color: run.underline_color(),
+                        cursor: run.underline_cursor(),
                     })
                 } else {
                     None
                 },
+                underline_skip_trailing_whitespace: run
+                    .underline_skip_trailing_whitespace(),
+                strikethrough: run.strikethrough().then(|| Strikethrough {
+                    offset: run.strikethrough_offset(),
+                    size: run.strikethrough_size(),
+                    color: run.strikethrough_color(),
+                }),
+                frame: run.frame(),
+                shadow: None,
+                outline: None,
+                vertical_align: VerticalAlign::Baseline,
+                kerning: false,
+                proportional: false,
+                glyph_span: None,
+                ligatures: run.ligatures(),
+                presentation: Presentation::Auto,
+                bold: run.is_bold(),
+                ansi_index: run.ansi_index(),
+                selected: run.selected(),
+                writing_mode: WritingMode::Horizontal,
+                direction: TextDirection::LeftToRight,
+                color_space: ColorSpace::Srgb,
             };
 
             if hash > 0 {
@@ -758,7 +2068,7 @@ fn draw_layout(
         }
 
         if hash > 0 && !cache.is_empty() {
-            draw_layout_cache.insert(id, cache);
+            draw_layout_cache.insert(id, cache, has_cursor);
         }
     }
 }
@@ -786,7 +2096,7 @@ fn fetch_dimensions(
                     let x = px + glyph.x;
                     let y = py - glyph.y;
                     px += glyph.advance;
-                    glyphs.push(Glyph { id: glyph.id, x, y });
+                    glyphs.push(Glyph::new(glyph.id, x, y));
                 }
             }
             let color = run.color();
@@ -797,12 +2107,33 @@ fn fetch_dimensions(
                 font_size: run.font_size(),
                 color,
                 cursor: run.cursor(),
+                cursor_width: None,
+                cursor_cell: None,
                 background_color: None,
+                default_background: run.default_background(),
                 baseline: py,
                 topline: py - line.ascent(),
                 line_height,
+                glyph_height: None,
                 advance: px - run_x,
                 underline: None,
+                underline_skip_trailing_whitespace: false,
+                strikethrough: None,
+                frame: None,
+                shadow: None,
+                outline: None,
+                vertical_align: VerticalAlign::Baseline,
+                kerning: false,
+                proportional: false,
+                glyph_span: None,
+                ligatures: run.ligatures(),
+                presentation: Presentation::Auto,
+                bold: run.is_bold(),
+                ansi_index: run.ansi_index(),
+                selected: run.selected(),
+                writing_mode: WritingMode::Horizontal,
+                direction: TextDirection::LeftToRight,
+                color_space: ColorSpace::Srgb,
             };
 
             if style.advance > 0. && line_height > 0. {
@@ -822,9 +2153,1282 @@ fn fetch_dimensions(
     dimension
 }
 
+/// `rich_text.wgsl` with the dual-source `fs_subpixel` entry point.
+fn subpixel_source() -> String {
+    [
+        include_str!("rich_text.wgsl"),
+        include_str!("subpixel.wgsl"),
+    ]
+    .join("\n")
+}
+
+/// Values of the shader overrides for a target of `format`.
+fn pipeline_constants(
+    format: wgpu::TextureFormat,
+) -> std::collections::HashMap<String, f64> {
+    let extended_range = is_extended_range(format);
+    std::collections::HashMap::from([(
+        "extended_range".to_string(),
+        if extended_range { 1. } else { 0. },
+    )])
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    depth_tested: bool,
+    subpixel: bool,
+) -> wgpu::RenderPipeline {
+    let (entry_point, blend) = if subpixel {
+        ("fs_subpixel", SUBPIXEL_BLEND)
+    } else {
+        ("fs_main", BLEND)
+    };
+    let constants = pipeline_constants(format);
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        cache: None,
+        label: None,
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[wgpu::VertexBufferLayout {
+                array_stride: mem::size_of::<Vertex>() as u64,
+                // https://docs.rs/wgpu/latest/wgpu/enum.VertexStepMode.html
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &wgpu::vertex_attr_array!(
+                    0 => Float32x4,
+                    1 => Float32x4,
+                    2 => Float32x2,
+                ),
+            }],
+        },
+        fragment: Some(wgpu::FragmentState {
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &constants,
+                ..Default::default()
+            },
+            module: shader,
+            entry_point,
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        // Quads are drawn in whichever winding the projection leaves them,
+        // which `set_flip_y` and mirroring transforms reverse. Culling would
+        // only ever skip the back of two triangle quads, saving nothing.
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: depth_stencil_state(depth_tested),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Transform from vertex coordinates to clip space for a target of `width`
+/// by `height`: `user_transform` first, then `rotation` and the projection.
+fn frame_transform(
+    width: f32,
+    height: f32,
+    flip_y: bool,
+    rotation: Rotation,
+    user_transform: &[f32; 16],
+) -> [f32; 16] {
+    let projection = if flip_y {
+        orthographic_projection_flipped(width, height)
+    } else {
+        orthographic_projection(width, height)
+    };
+    multiply_matrix(
+        &projection,
+        &multiply_matrix(&rotation_matrix(rotation, width, height), user_transform),
+    )
+}
+
+#[inline]
+fn depth_stencil_state(depth_tested: bool) -> Option<wgpu::DepthStencilState> {
+    if !depth_tested {
+        return None;
+    }
+    Some(wgpu::DepthStencilState {
+        format: DEPTH_FORMAT,
+        depth_write_enabled: true,
+        // The buffer is cleared to 0.0, so higher depths win and equal
+        // depths keep submission order
+        depth_compare: wgpu::CompareFunction::GreaterEqual,
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    })
+}
+
+/// Bounds in target pixels, from the top left corner, of `rect` drawn
+/// through `transform`, the clip space transform of a `width` by `height`
+/// target. `None` if a corner is behind the viewer.
+fn transformed_bounds(
+    rect: Rect,
+    transform: &[f32; 16],
+    width: f32,
+    height: f32,
+) -> Option<Rect> {
+    let corners = [
+        (rect.x, rect.y),
+        (rect.x + rect.width, rect.y),
+        (rect.x, rect.y + rect.height),
+        (rect.x + rect.width, rect.y + rect.height),
+    ];
+    let (mut left, mut top) = (f32::INFINITY, f32::INFINITY);
+    let (mut right, mut bottom) = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+    for (x, y) in corners {
+        // Column major, like the shader reads it
+        let clip = |row: usize| {
+            transform[row] * x + transform[4 + row] * y + transform[12 + row]
+        };
+        let w = clip(3);
+        if w <= 0. {
+            return None;
+        }
+        let x = (clip(0) / w + 1.) / 2. * width;
+        let y = (1. - clip(1) / w) / 2. * height;
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x);
+        bottom = bottom.max(y);
+    }
+    Some(Rect::new(left, top, right - left, bottom - top))
+}
+
+/// Clamps `rect` to a `width` x `height` target and rounds it out to whole
+/// pixels. Returns `None` when nothing is left to draw.
+#[inline]
+fn scissor_rect(rect: Rect, width: f32, height: f32) -> Option<(u32, u32, u32, u32)> {
+    let x = rect.x.floor().clamp(0., width);
+    let y = rect.y.floor().clamp(0., height);
+    let right = (rect.x + rect.width).ceil().clamp(0., width);
+    let bottom = (rect.y + rect.height).ceil().clamp(0., height);
+    if right <= x || bottom <= y {
+        return None;
+    }
+    Some((x as u32, y as u32, (right - x) as u32, (bottom - y) as u32))
+}
+
+/// Lets the vertex, index and indirect buffers be read by the culling pass
+/// on devices that run it.
+#[inline]
+fn storage_usage(device: &wgpu::Device) -> wgpu::BufferUsages {
+    if GpuCulling::is_supported(device) {
+        wgpu::BufferUsages::STORAGE
+    } else {
+        wgpu::BufferUsages::empty()
+    }
+}
+
+/// Size of the arguments of one indirect draw.
+const INDIRECT_ARGS_SIZE: u64 =
+    mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>() as u64;
+
+/// Arguments of `draws` for `draw_indexed_indirect`, one after the other,
+/// each drawing a single instance like the direct draws do.
+fn indirect_args(draws: &[Draw]) -> Vec<u8> {
+    let mut args = Vec::with_capacity(draws.len() * INDIRECT_ARGS_SIZE as usize);
+    for draw in draws {
+        let draw = wgpu::util::DrawIndexedIndirectArgs {
+            index_count: draw.indices.len() as u32,
+            instance_count: 1,
+            first_index: draw.indices.start,
+            base_vertex: 0,
+            first_instance: 0,
+        };
+        args.extend_from_slice(draw.as_bytes());
+    }
+    args
+}
+
 #[inline]
 fn next_copy_buffer_size(size: u64) -> u64 {
     let align_mask = wgpu::COPY_BUFFER_ALIGNMENT - 1;
     ((size.next_power_of_two() + align_mask) & !align_mask)
         .max(wgpu::COPY_BUFFER_ALIGNMENT)
 }
+
+/// Pending copy of a rendered texture into a mappable buffer,
+/// created by `RichTextBrush::read_pixels`.
+pub struct PixelReadback {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    is_bgra: bool,
+}
+
+impl PixelReadback {
+    /// Waits for the GPU to finish the copy and returns tightly-packed RGBA8
+    /// rows. The encoder used in `read_pixels` must have been submitted
+    /// before calling this. Returns `None` if the buffer could not be mapped.
+    pub fn wait(self, ctx: &Context) -> Option<Vec<u8>> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        ctx.device.poll(wgpu::Maintain::Wait);
+        receiver.recv().ok()?.ok()?;
+
+        let mut pixels = unpad_rows(
+            &slice.get_mapped_range(),
+            self.width,
+            self.height,
+            self.padded_bytes_per_row,
+        );
+        self.buffer.unmap();
+
+        if self.is_bgra {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Some(pixels)
+    }
+}
+
+impl RichTextBrush {
+    /// Records a copy of `texture` into a mappable buffer. The texture must
+    /// be a 4-bytes-per-pixel format (Rgba8 or Bgra8) created with
+    /// `wgpu::TextureUsages::COPY_SRC`. Submit `encoder` and then call
+    /// `PixelReadback::wait` to get the pixels.
+    pub fn read_pixels(
+        &self,
+        ctx: &Context,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) -> PixelReadback {
+        let width = texture.width();
+        let height = texture.height();
+        let padded_bytes_per_row = padded_bytes_per_row(width);
+        let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("rich_text::Readback"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+
+        PixelReadback {
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row,
+            is_bgra: matches!(
+                texture.format(),
+                wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+            ),
+        }
+    }
+}
+
+// copy_texture_to_buffer requires rows to be aligned to 256 bytes
+#[inline]
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let unpadded = width * 4;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded.div_ceil(align) * align
+}
+
+#[inline]
+fn unpad_rows(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+) -> Vec<u8> {
+    let row_size = width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_size * height as usize);
+    for row in data
+        .chunks(padded_bytes_per_row as usize)
+        .take(height as usize)
+    {
+        pixels.extend_from_slice(&row[..row_size]);
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sugarloaf::state::SugarState;
+    use crate::sugarloaf::{SugarloafRenderer, SugarloafWindowSize};
+
+    // A texture rendered to by a brush on a headless context, 16x16 unless
+    // sized with `with_config`
+    pub(super) struct Offscreen {
+        ctx: Context<'static>,
+        brush: RichTextBrush,
+        state: SugarState,
+        texture: wgpu::Texture,
+    }
+
+    impl Offscreen {
+        // Panics without an adapter, a software one like Mesa's llvmpipe is
+        // enough, rather than letting the tests pass without rendering
+        pub(super) fn new(format: wgpu::TextureFormat) -> Self {
+            Self::with_config(format, &RichTextBrushConfig::default(), (16, 16))
+        }
+
+        pub(super) fn with_config(
+            format: wgpu::TextureFormat,
+            config: &RichTextBrushConfig,
+            (width, height): (u32, u32),
+        ) -> Self {
+            let size = SugarloafWindowSize {
+                width: width as f32,
+                height: height as f32,
+            };
+            let ctx = futures::executor::block_on(Context::new_headless(
+                size,
+                format,
+                SugarloafRenderer::default(),
+            ))
+            .expect("the GPU tests need a wgpu adapter, e.g. Mesa's llvmpipe");
+            let brush = RichTextBrush::with_config(&ctx, config);
+            let mut state = SugarState::new(
+                crate::layout::SugarloafLayout::default(),
+                &crate::font::FontLibrary::default(),
+            );
+            state.current.layout.width = size.width;
+            state.current.layout.height = size.height;
+            let texture = ctx.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            });
+            Self {
+                ctx,
+                brush,
+                state,
+                texture,
+            }
+        }
+
+        // Renders what `draw` composes over `clear` and reads the texture
+        // back, as returned by `wgpu::Texture::copy_texture_to_buffer`
+        pub(super) fn render(
+            &mut self,
+            clear: wgpu::Color,
+            draw: impl FnOnce(&mut Compositor),
+        ) -> Vec<u8> {
+            let brush = &mut self.brush;
+            brush.comp.begin();
+            draw(&mut brush.comp);
+            brush.dlist.clear();
+            brush.finish_composition(&mut self.ctx);
+
+            brush.render_to_texture(&mut self.ctx, &self.state, &self.texture, clear);
+            self.read()
+        }
+
+        // Whether glyph masks are blended per channel, see `SUBPIXEL_BLEND`
+        pub(super) fn subpixel_text(&self) -> bool {
+            self.brush.is_subpixel_text(&self.ctx)
+        }
+
+        // Texture bytes, rows tightly packed
+        fn read(&self) -> Vec<u8> {
+            let bytes_per_pixel = self.texture.format().block_copy_size(None).unwrap();
+            let (width, height) = (self.texture.width(), self.texture.height());
+            let padded = (width * bytes_per_pixel)
+                .div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+                * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+            let buffer = self.ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: (padded * height) as u64,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let mut encoder = self
+                .ctx
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            encoder.copy_texture_to_buffer(
+                self.texture.as_image_copy(),
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(padded),
+                        rows_per_image: Some(height),
+                    },
+                },
+                self.texture.size(),
+            );
+            self.ctx.queue.submit(Some(encoder.finish()));
+
+            let slice = buffer.slice(..);
+            slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+            self.ctx.device.poll(wgpu::Maintain::Wait);
+            let data = slice.get_mapped_range();
+            data.chunks(padded as usize)
+                .flat_map(|row| &row[..(width * bytes_per_pixel) as usize])
+                .copied()
+                .collect()
+        }
+    }
+
+    // Compares the RGBA8 pixel at `x`, `y` of a 16 pixels wide readback,
+    // allowing for rounding differences between drivers
+    pub(super) fn assert_pixel(pixels: &[u8], x: usize, y: usize, expected: [u8; 4]) {
+        let i = (y * 16 + x) * 4;
+        let actual = &pixels[i..i + 4];
+        assert!(
+            actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 1),
+            "{actual:?} != {expected:?} at {x}, {y}"
+        );
+    }
+
+    #[test]
+    fn test_first_frame_draws_with_the_initial_transform() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        // The viewport matches the context, so the uniforms written by `new`
+        // are used as they are
+        assert_eq!(
+            target.brush.current_uniforms.transform,
+            orthographic_projection(16., 16.)
+        );
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.draw_rect(Rect::new(4., 4., 8., 8.), 0., &[1.0, 0.0, 0.0, 1.0]);
+        });
+        assert_pixel(&pixels, 8, 8, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 2, 2, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_images_are_tinted_by_their_color() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            let mut add = |texel: [u8; 4]| {
+                comp.add_image(image_cache::AddImage {
+                    format: PixelFormat::Rgba8,
+                    width: 1,
+                    height: 1,
+                    has_alpha: true,
+                    evictable: false,
+                    data: ImageData::Borrowed(&texel),
+                })
+                .unwrap()
+            };
+            let white = add([255, 255, 255, 255]);
+            let texel = add([128, 255, 0, 128]);
+            let rect = |x| Rect::new(x, 0., 1., 1.);
+            comp.draw_image(rect(0.), 0., &[1.0, 0.0, 0.0, 1.0], white);
+            // Untinted images are drawn as they are
+            comp.draw_image(rect(4.), 0., &[1.0, 1.0, 1.0, 1.0], texel);
+            // Fading keeps the color premultiplied
+            comp.draw_image(rect(8.), 0., &[1.0, 1.0, 1.0, 0.5], texel);
+        });
+
+        assert_pixel(&pixels, 0, 0, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 4, 0, [64, 128, 0, 128]);
+        assert_pixel(&pixels, 8, 0, [32, 64, 0, 64]);
+    }
+
+    #[test]
+    fn test_blend_preserves_transparent_target() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        // Opaque rects are drawn before translucent ones
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.draw_rect(Rect::new(0., 0., 4., 8.), 0., &[1.0, 1.0, 1.0, 1.0]);
+            comp.draw_rect(Rect::new(0., 0., 8., 8.), 0., &[0.0, 0.0, 1.0, 0.5]);
+        });
+
+        assert_pixel(&pixels, 6, 4, [0, 0, 128, 128]);
+        assert_pixel(&pixels, 2, 4, [128, 128, 255, 255]);
+        assert_pixel(&pixels, 12, 4, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_translucent_emoji_is_premultiplied() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        let blue = wgpu::Color {
+            r: 0.,
+            g: 0.,
+            b: 1.,
+            a: 1.,
+        };
+        let pixels = target.render(blue, |comp| {
+            // A half transparent red pixel of a color bitmap, then a
+            // transparent one, as decoded with straight alpha
+            let image = comp
+                .add_image(image_cache::AddImage {
+                    format: PixelFormat::Rgba8,
+                    width: 2,
+                    height: 1,
+                    has_alpha: true,
+                    evictable: false,
+                    data: ImageData::Borrowed(&[255, 0, 0, 128, 0, 0, 0, 0]),
+                })
+                .unwrap();
+            let white = [1.0, 1.0, 1.0, 1.0];
+            // Pixel centers on the texel centers
+            comp.draw_image(Rect::new(4., 0., 2., 1.), 0., &white, image);
+            // A pixel center halfway between both texels
+            comp.draw_image(Rect::new(0.5, 4., 2., 1.), 0., &white, image);
+        });
+
+        assert_pixel(&pixels, 4, 0, [128, 0, 127, 255]);
+        assert_pixel(&pixels, 5, 0, [0, 0, 255, 255]);
+        // Bilinear filtering gives half the coverage of the same red, without
+        // darkening it
+        assert_pixel(&pixels, 1, 4, [64, 0, 191, 255]);
+    }
+
+    // Lays `spans` out on a single line and draws it with `draw_layout`,
+    // returning the snapshot of what was drawn
+    fn layout_spans(
+        font_library: &crate::font::FontLibrary,
+        spans: &[(&str, crate::layout::FragmentStyle)],
+    ) -> crate::layout::RenderData {
+        let mut content = crate::layout::Content::builder();
+        for (text, style) in spans {
+            content.add_text(text, *style);
+        }
+        // Lines without a hash aren't drawn
+        content.set_current_line_hash(1);
+        content.break_line();
+        let mut layout_context = crate::layout::LayoutContext::new(font_library);
+        let mut builder =
+            layout_context.builder(crate::layout::Direction::LeftToRight, None, 1.);
+        content.build_ref().layout(&mut builder);
+        let mut render_data = crate::layout::RenderData::new();
+        builder.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        render_data
+    }
+
+    fn draw_spans(
+        comp: &mut Compositor,
+        spans: &[(&str, crate::layout::FragmentStyle)],
+    ) -> String {
+        let font_library = crate::font::FontLibrary::default();
+        let render_data = layout_spans(&font_library, spans);
+
+        comp.begin();
+        draw_layout(
+            comp,
+            &render_data,
+            0.,
+            0.,
+            &font_library.inner.read().unwrap(),
+            SugarDimensions {
+                width: 10.,
+                height: 20.,
+                scale: 1.,
+            },
+            &mut DrawLayoutCache::default(),
+        );
+        let mut list = DisplayList::new();
+        comp.finish(&mut list, |_| {});
+        list.to_snapshot()
+    }
+
+    #[test]
+    fn test_cursor_blink_only_redraws_cursor_lines() {
+        let font_library = crate::font::FontLibrary::default();
+        let mut comp = Compositor::new(2048);
+        let mut cache = DrawLayoutCache::default();
+        let cursor = crate::layout::FragmentStyle {
+            cursor: SugarCursor::Block([1.0, 0.0, 0.0, 1.0]),
+            ..Default::default()
+        };
+        // The same line at two positions is cached as two lines
+        for (x, style) in [(0., cursor), (100., Default::default())] {
+            draw_layout(
+                &mut comp,
+                &layout_spans(&font_library, &[("a", style)]),
+                x,
+                0.,
+                &font_library.inner.read().unwrap(),
+                SugarDimensions {
+                    width: 10.,
+                    height: 20.,
+                    scale: 1.,
+                },
+                &mut cache,
+            );
+        }
+        assert_eq!(cache.inner.len(), 2);
+
+        cache.clear_cursors();
+        assert_eq!(cache.inner.len(), 1);
+        assert!(cache.inner.keys().all(|id| id.ends_with("-100")));
+    }
+
+    #[test]
+    fn test_draw_layout_passes_the_ansi_index() {
+        let mut comp = Compositor::new(2048);
+        comp.set_bold_is_bright(true);
+        let mut style = crate::layout::FragmentStyle {
+            color: [0.8, 0.0, 0.0, 1.0],
+            ansi_index: Some(1),
+            ..Default::default()
+        };
+        style.font_attrs.1 = swash::Weight::BOLD;
+
+        let snapshot = draw_spans(&mut comp, &[("a", style)]);
+        let masks: Vec<&str> = snapshot
+            .lines()
+            .filter(|line| line.starts_with("mask"))
+            .collect();
+        assert_eq!(masks.len(), 1, "{snapshot}");
+        assert!(masks[0].contains("color=1,0,0,1"), "{snapshot}");
+    }
+
+    #[test]
+    fn test_draw_layout_passes_the_selection() {
+        let mut comp = Compositor::new(2048);
+        let foreground = [1.0, 1.0, 1.0, 1.0];
+        let background = [0.0, 0.0, 0.0, 1.0];
+        comp.set_forced_colors(Some(ForcedColors::new(foreground, background)));
+        let style = crate::layout::FragmentStyle {
+            selected: true,
+            ..Default::default()
+        };
+
+        // Selections are inverted
+        let snapshot = draw_spans(&mut comp, &[("a", style)]);
+        let line = |kind: &str| {
+            snapshot
+                .lines()
+                .find(|line| line.starts_with(kind))
+                .unwrap_or_else(|| panic!("no {kind} in {snapshot}"))
+                .to_owned()
+        };
+        assert!(line("mask").contains("color=0,0,0,1"), "{snapshot}");
+        assert!(line("rect").contains("color=1,1,1,1"), "{snapshot}");
+    }
+
+    #[test]
+    fn test_draw_layout_passes_the_frame() {
+        let mut comp = Compositor::new(2048);
+        let style = crate::layout::FragmentStyle {
+            frame: Some(text::Frame::Framed),
+            ..Default::default()
+        };
+
+        // The four sides of the border
+        let snapshot = draw_spans(&mut comp, &[("ab", style)]);
+        let rects = snapshot.lines().filter(|line| line.starts_with("rect"));
+        assert_eq!(rects.count(), 4, "{snapshot}");
+    }
+
+    #[test]
+    fn test_draw_layout_passes_underline_whitespace_skipping() {
+        let mut comp = Compositor::new(2048);
+        let mut style = crate::layout::FragmentStyle {
+            underline: true,
+            ..Default::default()
+        };
+        let mut underline_width = |style| {
+            let snapshot = draw_spans(&mut comp, &[("a   ", style)]);
+            let rect = snapshot.lines().find(|line| line.starts_with("rect"));
+            let width = rect.and_then(|rect| {
+                rect.split(' ')
+                    .find_map(|field| field.strip_prefix("w="))
+                    .and_then(|width| width.parse::<f32>().ok())
+            });
+            width.unwrap_or_else(|| panic!("no underline in {snapshot}"))
+        };
+
+        let full = underline_width(style);
+        style.underline_skip_trailing_whitespace = true;
+        assert!(underline_width(style) < full / 2.);
+    }
+
+    #[test]
+    fn test_draw_layout_passes_the_default_background() {
+        let mut comp = Compositor::new(2048);
+        comp.set_min_contrast(Some(4.5));
+        let style = crate::layout::FragmentStyle {
+            color: [0.3, 0.3, 0.3, 1.0],
+            default_background: [0.2, 0.2, 0.2, 1.0],
+            ..Default::default()
+        };
+
+        // The text is lightened to stand out from the background
+        let snapshot = draw_spans(&mut comp, &[("a", style)]);
+        let mask = snapshot.lines().find(|line| line.starts_with("mask"));
+        assert!(mask.is_some(), "{snapshot}");
+        assert!(!mask.unwrap().contains("color=0.3,0.3,0.3,1"), "{snapshot}");
+    }
+
+    #[test]
+    fn test_layout_sets_the_code_points_of_clusters() {
+        let font_library = crate::font::FontLibrary::default();
+        let style = crate::layout::FragmentStyle::default();
+        let render_data = layout_spans(&font_library, &[("a\u{E0}b", style)]);
+        let code_points: Vec<_> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.visual_clusters())
+            .map(|cluster| cluster.code_point())
+            .collect();
+
+        // Missing glyphs are boxed with the code point of their cluster
+        assert_eq!(&code_points[..3], [Some('a'), Some('\u{E0}'), Some('b')]);
+    }
+
+    #[test]
+    fn test_depth_buffer_matches_the_target() {
+        let config = RichTextBrushConfig {
+            depth_mode: DepthMode::Tested,
+            ..RichTextBrushConfig::default()
+        };
+        let mut target =
+            Offscreen::with_config(wgpu::TextureFormat::Rgba8Unorm, &config, (16, 16));
+        // A layout larger than the target, e.g. a pane cached at a smaller
+        // size
+        target.state.current.layout.width = 40.;
+        target.state.current.layout.height = 30.;
+
+        // The higher depth covers the lower one drawn after it
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.draw_rect(Rect::new(0., 0., 16., 16.), 0.5, &[1.0, 0.0, 0.0, 1.0]);
+            comp.draw_rect(Rect::new(0., 0., 16., 16.), 0.2, &[0.0, 0.0, 1.0, 1.0]);
+        });
+        assert_pixel(&pixels, 2, 2, [255, 0, 0, 255]);
+        let depth = target.brush.depth_texture.as_ref().unwrap();
+        assert_eq!((depth.width(), depth.height()), (16, 16));
+    }
+
+    #[cfg(feature = "bloom")]
+    #[test]
+    fn test_bloom_spreads_bright_pixels() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        target.brush.enable_bloom(&target.ctx, 0.5, 1.0, 4.0);
+
+        let pixels = target.render(wgpu::Color::TRANSPARENT, |comp| {
+            comp.draw_rect(Rect::new(6., 6., 4., 4.), 0.0, &[1.0, 1.0, 1.0, 1.0]);
+        });
+        assert_pixel(&pixels, 8, 8, [255, 255, 255, 255]);
+        // Outside the rect, only the glow covers the pixel
+        let glow = &pixels[(8 * 16 + 4) * 4..][..4];
+        assert!(glow[0] > 0 && glow[3] > 0, "{glow:?}");
+        assert_eq!(glow[0], glow[1]);
+    }
+
+    #[test]
+    fn test_chained_post_process_stages_keep_premultiplied_alpha() {
+        let format = wgpu::TextureFormat::Rgba8Unorm;
+        let target = Offscreen::new(format);
+        let ctx = &target.ctx;
+        let identity = PostProcessShader::Fragment(String::from(
+            "@fragment
+            fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+                return textureSampleLevel(source, source_sampler, input.uv, 0.0);
+            }",
+        ));
+        let mut first = PostProcess::new(ctx, &identity).unwrap();
+        let mut second = PostProcess::new(ctx, &identity).unwrap();
+
+        // Half transparent red, premultiplied like the text pass renders it
+        let mut encoder = ctx
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: Stage::source_view(&mut first, ctx, format),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.5,
+                        g: 0.0,
+                        b: 0.0,
+                        a: 0.5,
+                    }),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT);
+        let view = target
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let source = Stage::source_view(&mut second, ctx, format);
+        Stage::render(&mut first, ctx, &mut encoder, source, format, clear);
+        Stage::render(&mut second, ctx, &mut encoder, &view, format, clear);
+        ctx.queue.submit(Some(encoder.finish()));
+
+        // Straight alpha blending would multiply the color by alpha once
+        // per stage
+        assert_pixel(&target.read(), 8, 8, [128, 0, 0, 128]);
+    }
+
+    #[test]
+    fn test_shader_is_valid() {
+        use wgpu::naga;
+
+        let module =
+            naga::front::wgsl::parse_str(include_str!("rich_text.wgsl")).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_subpixel_shader_needs_dual_source_blending() {
+        use wgpu::naga;
+
+        let module = naga::front::wgsl::parse_str(&subpixel_source()).unwrap();
+        let validate = |capabilities| {
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all(), capabilities)
+                .validate(&module)
+        };
+        assert!(validate(naga::valid::Capabilities::empty()).is_err());
+        validate(naga::valid::Capabilities::DUAL_SOURCE_BLENDING).unwrap();
+    }
+
+    #[test]
+    fn test_dither_breaks_up_gradient_bands() {
+        // A 256x4 rect going from dark to slightly lighter gray, drawn on an 8
+        // bit target
+        let render = |dither: bool| -> Vec<Vec<u8>> {
+            let config = RichTextBrushConfig {
+                dither,
+                ..Default::default()
+            };
+            let mut target = Offscreen::with_config(
+                wgpu::TextureFormat::Rgba8Unorm,
+                &config,
+                (256, 4),
+            );
+            let pixels = target.render(wgpu::Color::BLACK, |comp| {
+                for x in 0..256 {
+                    let value = 0.1 + 0.05 * x as f32 / 256.;
+                    let rect = Rect::new(x as f32, 0., 1., 4.);
+                    comp.draw_rect(rect, 0., &[value, value, value, 1.0]);
+                }
+            });
+            // The red channel of every pixel
+            pixels
+                .chunks(256 * 4)
+                .map(|row| row.iter().step_by(4).copied().collect())
+                .collect()
+        };
+        // Average of every 4x4 block, in 16ths of a step
+        let blocks = |pixels: &[Vec<u8>]| -> Vec<u32> {
+            (0..64)
+                .map(|block| {
+                    pixels
+                        .iter()
+                        .flat_map(|row| &row[block * 4..block * 4 + 4])
+                        .map(|value| *value as u32)
+                        .sum()
+                })
+                .collect()
+        };
+        // Largest gap between a block and the gradient it replaces, in steps
+        let error = |blocks: &[u32]| {
+            blocks
+                .iter()
+                .enumerate()
+                .map(|(block, sum)| {
+                    let center = block as f32 * 4. + 1.5;
+                    let expected = (0.1 + 0.05 * center / 256.) * 255.;
+                    (*sum as f32 / 16. - expected).abs()
+                })
+                .fold(0., f32::max)
+        };
+
+        // Without dither the gradient collapses into flat bands, with it every
+        // block follows the gradient
+        let plain = blocks(&render(false));
+        assert!(error(&plain) > 0.4);
+        let dithered = blocks(&render(true));
+        assert!(error(&dithered) < 0.15);
+        assert!(dithered.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert!(dither_matrix(false).iter().all(|offset| *offset == 0.));
+    }
+
+    #[test]
+    fn test_atlas_size_is_clamped_to_the_device_limit() {
+        assert_eq!(atlas_size((512, 4096), 8192), (512, 4096));
+        assert_eq!(atlas_size((512, 4096), 2048), (512, 2048));
+        assert_eq!(atlas_size((4096, 1024), 8192), (1024, 1024));
+        assert_eq!(atlas_size((1024, 4096), 512), (512, 512));
+    }
+
+    #[test]
+    fn test_brush_fallbacks() {
+        let full = BrushCapabilities {
+            dual_source_blending: true,
+            indirect_draws: true,
+            gpu_culling: true,
+            extended_range: false,
+            max_texture_size: 8192,
+            blendable_target: true,
+        };
+        let config = RichTextBrushConfig {
+            subpixel_text: true,
+            indirect_draws: true,
+            gpu_culling: true,
+            ..Default::default()
+        };
+        assert!(BrushFallback::resolve(&config, (2048, 4096), &full).is_empty());
+        assert!(BrushFallback::resolve(
+            &RichTextBrushConfig::default(),
+            (2048, 2048),
+            &full
+        )
+        .is_empty());
+
+        let downlevel = BrushCapabilities {
+            dual_source_blending: false,
+            indirect_draws: false,
+            gpu_culling: false,
+            extended_range: false,
+            max_texture_size: 2048,
+            blendable_target: true,
+        };
+        assert_eq!(
+            BrushFallback::resolve(&config, (2048, 4096), &downlevel),
+            [
+                BrushFallback::GrayscaleText,
+                BrushFallback::DirectDraws,
+                BrushFallback::NoCulling,
+                BrushFallback::AtlasSize {
+                    requested: 4096,
+                    used: 2048
+                },
+            ]
+        );
+
+        // Culling runs on indirect draws, so it falls back without them
+        let config = RichTextBrushConfig {
+            gpu_culling: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            BrushFallback::resolve(&config, (2048, 2048), &full),
+            [BrushFallback::NoCulling]
+        );
+    }
+
+    #[test]
+    fn test_brush_requires_a_blendable_target() {
+        let context = |format| {
+            futures::executor::block_on(Context::new_headless(
+                SugarloafWindowSize {
+                    width: 16.,
+                    height: 16.,
+                },
+                format,
+                SugarloafRenderer::default(),
+            ))
+            .expect("the GPU tests need a wgpu adapter, e.g. Mesa's llvmpipe")
+        };
+        let config = RichTextBrushConfig::default();
+
+        let ctx = context(wgpu::TextureFormat::Rgba8Unorm);
+        let (brush, fallbacks) = RichTextBrush::new(&ctx, &config).unwrap();
+        assert!(fallbacks.is_empty());
+        assert_eq!(brush.capabilities(), BrushCapabilities::probe(&ctx));
+        assert!(brush.capabilities().blendable_target);
+
+        // 32 bit floats aren't blendable, there is no brush to fall back to
+        let ctx = context(wgpu::TextureFormat::Rgba32Float);
+        assert_eq!(
+            RichTextBrush::new(&ctx, &config).err(),
+            Some(BrushError::UnsupportedFormat(
+                wgpu::TextureFormat::Rgba32Float
+            ))
+        );
+    }
+
+    #[test]
+    fn test_extended_range_targets() {
+        let constants = pipeline_constants(wgpu::TextureFormat::Rgba16Float);
+        assert_eq!(constants["extended_range"], 1.);
+        for format in [
+            wgpu::TextureFormat::Bgra8Unorm,
+            wgpu::TextureFormat::Rgb10a2Unorm,
+        ] {
+            assert_eq!(pipeline_constants(format)["extended_range"], 0.);
+        }
+
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba16Float);
+        let pixels = target.render(wgpu::Color::BLACK, |comp| {
+            comp.draw_rect(Rect::new(0., 0., 4., 4.), 0., &[2.0, 1.5, 1.0, 0.5]);
+            comp.draw_rect(Rect::new(8., 0., 4., 4.), 0., &[4.0, 4.0, 4.0, 1.0]);
+        });
+        // Half floats of the pixel at `x`, `y`
+        let pixel = |x: usize, y: usize| -> [f32; 4] {
+            let i = (y * 16 + x) * 8;
+            std::array::from_fn(|c| {
+                half_to_f32(u16::from_le_bytes([
+                    pixels[i + c * 2],
+                    pixels[i + c * 2 + 1],
+                ]))
+            })
+        };
+        let linear = |c: f32| ((c + 0.055) / 1.055).powf(2.4);
+        let assert_close = |actual: [f32; 4], expected: [f32; 4]| {
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a - e).abs() < e * 0.01, "{actual:?} != {expected:?}");
+            }
+        };
+
+        // Colors past SDR white survive premultiplied blending on float
+        // targets, linearized
+        assert_close(pixel(2, 2), [linear(2.0) / 2., linear(1.5) / 2., 0.5, 1.0]);
+        assert_close(pixel(10, 2), [linear(4.0), linear(4.0), linear(4.0), 1.0]);
+    }
+
+    // Decodes an IEEE half float, which is finite in these tests
+    pub(super) fn half_to_f32(bits: u16) -> f32 {
+        let sign = if bits & 0x8000 != 0 { -1. } else { 1. };
+        let exponent = ((bits >> 10) & 0x1f) as i32;
+        let mantissa = (bits & 0x3ff) as f32 / 1024.;
+        if exponent == 0 {
+            return sign * mantissa * 2f32.powi(-14);
+        }
+        sign * (1. + mantissa) * 2f32.powi(exponent - 15)
+    }
+
+    // CPU time of `render_to_texture` per frame, uploads included, on a
+    // loop letting the GPU run two frames behind as a frame limiter would.
+    // Run with `cargo test --workspace --release bench_frame_uploads --
+    // --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_frame_uploads() {
+        let mut target = Offscreen::with_config(
+            wgpu::TextureFormat::Rgba8Unorm,
+            &RichTextBrushConfig::default(),
+            (256, 256),
+        );
+        const FRAMES: u32 = 600;
+        const RECTS: u32 = 20_000;
+        let mut total = std::time::Duration::ZERO;
+        let mut worst = total;
+        for frame in 0..FRAMES {
+            let brush = &mut target.brush;
+            brush.comp.begin();
+            for i in 0..RECTS {
+                let x = ((i * 7 + frame) % 250) as f32;
+                let y = ((i * 13) % 250) as f32;
+                brush
+                    .comp
+                    .draw_rect(Rect::new(x, y, 4., 4.), 0.0, &[1.0, 0.0, 0.0, 1.0]);
+            }
+            brush.dlist.clear();
+            brush.finish_composition(&mut target.ctx);
+
+            let start = Instant::now();
+            brush.render_to_texture(
+                &mut target.ctx,
+                &target.state,
+                &target.texture,
+                wgpu::Color::TRANSPARENT,
+            );
+            let elapsed = start.elapsed();
+            total += elapsed;
+            worst = worst.max(elapsed);
+            if frame % 2 == 1 {
+                target.ctx.device.poll(wgpu::Maintain::Wait);
+            }
+        }
+        println!(
+            "render_to_texture: {:?} per frame, {:?} at worst",
+            total / FRAMES,
+            worst
+        );
+    }
+
+    #[test]
+    fn test_mirrored_rects_are_drawn() {
+        let mut target = Offscreen::new(wgpu::TextureFormat::Rgba8Unorm);
+        // Mirroring reverses the winding of quads, which must not be culled
+        let draw = |target: &mut Offscreen| {
+            target.render(wgpu::Color::TRANSPARENT, |comp| {
+                comp.draw_rect(Rect::new(0., 0., 4., 4.), 0., &[1.0, 0.0, 0.0, 1.0]);
+            })
+        };
+        target.brush.set_transform([
+            -1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 16., 0., 0., 1.,
+        ]);
+        let pixels = draw(&mut target);
+        assert_pixel(&pixels, 14, 2, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 2, 2, [0, 0, 0, 0]);
+
+        target.brush.set_transform(IDENTITY_MATRIX);
+        target.brush.set_flip_y(true);
+        let pixels = draw(&mut target);
+        assert_pixel(&pixels, 2, 14, [255, 0, 0, 255]);
+        assert_pixel(&pixels, 2, 2, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_indirect_args() {
+        let draw = |indices: std::ops::Range<u32>| Draw {
+            pipeline: Pipeline::Opaque,
+            vertices: 0..0,
+            indices,
+            commands: 0..0,
+            mask: None,
+            image: None,
+        };
+        let args = indirect_args(&[draw(0..12), draw(12..18)]);
+        assert_eq!(args.len() as u64, 2 * INDIRECT_ARGS_SIZE);
+        let words: Vec<u32> = args
+            .chunks_exact(4)
+            .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        // Index count, instance count, first index, base vertex and first
+        // instance of each draw
+        assert_eq!(words, [12, 1, 0, 0, 0, 6, 1, 12, 0, 0]);
+        assert!(indirect_args(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_resized_transform_places_rects() {
+        let clip = |t: [f32; 16], [x, y]: [f32; 2]| {
+            [t[0] * x + t[4] * y + t[12], t[1] * x + t[5] * y + t[13]]
+        };
+        let corners = |width, height| {
+            let t =
+                frame_transform(width, height, false, Rotation::None, &IDENTITY_MATRIX);
+            [clip(t, [100., 50.]), clip(t, [200., 150.])]
+        };
+        // The same rect keeps its pixels, so takes less of a larger target
+        assert_eq!(corners(400., 200.), [[-0.5, 0.5], [0., -0.5]]);
+        assert_eq!(corners(800., 400.), [[-0.75, 0.75], [-0.5, 0.25]]);
+    }
+
+    #[test]
+    fn test_depth_stencil_state() {
+        assert!(depth_stencil_state(false).is_none());
+
+        let state = depth_stencil_state(true).unwrap();
+        assert_eq!(state.format, DEPTH_FORMAT);
+        assert!(state.depth_write_enabled);
+        // A higher depth must pass over a lower one already in the buffer
+        assert_eq!(state.depth_compare, wgpu::CompareFunction::GreaterEqual);
+    }
+
+    #[test]
+    fn test_scissor_rect() {
+        let rect = Rect::new(10.5, 20.2, 5., 5.);
+        assert_eq!(scissor_rect(rect, 100., 100.), Some((10, 20, 6, 6)));
+
+        let rect = Rect::new(-10., 90., 50., 50.);
+        assert_eq!(scissor_rect(rect, 100., 100.), Some((0, 90, 40, 10)));
+
+        let rect = Rect::new(200., 200., 5., 5.);
+        assert_eq!(scissor_rect(rect, 100., 100.), None);
+    }
+
+    #[test]
+    fn test_transformed_bounds() {
+        let rect = Rect::new(10., 20., 30., 5.);
+        let bounds = |flip_y, rotation, user_transform: &[f32; 16]| {
+            let transform = frame_transform(100., 50., flip_y, rotation, user_transform);
+            let bounds = transformed_bounds(rect, &transform, 100., 50.).unwrap();
+            [bounds.x, bounds.y, bounds.width, bounds.height]
+                .map(|value| (value * 100.).round() / 100.)
+        };
+
+        assert_eq!(
+            bounds(false, Rotation::None, &IDENTITY_MATRIX),
+            [10., 20., 30., 5.]
+        );
+        // Scissor rects are always from the top left corner
+        assert_eq!(
+            bounds(true, Rotation::None, &IDENTITY_MATRIX),
+            [10., 25., 30., 5.]
+        );
+        assert_eq!(
+            bounds(false, Rotation::Rotate180, &IDENTITY_MATRIX),
+            [60., 25., 30., 5.]
+        );
+        let mut translate = IDENTITY_MATRIX;
+        translate[12] = 15.;
+        translate[13] = -10.;
+        assert_eq!(
+            bounds(false, Rotation::None, &translate),
+            [25., 10., 30., 5.]
+        );
+
+        // Behind the viewer of a perspective transform
+        let mut behind = IDENTITY_MATRIX;
+        behind[15] = -1.;
+        let transform = frame_transform(100., 50., false, Rotation::None, &behind);
+        assert!(transformed_bounds(rect, &transform, 100., 50.).is_none());
+    }
+
+    #[test]
+    fn test_padded_bytes_per_row() {
+        assert_eq!(padded_bytes_per_row(1), 256);
+        assert_eq!(padded_bytes_per_row(64), 256);
+        assert_eq!(padded_bytes_per_row(65), 512);
+        assert_eq!(padded_bytes_per_row(800), 3328);
+    }
+
+    #[test]
+    fn test_unpad_rows() {
+        let width = 3;
+        let height = 2;
+        let padded = padded_bytes_per_row(width);
+        let mut data = vec![0u8; (padded * height) as usize];
+        for y in 0..height as usize {
+            for x in 0..(width as usize * 4) {
+                data[y * padded as usize + x] = (y * 100 + x) as u8;
+            }
+        }
+
+        let pixels = unpad_rows(&data, width, height, padded);
+        assert_eq!(pixels.len(), 24);
+        assert_eq!(pixels[0], 0);
+        assert_eq!(pixels[11], 11);
+        assert_eq!(pixels[12], 100);
+        assert_eq!(pixels[23], 111);
+    }
+}
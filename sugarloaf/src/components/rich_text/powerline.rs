@@ -0,0 +1,178 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Geometry for the powerline separators (U+E0B0 to U+E0B7), filled one
+//! pixel row at a time so they span the cell exactly whatever the font.
+
+use crate::components::rich_text::batch::Rect;
+
+/// Separators with builtin geometry: solid and thin triangles pointing
+/// right and left, then solid and thin half circles.
+pub const GLYPHS: [char; 8] = [
+    '\u{E0B0}', '\u{E0B1}', '\u{E0B2}', '\u{E0B3}', '\u{E0B4}', '\u{E0B5}', '\u{E0B6}',
+    '\u{E0B7}',
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum Shape {
+    Triangle,
+    Circle,
+}
+
+/// Returns true when `ch` has builtin geometry.
+#[inline]
+pub fn is_supported(ch: char) -> bool {
+    GLYPHS.contains(&ch)
+}
+
+/// Appends the rects drawing `ch` in `cell` with strokes `thickness` pixels
+/// wide, along with the alpha to draw them with. Returns false when `ch`
+/// isn't supported.
+pub fn rects(ch: char, cell: Rect, thickness: f32, out: &mut Vec<(Rect, f32)>) -> bool {
+    let (shape, solid, pointing_right) = match ch {
+        '\u{E0B0}' => (Shape::Triangle, true, true),
+        '\u{E0B1}' => (Shape::Triangle, false, true),
+        '\u{E0B2}' => (Shape::Triangle, true, false),
+        '\u{E0B3}' => (Shape::Triangle, false, false),
+        '\u{E0B4}' => (Shape::Circle, true, true),
+        '\u{E0B5}' => (Shape::Circle, false, true),
+        '\u{E0B6}' => (Shape::Circle, true, false),
+        '\u{E0B7}' => (Shape::Circle, false, false),
+        _ => return false,
+    };
+
+    // Snapped like box-drawing cells, so separators meet the neighbouring
+    // backgrounds without seams
+    let x0 = cell.x.round();
+    let x1 = (cell.x + cell.width).round();
+    let y0 = cell.y.round();
+    let y1 = (cell.y + cell.height).round();
+    let width = x1 - x0;
+    let half = (y1 - y0) / 2.;
+    if width <= 0. || half <= 0. {
+        return true;
+    }
+
+    // Horizontal extent of the shape at `distance` from the middle row, as a
+    // fraction of the cell width
+    let extent = |distance: f32| -> f32 {
+        let t = (distance / half).min(1.);
+        match shape {
+            Shape::Triangle => 1. - t,
+            Shape::Circle => (1. - t * t).sqrt(),
+        }
+    };
+
+    let mut y = y0;
+    while y < y1 {
+        let distance = (y + 0.5 - (y0 + half)).abs();
+        let outer = width * extent(distance);
+        let inner = if solid {
+            0.
+        } else {
+            // The stroke is measured along the normal, so it widens where
+            // the edge is steep
+            let slope =
+                width * (extent((distance - 0.5).max(0.)) - extent(distance + 0.5)).abs();
+            (outer - thickness * (1. + slope * slope).sqrt()).max(0.)
+        };
+        row(out, x0, x1, y, inner, outer, pointing_right);
+        y += 1.;
+    }
+
+    true
+}
+
+/// Fills the row `y` from `inner` to `outer`, measured from the left edge
+/// when pointing right or from the right edge otherwise. The last partial
+/// pixel is drawn with its coverage as alpha.
+fn row(
+    out: &mut Vec<(Rect, f32)>,
+    x0: f32,
+    x1: f32,
+    y: f32,
+    inner: f32,
+    outer: f32,
+    pointing_right: bool,
+) {
+    let start = inner.floor();
+    let full = outer.floor();
+    let coverage = outer - full;
+    let mut push = |from: f32, to: f32, alpha: f32| {
+        if to > from {
+            let x = if pointing_right { x0 + from } else { x1 - to };
+            out.push((Rect::new(x, y, to - from, 1.), alpha));
+        }
+    };
+    push(start, full, 1.);
+    if coverage > 0. {
+        push(full.max(start), full + 1., coverage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(ch: char) -> Vec<(Rect, f32)> {
+        let mut out = Vec::new();
+        assert!(rects(ch, Rect::new(0., 0., 8., 16.), 1., &mut out));
+        out
+    }
+
+    fn row_span(rects: &[(Rect, f32)], y: f32) -> (f32, f32) {
+        let row = rects.iter().filter(|(rect, _)| rect.y == y);
+        let start = row.clone().map(|(rect, _)| rect.x).fold(f32::MAX, f32::min);
+        let end = row
+            .map(|(rect, _)| rect.x + rect.width)
+            .fold(f32::MIN, f32::max);
+        (start, end)
+    }
+
+    #[test]
+    fn test_solid_triangles_fill_the_cell_edge() {
+        let right = draw('\u{E0B0}');
+        // Every row is drawn, and the rows around the middle reach the tip
+        for y in 0..16 {
+            assert_eq!(row_span(&right, y as f32).0, 0.);
+        }
+        assert_eq!(row_span(&right, 7.).1, 8.);
+        assert_eq!(row_span(&right, 8.).1, 8.);
+        assert!(row_span(&right, 0.).1 <= 1.);
+
+        let left = draw('\u{E0B2}');
+        for y in 0..16 {
+            assert_eq!(row_span(&left, y as f32).1, 8.);
+        }
+        assert_eq!(row_span(&left, 7.).0, 0.);
+    }
+
+    #[test]
+    fn test_partial_pixels_use_coverage() {
+        let rects = draw('\u{E0B0}');
+        assert!(rects
+            .iter()
+            .all(|(rect, alpha)| *alpha == 1. || rect.width == 1.));
+        assert!(rects.iter().any(|(_, alpha)| *alpha < 1.));
+    }
+
+    #[test]
+    fn test_thin_separators_are_hollow() {
+        for ch in ['\u{E0B1}', '\u{E0B5}'] {
+            let rects = draw(ch);
+            let (start, end) = row_span(&rects, 8.);
+            assert!(start > 0., "{ch:?} starts at {start}");
+            assert_eq!(end, 8.);
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut out = Vec::new();
+        assert!(!is_supported('\u{E0B8}'));
+        assert!(!rects('a', Rect::new(0., 0., 8., 16.), 1., &mut out));
+        assert!(out.is_empty());
+    }
+}
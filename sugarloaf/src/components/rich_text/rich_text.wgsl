@@ -1,5 +1,10 @@
 struct Globals {
     transform: mat4x4<f32>,
+    // Identity unless a ColorFilter is set on the brush
+    color_filter: mat4x4<f32>,
+    // Offset added to the pixel at x, y in column x % 4 and row y % 4, all
+    // zeros unless dithering is enabled
+    dither: mat4x4<f32>,
 }
 
 @group(0) @binding(0) var<uniform> globals: Globals;
@@ -43,22 +48,85 @@ fn vs_main(input: VertexInput) -> VertexOutput {
 
     out.f_use_tex = use_tex;
     out.f_use_mask = use_mask;
+    // v_pos.z carries the draw depth, it only affects layering when the
+    // pipeline has depth testing enabled (see DepthMode)
     out.position = globals.transform * vec4<f32>(input.v_pos.xy, 0.0, 1.0);
+    out.position.z = clamp(input.v_pos.z, 0.0, 1.0);
     return out;
 }
 
-@fragment
-fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
-    // return vec4<f32>(input.f_color.xyz, 1.0);
-    var out: vec4<f32> = input.f_color;
+// Set for float targets, which are linear and go past 1.0. Colors stay sRGB
+// encoded up to the output, 1.0 being SDR white
+override extended_range: bool = false;
 
+// Premultiplied color of a draw before its mask is applied. Images are
+// stored premultiplied, so their texels are multiplied by the premultiplied
+// draw color, which tints them and scales their alpha. White leaves them as
+// they are
+fn shade(input: VertexOutput) -> vec4<f32> {
+    let color = vec4<f32>(input.f_color.xyz * input.f_color.w, input.f_color.w);
     if input.f_use_tex > 0 {
-        out = textureSampleLevel(font_color_tex, font_sampler, input.f_uv, 0.0);
+        return textureSampleLevel(font_color_tex, font_sampler, input.f_uv, 0.0) * color;
+    }
+    return color;
+}
+
+// Applies the color filter to a premultiplied color, the filter itself
+// works on straight colors
+fn apply_filter(color: vec4<f32>) -> vec4<f32> {
+    var out = color;
+    if out.w > 0.0 {
+        out = vec4<f32>(out.xyz / out.w, out.w);
+    }
+    // Extended range targets also take negative components, colors out of
+    // the sRGB gamut
+    let limit = select(1.0, 65504.0, extended_range);
+    let low = select(0.0, -65504.0, extended_range);
+    out = clamp(
+        globals.color_filter * out,
+        vec4<f32>(low, low, low, 0.0),
+        vec4<f32>(limit, limit, limit, 1.0),
+    );
+    return vec4<f32>(out.xyz * out.w, out.w);
+}
+
+// Converts a premultiplied sRGB encoded color to what the target expects,
+// mirroring the transfer function for negative components as extended sRGB
+fn to_target(color: vec4<f32>) -> vec4<f32> {
+    if !extended_range || color.w <= 0.0 {
+        return color;
     }
+    let straight = color.xyz / color.w;
+    let c = abs(straight);
+    let linear = select(
+        pow((c + 0.055) / 1.055, vec3<f32>(2.4)),
+        c / 12.92,
+        c <= vec3<f32>(0.04045),
+    );
+    return vec4<f32>(sign(straight) * linear * color.w, color.w);
+}
+
+// Breaks up banding on 8 bit targets with an ordered dither, scaled by alpha
+// so uncovered pixels stay untouched. Float targets have no banding to hide
+fn apply_dither(color: vec4<f32>, position: vec2<f32>) -> vec4<f32> {
+    if extended_range {
+        return color;
+    }
+    let pixel = vec2<u32>(position) % 4u;
+    let offset = globals.dither[pixel.x][pixel.y] * color.w;
+    return vec4<f32>(color.xyz + vec3<f32>(offset), color.w);
+}
+
+// Outputs premultiplied colors, masks use the coverage of their first
+// channel for every channel
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    // return vec4<f32>(input.f_color.xyz, 1.0);
+    var out = shade(input);
 
     if input.f_use_mask > 0 {
-        out = vec4<f32>(out.xyz, textureSampleLevel(font_mask_tex, font_sampler, input.f_uv, 0.0).x);
+        out = out * textureSampleLevel(font_mask_tex, font_sampler, input.f_uv, 0.0).x;
     }
 
-    return out;
+    return apply_dither(to_target(apply_filter(out)), input.position.xy);
 }
\ No newline at end of file
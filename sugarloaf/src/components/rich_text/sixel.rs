@@ -0,0 +1,324 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Decodes Sixel payloads into RGBA images for the image cache. Gated
+//! behind the `sixel` feature.
+
+use crate::components::rich_text::image_cache::{AddImage, ImageData, PixelFormat};
+
+/// Number of color registers, as in xterm.
+const REGISTERS: usize = 1024;
+
+/// Largest image decoded, in pixels per side.
+const MAX_SIZE: usize = 4096;
+
+/// A decoded Sixel image, with straight alpha RGBA pixels. Pixels never
+/// painted are left transparent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SixelImage {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+}
+
+impl SixelImage {
+    /// Decodes the data of a Sixel sequence, that is everything between
+    /// the final `q` of the DCS introducer and the string terminator.
+    /// Returns `None` for payloads that paint no pixels.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        Decoder::new().decode(payload)
+    }
+
+    /// Request for adding the image to the cache.
+    pub fn as_add_image(&self) -> AddImage {
+        AddImage {
+            format: PixelFormat::Rgba8,
+            width: self.width,
+            height: self.height,
+            has_alpha: true,
+            evictable: true,
+            data: ImageData::Borrowed(&self.pixels),
+        }
+    }
+
+    /// Number of columns and rows of cells the image covers, rounded up
+    /// so it takes whole cells.
+    pub fn cells(&self, cell_width: f32, cell_height: f32) -> (u16, u16) {
+        let span = |pixels: u16, cell: f32| {
+            if cell <= 0. {
+                return 0;
+            }
+            (pixels as f32 / cell).ceil() as u16
+        };
+        (span(self.width, cell_width), span(self.height, cell_height))
+    }
+}
+
+struct Decoder {
+    palette: Vec<[u8; 3]>,
+    color: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        let mut palette = vec![[0; 3]; REGISTERS];
+        palette[..16].copy_from_slice(&VT340_PALETTE);
+        Self {
+            palette,
+            color: 0,
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    fn decode(mut self, payload: &[u8]) -> Option<SixelImage> {
+        let mut bytes = payload.iter().copied().peekable();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'"' => {
+                    // Raster attributes: aspect ratio, then the size of the
+                    // image, which painting may still grow.
+                    let params = params(&mut bytes);
+                    if let (Some(&width), Some(&height)) = (params.get(2), params.get(3))
+                    {
+                        self.reserve(width as usize, height as usize);
+                        self.height = self.height.max((height as usize).min(MAX_SIZE));
+                    }
+                }
+                b'#' => {
+                    let params = params(&mut bytes);
+                    let register = params.first().copied().unwrap_or(0) as usize;
+                    let register = register.min(REGISTERS - 1);
+                    if let [_, space, a, b, c] = params[..] {
+                        self.palette[register] = match space {
+                            1 => hls_to_rgb(a, b, c),
+                            2 => [percent(a), percent(b), percent(c)],
+                            _ => self.palette[register],
+                        };
+                    }
+                    self.color = register;
+                }
+                b'!' => {
+                    let count = params(&mut bytes).first().copied().unwrap_or(1);
+                    if let Some(sixel) = bytes.next() {
+                        self.put(sixel, count.max(1) as usize);
+                    }
+                }
+                b'$' => self.x = 0,
+                b'-' => {
+                    self.x = 0;
+                    self.y += 6;
+                }
+                b'?'..=b'~' => self.put(byte, 1),
+                _ => {}
+            }
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        self.pixels.truncate(self.width * self.height * 4);
+        Some(SixelImage {
+            width: self.width as u16,
+            height: self.height as u16,
+            pixels: self.pixels,
+        })
+    }
+
+    /// Grows the buffer to hold `width` by `height` pixels, keeping the
+    /// rows painted so far.
+    fn reserve(&mut self, width: usize, height: usize) {
+        let width = width.min(MAX_SIZE);
+        let height = height.min(MAX_SIZE);
+        let stride = self.width;
+        let rows = self.pixels.len() / (stride * 4).max(1);
+        if width <= stride && height <= rows {
+            return;
+        }
+        let new_stride = width.max(stride);
+        let new_rows = height.max(rows);
+        let mut pixels = vec![0; new_stride * new_rows * 4];
+        for row in 0..rows {
+            let src = &self.pixels[row * stride * 4..(row + 1) * stride * 4];
+            let start = row * new_stride * 4;
+            pixels[start..start + stride * 4].copy_from_slice(src);
+        }
+        self.pixels = pixels;
+        // The width is also the stride of the buffer.
+        self.width = self.width.max(width);
+    }
+
+    /// Paints the six vertical pixels of `sixel`, `count` times.
+    fn put(&mut self, sixel: u8, count: usize) {
+        let bits = sixel.wrapping_sub(b'?');
+        if bits > 0x3f {
+            return;
+        }
+        let end = (self.x + count).min(MAX_SIZE);
+        if bits == 0 {
+            self.x = end;
+            return;
+        }
+        let top = 8 - bits.leading_zeros() as usize;
+        let bottom = (self.y + top).min(MAX_SIZE);
+        if bottom <= self.y {
+            return;
+        }
+        self.reserve(end, bottom);
+        self.height = self.height.max(bottom);
+
+        let [r, g, b] = self.palette[self.color];
+        let stride = self.width;
+        for bit in 0..6 {
+            let y = self.y + bit;
+            if bits & (1 << bit) == 0 || y >= MAX_SIZE {
+                continue;
+            }
+            for x in self.x..end {
+                let offset = (y * stride + x) * 4;
+                self.pixels[offset..offset + 4].copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+        self.x = end;
+    }
+}
+
+/// Reads numeric parameters separated by `;`, stopping at the first other
+/// byte.
+fn params(bytes: &mut std::iter::Peekable<impl Iterator<Item = u8>>) -> Vec<u16> {
+    let mut params = Vec::new();
+    let mut current: Option<u16> = None;
+    while let Some(&byte) = bytes.peek() {
+        match byte {
+            b'0'..=b'9' => {
+                let digit = (byte - b'0') as u16;
+                current = Some(
+                    current
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+            }
+            b';' => params.push(current.take().unwrap_or(0)),
+            _ => break,
+        }
+        bytes.next();
+    }
+    if let Some(param) = current {
+        params.push(param);
+    }
+    params
+}
+
+/// Scales a `0..=100` color component to `0..=255`.
+#[inline]
+fn percent(value: u16) -> u8 {
+    ((value.min(100) as u32 * 255 + 50) / 100) as u8
+}
+
+/// Converts a DEC HLS color, where a hue of 0 is blue, 120 red and 240
+/// green, with lightness and saturation in `0..=100`.
+fn hls_to_rgb(hue: u16, lightness: u16, saturation: u16) -> [u8; 3] {
+    let l = lightness.min(100) as f32 / 100.;
+    let s = saturation.min(100) as f32 / 100.;
+    if s == 0. {
+        let gray = (l * 255.).round() as u8;
+        return [gray; 3];
+    }
+    let h = ((hue as u32 + 240) % 360) as f32 / 60.;
+    let c = (1. - (2. * l - 1.).abs()) * s;
+    let x = c * (1. - (h % 2. - 1.).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.),
+        1 => (x, c, 0.),
+        2 => (0., c, x),
+        3 => (0., x, c),
+        4 => (x, 0., c),
+        _ => (c, 0., x),
+    };
+    let m = l - c / 2.;
+    let channel = |v: f32| ((v + m) * 255.).round().clamp(0., 255.) as u8;
+    [channel(r), channel(g), channel(b)]
+}
+
+/// Colors of the first registers on a VT340, which most Sixel encoders
+/// assume when they don't define their own.
+const VT340_PALETTE: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [51, 51, 204],
+    [204, 33, 33],
+    [51, 204, 51],
+    [204, 51, 204],
+    [51, 204, 204],
+    [204, 204, 51],
+    [135, 135, 135],
+    [66, 66, 66],
+    [84, 84, 153],
+    [153, 66, 66],
+    [84, 153, 84],
+    [153, 84, 153],
+    [84, 153, 153],
+    [153, 153, 84],
+    [204, 204, 204],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0; 4];
+
+    fn pixel(image: &SixelImage, x: usize, y: usize) -> [u8; 4] {
+        let offset = (y * image.width as usize + x) * 4;
+        image.pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_decode_sixel() {
+        // Red over the top two rows of three columns, then blue over the
+        // bottom row of two columns, painted after a carriage return.
+        let image =
+            SixelImage::decode(b"\"1;1;3;7#1;2;100;0;0#2;2;0;0;100#1!3A$-#2!2@").unwrap();
+        assert_eq!((image.width, image.height), (3, 7));
+        assert_eq!(image.pixels.len(), 3 * 7 * 4);
+        for x in 0..3 {
+            assert_eq!(pixel(&image, x, 0), CLEAR);
+            assert_eq!(pixel(&image, x, 1), RED);
+            assert_eq!(pixel(&image, x, 2), CLEAR);
+        }
+        assert_eq!(pixel(&image, 0, 6), BLUE);
+        assert_eq!(pixel(&image, 1, 6), BLUE);
+        assert_eq!(pixel(&image, 2, 6), CLEAR);
+    }
+
+    #[test]
+    fn test_sixel_palette() {
+        // Register 2 is red on a VT340 until it is redefined in HLS, where
+        // a hue of 240 is green.
+        let image = SixelImage::decode(b"#2~#2;1;240;50;100~").unwrap();
+        assert_eq!(image.width, 2);
+        assert_eq!(pixel(&image, 0, 0), [204, 33, 33, 255]);
+        assert_eq!(pixel(&image, 1, 5), [0, 255, 0, 255]);
+        assert_eq!(SixelImage::decode(b"#1;2;0;0;0"), None);
+    }
+
+    #[test]
+    fn test_sixel_cells() {
+        let image = SixelImage::decode(b"!25~-!25~").unwrap();
+        assert_eq!((image.width, image.height), (25, 12));
+        assert_eq!(image.cells(10., 12.), (3, 1));
+        assert_eq!(image.cells(8., 5.), (4, 3));
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Packs the texture uploads of a frame into a single staging buffer, so
+//! they are copied with one buffer write and a batch of
+//! `copy_buffer_to_texture` instead of a `write_texture` per image.
+
+use crate::components::rich_text::image_cache::TextureId;
+
+/// Texture uploads of the last frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UploadStats {
+    /// Number of texture regions written.
+    pub uploads: u32,
+    /// Pixel data uploaded, in bytes.
+    pub bytes: u64,
+    /// Size of the staging buffer written, row padding included. Zero when
+    /// uploads aren't staged.
+    pub staged_bytes: u64,
+}
+
+/// A region of the staging data to copy into a texture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StagedCopy {
+    pub texture: TextureId,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Byte offset of the first row in the staging data.
+    pub offset: u64,
+    /// Stride between rows, padded to `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`.
+    pub bytes_per_row: u32,
+}
+
+#[derive(Default)]
+pub struct UploadStaging {
+    data: Vec<u8>,
+    copies: Vec<StagedCopy>,
+}
+
+impl UploadStaging {
+    /// Appends `data`, `width` by `height` pixels of `bytes_per_pixel`, to
+    /// be copied into `texture` at `x` and `y`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn stage(
+        &mut self,
+        texture: TextureId,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        bytes_per_pixel: u32,
+        data: &[u8],
+    ) {
+        let row = (width * bytes_per_pixel) as usize;
+        if row == 0 || height == 0 || data.len() < row * height as usize {
+            return;
+        }
+        let bytes_per_row = align(row, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize);
+        let offset = align(self.data.len(), wgpu::COPY_BUFFER_ALIGNMENT as usize);
+        self.data.resize(offset, 0);
+        for source in data.chunks_exact(row).take(height as usize) {
+            self.data.extend_from_slice(source);
+            self.data.resize(self.data.len() + bytes_per_row - row, 0);
+        }
+        self.copies.push(StagedCopy {
+            texture,
+            x,
+            y,
+            width,
+            height,
+            offset: offset as u64,
+            bytes_per_row: bytes_per_row as u32,
+        });
+    }
+
+    /// Staging data, padded to `wgpu::COPY_BUFFER_ALIGNMENT` so it can be
+    /// written to a buffer as is.
+    pub fn data(&mut self) -> &[u8] {
+        let len = align(self.data.len(), wgpu::COPY_BUFFER_ALIGNMENT as usize);
+        self.data.resize(len, 0);
+        &self.data
+    }
+
+    #[inline]
+    pub fn copies(&self) -> &[StagedCopy] {
+        &self.copies
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty()
+    }
+
+    /// Forgets the staged uploads, keeping the storage for the next frame.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.copies.clear();
+    }
+}
+
+#[inline]
+fn align(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rows_are_padded() {
+        let mut staging = UploadStaging::default();
+        // 3x2 RGBA image, rows of 12 bytes
+        let data: Vec<u8> = (0..24).collect();
+        staging.stage(TextureId(1), 4, 8, 3, 2, 4, &data);
+        // 1x1 mask placed after the first copy
+        staging.stage(TextureId(2), 0, 0, 1, 1, 1, &[7]);
+
+        let copies = staging.copies().to_vec();
+        assert_eq!(copies[0].offset, 0);
+        assert_eq!(copies[0].bytes_per_row, 256);
+        assert_eq!((copies[0].x, copies[0].y), (4, 8));
+        assert_eq!(copies[1].offset, 512);
+
+        let data = staging.data();
+        assert_eq!(data.len(), 768);
+        assert_eq!(&data[..12], &(0..12).collect::<Vec<u8>>()[..]);
+        assert_eq!(&data[256..268], &(12..24).collect::<Vec<u8>>()[..]);
+        assert_eq!(data[512], 7);
+    }
+
+    #[test]
+    fn test_short_data_is_skipped() {
+        let mut staging = UploadStaging::default();
+        staging.stage(TextureId(1), 0, 0, 2, 2, 4, &[0; 8]);
+        assert!(staging.is_empty());
+        assert!(staging.data().is_empty());
+    }
+}
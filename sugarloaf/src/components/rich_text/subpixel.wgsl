@@ -0,0 +1,27 @@
+// Dual-source variant of fs_main, appended to rich_text.wgsl when the device
+// supports DUAL_SOURCE_BLENDING. Masks are blended with the coverage of each
+// channel, everything else as in fs_main.
+
+struct SubpixelOutput {
+    @location(0) color: vec4<f32>,
+    // Per-channel blend factor of the destination, see SUBPIXEL_BLEND
+    @location(0) @second_blend_source coverage: vec4<f32>,
+}
+
+@fragment
+fn fs_subpixel(input: VertexOutput) -> SubpixelOutput {
+    var out: SubpixelOutput;
+    let color = apply_dither(to_target(apply_filter(shade(input))), input.position.xy);
+
+    if input.f_use_mask > 0 {
+        let coverage = textureSampleLevel(font_mask_tex, font_sampler, input.f_uv, 0.0).xyz;
+        let alpha = color.w * max(coverage.x, max(coverage.y, coverage.z));
+        out.color = vec4<f32>(color.xyz * coverage, alpha);
+        out.coverage = vec4<f32>(color.w * coverage, alpha);
+    } else {
+        out.color = color;
+        out.coverage = vec4<f32>(color.w);
+    }
+
+    return out;
+}
@@ -9,6 +9,7 @@
 // Eventually the file had updates to support other features like background-color,
 // text color, underline color and etc.
 
+use crate::components::rich_text::color::ColorSpace;
 use crate::sugarloaf::primitives::SugarCursor;
 use swash::{FontRef, GlyphId, NormalizedCoord};
 
@@ -25,33 +26,298 @@ pub struct TextRunStyle<'a> {
     pub color: [f32; 4],
     /// Background of the text.
     pub background_color: Option<[f32; 4]>,
+    /// Background of the terminal, behind runs without a
+    /// `background_color`. Color adjustments like the minimum contrast are
+    /// resolved against it for those runs, unless it is fully transparent,
+    /// in which case they are left alone.
+    pub default_background: [f32; 4],
     /// Baseline of the run.
     pub baseline: f32,
     /// Topline of the run (basically y axis).
     pub topline: f32,
-    /// Absolute line height of the run.
+    /// Absolute line height of the run, the height of its background and
+    /// cursor. Glyphs are drawn whole, the ink of tall ones can go past it.
     pub line_height: f32,
+    /// Height of the glyph box, the ascent plus descent of the line with
+    /// `baseline` that far below `topline` as the ascent. When given and
+    /// `line_height` is taller, glyphs and their decorations are moved down
+    /// to center the box in the line rather than leaving the extra space
+    /// below. Horizontal runs only.
+    pub glyph_height: Option<f32>,
     /// Total advance of the run.
     pub advance: f32,
     /// Underline style.
     pub underline: Option<UnderlineStyle>,
+    /// Ends the underline at the last glyph with ink instead of the end of
+    /// the run, so trailing whitespace isn't underlined. Horizontal runs
+    /// only.
+    pub underline_skip_trailing_whitespace: bool,
+    /// Strikethrough style.
+    pub strikethrough: Option<Strikethrough>,
+    /// Border drawn around the run in the color of the text.
+    pub frame: Option<Frame>,
     /// Cursor style.
     pub cursor: SugarCursor,
+    /// Width of the grapheme cluster under the cursor, in cells times the
+    /// cell width. A cluster may shape to several glyphs (ZWJ emoji, flags),
+    /// so the run advance is only used when this is missing.
+    pub cursor_width: Option<f32>,
+    /// Cell the cursor is on and the number of cells the run covers, for
+    /// runs where a single glyph spans several cells, like a ligature. The
+    /// cursor is drawn over that cell of the run rather than from its start.
+    pub cursor_cell: Option<(u16, u16)>,
+    /// Drop shadow drawn beneath the glyphs.
+    pub shadow: Option<Shadow>,
+    /// Outline width in pixels and color, stroked around the glyphs.
+    pub outline: Option<(f32, [f32; 4])>,
+    /// Vertical alignment of the glyphs relative to the baseline.
+    pub vertical_align: VerticalAlign,
+    /// Applies the font's kerning to adjacent glyphs, for callers that
+    /// position glyphs by advance without shaping.
+    pub kerning: bool,
+    /// Places the glyphs one after the other by their advance in the font,
+    /// from the start of the run, instead of at their own positions in the
+    /// cell grid. The run is as wide as the summed advances, which sizes its
+    /// background and underline.
+    pub proportional: bool,
+    /// Width every glyph is fitted into, the cells it spans times the cell
+    /// width, instead of trusting the font advance. Icon fonts often report
+    /// advances that don't match the cells their glyphs cover, so glyphs
+    /// are rescaled for their ink to fill the width, within the line height
+    /// and keeping their aspect ratio, then centered in it. `None` draws
+    /// glyphs at the font size.
+    pub glyph_span: Option<f32>,
+    /// Whether the run was shaped with ligatures. Without them every cell
+    /// has its own glyph, so `cursor_cell` isn't needed.
+    pub ligatures: bool,
+    /// Preferred presentation for glyphs with both text and emoji variants,
+    /// as selected by VS15 and VS16.
+    pub presentation: Presentation,
+    /// The run is bold, see `ansi_index`.
+    pub bold: bool,
+    /// Palette index `color` was resolved from, when it is an ANSI color.
+    /// Bold runs of the eight base colors may be drawn bright.
+    pub ansi_index: Option<u8>,
+    /// The run is selected, drawn with the selection colors when colors
+    /// are forced.
+    pub selected: bool,
+    /// Direction the glyphs of the run are laid out in.
+    pub writing_mode: WritingMode,
+    /// Direction horizontal runs are laid out in.
+    pub direction: TextDirection,
+    /// Color space every color of the run is defined in, they are converted
+    /// to the sRGB the target is displayed in. Extended range targets keep
+    /// Display P3 colors out of the sRGB gamut, others clamp them to it.
+    pub color_space: ColorSpace,
+}
+
+/// Presentation of glyphs that can be drawn either as text or as emoji.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Presentation {
+    /// Uses color glyphs when the font has them.
+    #[default]
+    Auto,
+    /// Monochrome, tinted with the run color (VS15). Color only glyphs are
+    /// reduced to their coverage.
+    Text,
+    /// Color glyphs (VS16), falling back to the outline.
+    Emoji,
+}
+
+/// How glyph outlines are fitted to the pixel grid when rasterized, see
+/// `Compositor::set_hinting`.
+///
+/// Glyphs are positioned horizontally to a quarter of a pixel, each offset
+/// rasterized apart. Hinting only moves outlines vertically, so it keeps
+/// those positions and the spacing they give, except `Full` which trades
+/// them for stems landing on the same pixel columns every time.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Hinting {
+    /// Outlines are rasterized as designed, soft at small sizes.
+    None,
+    /// Outlines are fitted to the pixel grid vertically, sharpening
+    /// horizontal stems and the baseline while keeping glyph shapes.
+    #[default]
+    Slight,
+    /// Same as `Slight` with glyphs snapped to whole pixels horizontally,
+    /// for the crispest vertical stems at the cost of uneven spacing.
+    Full,
+}
+
+/// Vertical alignment of a text run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum VerticalAlign {
+    #[default]
+    Baseline,
+    /// Reduced size, raised above the baseline.
+    Superscript,
+    /// Reduced size, lowered below the baseline.
+    Subscript,
+}
+
+impl VerticalAlign {
+    /// Factor applied to the font size of the run.
+    #[inline]
+    pub fn scale(self) -> f32 {
+        match self {
+            VerticalAlign::Baseline => 1.0,
+            VerticalAlign::Superscript | VerticalAlign::Subscript => 0.65,
+        }
+    }
+
+    /// Vertical offset of the baseline for a run of `font_size`, positive
+    /// values go down.
+    #[inline]
+    pub fn baseline_shift(self, font_size: f32) -> f32 {
+        match self {
+            VerticalAlign::Baseline => 0.,
+            VerticalAlign::Superscript => -(font_size * 0.35).round(),
+            VerticalAlign::Subscript => (font_size * 0.15).round(),
+        }
+    }
+}
+
+/// Direction in which a text run is laid out.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WritingMode {
+    /// Glyphs go across a row at their own positions.
+    #[default]
+    Horizontal,
+    /// Upright glyphs go down a column, as in CJK vertical writing. The
+    /// glyph positions are ignored, glyphs are stacked from the top of the
+    /// run rect by their vertical advance and centered in a column
+    /// `line_height` wide. The run length takes the place of its width, and
+    /// the underline is drawn as a line along the right side of the column.
+    Vertical,
+}
+
+/// Direction of a horizontal run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TextDirection {
+    #[default]
+    LeftToRight,
+    /// Glyphs are given in logical order, positioned left to right from the
+    /// start of the run, and are mirrored across it: the first glyph ends at
+    /// the right edge, as in Arabic and Hebrew. `cursor_cell` counts from
+    /// the right edge too, and the caret sits at the right of its cell.
+    RightToLeft,
 }
 
 /// Underline decoration style.
 #[derive(Copy, Clone)]
 pub struct UnderlineStyle {
-    /// Offset of the underline stroke.
+    /// Offset of the underline stroke, the font's underline position when
+    /// `None`.
+    pub offset: Option<f32>,
+    /// Thickness of the underline stroke, the font's underline thickness
+    /// when `None`.
+    pub size: Option<f32>,
+    /// Color of the underline.
+    pub color: [f32; 4],
+    /// The underline is the cursor: `size` is then in logical pixels and
+    /// scaled like cursors are, and it hides while the cursor blinks off.
+    pub cursor: bool,
+}
+
+/// Strikethrough style.
+#[derive(Copy, Clone)]
+pub struct Strikethrough {
+    /// Offset of the top of the stroke above the baseline.
     pub offset: f32,
-    /// Thickness of the underline stroke.
+    /// Thickness of the stroke.
     pub size: f32,
-    /// Color of the underline.
+    /// Color of the stroke, the color of the text when `None`.
+    pub color: Option<[f32; 4]>,
+}
+
+/// Border around a text run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// Rectangle around the run (SGR 51).
+    Framed,
+    /// Rounded outline with the ends of the run as half circles (SGR 52).
+    Encircled,
+}
+
+/// Appearance of the box drawn in place of glyphs missing from the font,
+/// see `Compositor::set_missing_glyph_style`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MissingGlyphStyle {
+    /// Thickness of the box in logical pixels, scaled like cursors are.
+    pub thickness: f32,
+    /// Writes the hex code point in the box, for glyphs that carry one, see
+    /// `Glyph::with_code_point`.
+    pub show_code_point: bool,
+    /// Color of the box, the color of the text when `None`. In sRGB, like
+    /// the colors given to `draw_rect`.
+    pub color: Option<[f32; 4]>,
+}
+
+impl Default for MissingGlyphStyle {
+    fn default() -> Self {
+        Self {
+            thickness: 1.0,
+            show_code_point: false,
+            color: None,
+        }
+    }
+}
+
+/// Drop shadow style.
+#[derive(Copy, Clone)]
+pub struct Shadow {
+    /// Horizontal and vertical offset of the shadow.
+    pub offset: (f32, f32),
+    /// Amount the shadow grows on each side, a cheap approximation of blur.
+    pub spread: f32,
+    /// Color of the shadow.
     pub color: [f32; 4],
 }
 
+/// Tab stops of a line, every `width` cells counted from `origin`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TabStops {
+    /// Position of the first column.
+    pub origin: f32,
+    /// Width of a cell.
+    pub cell_width: f32,
+    /// Number of cells between two stops.
+    pub width: usize,
+}
+
+impl TabStops {
+    pub const DEFAULT_WIDTH: usize = 8;
+
+    /// Returns the position of the first tab stop after `x`. A tab always
+    /// advances, so a tab at column 0 or right after another one moves by a
+    /// whole stop. A `width` of 0 advances by a single cell.
+    pub fn next(&self, x: f32) -> f32 {
+        if self.width == 0 {
+            return x + self.cell_width;
+        }
+        // Positions are whole cells, rounding absorbs accumulated error
+        let column = ((x - self.origin) / self.cell_width).round().max(0.) as usize;
+        let stop = (column / self.width + 1) * self.width;
+        self.origin + stop as f32 * self.cell_width
+    }
+}
+
 /// Positioned glyph in a text run.
-#[derive(Copy, Clone)]
+///
+/// A run for `draw_glyphs` can be built from the glyph ids and advances of
+/// any shaper:
+///
+/// ```
+/// use sugarloaf::components::rich_text::text::Glyph;
+///
+/// let run: Vec<Glyph> = [36, 72, 79]
+///     .into_iter()
+///     .enumerate()
+///     .map(|(i, id)| Glyph::new(id, i as f32 * 8., 16.))
+///     .collect();
+/// assert_eq!(run[2], Glyph::new(79, 16., 16.));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Glyph {
     /// Glyph identifier.
     pub id: GlyphId,
@@ -59,4 +325,71 @@ pub struct Glyph {
     pub x: f32,
     /// Y offset of the glyph.
     pub y: f32,
+    /// Character the glyph was shaped from, written in the box drawn when
+    /// the glyph is missing from the font, see `MissingGlyphStyle`.
+    pub code_point: Option<char>,
+    /// Depth of the glyph and its background, overriding the depth of the
+    /// run so it can be layered apart from its neighbours.
+    pub depth: Option<f32>,
+}
+
+impl Glyph {
+    /// Creates a glyph `id` positioned at `x` and `y`, drawn at the depth
+    /// of its run.
+    #[inline]
+    pub const fn new(id: GlyphId, x: f32, y: f32) -> Self {
+        Self {
+            id,
+            x,
+            y,
+            code_point: None,
+            depth: None,
+        }
+    }
+
+    /// Returns the glyph shaped from `code_point`.
+    #[inline]
+    pub const fn with_code_point(self, code_point: char) -> Self {
+        Self {
+            code_point: Some(code_point),
+            ..self
+        }
+    }
+
+    /// Returns the glyph drawn at `depth` instead of the depth of its run.
+    #[inline]
+    pub const fn with_depth(self, depth: f32) -> Self {
+        Self {
+            depth: Some(depth),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tab_stops() {
+        let stops = TabStops {
+            origin: 10.,
+            cell_width: 8.,
+            width: TabStops::DEFAULT_WIDTH,
+        };
+        // Column 0 goes to the first stop rather than staying put
+        assert_eq!(stops.next(10.), 10. + 64.);
+        assert_eq!(stops.next(10. + 3. * 8.), 10. + 64.);
+        // Consecutive tabs move a whole stop each
+        assert_eq!(stops.next(10. + 64.), 10. + 128.);
+        assert_eq!(stops.next(10. + 128.), 10. + 192.);
+        // Fractional positions from accumulated advances
+        assert_eq!(stops.next(10. + 7. * 8. - 0.001), 10. + 64.);
+
+        let stops = TabStops { width: 4, ..stops };
+        assert_eq!(stops.next(10. + 5. * 8.), 10. + 64.);
+
+        let stops = TabStops { width: 0, ..stops };
+        assert_eq!(stops.next(10. + 5. * 8.), 10. + 48.);
+    }
 }
@@ -0,0 +1,44 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Base64 decoding for the payloads of graphics protocols.
+
+/// Decodes standard base64, skipping padding and line breaks. Returns
+/// `None` on any other byte outside the alphabet.
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &byte in input {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' | b'\n' | b'\r' => continue,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            output.push((bits >> count) as u8);
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode(b"aGk=").unwrap(), b"hi");
+        assert_eq!(decode(b"aGVs\nbG8=").unwrap(), b"hello");
+        assert!(decode(b"a*").is_none());
+    }
+}
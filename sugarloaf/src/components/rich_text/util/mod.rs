@@ -1,3 +1,4 @@
 pub mod atomic;
+pub mod base64;
 pub mod fxhash;
 pub mod string;
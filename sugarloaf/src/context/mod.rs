@@ -3,15 +3,32 @@ use crate::SugarloafRenderer;
 
 pub struct Context<'a> {
     pub device: wgpu::Device,
-    pub surface: wgpu::Surface<'a>,
+    /// `None` for contexts created with `new_headless`, which only render
+    /// into textures.
+    pub surface: Option<wgpu::Surface<'a>>,
     pub queue: wgpu::Queue,
     pub format: wgpu::TextureFormat,
+    /// Initial and maximum size of the glyph atlases, as requested in
+    /// `SugarloafRenderer`.
+    pub atlas_size: (u16, u16),
     pub size: SugarloafWindowSize,
     pub scale: f32,
     alpha_mode: wgpu::CompositeAlphaMode,
     pub adapter_info: wgpu::AdapterInfo,
 }
 
+/// Returns whether a target of `format` is linear and holds colors past
+/// 1.0, which is SDR white. The actual brightness of SDR white is up to the
+/// platform: scRGB on Windows puts it at 80 nits, EDR on macOS at the
+/// current display brightness.
+#[inline]
+pub fn is_extended_range(format: wgpu::TextureFormat) -> bool {
+    matches!(
+        format,
+        wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rgba32Float
+    )
+}
+
 #[inline]
 #[cfg(not(target_os = "macos"))]
 fn find_best_texture_format(formats: Vec<wgpu::TextureFormat>) -> wgpu::TextureFormat {
@@ -106,34 +123,16 @@ impl Context<'_> {
         #[cfg(target_os = "macos")]
         let format = wgpu::TextureFormat::Bgra8Unorm;
         #[cfg(not(target_os = "macos"))]
-        let format = find_best_texture_format(caps.formats);
-
-        let (device, queue) = (async {
-            {
-                if let Ok(result) = adapter
-                    .request_device(&wgpu::DeviceDescriptor::default(), None)
-                    .await
-                {
-                    result
-                } else {
-                    // These downlevel limits will allow the code to run on all possible hardware
-                    adapter
-                        .request_device(
-                            &wgpu::DeviceDescriptor {
-                                memory_hints: wgpu::MemoryHints::Performance,
-                                label: None,
-                                required_features: wgpu::Features::empty(),
-                                required_limits: wgpu::Limits::downlevel_webgl2_defaults(
-                                ),
-                            },
-                            None,
-                        )
-                        .await
-                        .expect("Request device")
-                }
-            }
-        })
-        .await;
+        let format = find_best_texture_format(caps.formats.clone());
+        let format = if renderer_config.enable_hdr
+            && caps.formats.contains(&wgpu::TextureFormat::Rgba16Float)
+        {
+            wgpu::TextureFormat::Rgba16Float
+        } else {
+            format
+        };
+
+        let (device, queue) = request_device(&adapter).await;
 
         let alpha_mode = if caps
             .alpha_modes
@@ -166,8 +165,12 @@ impl Context<'_> {
         Context {
             device,
             queue,
-            surface,
+            surface: Some(surface),
             format,
+            atlas_size: (
+                renderer_config.atlas_initial_size,
+                renderer_config.atlas_max_size,
+            ),
             alpha_mode,
             size: SugarloafWindowSize {
                 width: size.width,
@@ -178,10 +181,61 @@ impl Context<'_> {
         }
     }
 
+    /// Creates a context without a surface, for rendering into textures of
+    /// `format`, e.g. offscreen. Returns `None` if there is no adapter.
+    pub async fn new_headless<'a>(
+        size: SugarloafWindowSize,
+        format: wgpu::TextureFormat,
+        renderer_config: SugarloafRenderer,
+    ) -> Option<Context<'a>> {
+        let backend =
+            wgpu::util::backend_bits_from_env().unwrap_or(renderer_config.backend);
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: backend,
+            ..Default::default()
+        });
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: renderer_config.power_preference,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await?;
+
+        log::info!("Selected headless adapter: {:?}", adapter.get_info());
+
+        let (device, queue) = request_device(&adapter).await;
+
+        Some(Context {
+            device,
+            queue,
+            surface: None,
+            format,
+            atlas_size: (
+                renderer_config.atlas_initial_size,
+                renderer_config.atlas_max_size,
+            ),
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            size,
+            scale: 1.0,
+            adapter_info: adapter.get_info(),
+        })
+    }
+
+    /// Returns whether the target is an extended range one, see
+    /// `SugarloafRenderer::enable_hdr`.
+    #[inline]
+    pub fn is_hdr(&self) -> bool {
+        is_extended_range(self.format)
+    }
+
     pub fn resize(&mut self, width: u32, height: u32) {
         self.size.width = width as f32;
         self.size.height = height as f32;
-        self.surface.configure(
+        let Some(surface) = &self.surface else {
+            return;
+        };
+        surface.configure(
             &self.device,
             &wgpu::SurfaceConfiguration {
                 usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -196,3 +250,36 @@ impl Context<'_> {
         );
     }
 }
+
+async fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
+    if let Ok(result) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                // Optional, used for subpixel text and indirect draws when
+                // available
+                required_features: adapter.features()
+                    & (wgpu::Features::DUAL_SOURCE_BLENDING
+                        | wgpu::Features::INDIRECT_FIRST_INSTANCE),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+    {
+        result
+    } else {
+        // These downlevel limits will allow the code to run on all possible hardware
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    memory_hints: wgpu::MemoryHints::Performance,
+                    label: None,
+                    required_features: wgpu::Features::empty(),
+                    required_limits: wgpu::Limits::downlevel_webgl2_defaults(),
+                },
+                None,
+            )
+            .await
+            .expect("Request device")
+    }
+}
@@ -114,6 +114,24 @@ impl LayoutContext {
     pub fn clear_cache(&mut self) {
         self.cache.inner.clear();
     }
+
+    /// OpenType features applied to every run, see `set_font_features`.
+    #[inline]
+    pub fn font_features(&self) -> &[Setting<u16>] {
+        &self.state.font_features
+    }
+
+    /// Shapes every run with `features` enabled or disabled, like stylistic
+    /// sets (`ss01` to `ss20`) or contextual alternates (`calt`). Features
+    /// of a span are applied after them, and a span without ligatures still
+    /// turns `liga` and `calt` off. Cached runs were shaped with the
+    /// previous features, so the cache is cleared when they change.
+    pub fn set_font_features(&mut self, features: &[Setting<u16>]) {
+        if self.state.font_features != features {
+            self.state.font_features = features.to_vec();
+            self.clear_cache();
+        }
+    }
 }
 
 /// Builder for computing the layout of a paragraph.
@@ -329,6 +347,7 @@ impl<'a> ParagraphBuilder<'a> {
                     // || style.lang != prev_style.lang
                     || style.font_features != prev_style.font_features
                     || style.font_vars != prev_style.font_vars
+                    || style.ligatures != prev_style.ligatures
             }
         } else {
             true
@@ -594,6 +613,18 @@ impl<'a> ParagraphBuilder<'a> {
 //     }
 // }
 
+/// Features turned off for runs shaped without ligatures.
+const NO_LIGATURES: [Setting<u16>; 2] = [
+    Setting {
+        tag: swash::tag_from_bytes(b"liga"),
+        value: 0,
+    },
+    Setting {
+        tag: swash::tag_from_bytes(b"calt"),
+        value: 0,
+    },
+];
+
 #[inline]
 fn real_script(script: Script) -> bool {
     script != Script::Common && script != Script::Inherited && script != Script::Unknown
@@ -761,18 +792,28 @@ where
     }
 
     let current_font_id = state.font_id.unwrap();
+    let no_ligatures = if state.span.ligatures {
+        &[][..]
+    } else {
+        &NO_LIGATURES[..]
+    };
     let mut shaper = scx
         .builder(fonts[current_font_id].as_ref())
         .script(state.script)
         // .language(state.span.lang)
         .direction(dir)
         .size(state.size)
+        .features(state.state.font_features.iter().copied())
         .features(state.features.iter().copied())
+        .features(no_ligatures.iter().copied())
         .variations(state.synth.variations().iter().copied())
         .variations(state.vars.iter().copied())
         .build();
 
     let mut synth = Synthesis::default();
+    // Source offset and first character of every cluster added, see
+    // `Cluster::code_point`
+    let mut code_points = Vec::new();
     loop {
         // for c in cluster.chars().iter() {
         //     let width = c.ch.width().unwrap_or(1);
@@ -780,6 +821,9 @@ where
         //         println!("{:?} {}", c, c.ch.width().unwrap_or(1));
         //     }
         // }
+        if let Some(first) = cluster.chars().first() {
+            code_points.push((cluster.range().start, first.ch));
+        }
         shaper.add_cluster(cluster);
 
         if !parser.next(cluster) {
@@ -790,6 +834,7 @@ where
                 state.level,
                 current_line as u32,
                 state.state.lines[current_line].hash,
+                &code_points,
                 shaper,
             );
             return false;
@@ -817,6 +862,7 @@ where
                 state.level,
                 current_line as u32,
                 state.state.lines[current_line].hash,
+                &code_points,
                 shaper,
             );
             state.font_id = next_font;
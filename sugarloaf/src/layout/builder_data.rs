@@ -84,6 +84,9 @@ pub struct BuilderState {
     pub features: FontSettingCache<u16>,
     /// Font variation setting cache.
     pub vars: FontSettingCache<f32>,
+    /// OpenType features applied to every run, before those of its span,
+    /// e.g. `ss19` for a slashed zero. Kept across paragraphs.
+    pub font_features: Vec<Setting<u16>>,
     /// User specified scale.
     pub scale: f32,
 }
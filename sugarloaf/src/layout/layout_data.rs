@@ -31,6 +31,8 @@ pub struct ClusterData {
     pub len: u8,
     /// Offset of the cluster in the source text.
     pub offset: u32,
+    /// First character of the cluster in the source text.
+    pub code_point: Option<char>,
     /// Depending on `flags`, either an index into `glyphs` or an index
     /// into `detailed_clusters`
     pub glyphs: u32,
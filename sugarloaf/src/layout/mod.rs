@@ -23,6 +23,8 @@ pub use render_data::RenderData;
 
 #[doc(inline)]
 pub use swash::text::Language;
+#[doc(inline)]
+pub use swash::Setting;
 
 /// Iterators over elements of a paragraph.
 pub mod iter {
@@ -13,6 +13,7 @@
 use super::layout_data::*;
 use super::line_breaker::BreakLines;
 use super::Direction;
+use crate::components::rich_text::text::Frame;
 use crate::font::{
     Style, Weight, FONT_ID_BOLD, FONT_ID_BOLD_ITALIC, FONT_ID_ITALIC, FONT_ID_REGULAR,
 };
@@ -83,6 +84,8 @@ pub struct CachedClusterData {
     pub len: u8,
     /// Offset of the cluster in the source text.
     pub offset: u32,
+    /// First character of the cluster in the source text.
+    pub code_point: Option<char>,
     /// Depending on `flags`, either an index into `glyphs` or an index
     /// into `detailed_clusters`
     pub glyphs: Vec<GlyphData>,
@@ -153,6 +156,7 @@ impl RenderData {
                     flags: cached_cluster.flags,
                     len: cached_cluster.len,
                     offset: cached_cluster.offset,
+                    code_point: cached_cluster.code_point,
                     glyphs: glyphs_start,
                 });
             }
@@ -190,6 +194,7 @@ impl RenderData {
         level: u8,
         line: u32,
         hash: Option<u64>,
+        code_points: &[(u32, char)],
         shaper: Shaper<'_>,
     ) {
         // In case is a new line,
@@ -211,8 +216,15 @@ impl RenderData {
         let mut advance = 0.;
         let mut last_span = self.data.last_span;
         let mut span_data = &styles[last_span];
+        // Clusters come out in the order they were added, except for the
+        // ones merged into ligatures
+        let mut code_points = code_points.iter();
 
         shaper.shape_with(|c| {
+            let code_point = code_points
+                .by_ref()
+                .find(|(start, _)| *start == c.source.start)
+                .map(|(_, ch)| *ch);
             if c.info.boundary() == Boundary::Mandatory {
                 if let Some(c) = self.data.clusters.last_mut() {
                     c.flags |= CLUSTER_NEWLINE;
@@ -268,6 +280,7 @@ impl RenderData {
                             flags: current_cluster.flags,
                             len: current_cluster.len,
                             offset: current_cluster.offset,
+                            code_point: current_cluster.code_point,
                             glyphs: glyphs_data.to_vec(),
                             details: detailed_clusters,
                         });
@@ -322,6 +335,7 @@ impl RenderData {
                     flags: base_flags | CLUSTER_DETAILED,
                     len,
                     offset: c.source.start,
+                    code_point,
                     glyphs: detail_index,
                 };
                 self.data.clusters.push(cluster);
@@ -337,6 +351,7 @@ impl RenderData {
                     flags,
                     len,
                     offset: c.source.start,
+                    code_point,
                     glyphs: glyphs_start,
                 };
                 self.data.clusters.push(cluster);
@@ -349,6 +364,7 @@ impl RenderData {
                         flags: CLUSTER_CONTINUATION | CLUSTER_EMPTY,
                         len: (component.end - component.start) as u8,
                         offset: component.start,
+                        code_point: None,
                         glyphs: component_advance.to_bits(),
                     };
                     self.data.clusters.push(cluster);
@@ -405,6 +421,7 @@ impl RenderData {
                 flags: current_cluster.flags,
                 len: current_cluster.len,
                 offset: current_cluster.offset,
+                code_point: current_cluster.code_point,
                 glyphs: glyphs_data.to_vec(),
                 details: detailed_clusters,
             });
@@ -514,6 +531,12 @@ impl<'a> Run<'a> {
         self.run.span
     }
 
+    /// Returns true if the run is bold.
+    #[inline]
+    pub fn is_bold(&self) -> bool {
+        self.run.span.font_attrs.1 == Weight::BOLD
+    }
+
     #[inline]
     pub fn font_id_based_on_attr(&self) -> usize {
         let is_italic = self.run.span.font_attrs.2 == Style::Italic;
@@ -548,6 +571,18 @@ impl<'a> Run<'a> {
         self.run.span.color
     }
 
+    /// Returns true if the run is selected.
+    #[inline]
+    pub fn selected(&self) -> bool {
+        self.run.span.selected
+    }
+
+    /// Returns the palette index the color of the run was resolved from.
+    #[inline]
+    pub fn ansi_index(&self) -> Option<u8> {
+        self.run.span.ansi_index
+    }
+
     #[inline]
     pub fn char_width(&self) -> f32 {
         self.run.span.width
@@ -565,6 +600,12 @@ impl<'a> Run<'a> {
         self.run.span.cursor
     }
 
+    /// Returns true if the run was shaped with ligatures.
+    #[inline]
+    pub fn ligatures(&self) -> bool {
+        self.run.span.ligatures
+    }
+
     /// Returns the direction of the run.
     pub fn direction(&self) -> Direction {
         if self.run.level & 1 != 0 {
@@ -594,6 +635,12 @@ impl<'a> Run<'a> {
         self.run.span.background_color
     }
 
+    /// Returns the background of the terminal behind the run.
+    #[inline]
+    pub fn default_background(&self) -> [f32; 4] {
+        self.run.span.default_background
+    }
+
     /// Returns true if the run has an underline decoration.
     #[inline]
     pub fn underline(&self) -> bool {
@@ -622,6 +669,50 @@ impl<'a> Run<'a> {
             .unwrap_or(self.run.strikeout_size)
     }
 
+    /// Returns true if the underline of the run is the cursor.
+    #[inline]
+    pub fn underline_cursor(&self) -> bool {
+        self.run.span.underline_cursor
+    }
+
+    /// Returns true if the underline of the run ends at its last glyph with
+    /// ink.
+    #[inline]
+    pub fn underline_skip_trailing_whitespace(&self) -> bool {
+        self.run.span.underline_skip_trailing_whitespace
+    }
+
+    /// Returns true if the run has a strikethrough decoration.
+    #[inline]
+    pub fn strikethrough(&self) -> bool {
+        self.run.span.strikethrough
+    }
+
+    /// Returns the strikethrough offset above the baseline for the run.
+    #[inline]
+    pub fn strikethrough_offset(&self) -> f32 {
+        self.run.strikeout_offset
+    }
+
+    /// Returns the strikethrough size for the run.
+    #[inline]
+    pub fn strikethrough_size(&self) -> f32 {
+        self.run.strikeout_size
+    }
+
+    /// Returns the strikethrough color for the run, if it differs from the
+    /// text color.
+    #[inline]
+    pub fn strikethrough_color(&self) -> Option<[f32; 4]> {
+        self.run.span.strikethrough_color
+    }
+
+    /// Returns the border drawn around the run.
+    #[inline]
+    pub fn frame(&self) -> Option<Frame> {
+        self.run.span.frame
+    }
+
     /// Returns an iterator over the clusters in logical order.
     #[inline]
     pub fn clusters(&self) -> Clusters<'a> {
@@ -761,6 +852,12 @@ impl<'a> Cluster<'a> {
         self.cluster.info
     }
 
+    /// Returns the first character of the cluster in the source text.
+    #[inline]
+    pub fn code_point(&self) -> Option<char> {
+        self.cluster.code_point
+    }
+
     /// Returns true if the cluster is empty. This occurs when ignorable
     /// glyphs are removed by the shaper.
     #[inline]
@@ -9,6 +9,7 @@
 // This file however suffered updates made by Raphael Amorim to support
 // underline_color, background_color, text color and other functionalities
 
+use crate::components::rich_text::text::Frame;
 use crate::layout::builder_data::FontSettingKey;
 use crate::layout::builder_data::EMPTY_FONT_SETTINGS;
 use crate::sugarloaf::primitives::SugarCursor;
@@ -36,12 +37,21 @@ pub struct FragmentStyle {
     pub font_size: f32,
     /// Font color.
     pub color: [f32; 4],
+    /// Palette index `color` was resolved from, when it is an ANSI color.
+    /// See `TextRunStyle::ansi_index`.
+    pub ansi_index: Option<u8>,
     /// Background color.
     pub background_color: Option<[f32; 4]>,
+    /// Background of the terminal behind the span, see
+    /// `TextRunStyle::default_background`.
+    pub default_background: [f32; 4],
     /// Font features.
     pub font_features: FontSettingKey,
     /// Font variations.
     pub font_vars: FontSettingKey,
+    /// Shape with the font's ligatures and contextual alternates, `liga` and
+    /// `calt`. Off keeps `!=` as two glyphs.
+    pub ligatures: bool,
     /// Additional spacing between letters (clusters) of text.
     pub letter_spacing: f32,
     /// Additional spacing between words of text.
@@ -56,10 +66,23 @@ pub struct FragmentStyle {
     pub underline_color: Option<[f32; 4]>,
     /// Thickness of an underline.
     pub underline_size: Option<f32>,
+    /// The underline is the cursor, see `UnderlineStyle::cursor`.
+    pub underline_cursor: bool,
+    /// End the underline at the last glyph with ink, see
+    /// `TextRunStyle::underline_skip_trailing_whitespace`.
+    pub underline_skip_trailing_whitespace: bool,
+    /// Enable strikethrough decoration.
+    pub strikethrough: bool,
+    /// Color of a strikethrough, the font color when `None`.
+    pub strikethrough_color: Option<[f32; 4]>,
+    /// Border drawn around the span, see `TextRunStyle::frame`.
+    pub frame: Option<Frame>,
     /// Text case transformation.
     // pub text_transform: TextTransform,
     /// Cursor
     pub cursor: SugarCursor,
+    /// The span is selected, see `TextRunStyle::selected`.
+    pub selected: bool,
 }
 
 impl Default for FragmentStyle {
@@ -74,16 +97,25 @@ impl Default for FragmentStyle {
             font_size: 16.,
             font_features: EMPTY_FONT_SETTINGS,
             font_vars: EMPTY_FONT_SETTINGS,
+            ligatures: true,
             letter_spacing: 0.,
             word_spacing: 0.,
             line_spacing: 1.,
             color: [1.0, 1.0, 1.0, 1.0],
+            ansi_index: None,
             background_color: None,
+            default_background: [0.0; 4],
             cursor: SugarCursor::Disabled,
+            selected: false,
             underline: false,
             underline_offset: None,
             underline_color: None,
             underline_size: None,
+            underline_cursor: false,
+            underline_skip_trailing_whitespace: false,
+            strikethrough: false,
+            strikethrough_color: None,
+            frame: None,
             // text_transform: TextTransform::None,
         }
     }
@@ -101,16 +133,25 @@ impl FragmentStyle {
             font_size: 16. * scale,
             font_features: EMPTY_FONT_SETTINGS,
             font_vars: EMPTY_FONT_SETTINGS,
+            ligatures: true,
             letter_spacing: 0.,
             word_spacing: 0.,
             line_spacing: 1.,
             color: [1.0, 1.0, 1.0, 1.0],
+            ansi_index: None,
             background_color: None,
+            default_background: [0.0; 4],
             cursor: SugarCursor::Disabled,
+            selected: false,
             underline: false,
             underline_offset: None,
             underline_color: None,
             underline_size: None,
+            underline_cursor: false,
+            underline_skip_trailing_whitespace: false,
+            strikethrough: false,
+            strikethrough_color: None,
+            frame: None,
             // text_transform: TextTransform::None,
         }
     }
@@ -140,7 +181,8 @@ impl From<&Sugar> for FragmentStyle {
                 style.underline = true;
                 style.underline_offset = Some(-1.);
                 style.underline_color = Some(cursor_color);
-                style.underline_size = Some(-1.);
+                style.underline_size = Some(1.);
+                style.underline_cursor = true;
 
                 has_underline_cursor = true;
             }
@@ -162,9 +204,7 @@ impl From<&Sugar> for FragmentStyle {
                 }
             }
             SugarDecoration::Strikethrough => {
-                style.underline = true;
-                style.underline_offset = Some(style.font_size / 2.);
-                style.underline_size = Some(2.);
+                style.strikethrough = true;
             }
             _ => {}
         }
@@ -1,3 +1,6 @@
+#[macro_use]
+mod trace;
+
 pub mod components;
 pub mod context;
 pub mod font;
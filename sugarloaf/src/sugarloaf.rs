@@ -7,12 +7,12 @@ mod tree;
 use crate::components::core::{image::Handle, shapes::Rectangle};
 use crate::components::layer::{self, LayerBrush};
 use crate::components::rect::{Rect, RectBrush};
-use crate::components::rich_text::RichTextBrush;
+use crate::components::rich_text::{RichTextBrush, RichTextBrushConfig};
 use crate::components::text;
 use crate::context::Context;
 use crate::font::fonts::SugarloafFont;
 use crate::font::FontLibrary;
-use crate::layout::SugarloafLayout;
+use crate::layout::{Setting, SugarloafLayout};
 use crate::sugarloaf::layer::types;
 use crate::Sugar;
 use crate::{SugarBlock, SugarText};
@@ -67,6 +67,19 @@ pub struct SugarloafWindow {
 pub struct SugarloafRenderer {
     pub power_preference: wgpu::PowerPreference,
     pub backend: wgpu::Backends,
+    /// Picks an `Rgba16Float` target when the surface supports it, so colors
+    /// brighter than SDR white, i.e. past 1.0, are displayed on HDR screens.
+    /// Colors are still given sRGB encoded, the rich text brush linearizes
+    /// them for the float target. See `context::is_extended_range` for what
+    /// 1.0 maps to. Display P3 text runs keep their full gamut on it.
+    pub enable_hdr: bool,
+    /// Size the glyph atlases start at, in pixels. They grow toward
+    /// `atlas_max_size` as glyphs are added, so starting small saves memory
+    /// on constrained devices at the cost of repacking as they grow.
+    pub atlas_initial_size: u16,
+    /// Size the glyph atlases grow to before more atlases are added. Both
+    /// sizes are clamped to the device's `max_texture_dimension_2d`.
+    pub atlas_max_size: u16,
 }
 
 impl Default for SugarloafRenderer {
@@ -79,6 +92,9 @@ impl Default for SugarloafRenderer {
         SugarloafRenderer {
             power_preference: wgpu::PowerPreference::HighPerformance,
             backend: default_backend,
+            enable_hdr: false,
+            atlas_initial_size: 2048,
+            atlas_max_size: 2048,
         }
     }
 }
@@ -127,7 +143,12 @@ impl Sugarloaf<'_> {
 
         let rect_brush = RectBrush::init(&ctx);
         let layer_brush = LayerBrush::new(&ctx);
-        let rich_text_brush = RichTextBrush::new(&ctx);
+        let (rich_text_brush, fallbacks) =
+            RichTextBrush::new(&ctx, &RichTextBrushConfig::default())
+                .unwrap_or_else(|error| panic!("{error}"));
+        if !fallbacks.is_empty() {
+            log::warn!("rich_text: unsupported features {:?}", fallbacks);
+        }
 
         let state = SugarState::new(layout, font_library);
 
@@ -154,6 +175,14 @@ impl Sugarloaf<'_> {
         self.state.set_fonts(font_library);
     }
 
+    /// Enables or disables OpenType features, e.g. stylistic sets or
+    /// contextual alternates, for all text. Takes effect on the next layout.
+    #[inline]
+    pub fn set_font_features(&mut self, features: &[Setting<u16>]) {
+        self.state.compositors.advanced.set_font_features(features);
+        self.rich_text_brush.clear_layout_cache();
+    }
+
     #[inline]
     pub fn get_context(&self) -> &Context {
         &self.ctx
@@ -261,12 +290,14 @@ impl Sugarloaf<'_> {
     pub fn resize(&mut self, width: u32, height: u32) {
         self.ctx.resize(width, height);
         self.state.compute_layout_resize(width, height);
+        self.rich_text_brush.resize(width as f32, height as f32);
     }
 
     #[inline]
     pub fn rescale(&mut self, scale: f32) {
         self.ctx.scale = scale;
         self.state.compute_layout_rescale(scale);
+        self.rich_text_brush.set_scale(scale);
     }
 
     #[inline]
@@ -309,7 +340,12 @@ impl Sugarloaf<'_> {
             return;
         }
 
-        match self.ctx.surface.get_current_texture() {
+        // Headless contexts have nothing to present to
+        let Some(surface) = &self.ctx.surface else {
+            self.clean_state();
+            return;
+        };
+        match surface.get_current_texture() {
             Ok(frame) => {
                 let mut encoder = self.ctx.device.create_command_encoder(
                     &wgpu::CommandEncoderDescriptor { label: None },
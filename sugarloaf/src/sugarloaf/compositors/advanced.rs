@@ -13,6 +13,7 @@ use crate::layout::{
     Content, ContentBuilder, Direction, FragmentStyle, LayoutContext, RenderData,
 };
 use crate::sugarloaf::tree::SugarTree;
+use swash::Setting;
 use unicode_width::UnicodeWidthChar;
 
 pub struct Advanced {
@@ -54,7 +55,14 @@ impl Advanced {
 
     #[inline]
     pub fn set_fonts(&mut self, fonts: &FontLibrary) {
+        let features = self.layout_context.font_features().to_vec();
         self.layout_context = LayoutContext::new(fonts);
+        self.layout_context.set_font_features(&features);
+    }
+
+    #[inline]
+    pub fn set_font_features(&mut self, features: &[Setting<u16>]) {
+        self.layout_context.set_font_features(features);
     }
 
     #[inline]
@@ -0,0 +1,32 @@
+//! Diagnostics of the expensive parts of rendering, e.g. glyph
+//! rasterization, atlas growth and buffer reallocation, emitted with
+//! `tracing` under the `trace` feature. Without it the macros expand to
+//! nothing, so their arguments aren't even evaluated.
+
+/// Emits a `tracing` event at `$level`, e.g.
+/// `trace_event!(debug, size, "atlas grew")`.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "trace")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+/// Enters a `tracing` span at `$level` until the end of the enclosing
+/// block, e.g. `trace_span!(trace, "rasterize_glyph", id)`.
+macro_rules! trace_span {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "trace")]
+        let _span = tracing::span!(trace_level!($level), $($arg)+).entered();
+    };
+}
+
+#[cfg(feature = "trace")]
+macro_rules! trace_level {
+    (trace) => {
+        tracing::Level::TRACE
+    };
+    (debug) => {
+        tracing::Level::DEBUG
+    };
+}